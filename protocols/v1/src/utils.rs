@@ -18,6 +18,12 @@ impl HexBytes {
     }
 }
 
+impl From<Vec<u8>> for HexBytes {
+    fn from(v: Vec<u8>) -> Self {
+        HexBytes(v)
+    }
+}
+
 impl From<HexBytes> for Value {
     fn from(eb: HexBytes) -> Self {
         Into::<String>::into(eb).into()
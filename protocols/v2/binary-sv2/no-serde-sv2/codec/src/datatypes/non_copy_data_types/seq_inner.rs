@@ -31,6 +31,28 @@ impl<'a, const SIZE: usize> Seq0255<'a, super::inner::Inner<'a, true, SIZE, 0, 0
     }
 }
 
+// TODO add test for that and implement it also with serde!!!!
+impl<'a, const SIZE: usize, const HEADERSIZE: usize, const MAXSIZE: usize>
+    Seq064K<'a, super::inner::Inner<'a, false, SIZE, HEADERSIZE, MAXSIZE>>
+{
+    pub fn to_vec(&self) -> Vec<Vec<u8>> {
+        self.0.iter().map(|x| x.to_vec()).collect()
+    }
+    pub fn inner_as_ref(&self) -> Vec<&[u8]> {
+        self.0.iter().map(|x| x.inner_as_ref()).collect()
+    }
+}
+
+// TODO add test for that and implement it also with serde!!!!
+impl<'a, const SIZE: usize> Seq064K<'a, super::inner::Inner<'a, true, SIZE, 0, 0>> {
+    pub fn to_vec(&self) -> Vec<Vec<u8>> {
+        self.0.iter().map(|x| x.to_vec()).collect()
+    }
+    pub fn inner_as_ref(&self) -> Vec<&[u8]> {
+        self.0.iter().map(|x| x.inner_as_ref()).collect()
+    }
+}
+
 #[cfg(not(feature = "no_std"))]
 use std::io::Read;
 
@@ -303,6 +325,13 @@ impl<'a, T: Fixed> Seq064K<'a, T> {
         // Safe unwrap cause the initial value is a valid Seq064K
         Seq064K::new(self.0).unwrap()
     }
+
+    pub fn to_vec(&self) -> Vec<T>
+    where
+        T: Copy,
+    {
+        self.0.clone()
+    }
 }
 
 impl<'a, const ISFIXED: bool, const SIZE: usize, const HEADERSIZE: usize, const MAXSIZE: usize>
@@ -102,7 +102,8 @@ pub trait IsMiningUpstream<Down: IsMiningDownstream, Sel: DownstreamMiningSelect
     IsUpstream<Down, Sel>
 {
     fn total_hash_rate(&self) -> u64;
-    fn add_hash_rate(&mut self, to_add: u64);
+    /// `to_add` is negative when hash rate is removed (e.g. a channel is closed)
+    fn add_hash_rate(&mut self, to_add: i64);
     fn get_opened_channels(&mut self) -> &mut Vec<UpstreamChannel>;
     fn update_channels(&mut self, c: UpstreamChannel);
     fn is_header_only(&self) -> bool {
@@ -159,7 +160,7 @@ impl<Down: IsMiningDownstream + D> IsMiningUpstream<Down, NullDownstreamMiningSe
         unreachable!("Null selector do not have hash rate");
     }
 
-    fn add_hash_rate(&mut self, _to_add: u64) {
+    fn add_hash_rate(&mut self, _to_add: i64) {
         unreachable!("Null selector can not add hash rate");
     }
     fn get_opened_channels(&mut self) -> &mut Vec<UpstreamChannel> {
@@ -201,6 +202,11 @@ impl RequestIdMapper {
         new_id
     }
 
+    /// Returns the downstream id mapped to `upstream_id`.
+    pub fn get(&self, upstream_id: u32) -> Option<u32> {
+        self.request_ids_map.get(&upstream_id).copied()
+    }
+
     /// Removes a upstream/downstream mapping from the `RequsetIdMapper`.
     pub fn remove(&mut self, upstream_id: u32) -> Option<u32> {
         self.request_ids_map.remove(&upstream_id)
@@ -250,6 +256,15 @@ mod tests {
         assert!(request_id_mapper.request_ids_map.is_empty());
     }
 
+    #[test]
+    fn gets_downstream_id_from_request_id_mapper() {
+        let mut request_id_mapper = RequestIdMapper::new();
+        let upstream_id = request_id_mapper.on_open_channel(7);
+
+        assert_eq!(request_id_mapper.get(upstream_id), Some(7));
+        assert_eq!(request_id_mapper.get(upstream_id + 1), None);
+    }
+
     #[test]
     fn downstream_channel_returns_group_id_on_receiving_standard_channel() {
         let expect = 0;
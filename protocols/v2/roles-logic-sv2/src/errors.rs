@@ -18,6 +18,11 @@ pub enum Error {
     RequestIdNotMapped(u32),
     NoUpstreamsConnected,
     UnknownRequestId(u32),
+    /// SetCustomMiningJob.coinbase_prefix is not a valid BIP34 block height
+    InvalidCoinbasePrefix,
+    InvalidCoinbaseOutputs,
+    /// JobsCreators need at least one recipient and the sum of the shares must be greater than 0
+    InvalidCoinbaseRecipients,
 }
 
 impl From<BinarySv2Error> for Error {
@@ -59,6 +64,12 @@ impl Display for Error {
                 before relaying open channel request to upstream",
                 id
             ),
+            InvalidCoinbasePrefix => write!(f, "Invalid coinbase prefix"),
+            InvalidCoinbaseOutputs => write!(f, "Invalid coinbase outputs"),
+            InvalidCoinbaseRecipients => write!(
+                f,
+                "Coinbase recipients must be not empty and the sum of the shares greater than 0"
+            ),
         }
     }
 }
@@ -0,0 +1,266 @@
+use super::SendTo_;
+use crate::{errors::Error, parsers::JobNegotiation, utils::Mutex};
+use job_negotiation_sv2::{
+    AllocateMiningJobToken, AllocateMiningJobTokenSuccess, CommitMiningJob, CommitMiningJobError,
+    CommitMiningJobSuccess, IdentifyTransactions, IdentifyTransactionsSuccess,
+    ProvideMissingTransactions, ProvideMissingTransactionsSuccess,
+};
+
+pub type SendTo = SendTo_<JobNegotiation<'static>, ()>;
+use core::convert::TryInto;
+use std::sync::Arc;
+
+/// Implemented by the Job Negotiator in order to handle the messages sent by the pool
+pub trait ParseServerJobNegotiationMessages
+where
+    Self: Sized,
+{
+    fn handle_message_job_negotiation(
+        self_: Arc<Mutex<Self>>,
+        message_type: u8,
+        payload: &mut [u8],
+    ) -> Result<SendTo, Error> {
+        // Is ok to unwrap a safe_lock result
+        match (message_type, payload).try_into() {
+            Ok(JobNegotiation::AllocateMiningJobTokenSuccess(m)) => self_
+                .safe_lock(|x| x.handle_allocate_mining_job_token_success(m))
+                .unwrap(),
+            Ok(JobNegotiation::CommitMiningJobSuccess(m)) => self_
+                .safe_lock(|x| x.handle_commit_mining_job_success(m))
+                .unwrap(),
+            Ok(JobNegotiation::CommitMiningJobError(m)) => self_
+                .safe_lock(|x| x.handle_commit_mining_job_error(m))
+                .unwrap(),
+            Ok(JobNegotiation::IdentifyTransactions(m)) => self_
+                .safe_lock(|x| x.handle_identify_transactions(m))
+                .unwrap(),
+            Ok(JobNegotiation::ProvideMissingTransactions(m)) => self_
+                .safe_lock(|x| x.handle_provide_missing_transactions(m))
+                .unwrap(),
+            Ok(JobNegotiation::AllocateMiningJobToken(_)) => Err(Error::UnexpectedMessage),
+            Ok(JobNegotiation::CommitMiningJob(_)) => Err(Error::UnexpectedMessage),
+            Ok(JobNegotiation::IdentifyTransactionsSuccess(_)) => Err(Error::UnexpectedMessage),
+            Ok(JobNegotiation::ProvideMissingTransactionsSuccess(_)) => {
+                Err(Error::UnexpectedMessage)
+            }
+            Err(e) => Err(e),
+        }
+    }
+    fn handle_allocate_mining_job_token_success(
+        &mut self,
+        m: AllocateMiningJobTokenSuccess,
+    ) -> Result<SendTo, Error>;
+    fn handle_commit_mining_job_success(
+        &mut self,
+        m: CommitMiningJobSuccess,
+    ) -> Result<SendTo, Error>;
+    fn handle_commit_mining_job_error(&mut self, m: CommitMiningJobError) -> Result<SendTo, Error>;
+    fn handle_identify_transactions(&mut self, m: IdentifyTransactions) -> Result<SendTo, Error>;
+    fn handle_provide_missing_transactions(
+        &mut self,
+        m: ProvideMissingTransactions,
+    ) -> Result<SendTo, Error>;
+}
+
+/// Implemented by the pool in order to handle the messages sent by the Job Negotiator
+pub trait ParseClientJobNegotiationMessages
+where
+    Self: Sized,
+{
+    fn handle_message_job_negotiation(
+        self_: Arc<Mutex<Self>>,
+        message_type: u8,
+        payload: &mut [u8],
+    ) -> Result<SendTo, Error> {
+        // Is ok to unwrap a safe_lock result
+        match (message_type, payload).try_into() {
+            Ok(JobNegotiation::AllocateMiningJobToken(m)) => self_
+                .safe_lock(|x| x.handle_allocate_mining_job_token(m))
+                .unwrap(),
+            Ok(JobNegotiation::CommitMiningJob(m)) => {
+                self_.safe_lock(|x| x.handle_commit_mining_job(m)).unwrap()
+            }
+            Ok(JobNegotiation::IdentifyTransactionsSuccess(m)) => self_
+                .safe_lock(|x| x.handle_identify_transactions_success(m))
+                .unwrap(),
+            Ok(JobNegotiation::ProvideMissingTransactionsSuccess(m)) => self_
+                .safe_lock(|x| x.handle_provide_missing_transactions_success(m))
+                .unwrap(),
+            Ok(JobNegotiation::AllocateMiningJobTokenSuccess(_)) => Err(Error::UnexpectedMessage),
+            Ok(JobNegotiation::CommitMiningJobSuccess(_)) => Err(Error::UnexpectedMessage),
+            Ok(JobNegotiation::CommitMiningJobError(_)) => Err(Error::UnexpectedMessage),
+            Ok(JobNegotiation::IdentifyTransactions(_)) => Err(Error::UnexpectedMessage),
+            Ok(JobNegotiation::ProvideMissingTransactions(_)) => Err(Error::UnexpectedMessage),
+            Err(e) => Err(e),
+        }
+    }
+    fn handle_allocate_mining_job_token(
+        &mut self,
+        m: AllocateMiningJobToken,
+    ) -> Result<SendTo, Error>;
+    fn handle_commit_mining_job(&mut self, m: CommitMiningJob) -> Result<SendTo, Error>;
+    fn handle_identify_transactions_success(
+        &mut self,
+        m: IdentifyTransactionsSuccess,
+    ) -> Result<SendTo, Error>;
+    fn handle_provide_missing_transactions_success(
+        &mut self,
+        m: ProvideMissingTransactionsSuccess,
+    ) -> Result<SendTo, Error>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use const_sv2::{
+        MESSAGE_TYPE_ALLOCATE_MINING_JOB_SUCCESS, MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN,
+    };
+
+    #[derive(Default)]
+    struct Negotiator {
+        handled: Vec<&'static str>,
+    }
+
+    impl ParseServerJobNegotiationMessages for Negotiator {
+        fn handle_allocate_mining_job_token_success(
+            &mut self,
+            _: AllocateMiningJobTokenSuccess,
+        ) -> Result<SendTo, Error> {
+            self.handled.push("allocate_mining_job_token_success");
+            Ok(SendTo::None(None))
+        }
+        fn handle_commit_mining_job_success(
+            &mut self,
+            _: CommitMiningJobSuccess,
+        ) -> Result<SendTo, Error> {
+            self.handled.push("commit_mining_job_success");
+            Ok(SendTo::None(None))
+        }
+        fn handle_commit_mining_job_error(
+            &mut self,
+            _: CommitMiningJobError,
+        ) -> Result<SendTo, Error> {
+            self.handled.push("commit_mining_job_error");
+            Ok(SendTo::None(None))
+        }
+        fn handle_identify_transactions(
+            &mut self,
+            _: IdentifyTransactions,
+        ) -> Result<SendTo, Error> {
+            self.handled.push("identify_transactions");
+            Ok(SendTo::None(None))
+        }
+        fn handle_provide_missing_transactions(
+            &mut self,
+            _: ProvideMissingTransactions,
+        ) -> Result<SendTo, Error> {
+            self.handled.push("provide_missing_transactions");
+            Ok(SendTo::None(None))
+        }
+    }
+
+    #[derive(Default)]
+    struct Pool {
+        handled: Vec<&'static str>,
+    }
+
+    impl ParseClientJobNegotiationMessages for Pool {
+        fn handle_allocate_mining_job_token(
+            &mut self,
+            _: AllocateMiningJobToken,
+        ) -> Result<SendTo, Error> {
+            self.handled.push("allocate_mining_job_token");
+            Ok(SendTo::None(None))
+        }
+        fn handle_commit_mining_job(&mut self, _: CommitMiningJob) -> Result<SendTo, Error> {
+            self.handled.push("commit_mining_job");
+            Ok(SendTo::None(None))
+        }
+        fn handle_identify_transactions_success(
+            &mut self,
+            _: IdentifyTransactionsSuccess,
+        ) -> Result<SendTo, Error> {
+            self.handled.push("identify_transactions_success");
+            Ok(SendTo::None(None))
+        }
+        fn handle_provide_missing_transactions_success(
+            &mut self,
+            _: ProvideMissingTransactionsSuccess,
+        ) -> Result<SendTo, Error> {
+            self.handled.push("provide_missing_transactions_success");
+            Ok(SendTo::None(None))
+        }
+    }
+
+    fn allocate_mining_job_token() -> Vec<u8> {
+        binary_sv2::to_bytes(AllocateMiningJobToken {
+            user_identifier: "user".to_string().into_bytes().try_into().unwrap(),
+            request_id: 1,
+        })
+        .unwrap()
+    }
+
+    fn allocate_mining_job_token_success() -> Vec<u8> {
+        binary_sv2::to_bytes(AllocateMiningJobTokenSuccess {
+            request_id: 1,
+            mining_job_token: 2,
+            coinbase_output_max_additional_size: 100,
+            async_mining_allowed: true,
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn server_message_reaches_the_negotiator_handler() {
+        let negotiator = Arc::new(Mutex::new(Negotiator::default()));
+        let mut payload = allocate_mining_job_token_success();
+        let result = Negotiator::handle_message_job_negotiation(
+            negotiator.clone(),
+            MESSAGE_TYPE_ALLOCATE_MINING_JOB_SUCCESS,
+            &mut payload,
+        );
+        assert!(matches!(result, Ok(SendTo::None(None))));
+        let handled = negotiator.safe_lock(|n| n.handled.clone()).unwrap();
+        assert_eq!(handled, vec!["allocate_mining_job_token_success"]);
+    }
+
+    #[test]
+    fn client_message_is_unexpected_for_the_negotiator() {
+        let negotiator = Arc::new(Mutex::new(Negotiator::default()));
+        let mut payload = allocate_mining_job_token();
+        let result = Negotiator::handle_message_job_negotiation(
+            negotiator.clone(),
+            MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN,
+            &mut payload,
+        );
+        assert!(matches!(result, Err(Error::UnexpectedMessage)));
+        assert!(negotiator.safe_lock(|n| n.handled.is_empty()).unwrap());
+    }
+
+    #[test]
+    fn client_message_reaches_the_pool_handler() {
+        let pool = Arc::new(Mutex::new(Pool::default()));
+        let mut payload = allocate_mining_job_token();
+        let result = Pool::handle_message_job_negotiation(
+            pool.clone(),
+            MESSAGE_TYPE_ALLOCATE_MINING_JOB_TOKEN,
+            &mut payload,
+        );
+        assert!(matches!(result, Ok(SendTo::None(None))));
+        let handled = pool.safe_lock(|p| p.handled.clone()).unwrap();
+        assert_eq!(handled, vec!["allocate_mining_job_token"]);
+    }
+
+    #[test]
+    fn server_message_is_unexpected_for_the_pool() {
+        let pool = Arc::new(Mutex::new(Pool::default()));
+        let mut payload = allocate_mining_job_token_success();
+        let result = Pool::handle_message_job_negotiation(
+            pool.clone(),
+            MESSAGE_TYPE_ALLOCATE_MINING_JOB_SUCCESS,
+            &mut payload,
+        );
+        assert!(matches!(result, Err(Error::UnexpectedMessage)));
+        assert!(pool.safe_lock(|p| p.handled.is_empty()).unwrap());
+    }
+}
@@ -115,6 +115,9 @@ pub trait ParseDownstreamMiningMessages<
                     .safe_lock(|self_| self_.handle_update_channel(m))
                     .unwrap(),
             },
+            Ok(Mining::CloseChannel(m)) => self_mutex
+                .safe_lock(|self_| self_.handle_close_channel(m))
+                .unwrap(),
             Ok(Mining::SubmitSharesStandard(m)) => match channel_type {
                 SupportedChannelTypes::Standard => self_mutex
                     .safe_lock(|self_| self_.handle_submit_shares_standard(m))
@@ -169,6 +172,8 @@ pub trait ParseDownstreamMiningMessages<
 
     fn handle_update_channel(&mut self, m: UpdateChannel) -> Result<SendTo<Up>, Error>;
 
+    fn handle_close_channel(&mut self, m: CloseChannel) -> Result<SendTo<Up>, Error>;
+
     fn handle_submit_shares_standard(
         &mut self,
         m: SubmitSharesStandard,
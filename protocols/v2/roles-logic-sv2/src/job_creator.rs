@@ -5,19 +5,22 @@ use bitcoin::{
         script::Script,
         transaction::{OutPoint, Transaction, TxIn, TxOut},
     },
-    util::psbt::serialize::Serialize,
+    consensus::Decodable,
+    util::psbt::serialize::{Deserialize, Serialize},
 };
 pub use bitcoin::{
     secp256k1::SecretKey,
     util::ecdsa::{PrivateKey, PublicKey},
 };
-use mining_sv2::NewExtendedMiningJob;
+use mining_sv2::{NewExtendedMiningJob, SetCustomMiningJob};
 use std::{collections::HashMap, convert::TryInto};
 use template_distribution_sv2::{NewTemplate, SetNewPrevHash};
 
 const SCRIPT_PREFIX_LEN: usize = 4;
 const PREV_OUT_LEN: usize = 38;
 const EXTRANONCE_LEN: usize = 32;
+/// OP_RETURN OP_PUSHBYTES_36 0xaa21a9ed (BIP141)
+const WITNESS_COMMITMENT_HEADER: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
 
 /// Used by pool one for each group channel
 /// extended and standard channel not supported
@@ -35,10 +38,6 @@ impl JobCreator {
         new_template: &mut NewTemplate,
         coinbase_outputs: &[TxOut],
     ) -> Result<NewExtendedMiningJob<'static>, Error> {
-        assert!(
-            new_template.coinbase_tx_outputs_count == 0,
-            "node provided outputs not supported yet"
-        );
         let script_prefix = new_template.coinbase_prefix.to_vec();
         // Is ok to panic here cause condition will be always true when not in a test chain
         // (regtest ecc ecc)
@@ -48,8 +47,13 @@ impl JobCreator {
         );
         let bip34_len = script_prefix[1] as usize;
         let bip34_bytes = script_prefix[1..2 + bip34_len].to_vec();
+        let script_prefix_len = bip34_bytes.len();
+
+        // Pool outputs first then the outputs required by the node (eg the witness commitment)
+        let mut outputs = coinbase_outputs.to_vec();
+        outputs.extend(template_coinbase_outputs(new_template)?);
 
-        let coinbase = self.coinbase(
+        let coinbase = Self::coinbase(
             bip34_bytes,
             new_template
                 .coinbase_tx_version
@@ -57,7 +61,7 @@ impl JobCreator {
                 .expect("invalid version"),
             new_template.coinbase_tx_locktime,
             new_template.coinbase_tx_input_sequence,
-            coinbase_outputs,
+            &outputs,
         );
         let new_extended_mining_job: NewExtendedMiningJob<'static> = NewExtendedMiningJob {
             channel_id: self.group_channel_id,
@@ -66,8 +70,8 @@ impl JobCreator {
             version: new_template.version,
             version_rolling_allowed: self.version_rolling_allowed,
             merkle_path: new_template.merkle_path.clone().into_static(),
-            coinbase_tx_prefix: Self::coinbase_tx_prefix(&coinbase, SCRIPT_PREFIX_LEN)?,
-            coinbase_tx_suffix: Self::coinbase_tx_suffix(&coinbase, SCRIPT_PREFIX_LEN)?,
+            coinbase_tx_prefix: Self::coinbase_tx_prefix(&coinbase, script_prefix_len)?,
+            coinbase_tx_suffix: Self::coinbase_tx_suffix(&coinbase, script_prefix_len)?,
         };
         self.template_id_to_job_id
             .insert(new_template.template_id, new_extended_mining_job.job_id);
@@ -78,6 +82,8 @@ impl JobCreator {
         self.template_id_to_job_id.get(&template_id).copied()
     }
 
+    /// The prefix and the suffix use the legacy serialization cause the merkle root commit to
+    /// the txid, see `coinbase_with_witness`
     fn coinbase_tx_prefix(
         coinbase: &Transaction,
         coinbase_tx_input_script_prefix_byte_len: usize,
@@ -104,9 +110,7 @@ impl JobCreator {
     }
 
     /// coinbase_tx_input_script_prefix: extranonce prefix (script lenght + bip34 block height) provided by the node
-    /// It assume that NewTemplate.coinbase_tx_outputs == 0
     fn coinbase(
-        &self,
         mut bip34_bytes: Vec<u8>,
         version: i32,
         lock_time: u32,
@@ -137,33 +141,89 @@ pub struct JobsCreators {
     /// Computed by the pool
     coinbase_outputs: Vec<TxOut>,
     block_reward_staoshi: u64,
-    pub_key: PublicKey,
+    /// (script_pubkey, share) the block reward is split between the scripts proportionally to
+    /// the shares
+    recipients: Vec<(Script, u64)>,
     lasts_new_template: Vec<NewTemplate<'static>>,
     //last_prev_hash: Pr
 }
 
 impl JobsCreators {
-    pub fn new(block_reward_staoshi: u64, pub_key: PublicKey) -> Option<Self> {
-        Some(Self {
+    /// Recipients can use any script (P2PKH, P2SH, P2WPKH, P2WSH, P2TR or raw scripts), outputs
+    /// are added to the coinbase in the same order of the recipients
+    pub fn new(block_reward_staoshi: u64, recipients: Vec<(Script, u64)>) -> Result<Self, Error> {
+        Self::check_recipients(&recipients)?;
+        let mut self_ = Self {
             jobs_creators: vec![],
-            coinbase_outputs: vec![Self::new_output(block_reward_staoshi, pub_key)?],
+            coinbase_outputs: vec![],
             block_reward_staoshi,
-            pub_key,
+            recipients,
             lasts_new_template: Vec::new(),
-        })
+        };
+        self_.coinbase_outputs = self_.new_outputs(block_reward_staoshi);
+        Ok(self_)
+    }
+
+    fn check_recipients(recipients: &[(Script, u64)]) -> Result<(), Error> {
+        let total_shares = recipients
+            .iter()
+            .try_fold(0_u64, |total, (_, share)| total.checked_add(*share));
+        if recipients.is_empty() || total_shares.unwrap_or(0) == 0 {
+            return Err(Error::InvalidCoinbaseRecipients);
+        }
+        Ok(())
+    }
+
+    /// Replace the recipients of the block reward, jobs created from now on pay the new
+    /// recipients
+    pub fn set_recipients(&mut self, recipients: Vec<(Script, u64)>) -> Result<(), Error> {
+        Self::check_recipients(&recipients)?;
+        self.recipients = recipients;
+        self.coinbase_outputs = self.new_outputs(self.block_reward_staoshi);
+        Ok(())
     }
 
-    fn new_output(block_reward_staoshi: u64, pub_key: PublicKey) -> Option<TxOut> {
-        let script_pubkey = Script::new_v0_wpkh(&pub_key.wpubkey_hash()?);
-        Some(TxOut {
-            value: block_reward_staoshi,
-            script_pubkey,
-        })
+    /// Serialized size of the outputs added by the pool to the coinbase, it is what the Template
+    /// Provider need in CoinbaseOutputDataSize
+    pub fn coinbase_outputs_size(&self) -> u32 {
+        self.coinbase_outputs
+            .iter()
+            .map(|output| output.serialize().len() as u32)
+            .sum()
     }
 
+    /// Split the value between the recipients proportionally to their shares. Each recipient get
+    /// the floor of its part, the satoshis left by the rounding are given one each to the
+    /// recipients with a share greater than 0 in order, so that the sum of the outputs is always
+    /// equal to the value.
     pub fn new_outputs(&self, block_reward_staoshi: u64) -> Vec<TxOut> {
-        // safe unwrap cause pub key in self is compressed
-        vec![Self::new_output(block_reward_staoshi, self.pub_key).unwrap()]
+        let total_shares: u128 = self.recipients.iter().map(|(_, s)| *s as u128).sum();
+        let mut values: Vec<u64> = self
+            .recipients
+            .iter()
+            .map(|(_, share)| {
+                ((block_reward_staoshi as u128 * *share as u128) / total_shares) as u64
+            })
+            .collect();
+        let mut remainder = block_reward_staoshi - values.iter().sum::<u64>();
+        for (value, (_, share)) in values.iter_mut().zip(&self.recipients) {
+            if remainder == 0 {
+                break;
+            }
+            if *share == 0 {
+                continue;
+            }
+            *value += 1;
+            remainder -= 1;
+        }
+        self.recipients
+            .iter()
+            .zip(values)
+            .map(|((script_pubkey, _), value)| TxOut {
+                value,
+                script_pubkey: script_pubkey.clone(),
+            })
+            .collect()
     }
 
     pub fn on_new_template(
@@ -229,6 +289,12 @@ impl JobsCreators {
         Ok(res)
     }
 
+    /// No more jobs are created for the group channel
+    pub fn remove_group_channel(&mut self, group_channel_id: u32) {
+        self.jobs_creators
+            .retain(|jc| jc.group_channel_id != group_channel_id);
+    }
+
     pub fn job_id_from_template(&self, template_id: u64, group_id: u32) -> Option<u32> {
         for jc in &self.jobs_creators {
             if jc.group_channel_id == group_id {
@@ -238,3 +304,251 @@ impl JobsCreators {
         None
     }
 }
+
+/// NewTemplate.coinbase_tx_outputs are serialized one after the other
+pub fn template_coinbase_outputs(template: &NewTemplate) -> Result<Vec<TxOut>, Error> {
+    let mut data = template.coinbase_tx_outputs.inner_as_ref();
+    let mut outputs = Vec::with_capacity(template.coinbase_tx_outputs_count as usize);
+    for _ in 0..template.coinbase_tx_outputs_count {
+        outputs
+            .push(TxOut::consensus_decode(&mut data).map_err(|_| Error::InvalidCoinbaseOutputs)?);
+    }
+    Ok(outputs)
+}
+
+/// Jobs are built with the legacy serialization of the coinbase. When the coinbase has a witness
+/// commitment the block must contain the segwit serialization of the coinbase with the 32 bytes
+/// witness reserved value (BIP141), otherwise the coinbase is returned as it is. The txid does
+/// not change so the merkle root of the job is still valid.
+pub fn coinbase_with_witness(coinbase: &[u8]) -> Result<Vec<u8>, Error> {
+    let tx = Transaction::deserialize(coinbase).map_err(|_| Error::InvalidCoinbaseOutputs)?;
+    let has_witness_commitment = tx.output.iter().any(|output| {
+        output
+            .script_pubkey
+            .as_bytes()
+            .starts_with(&WITNESS_COMMITMENT_HEADER)
+    });
+    if !has_witness_commitment {
+        return Ok(coinbase.to_vec());
+    }
+    let (version, rest) = coinbase.split_at(4);
+    let (body, lock_time) = rest.split_at(rest.len() - 4);
+    let mut segwit = Vec::with_capacity(coinbase.len() + 36);
+    segwit.extend_from_slice(version);
+    // marker and flag
+    segwit.extend_from_slice(&[0x00, 0x01]);
+    segwit.extend_from_slice(body);
+    // one witness item of 32 bytes
+    segwit.extend_from_slice(&[0x01, 0x20]);
+    segwit.extend_from_slice(&[0; 32]);
+    segwit.extend_from_slice(lock_time);
+    Ok(segwit)
+}
+
+/// Used by the pool to accept a SetCustomMiningJob: the coinbase is built like the coinbase of the
+/// pool's jobs but with the BIP34 block height, the outputs and the values of the custom job
+pub fn extended_job_from_custom_job(
+    custom_job: &SetCustomMiningJob,
+    job_id: u32,
+    version_rolling_allowed: bool,
+) -> Result<NewExtendedMiningJob<'static>, Error> {
+    // 1 byte for the len and 3 bytes for the height
+    let bip34_bytes = custom_job.coinbase_prefix.to_le_bytes();
+    if bip34_bytes[0] != 3 {
+        return Err(Error::InvalidCoinbasePrefix);
+    }
+    let mut coinbase_outputs = Vec::new();
+    for output in custom_job.coinbase_tx_outputs.inner_as_ref() {
+        coinbase_outputs
+            .push(TxOut::deserialize(output).map_err(|_| Error::InvalidCoinbaseOutputs)?);
+    }
+    let coinbase = JobCreator::coinbase(
+        bip34_bytes.to_vec(),
+        custom_job.coinbase_tx_version as i32,
+        custom_job.coinbase_tx_locktime,
+        custom_job.coinbase_tx_input_n_sequence,
+        &coinbase_outputs,
+    );
+    Ok(NewExtendedMiningJob {
+        channel_id: custom_job.channel_id,
+        job_id,
+        future_job: custom_job.future_job,
+        version: custom_job.version,
+        version_rolling_allowed,
+        merkle_path: custom_job.merkle_path.clone().into_static(),
+        coinbase_tx_prefix: JobCreator::coinbase_tx_prefix(&coinbase, SCRIPT_PREFIX_LEN)?,
+        coinbase_tx_suffix: JobCreator::coinbase_tx_suffix(&coinbase, SCRIPT_PREFIX_LEN)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(n: u8) -> Script {
+        Script::from(vec![n])
+    }
+
+    #[test]
+    fn jobs_creators_need_recipients() {
+        assert!(JobsCreators::new(100, vec![]).is_err());
+        assert!(JobsCreators::new(100, vec![(script(1), 0), (script(2), 0)]).is_err());
+        assert!(JobsCreators::new(100, vec![(script(1), u64::MAX), (script(2), 1)]).is_err());
+    }
+
+    #[test]
+    fn removed_group_channels_do_not_get_jobs() {
+        let mut jobs_creators = JobsCreators::new(1000, vec![(script(1), 1)]).unwrap();
+        jobs_creators.new_group_channel(1, true).unwrap();
+        jobs_creators.new_group_channel(2, true).unwrap();
+
+        jobs_creators.remove_group_channel(1);
+
+        let group_ids: Vec<u32> = jobs_creators
+            .jobs_creators
+            .iter()
+            .map(|jc| jc.group_channel_id)
+            .collect();
+        assert_eq!(group_ids, vec![2]);
+    }
+
+    #[test]
+    fn new_outputs_split_value_proportionally() {
+        let jobs_creators = JobsCreators::new(1000, vec![(script(1), 3), (script(2), 1)]).unwrap();
+        let outputs = jobs_creators.new_outputs(1000);
+        assert_eq!(outputs[0].value, 750);
+        assert_eq!(outputs[0].script_pubkey, script(1));
+        assert_eq!(outputs[1].value, 250);
+        assert_eq!(outputs[1].script_pubkey, script(2));
+    }
+
+    #[test]
+    fn new_outputs_give_rounding_remainder_in_order() {
+        let jobs_creators = JobsCreators::new(
+            10,
+            vec![
+                (script(1), 1),
+                (script(2), 0),
+                (script(3), 1),
+                (script(4), 1),
+            ],
+        )
+        .unwrap();
+        let values: Vec<u64> = jobs_creators
+            .new_outputs(10)
+            .iter()
+            .map(|output| output.value)
+            .collect();
+        assert_eq!(values, vec![4, 0, 3, 3]);
+    }
+
+    #[test]
+    fn new_outputs_sum_is_the_block_reward() {
+        let recipients = vec![(script(1), 7), (script(2), 13), (script(3), 29)];
+        let jobs_creators = JobsCreators::new(625_000_000_000, recipients).unwrap();
+        for value in [0, 1, 48, 625_000_000_001, u64::MAX] {
+            let outputs = jobs_creators.new_outputs(value);
+            assert_eq!(outputs.iter().map(|o| o.value).sum::<u64>(), value);
+        }
+    }
+
+    #[test]
+    fn set_recipients_replace_the_outputs() {
+        let mut jobs_creators = JobsCreators::new(1000, vec![(script(1), 1)]).unwrap();
+        // 8 bytes value + 1 byte script len + 1 byte script
+        assert_eq!(jobs_creators.coinbase_outputs_size(), 10);
+        assert!(jobs_creators.set_recipients(vec![]).is_err());
+        jobs_creators
+            .set_recipients(vec![(script(2), 1), (Script::from(vec![3; 22]), 1)])
+            .unwrap();
+        assert_eq!(jobs_creators.coinbase_outputs_size(), 10 + 31);
+        assert_eq!(jobs_creators.coinbase_outputs[0].script_pubkey, script(2));
+        assert_eq!(jobs_creators.coinbase_outputs[0].value, 500);
+    }
+
+    fn witness_commitment() -> TxOut {
+        let mut script = WITNESS_COMMITMENT_HEADER.to_vec();
+        script.extend_from_slice(&[7; 32]);
+        TxOut {
+            value: 0,
+            script_pubkey: script.into(),
+        }
+    }
+
+    fn template_with_outputs(outputs: &[TxOut]) -> NewTemplate<'static> {
+        let serialized: Vec<u8> = outputs.iter().flat_map(|o| o.serialize()).collect();
+        NewTemplate {
+            template_id: 1,
+            future_template: false,
+            version: 0x2000_0000,
+            coinbase_tx_version: 2,
+            coinbase_prefix: vec![4, 3, 0x10, 0x27, 0x00].try_into().unwrap(),
+            coinbase_tx_input_sequence: u32::MAX,
+            coinbase_tx_value_remaining: 1000,
+            coinbase_tx_outputs_count: outputs.len() as u32,
+            coinbase_tx_outputs: serialized.try_into().unwrap(),
+            coinbase_tx_locktime: 0,
+            merkle_path: binary_sv2::Seq0255::new(Vec::new()).unwrap(),
+        }
+    }
+
+    fn job_coinbase(job: &NewExtendedMiningJob) -> Vec<u8> {
+        let mut coinbase = job.coinbase_tx_prefix.to_vec();
+        coinbase.extend_from_slice(&[0; EXTRANONCE_LEN]);
+        coinbase.extend_from_slice(job.coinbase_tx_suffix.inner_as_ref());
+        coinbase
+    }
+
+    #[test]
+    fn template_outputs_are_appended_after_pool_outputs() {
+        let mut jobs_creators =
+            JobsCreators::new(1000, vec![(script(1), 1), (script(2), 1)]).unwrap();
+        jobs_creators.new_group_channel(1, true).unwrap();
+        let mut template = template_with_outputs(&[witness_commitment()]);
+        let jobs = jobs_creators.on_new_template(&mut template).unwrap();
+
+        let coinbase = Transaction::deserialize(&job_coinbase(&jobs[&1])).unwrap();
+        let mut expected = jobs_creators.new_outputs(1000);
+        expected.push(witness_commitment());
+        assert_eq!(coinbase.output, expected);
+        assert_eq!(
+            coinbase.input[0].script_sig.as_bytes(),
+            &[&[3, 0x10, 0x27, 0x00][..], &[0; EXTRANONCE_LEN][..]].concat()[..]
+        );
+    }
+
+    #[test]
+    fn invalid_template_outputs_are_an_error() {
+        let mut jobs_creators = JobsCreators::new(1000, vec![(script(1), 1)]).unwrap();
+        jobs_creators.new_group_channel(1, true).unwrap();
+        let mut template = template_with_outputs(&[witness_commitment()]);
+        template.coinbase_tx_outputs_count = 2;
+        assert!(jobs_creators.on_new_template(&mut template).is_err());
+    }
+
+    #[test]
+    fn coinbase_with_witness_commitment_is_segwit_serialized() {
+        let mut jobs_creators = JobsCreators::new(1000, vec![(script(1), 1)]).unwrap();
+        jobs_creators.new_group_channel(1, true).unwrap();
+        let mut template = template_with_outputs(&[witness_commitment()]);
+        let jobs = jobs_creators.on_new_template(&mut template).unwrap();
+        let legacy = job_coinbase(&jobs[&1]);
+
+        let segwit = coinbase_with_witness(&legacy).unwrap();
+        let legacy = Transaction::deserialize(&legacy).unwrap();
+        let segwit = Transaction::deserialize(&segwit).unwrap();
+        let witness: Vec<Vec<u8>> = segwit.input[0].witness.iter().map(|w| w.to_vec()).collect();
+        assert_eq!(witness, vec![vec![0; 32]]);
+        assert_eq!(segwit.txid(), legacy.txid());
+    }
+
+    #[test]
+    fn coinbase_without_witness_commitment_is_unchanged() {
+        let mut jobs_creators = JobsCreators::new(1000, vec![(script(1), 1)]).unwrap();
+        jobs_creators.new_group_channel(1, true).unwrap();
+        let mut template = template_with_outputs(&[]);
+        let jobs = jobs_creators.on_new_template(&mut template).unwrap();
+        let coinbase = job_coinbase(&jobs[&1]);
+        assert_eq!(coinbase_with_witness(&coinbase).unwrap(), coinbase);
+    }
+}
@@ -6,9 +6,13 @@ use crate::{
 use bitcoin::hashes::{sha256d, Hash, HashEngine};
 use mining_sv2::{
     NewExtendedMiningJob, NewMiningJob, SetNewPrevHash, SubmitSharesError, SubmitSharesStandard,
-    Target,
+    SubmitSharesSuccess, Target,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    sync::Arc,
 };
-use std::{collections::HashMap, convert::TryInto, sync::Arc};
 
 fn extended_to_standard_job_for_group_channel<'a>(
     extended: &NewExtendedMiningJob,
@@ -31,7 +35,6 @@ fn extended_to_standard_job_for_group_channel<'a>(
         merkle_root: merkle_root?.try_into().ok()?,
     })
 }
-#[allow(dead_code)]
 struct BlockHeader<'a> {
     version: u32,
     prev_hash: &'a [u8],
@@ -42,21 +45,20 @@ struct BlockHeader<'a> {
 }
 
 impl<'a> BlockHeader<'a> {
-    #[allow(dead_code)]
+    /// Every field is serialized little endian as in a bitcoin block header, the hash is little
+    /// endian as the targets it is compared with
     pub fn hash(&self) -> Target {
         let mut engine = sha256d::Hash::engine();
         engine.input(&self.version.to_le_bytes());
         engine.input(self.prev_hash);
         engine.input(self.merkle_root);
-        engine.input(&self.timestamp.to_be_bytes());
-        engine.input(&self.nbits.to_be_bytes());
-        engine.input(&self.nonce.to_be_bytes());
-        let hashed = sha256d::Hash::from_engine(engine).into_inner();
-        hashed.into()
+        engine.input(&self.timestamp.to_le_bytes());
+        engine.input(&self.nbits.to_le_bytes());
+        engine.input(&self.nonce.to_le_bytes());
+        sha256d::Hash::from_engine(engine).into_inner().into()
     }
 }
 
-#[allow(dead_code)]
 fn target_from_shares(
     job: &DownstreamJob,
     prev_hash: &[u8],
@@ -76,6 +78,7 @@ fn target_from_shares(
 
 #[derive(Debug)]
 struct DownstreamJob {
+    channel_id: u32,
     merkle_root: Vec<u8>,
     extended_job_id: u32,
 }
@@ -99,12 +102,31 @@ pub struct GroupChannelJobDispatcher {
     ids: Arc<Mutex<Id>>,
     // extended_id -> channel_id -> stanrd_id
     extended_id_to_job_id: HashMap<u32, HashMap<u32, u32>>,
+    // extended_id -> extended job, used to compute the jobs again when a prefix change
+    extended_jobs: HashMap<u32, NewExtendedMiningJob<'static>>,
     nbits: u32,
+    // channel_id -> targets
+    channel_targets: HashMap<u32, ChannelTargets>,
+    // standard job ids of the jobs that were valid before the last prev hash
+    stale_jobs: HashSet<u32>,
+    // (channel_id, job_id, nonce, ntime, version) of the shares submitted since the last prev hash
+    submitted_shares: HashSet<(u32, u32, u32, u32, u32)>,
 }
 
+/// A share must meet the downstream target to be valid and the upstream target to be forwarded
+#[derive(Debug, Clone)]
+struct ChannelTargets {
+    downstream: Target,
+    upstream: Target,
+}
+
+#[derive(Debug)]
 pub enum SendSharesResponse {
-    //ValidAndMeetUpstreamTarget((SubmitSharesStandard,SubmitSharesSuccess)),
+    /// The share meet the upstream target and must be forwarded
     Valid(SubmitSharesStandard),
+    /// The share meet the downstream target but not the upstream one, it is acknowledged to the
+    /// downstream without being forwarded
+    ValidForDownstream(SubmitSharesSuccess),
     Invalid(SubmitSharesError<'static>),
 }
 
@@ -118,6 +140,54 @@ impl GroupChannelJobDispatcher {
             ids,
             nbits: 0,
             extended_id_to_job_id: HashMap::new(),
+            extended_jobs: HashMap::new(),
+            channel_targets: HashMap::new(),
+            stale_jobs: HashSet::new(),
+            submitted_shares: HashSet::new(),
+        }
+    }
+
+    /// Must be called for every standard channel opened in the group, at the beginning the
+    /// downstream target is the target set by the upstream
+    pub fn add_channel(&mut self, channel_id: u32, target: Target) {
+        let targets = ChannelTargets {
+            downstream: target.clone(),
+            upstream: target,
+        };
+        self.channel_targets.insert(channel_id, targets);
+    }
+
+    /// Forget the channel and its jobs, its shares are rejected from now on
+    pub fn remove_channel(&mut self, channel_id: u32) {
+        self.channel_targets.remove(&channel_id);
+        self.jobs.retain(|_, job| job.channel_id != channel_id);
+        for future_jobs in self.future_jobs.values_mut() {
+            future_jobs.retain(|_, job| job.channel_id != channel_id);
+        }
+        for channel_id_to_job_id in self.extended_id_to_job_id.values_mut() {
+            channel_id_to_job_id.remove(&channel_id);
+        }
+        self.submitted_shares
+            .retain(|(share_channel_id, ..)| *share_channel_id != channel_id);
+    }
+
+    /// Target that the shares of the channel must meet to be valid
+    pub fn set_downstream_target(&mut self, channel_id: u32, target: Target) {
+        if let Some(targets) = self.channel_targets.get_mut(&channel_id) {
+            targets.downstream = target;
+        }
+    }
+
+    pub fn downstream_target(&self, channel_id: u32) -> Option<Target> {
+        self.channel_targets
+            .get(&channel_id)
+            .map(|targets| targets.downstream.clone())
+    }
+
+    /// Target that the shares of the channel must meet to be forwarded upstream
+    pub fn set_upstream_target(&mut self, channel_id: u32, target: Target) {
+        if let Some(targets) = self.channel_targets.get_mut(&channel_id) {
+            targets.upstream = target;
         }
     }
 
@@ -141,6 +211,10 @@ impl GroupChannelJobDispatcher {
                 .or_insert_with(HashMap::new);
         }
 
+        self.extended_jobs
+            .entry(extended.job_id)
+            .or_insert_with(|| extended.as_static());
+
         // Is fine to unwrap a safe_lock result
         let standard_job_id = self.ids.safe_lock(|ids| ids.next()).unwrap();
 
@@ -152,6 +226,7 @@ impl GroupChannelJobDispatcher {
             standard_job_id,
         )?;
         let job = DownstreamJob {
+            channel_id: channel.channel_id,
             merkle_root: new_mining_job_message.merkle_root.to_vec(),
             extended_job_id: extended.job_id,
         };
@@ -183,9 +258,14 @@ impl GroupChannelJobDispatcher {
             .get_mut(&message.job_id)
             .ok_or(Error::PrevHashRequireNonExistentJobId(message.job_id))?;
         std::mem::swap(&mut self.jobs, jobs);
+        self.stale_jobs = jobs.keys().copied().collect();
         self.prev_hash = message.prev_hash.to_vec();
         self.nbits = message.nbits;
         self.future_jobs.clear();
+        self.submitted_shares.clear();
+        let active_jobs: HashSet<u32> = self.jobs.values().map(|j| j.extended_job_id).collect();
+        self.extended_jobs
+            .retain(|extended_job_id, _| active_jobs.contains(extended_job_id));
         match self.extended_id_to_job_id.remove(&message.job_id) {
             Some(map) => {
                 self.extended_id_to_job_id.clear();
@@ -198,28 +278,142 @@ impl GroupChannelJobDispatcher {
         }
     }
 
-    // (response, upstream id)
-    pub fn on_submit_shares(&self, shares: SubmitSharesStandard) -> SendSharesResponse {
-        let id = shares.job_id;
-        if let Some(job) = self.jobs.get(&id) {
-            let success = SubmitSharesStandard {
-                channel_id: shares.channel_id,
-                sequence_number: shares.sequence_number,
-                job_id: job.extended_job_id,
-                nonce: shares.nonce,
-                ntime: shares.ntime,
-                version: shares.version,
+    /// The upstream changed the extranonce prefix of the channel: the merkle roots of the jobs of
+    /// the channel are computed again with the new prefix. Every job get a new id, the shares
+    /// for the old ids are stale from now on, and is returned to be sent downstream.
+    pub fn on_set_extranonce_prefix(
+        &mut self,
+        channel_id: u32,
+        extranonce_prefix: &[u8],
+    ) -> Vec<NewMiningJob<'static>> {
+        let mut new_jobs = Vec::new();
+        let old_ids: Vec<u32> = self
+            .jobs
+            .iter()
+            .filter(|(_, job)| job.channel_id == channel_id)
+            .map(|(job_id, _)| *job_id)
+            .collect();
+        for old_id in old_ids {
+            // Safe unwrap the ids are taken from the map
+            let job = self.jobs.remove(&old_id).unwrap();
+            self.stale_jobs.insert(old_id);
+            if let Some((job, new_job)) = self.job_with_prefix(job, extranonce_prefix, false) {
+                self.jobs.insert(new_job.job_id, job);
+                new_jobs.push(new_job);
+            }
+        }
+        let extended_ids: Vec<u32> = self.future_jobs.keys().copied().collect();
+        for extended_id in extended_ids {
+            let old_id = match self.extended_id_to_job_id.get(&extended_id) {
+                Some(channel_id_to_job_id) => match channel_id_to_job_id.get(&channel_id) {
+                    Some(old_id) => *old_id,
+                    None => continue,
+                },
+                None => continue,
             };
-            SendSharesResponse::Valid(success)
-        } else {
-            let error = SubmitSharesError {
+            // Safe unwrap the key is taken from the map
+            let future_jobs = self.future_jobs.get_mut(&extended_id).unwrap();
+            let job = match future_jobs.remove(&old_id) {
+                Some(job) => job,
+                None => continue,
+            };
+            if let Some((job, new_job)) = self.job_with_prefix(job, extranonce_prefix, true) {
+                let job_id = new_job.job_id;
+                // Safe unwraps the keys are checked above
+                self.future_jobs
+                    .get_mut(&extended_id)
+                    .unwrap()
+                    .insert(job_id, job);
+                self.extended_id_to_job_id
+                    .get_mut(&extended_id)
+                    .unwrap()
+                    .insert(channel_id, job_id);
+                new_jobs.push(new_job);
+            }
+        }
+        new_jobs
+    }
+
+    fn job_with_prefix(
+        &self,
+        job: DownstreamJob,
+        extranonce_prefix: &[u8],
+        future_job: bool,
+    ) -> Option<(DownstreamJob, NewMiningJob<'static>)> {
+        let extended = self.extended_jobs.get(&job.extended_job_id)?;
+        // Is fine to unwrap a safe_lock result
+        let job_id = self.ids.safe_lock(|ids| ids.next()).unwrap();
+        let mut new_job = extended_to_standard_job_for_group_channel(
+            extended,
+            extranonce_prefix,
+            job.channel_id,
+            job_id,
+        )?;
+        new_job.future_job = future_job;
+        let job = DownstreamJob {
+            channel_id: job.channel_id,
+            merkle_root: new_job.merkle_root.to_vec(),
+            extended_job_id: job.extended_job_id,
+        };
+        Some((job, new_job))
+    }
+
+    /// Reconstruct the header of the share and check it against the channel targets. Shares that
+    /// are stale, duplicated or that do not meet the downstream target are rejected with the
+    /// corresponding SubmitSharesError code.
+    pub fn on_submit_shares(&mut self, shares: SubmitSharesStandard) -> SendSharesResponse {
+        let targets = match self.channel_targets.get(&shares.channel_id) {
+            Some(targets) => targets,
+            None => return SendSharesResponse::Invalid(share_error(&shares, "invalid-channel-id")),
+        };
+        let job = match self.jobs.get(&shares.job_id) {
+            Some(job) => job,
+            None if self.stale_jobs.contains(&shares.job_id) => {
+                return SendSharesResponse::Invalid(share_error(&shares, "stale-share"))
+            }
+            None => return SendSharesResponse::Invalid(share_error(&shares, "invalid-job-id")),
+        };
+        let hash = target_from_shares(job, &self.prev_hash, self.nbits, &shares);
+        if hash > targets.downstream {
+            return SendSharesResponse::Invalid(share_error(&shares, "difficulty-too-low"));
+        }
+        let share = (
+            shares.channel_id,
+            shares.job_id,
+            shares.nonce,
+            shares.ntime,
+            shares.version,
+        );
+        if !self.submitted_shares.insert(share) {
+            return SendSharesResponse::Invalid(share_error(&shares, "duplicate-share"));
+        }
+        if hash > targets.upstream {
+            let success = SubmitSharesSuccess {
                 channel_id: shares.channel_id,
-                sequence_number: shares.sequence_number,
-                // Below unwrap never panic
-                error_code: "".to_string().into_bytes().try_into().unwrap(),
+                last_sequence_number: shares.sequence_number,
+                new_submits_accepted_count: 1,
+                new_shares_sum: 1,
             };
-            SendSharesResponse::Invalid(error)
+            return SendSharesResponse::ValidForDownstream(success);
         }
+        let valid = SubmitSharesStandard {
+            channel_id: shares.channel_id,
+            sequence_number: shares.sequence_number,
+            job_id: job.extended_job_id,
+            nonce: shares.nonce,
+            ntime: shares.ntime,
+            version: shares.version,
+        };
+        SendSharesResponse::Valid(valid)
+    }
+}
+
+fn share_error(shares: &SubmitSharesStandard, error_code: &str) -> SubmitSharesError<'static> {
+    SubmitSharesError {
+        channel_id: shares.channel_id,
+        sequence_number: shares.sequence_number,
+        // Below unwrap never panic
+        error_code: error_code.to_string().into_bytes().try_into().unwrap(),
     }
 }
 
@@ -530,6 +724,10 @@ mod tests {
             ids: Arc::new(Mutex::new(Id::new())),
             nbits: 0,
             extended_id_to_job_id: HashMap::new(),
+            extended_jobs: HashMap::new(),
+            channel_targets: HashMap::new(),
+            stale_jobs: HashSet::new(),
+            submitted_shares: HashSet::new(),
         };
 
         let ids = Arc::new(Mutex::new(Id::new()));
@@ -670,4 +868,210 @@ mod tests {
     //        "GroupChannelJobDispatcher does not have any future jobs"
     //    );
     //}
+
+    fn dispatcher_with_job(
+        downstream_target: [u8; 32],
+        upstream_target: [u8; 32],
+    ) -> GroupChannelJobDispatcher {
+        let ids = Arc::new(Mutex::new(Id::new()));
+        let mut dispatcher = GroupChannelJobDispatcher::new(ids);
+        dispatcher.add_channel(1, downstream_target.into());
+        dispatcher.set_upstream_target(1, upstream_target.into());
+        let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+        let job = DownstreamJob {
+            channel_id: 1,
+            merkle_root: genesis.header.merkle_root.into_inner().to_vec(),
+            extended_job_id: 7,
+        };
+        dispatcher.jobs.insert(2, job);
+        dispatcher.prev_hash = genesis.header.prev_blockhash.into_inner().to_vec();
+        dispatcher.nbits = genesis.header.bits;
+        dispatcher
+    }
+
+    fn genesis_share() -> SubmitSharesStandard {
+        let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+        SubmitSharesStandard {
+            channel_id: 1,
+            sequence_number: 0,
+            job_id: 2,
+            nonce: genesis.header.nonce,
+            ntime: genesis.header.time,
+            version: genesis.header.version as u32,
+        }
+    }
+
+    fn error_code(response: SendSharesResponse) -> String {
+        match response {
+            SendSharesResponse::Invalid(e) => {
+                String::from_utf8(e.error_code.inner_as_ref().to_vec()).unwrap()
+            }
+            response => panic!("Expected SubmitSharesError, got {:?}", response),
+        }
+    }
+
+    #[test]
+    fn gets_header_hash() {
+        let genesis = bitcoin::blockdata::constants::genesis_block(bitcoin::Network::Bitcoin);
+        let header = BlockHeader {
+            version: genesis.header.version as u32,
+            prev_hash: &genesis.header.prev_blockhash.into_inner(),
+            merkle_root: &genesis.header.merkle_root.into_inner(),
+            timestamp: genesis.header.time,
+            nbits: genesis.header.bits,
+            nonce: genesis.header.nonce,
+        };
+        let expect: Target = genesis.block_hash().into_inner().into();
+
+        assert_eq!(expect, header.hash());
+    }
+
+    #[test]
+    fn forwards_shares_that_meet_the_upstream_target() {
+        let mut dispatcher = dispatcher_with_job([255; 32], [255; 32]);
+
+        match dispatcher.on_submit_shares(genesis_share()) {
+            SendSharesResponse::Valid(share) => assert_eq!(share.job_id, 7),
+            response => panic!("Expected a valid share, got {:?}", response),
+        }
+    }
+
+    #[test]
+    fn acknowledges_shares_that_only_meet_the_downstream_target() {
+        let mut upstream_target = [0; 32];
+        upstream_target[0] = 1;
+        let mut dispatcher = dispatcher_with_job([255; 32], upstream_target);
+
+        match dispatcher.on_submit_shares(genesis_share()) {
+            SendSharesResponse::ValidForDownstream(success) => {
+                assert_eq!(success.channel_id, 1);
+                assert_eq!(success.new_submits_accepted_count, 1);
+            }
+            response => panic!("Expected a share valid for downstream, got {:?}", response),
+        }
+    }
+
+    #[test]
+    fn rejects_shares_above_the_downstream_target() {
+        let mut target = [0; 32];
+        target[0] = 1;
+        let mut dispatcher = dispatcher_with_job(target, target);
+
+        let response = dispatcher.on_submit_shares(genesis_share());
+
+        assert_eq!(error_code(response), "difficulty-too-low");
+    }
+
+    #[test]
+    fn rejects_duplicated_shares() {
+        let mut dispatcher = dispatcher_with_job([255; 32], [255; 32]);
+        dispatcher.on_submit_shares(genesis_share());
+
+        let response = dispatcher.on_submit_shares(genesis_share());
+
+        assert_eq!(error_code(response), "duplicate-share");
+    }
+
+    #[test]
+    fn rejects_shares_of_removed_channels() {
+        let mut dispatcher = dispatcher_with_job([255; 32], [255; 32]);
+
+        dispatcher.remove_channel(1);
+
+        assert!(dispatcher.jobs.is_empty());
+        assert_eq!(
+            error_code(dispatcher.on_submit_shares(genesis_share())),
+            "invalid-channel-id"
+        );
+    }
+
+    #[test]
+    fn rejects_shares_with_unknown_job_or_channel() {
+        let mut dispatcher = dispatcher_with_job([255; 32], [255; 32]);
+        let mut share = genesis_share();
+        share.job_id = 3;
+        assert_eq!(
+            error_code(dispatcher.on_submit_shares(share)),
+            "invalid-job-id"
+        );
+
+        dispatcher.stale_jobs.insert(3);
+        let mut share = genesis_share();
+        share.job_id = 3;
+        assert_eq!(
+            error_code(dispatcher.on_submit_shares(share)),
+            "stale-share"
+        );
+
+        let mut share = genesis_share();
+        share.channel_id = 2;
+        assert_eq!(
+            error_code(dispatcher.on_submit_shares(share)),
+            "invalid-channel-id"
+        );
+    }
+
+    /// Job with a coinbase that has the 32 bytes extranonce as input script
+    fn extended_job(job_id: u32, future_job: bool) -> NewExtendedMiningJob<'static> {
+        let mut coinbase_tx_prefix = vec![2, 0, 0, 0, 1];
+        coinbase_tx_prefix.extend_from_slice(&[0; 32]);
+        coinbase_tx_prefix.extend_from_slice(&[255, 255, 255, 255, 32]);
+        let mut coinbase_tx_suffix = vec![255, 255, 255, 255, 1];
+        coinbase_tx_suffix.extend_from_slice(&[0; 8]);
+        coinbase_tx_suffix.extend_from_slice(&[0, 0, 0, 0, 0]);
+        NewExtendedMiningJob {
+            channel_id: 0,
+            job_id,
+            future_job,
+            version: 0x2000_0000,
+            version_rolling_allowed: false,
+            merkle_path: binary_sv2::Seq0255::new(Vec::new()).unwrap(),
+            coinbase_tx_prefix: coinbase_tx_prefix.try_into().unwrap(),
+            coinbase_tx_suffix: coinbase_tx_suffix.try_into().unwrap(),
+        }
+    }
+
+    #[test]
+    fn recomputes_jobs_on_new_extranonce_prefix() {
+        let ids = Arc::new(Mutex::new(Id::new()));
+        let mut dispatcher = GroupChannelJobDispatcher::new(ids);
+        let extranonce: binary_sv2::B032 = [1; 32].to_vec().try_into().unwrap();
+        let channel = StandardChannel {
+            channel_id: 1,
+            group_id: 0,
+            target: [255; 32].into(),
+            extranonce: extranonce.into(),
+        };
+        dispatcher.add_channel(1, [255; 32].into());
+        let active = extended_job(4, false);
+        let future = extended_job(5, true);
+        let old_active = dispatcher
+            .on_new_extended_mining_job(&active, &channel)
+            .unwrap();
+        let old_future = dispatcher
+            .on_new_extended_mining_job(&future, &channel)
+            .unwrap();
+
+        let new_jobs = dispatcher.on_set_extranonce_prefix(1, &[2; 32]);
+
+        assert_eq!(new_jobs.len(), 2);
+        let expected_root = merkle_root_from_path(
+            active.coinbase_tx_prefix.inner_as_ref(),
+            active.coinbase_tx_suffix.inner_as_ref(),
+            &[2; 32],
+            &[] as &[&[u8]],
+        )
+        .unwrap();
+        for job in &new_jobs {
+            assert_eq!(job.merkle_root.to_vec(), expected_root);
+            assert_ne!(job.merkle_root.to_vec(), old_active.merkle_root.to_vec());
+        }
+        let new_active = new_jobs.iter().find(|job| !job.future_job).unwrap();
+        let new_future = new_jobs.iter().find(|job| job.future_job).unwrap();
+        assert_eq!(dispatcher.extended_id_to_job_id[&5][&1], new_future.job_id);
+        assert!(dispatcher.stale_jobs.contains(&old_active.job_id));
+        assert!(!dispatcher.jobs.contains_key(&old_active.job_id));
+        assert!(dispatcher.jobs.contains_key(&new_active.job_id));
+        assert!(!dispatcher.future_jobs[&5].contains_key(&old_future.job_id));
+    }
 }
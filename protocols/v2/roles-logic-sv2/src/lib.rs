@@ -40,6 +40,7 @@ pub mod parsers;
 pub mod routing_logic;
 pub mod selectors;
 pub mod utils;
+pub mod vardiff;
 pub use bitcoin;
 pub use common_messages_sv2;
 pub use job_negotiation_sv2;
@@ -19,6 +19,10 @@
 //! MiningProxyRoutingLogic -> routing logic valid for a standard Sv2 mining proxy it is both a
 //!     CommonRouter and a MiningRouter
 //!
+//! UpstreamSelectionLogic -> implemented by the strategies used by MiningProxyRoutingLogic to
+//!     select an upstream between the ones that can be paired with a downstream (LeastHashRate,
+//!     WeightedHashRate, PriorityFailover, RoundRobin)
+//!
 use crate::{
     common_properties::{CommonDownstreamData, IsMiningDownstream, IsMiningUpstream, PairSettings},
     errors::Error,
@@ -29,7 +33,7 @@ use crate::{
     utils::{Id, Mutex},
 };
 use common_messages_sv2::{
-    has_requires_std_job, Protocol, SetupConnection, SetupConnectionSuccess,
+    has_requires_std_job, has_version_rolling, Protocol, SetupConnection, SetupConnectionSuccess,
 };
 use mining_sv2::{OpenStandardMiningChannel, OpenStandardMiningChannelSuccess};
 use std::{collections::HashMap, fmt::Debug as D, marker::PhantomData, sync::Arc};
@@ -191,6 +195,9 @@ impl<
             (Protocol::MiningProtocol, true) => {
                 self.on_setup_connection_mining_header_only(&pair_settings)
             }
+            (Protocol::MiningProtocol, false) => {
+                self.on_setup_connection_mining_extended(&pair_settings)
+            }
             // TODO add handler for other protocols
             _ => panic!(),
         }
@@ -253,9 +260,10 @@ impl<
             // If we are here a list of possible upstream has been already selected so the below
             // unwrap can not panic
             .unwrap();
-        // TODO the upstream selection logic should be specified by the caller
-        let upstream =
-            Self::select_upstreams(&mut upstreams.to_vec()).ok_or(Error::NoUpstreamsConnected)?;
+        let upstreams = upstreams.to_vec();
+        let upstream = self
+            .select_upstreams(&upstreams)
+            .ok_or(Error::NoUpstreamsConnected)?;
         let old_id = request.get_request_id_as_u32();
         let new_req_id = upstream
             // if we are here get_mapper should always return Some(mappe) so below unwrap is ok
@@ -277,7 +285,139 @@ pub struct MiningProxyRoutingLogic<
     pub upstream_selector: GeneralMiningSelector<Sel, Down, Up>,
     pub downstream_id_generator: Id,
     pub downstream_to_upstream_map: HashMap<CommonDownstreamData, Vec<Arc<Mutex<Up>>>>,
-    //pub upstream_startegy: MiningUpstreamSelectionStrategy<Up,Down,Sel>,
+    pub upstream_selection: Box<dyn UpstreamSelectionLogic<Down, Up, Sel>>,
+}
+
+/// Strategy used to select an upstream between the ones that can be paired with a downstream.
+/// `select_upstream` is only called with a non empty slice of upstreams.
+pub trait UpstreamSelectionLogic<
+    Down: IsMiningDownstream + D,
+    Up: IsMiningUpstream<Down, Sel> + D,
+    Sel: DownstreamMiningSelector<Down> + D,
+>: D + Send
+{
+    fn select_upstream(&mut self, ups: &[Arc<Mutex<Up>>]) -> Option<Arc<Mutex<Up>>>;
+}
+
+/// Try to return an upstream that is not header only
+/// Return the upstream that have got less hash rate from downstreams
+#[derive(Debug, Default)]
+pub struct LeastHashRate();
+
+impl<
+        Down: IsMiningDownstream + D,
+        Up: IsMiningUpstream<Down, Sel> + D,
+        Sel: DownstreamMiningSelector<Down> + D,
+    > UpstreamSelectionLogic<Down, Up, Sel> for LeastHashRate
+{
+    fn select_upstream(&mut self, ups: &[Arc<Mutex<Up>>]) -> Option<Arc<Mutex<Up>>> {
+        let mut ups = ups.to_vec();
+        if !filter_header_only(&mut ups).is_empty() {
+            Some(minor_total_hr_upstream(&mut filter_header_only(&mut ups)))
+        } else {
+            Some(minor_total_hr_upstream(&mut ups))
+        }
+    }
+}
+
+/// Split the hash rate between the upstreams proportionally to their weights: the upstream with
+/// the lowest total_hash_rate / weight is returned. Upstreams not in `weights` have weight 1 and
+/// upstreams with weight 0 are never selected.
+#[derive(Debug, Default)]
+pub struct WeightedHashRate {
+    /// upstream id -> weight
+    pub weights: HashMap<u32, u64>,
+}
+
+impl<
+        Down: IsMiningDownstream + D,
+        Up: IsMiningUpstream<Down, Sel> + D,
+        Sel: DownstreamMiningSelector<Down> + D,
+    > UpstreamSelectionLogic<Down, Up, Sel> for WeightedHashRate
+{
+    fn select_upstream(&mut self, ups: &[Arc<Mutex<Up>>]) -> Option<Arc<Mutex<Up>>> {
+        ups.iter()
+            .filter_map(|up_mutex| {
+                // Is fine to unwrap a safe_lock result
+                let (id, hash_rate) = up_mutex
+                    .safe_lock(|up| (up.get_id(), up.total_hash_rate()))
+                    .unwrap();
+                match self.weights.get(&id).copied().unwrap_or(1) {
+                    0 => None,
+                    weight => Some((up_mutex, hash_rate as u128, weight as u128)),
+                }
+            })
+            // hash_rate / weight is compared without dividing
+            .reduce(|acc, item| {
+                if item.1 * acc.2 < acc.1 * item.2 {
+                    item
+                } else {
+                    acc
+                }
+            })
+            .map(|(up_mutex, _, _)| up_mutex.clone())
+    }
+}
+
+/// Return the first upstream in `priorities` that can be paired with the downstream, upstreams
+/// that are not connected can not be paired so the next one is used until they are back.
+/// Upstreams not in `priorities` are used after the others.
+#[derive(Debug, Default)]
+pub struct PriorityFailover {
+    /// upstream ids from the preferred one
+    pub priorities: Vec<u32>,
+}
+
+impl<
+        Down: IsMiningDownstream + D,
+        Up: IsMiningUpstream<Down, Sel> + D,
+        Sel: DownstreamMiningSelector<Down> + D,
+    > UpstreamSelectionLogic<Down, Up, Sel> for PriorityFailover
+{
+    fn select_upstream(&mut self, ups: &[Arc<Mutex<Up>>]) -> Option<Arc<Mutex<Up>>> {
+        ups.iter()
+            .min_by_key(|up_mutex| {
+                // Is fine to unwrap a safe_lock result
+                let id = up_mutex.safe_lock(|up| up.get_id()).unwrap();
+                let position = self.priorities.iter().position(|p| *p == id);
+                (position.unwrap_or(self.priorities.len()), id)
+            })
+            .cloned()
+    }
+}
+
+/// Return the upstreams one after the other ordered by id, an upstream that can not be paired is
+/// skipped
+#[derive(Debug, Default)]
+pub struct RoundRobin {
+    last_selected: Option<u32>,
+}
+
+impl<
+        Down: IsMiningDownstream + D,
+        Up: IsMiningUpstream<Down, Sel> + D,
+        Sel: DownstreamMiningSelector<Down> + D,
+    > UpstreamSelectionLogic<Down, Up, Sel> for RoundRobin
+{
+    fn select_upstream(&mut self, ups: &[Arc<Mutex<Up>>]) -> Option<Arc<Mutex<Up>>> {
+        let mut ups: Vec<(u32, &Arc<Mutex<Up>>)> = ups
+            .iter()
+            // Is fine to unwrap a safe_lock result
+            .map(|up_mutex| (up_mutex.safe_lock(|up| up.get_id()).unwrap(), up_mutex))
+            .collect();
+        ups.sort_by_key(|(id, _)| *id);
+        let next = match self.last_selected {
+            Some(last) => ups
+                .iter()
+                .find(|(id, _)| *id > last)
+                .or_else(|| ups.first()),
+            None => ups.first(),
+        };
+        next.map(|(id, up_mutex)| {
+            self.last_selected = Some(*id);
+            (*up_mutex).clone()
+        })
+    }
 }
 
 fn minor_total_hr_upstream<Down, Up, Sel>(ups: &mut Vec<Arc<Mutex<Up>>>) -> Arc<Mutex<Up>>
@@ -316,45 +456,27 @@ where
         .collect()
 }
 
-/// If only one upstream is avaiable return it
-/// Try to return an upstream that is not header only
-/// Return the upstream that have got less hash rate from downstreams
-fn select_upstream<Down, Up, Sel>(ups: &mut Vec<Arc<Mutex<Up>>>) -> Option<Arc<Mutex<Up>>>
-where
-    Down: IsMiningDownstream + D,
-    Up: IsMiningUpstream<Down, Sel> + D,
-    Sel: DownstreamMiningSelector<Down> + D,
-{
-    if ups.is_empty() {
-        None
-    } else if ups.len() == 1 {
-        Some(ups[0].clone())
-    } else if !filter_header_only(ups).is_empty() {
-        Some(minor_total_hr_upstream(&mut filter_header_only(ups)))
-    } else {
-        Some(minor_total_hr_upstream(ups))
-    }
-}
-
 impl<
         Down: IsMiningDownstream + D,
         Up: IsMiningUpstream<Down, Sel> + D,
         Sel: DownstreamMiningSelector<Down> + D,
     > MiningProxyRoutingLogic<Down, Up, Sel>
 {
-    /// TODO this should stay in a enum UpstreamSelectionLogic that get passed from the caller to
-    /// the several methods
-    fn select_upstreams(ups: &mut Vec<Arc<Mutex<Up>>>) -> Option<Arc<Mutex<Up>>> {
-        select_upstream(ups)
+    /// Let `upstream_selection` choose between the avaiable upstreams, also when there is only
+    /// one as the strategy can refuse it (eg an upstream with weight 0)
+    fn select_upstreams(&mut self, ups: &[Arc<Mutex<Up>>]) -> Option<Arc<Mutex<Up>>> {
+        if ups.is_empty() {
+            None
+        } else {
+            self.upstream_selection.select_upstream(ups)
+        }
     }
 
     /// On setup conection the proxy find all the upstreams that support the downstream connection
     /// create a downstream message parser that points to all the possible upstreams and then respond
     /// with suppported flags.
     ///
-    /// The upstream with min total_hash_rate is selected (TODO a method to let the caller wich
-    /// upstream select from the possible ones should be added
-    /// on_setup_connection_mining_header_only_2 that return a Vec of possibe upstreams)
+    /// The upstream is selected by `upstream_selection`
     ///
     /// This function return downstream id that the new created downstream must return via the
     /// trait function get_id and the flags of the paired upstream
@@ -362,10 +484,10 @@ impl<
         &mut self,
         pair_settings: &PairSettings,
     ) -> Result<(CommonDownstreamData, SetupConnectionSuccess), Error> {
-        let mut upstreams = self.upstream_selector.on_setup_connection(pair_settings)?;
-        // TODO the upstream selection logic should be specified by the caller
-        let upstream =
-            Self::select_upstreams(&mut upstreams.0).ok_or(Error::NoUpstreamsConnected)?;
+        let upstreams = self.upstream_selector.on_setup_connection(pair_settings)?;
+        let upstream = self
+            .select_upstreams(&upstreams.0)
+            .ok_or(Error::NoUpstreamsConnected)?;
         let downstream_data = CommonDownstreamData {
             header_only: true,
             work_selection: false,
@@ -381,6 +503,33 @@ impl<
         Ok((downstream_data, message))
     }
 
+    /// Same as `on_setup_connection_mining_header_only` but for downstreams that do not require
+    /// standard jobs (e.g. another proxy). Header only upstreams are filtered out as extended
+    /// channels can not be opened with them.
+    pub fn on_setup_connection_mining_extended(
+        &mut self,
+        pair_settings: &PairSettings,
+    ) -> Result<(CommonDownstreamData, SetupConnectionSuccess), Error> {
+        let mut upstreams = self.upstream_selector.on_setup_connection(pair_settings)?;
+        let upstreams = filter_header_only(&mut upstreams.0);
+        let upstream = self
+            .select_upstreams(&upstreams)
+            .ok_or(Error::NoUpstreamsConnected)?;
+        let downstream_data = CommonDownstreamData {
+            header_only: false,
+            work_selection: false,
+            version_rolling: has_version_rolling(pair_settings.flags),
+        };
+        let message = SetupConnectionSuccess {
+            used_version: 2,
+            // Is fine to unwrap a safe_lock result
+            flags: upstream.safe_lock(|u| u.get_flags()).unwrap(),
+        };
+        self.downstream_to_upstream_map
+            .insert(downstream_data, vec![upstream]);
+        Ok((downstream_data, message))
+    }
+
     /// On open standard channel request:
     /// 1. an upstream must be selected between the possibles upstreams for this downstream, if the
     ///    downstream* is header only, just one upstream will be there so the choice is easy, if not
@@ -414,6 +563,7 @@ impl<
             .clone();
         upstream
             .safe_lock(|u| {
+                u.add_hash_rate(request.nominal_hash_rate as i64);
                 let selector = u.get_remote_selector();
                 selector.on_open_standard_channel_request(request.request_id.as_u32(), downstream)
             })
@@ -440,3 +590,164 @@ impl<
 //        Self::new()
 //    }
 //}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::common_properties::{IsUpstream, RequestIdMapper, UpstreamChannel};
+
+    #[derive(Debug)]
+    struct TestUpstream {
+        id: u32,
+        hash_rate: u64,
+        header_only: bool,
+        selector: NullDownstreamMiningSelector,
+    }
+
+    impl IsUpstream<(), NullDownstreamMiningSelector> for TestUpstream {
+        fn get_version(&self) -> u16 {
+            2
+        }
+        fn get_flags(&self) -> u32 {
+            if self.header_only {
+                1 << 31
+            } else {
+                0
+            }
+        }
+        fn get_supported_protocols(&self) -> Vec<Protocol> {
+            vec![Protocol::MiningProtocol]
+        }
+        fn get_id(&self) -> u32 {
+            self.id
+        }
+        fn get_mapper(&mut self) -> Option<&mut RequestIdMapper> {
+            None
+        }
+        fn get_remote_selector(&mut self) -> &mut NullDownstreamMiningSelector {
+            &mut self.selector
+        }
+    }
+
+    impl IsMiningUpstream<(), NullDownstreamMiningSelector> for TestUpstream {
+        fn total_hash_rate(&self) -> u64 {
+            self.hash_rate
+        }
+        fn add_hash_rate(&mut self, to_add: i64) {
+            self.hash_rate = (self.hash_rate as i64 + to_add) as u64;
+        }
+        fn get_opened_channels(&mut self) -> &mut Vec<UpstreamChannel> {
+            unreachable!()
+        }
+        fn update_channels(&mut self, _: UpstreamChannel) {
+            unreachable!()
+        }
+    }
+
+    type Strategy = dyn UpstreamSelectionLogic<(), TestUpstream, NullDownstreamMiningSelector>;
+
+    fn upstream(id: u32, hash_rate: u64, header_only: bool) -> Arc<Mutex<TestUpstream>> {
+        Arc::new(Mutex::new(TestUpstream {
+            id,
+            hash_rate,
+            header_only,
+            selector: NullDownstreamMiningSelector::new(),
+        }))
+    }
+
+    fn selected_id(strategy: &mut Strategy, ups: &[Arc<Mutex<TestUpstream>>]) -> Option<u32> {
+        strategy
+            .select_upstream(ups)
+            .map(|up| up.safe_lock(|up| up.id).unwrap())
+    }
+
+    fn weights(weights: &[(u32, u64)]) -> WeightedHashRate {
+        WeightedHashRate {
+            weights: weights.iter().copied().collect(),
+        }
+    }
+
+    #[test]
+    fn least_hash_rate_prefers_upstreams_that_are_not_header_only() {
+        let ups = vec![
+            upstream(0, 10, true),
+            upstream(1, 30, false),
+            upstream(2, 20, false),
+        ];
+        assert_eq!(selected_id(&mut LeastHashRate(), &ups), Some(2));
+
+        let ups = vec![upstream(0, 10, true), upstream(1, 5, true)];
+        assert_eq!(selected_id(&mut LeastHashRate(), &ups), Some(1));
+    }
+
+    #[test]
+    fn weighted_hash_rate_splits_the_hash_rate_by_weight() {
+        let mut strategy = weights(&[(0, 3), (1, 1)]);
+        let ups = vec![upstream(0, 0, false), upstream(1, 0, false)];
+        let mut selected = vec![];
+        for _ in 0..8 {
+            let id = selected_id(&mut strategy, &ups).unwrap();
+            ups[id as usize]
+                .safe_lock(|up| up.add_hash_rate(10))
+                .unwrap();
+            selected.push(id);
+        }
+        assert_eq!(selected.iter().filter(|id| **id == 0).count(), 6);
+        assert_eq!(selected.iter().filter(|id| **id == 1).count(), 2);
+    }
+
+    #[test]
+    fn weighted_hash_rate_never_selects_weight_zero() {
+        let mut strategy = weights(&[(0, 0)]);
+        let ups = vec![upstream(0, 0, false), upstream(1, 100, false)];
+        assert_eq!(selected_id(&mut strategy, &ups), Some(1));
+        assert_eq!(selected_id(&mut strategy, &ups[..1]), None);
+    }
+
+    #[test]
+    fn priority_failover_uses_the_preferred_available_upstream() {
+        let mut strategy = PriorityFailover {
+            priorities: vec![2, 0],
+        };
+        let ups = vec![
+            upstream(0, 0, false),
+            upstream(1, 0, false),
+            upstream(2, 0, false),
+        ];
+        assert_eq!(selected_id(&mut strategy, &ups), Some(2));
+        // upstream 2 is not connected
+        assert_eq!(selected_id(&mut strategy, &ups[..2]), Some(0));
+        // upstreams not in the priorities are used last
+        assert_eq!(selected_id(&mut strategy, &ups[1..2]), Some(1));
+    }
+
+    #[test]
+    fn round_robin_selects_the_upstreams_one_after_the_other() {
+        let mut strategy = RoundRobin::default();
+        let ups = vec![
+            upstream(2, 0, false),
+            upstream(0, 0, false),
+            upstream(1, 0, false),
+        ];
+        let selected: Vec<u32> = (0..4)
+            .map(|_| selected_id(&mut strategy, &ups).unwrap())
+            .collect();
+        assert_eq!(selected, vec![0, 1, 2, 0]);
+        // upstream 1 is skipped when it can not be paired
+        assert_eq!(selected_id(&mut strategy, &ups[..2]), Some(2));
+    }
+
+    #[test]
+    fn select_upstreams_delegates_also_with_a_single_upstream() {
+        let mut logic: MiningProxyRoutingLogic<(), TestUpstream, NullDownstreamMiningSelector> =
+            MiningProxyRoutingLogic {
+                upstream_selector: GeneralMiningSelector::new(vec![]),
+                downstream_id_generator: Id::new(),
+                downstream_to_upstream_map: HashMap::new(),
+                upstream_selection: Box::new(weights(&[(0, 0)])),
+            };
+        assert!(logic.select_upstreams(&[]).is_none());
+        assert!(logic.select_upstreams(&[upstream(0, 0, false)]).is_none());
+        assert!(logic.select_upstreams(&[upstream(1, 0, false)]).is_some());
+    }
+}
@@ -29,6 +29,71 @@ impl<Down: IsDownstream> ProxyDownstreamMiningSelector<Down> {
     {
         Arc::new(Mutex::new(Self::new()))
     }
+
+    /// Forget every channel and return each known downstream once. Used when the channels opened
+    /// with the upstream are lost (e.g. the connection with the upstream dropped).
+    pub fn remove_downstreams(&mut self) -> Vec<Arc<Mutex<Down>>> {
+        let mut downstreams: Vec<Arc<Mutex<Down>>> = Vec::new();
+        let all = self
+            .request_id_to_remotes
+            .drain()
+            .map(|(_, d)| d)
+            .chain(self.channel_id_to_downstream.drain().map(|(_, d)| d))
+            .chain(
+                self.channel_id_to_downstreams
+                    .drain()
+                    .flat_map(|(_, ds)| ds),
+            );
+        for downstream in all {
+            if !downstreams.iter().any(|d| Arc::ptr_eq(d, &downstream)) {
+                downstreams.push(downstream);
+            }
+        }
+        downstreams
+    }
+
+    /// Forget a standard channel of the group, return the downstream that opened it
+    pub fn remove_channel(&mut self, group_id: u32, channel_id: u32) -> Option<Arc<Mutex<Down>>> {
+        let downstream = self.channel_id_to_downstream.remove(&channel_id)?;
+        // The downstream is in the group once for every channel that it opened in it
+        if let Some(downstreams) = self.channel_id_to_downstreams.get_mut(&group_id) {
+            if let Some(position) = downstreams.iter().position(|d| Arc::ptr_eq(d, &downstream)) {
+                downstreams.remove(position);
+            }
+            if downstreams.is_empty() {
+                self.channel_id_to_downstreams.remove(&group_id);
+            }
+        }
+        Some(downstream)
+    }
+
+    /// Forget a request that will not be answered by the upstream (e.g. the channel is opened by
+    /// the proxy), return the downstream that sent it
+    pub fn remove_request(&mut self, request_id: u32) -> Option<Arc<Mutex<Down>>> {
+        self.request_id_to_remotes.remove(&request_id)
+    }
+
+    /// Forget every channel of the downstream (e.g. the downstream disconnected), return the ids
+    /// of the standard channels that it opened
+    pub fn remove_downstream(&mut self, downstream: &Arc<Mutex<Down>>) -> Vec<u32> {
+        self.request_id_to_remotes
+            .retain(|_, d| !Arc::ptr_eq(d, downstream));
+        for downstreams in self.channel_id_to_downstreams.values_mut() {
+            downstreams.retain(|d| !Arc::ptr_eq(d, downstream));
+        }
+        self.channel_id_to_downstreams
+            .retain(|_, downstreams| !downstreams.is_empty());
+        let channel_ids: Vec<u32> = self
+            .channel_id_to_downstream
+            .iter()
+            .filter(|(_, d)| Arc::ptr_eq(d, downstream))
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        for channel_id in &channel_ids {
+            self.channel_id_to_downstream.remove(channel_id);
+        }
+        channel_ids
+    }
 }
 
 impl<Down: IsMiningDownstream> DownstreamMiningSelector<Down>
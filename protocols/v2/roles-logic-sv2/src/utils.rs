@@ -4,7 +4,7 @@ use binary_sv2::U256;
 use bitcoin::{
     blockdata::block::BlockHeader,
     hash_types::{BlockHash, TxMerkleNode},
-    hashes::{sha256d::Hash as DHash, Hash},
+    hashes::{sha256, sha256d::Hash as DHash, siphash24, Hash, HashEngine},
     util::{hash::bitcoin_merkle_root, psbt::serialize::Deserialize},
     Transaction,
 };
@@ -159,6 +159,45 @@ pub fn target_from_hash_rate(hash_per_second: f32, share_per_min: f32) -> U256<'
     target.into()
 }
 
+/// Inverse of target_from_hash_rate: the hash rate for which `target` give `share_per_min`
+/// hash_per_second = (shar_per_min / 60) * 2^32 * (u256_max / target)
+pub fn hash_rate_from_target(target: U256<'static>, share_per_min: f32) -> f32 {
+    // target is little endian
+    let target = target
+        .inner_as_ref()
+        .iter()
+        .rev()
+        .fold(0.0, |acc, byte| acc * 256.0 + *byte as f64);
+    let u256_max = 2_f64.powi(256);
+    let hash_per_second = (share_per_min as f64 / 60.0) * u32::MAX as f64 * (u256_max / target);
+    hash_per_second.min(f32::MAX as f64) as f32
+}
+
+/// Hash of a transaction as used by the Job Negotiation Protocol: SHA256(transaction_data)
+pub fn tx_hash(transaction_data: &[u8]) -> [u8; 32] {
+    sha256::Hash::hash(transaction_data).into_inner()
+}
+
+/// Short transaction id used in CommitMiningJob.tx_short_hash_list
+/// SipHash-2-4 of the transaction hash, like in BIP152 the keys k0 and k1 are the first two little
+/// endian u64 of SHA256(tx_short_hash_nonce) and the upper two bytes of the result are set to 0
+pub fn tx_short_hash(tx_hash: &[u8], tx_short_hash_nonce: u64) -> u64 {
+    let keys = sha256::Hash::hash(&tx_short_hash_nonce.to_le_bytes()).into_inner();
+    // below never panic keys is 32 bytes
+    let k0 = u64::from_le_bytes(keys[0..8].try_into().unwrap());
+    let k1 = u64::from_le_bytes(keys[8..16].try_into().unwrap());
+    siphash24::Hash::hash_to_u64_with_keys(k0, k1, tx_hash) & 0x0000_ffff_ffff_ffff
+}
+
+/// CommitMiningJob.tx_hash_list_hash: SHA256 of the concatenation of the transactions hashes
+pub fn tx_hash_list_hash<T: AsRef<[u8]>>(tx_hashes: &[T]) -> U256<'static> {
+    let mut engine = sha256::Hash::engine();
+    for hash in tx_hashes {
+        engine.input(hash.as_ref());
+    }
+    sha256::Hash::from_engine(engine).into_inner().into()
+}
+
 #[cfg(test)]
 mod tests {
     #[cfg(feature = "serde")]
@@ -362,4 +401,41 @@ mod tests {
 
         assert_eq!(actual, expect);
     }
+
+    #[test]
+    fn tx_short_hash_upper_bytes_are_zero() {
+        let hash = super::tx_hash(&[1, 2, 3]);
+        for nonce in 0..100 {
+            let short_hash = super::tx_short_hash(&hash, nonce);
+            assert_eq!(short_hash >> 48, 0);
+            assert_eq!(short_hash, super::tx_short_hash(&hash, nonce));
+        }
+        assert_ne!(
+            super::tx_short_hash(&hash, 1),
+            super::tx_short_hash(&hash, 2)
+        );
+    }
+
+    #[test]
+    fn tx_hash_list_hash_is_hash_of_concatenated_hashes() {
+        let hashes = vec![super::tx_hash(&[1]), super::tx_hash(&[2])];
+        let concatenated = [&hashes[0][..], &hashes[1][..]].concat();
+        let expect: U256 = super::tx_hash(&concatenated).into();
+        assert_eq!(super::tx_hash_list_hash(&hashes), expect);
+    }
+
+    #[test]
+    fn target_from_hash_rate_round_trips_through_target() {
+        let tera = 1_000_000_000_000.0;
+        let target = super::target_from_hash_rate(tera, 6.0);
+        let back: U256 = mining_sv2::Target::from(target.clone()).into();
+        assert_eq!(back, target);
+        let hash_rate = super::hash_rate_from_target(back, 6.0);
+        assert!((hash_rate / tera - 1.0).abs() < 0.01);
+        // an higher hash rate get a lower target
+        assert!(
+            mining_sv2::Target::from(super::target_from_hash_rate(2.0 * tera, 6.0))
+                < mining_sv2::Target::from(target)
+        );
+    }
 }
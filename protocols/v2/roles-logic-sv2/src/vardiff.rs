@@ -0,0 +1,377 @@
+//! Variable difficulty
+//!
+//! Every channel start with a target computed from the hash rate declared by the downstream (or
+//! with a target given by the caller). The shares accepted for the channel are tracked over a
+//! sliding window and used to estimate the real hash rate of the channel, when the estimation
+//! differ too much from the hash rate used for the current target the channel is retargeted so
+//! that it submit `shares_per_minute` shares and a SetTarget for the downstream is returned.
+//!
+//! Retargets happen on accepted shares and on `on_tick`, that must be called periodically so that
+//! channels that do not submit shares at all get an easier target.
+//!
+//! Targets taken and returned are little endian as the ones sent on the wire.
+use crate::utils::{hash_rate_from_target, target_from_hash_rate};
+use binary_sv2::U256;
+use mining_sv2::{SetTarget, Target};
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// target_from_hash_rate do not accept lower hash rates
+const MIN_HASH_RATE: f32 = 1_000_000_000.0;
+
+/// The hash rate of a channel is multiplied or divided at most by this factor on every retarget so
+/// that a window with few shares can not move the target too far
+const MAX_RETARGET_FACTOR: f32 = 4.0;
+
+#[derive(Debug, Clone)]
+pub struct VardiffConfig {
+    /// Shares per minute that every channel should submit
+    pub shares_per_minute: f32,
+    /// Shares older than the window are not used to estimate the hash rate
+    pub window: Duration,
+    /// Minimum time between two retargets of the same channel
+    pub retarget_interval: Duration,
+    /// The channel is retargeted only if the estimated hash rate differ from the current one more
+    /// than this fraction (e.g. 0.3 is 30%)
+    pub tolerance: f32,
+}
+
+impl VardiffConfig {
+    pub fn new(shares_per_minute: f32, window: Duration, retarget_interval: Duration) -> Self {
+        Self {
+            shares_per_minute,
+            window,
+            retarget_interval,
+            tolerance: 0.3,
+        }
+    }
+
+    /// Lowest hash rate for which target_from_hash_rate return a target that is not greater
+    /// than the max target
+    fn min_hash_rate(&self) -> f32 {
+        let min_for_shares_per_minute = u32::MAX as f32 * self.shares_per_minute / 60.0;
+        MIN_HASH_RATE.max(min_for_shares_per_minute)
+    }
+}
+
+#[derive(Debug)]
+struct ChannelVardiff {
+    /// Hash rate used to compute the current target
+    hash_rate: f32,
+    target: U256<'static>,
+    /// Requested by the downstream with UpdateChannel, the target is never greater than this
+    maximum_target: U256<'static>,
+    /// (accepted at, hashes needed on average to find the share)
+    shares: VecDeque<(Instant, f64)>,
+    opened_at: Instant,
+    last_retarget: Instant,
+}
+
+#[derive(Debug)]
+pub struct Vardiff {
+    config: VardiffConfig,
+    channels: HashMap<u32, ChannelVardiff>,
+}
+
+impl Vardiff {
+    pub fn new(config: VardiffConfig) -> Self {
+        Self {
+            config,
+            channels: HashMap::new(),
+        }
+    }
+
+    /// Start tracking a channel, the initial target is computed from the hash rate declared by
+    /// the downstream in the open channel message and returned
+    pub fn add_channel(
+        &mut self,
+        channel_id: u32,
+        nominal_hash_rate: f32,
+        now: Instant,
+    ) -> U256<'static> {
+        let hash_rate = nominal_hash_rate.max(self.config.min_hash_rate());
+        let target = target_from_hash_rate(hash_rate, self.config.shares_per_minute);
+        self.insert_channel(channel_id, hash_rate, target.clone(), now);
+        target
+    }
+
+    /// Start tracking a channel that has already a target
+    pub fn add_channel_with_target(
+        &mut self,
+        channel_id: u32,
+        target: U256<'static>,
+        now: Instant,
+    ) {
+        let hash_rate = hash_rate_from_target(target.clone(), self.config.shares_per_minute);
+        self.insert_channel(channel_id, hash_rate, target, now);
+    }
+
+    fn insert_channel(
+        &mut self,
+        channel_id: u32,
+        hash_rate: f32,
+        target: U256<'static>,
+        now: Instant,
+    ) {
+        let channel = ChannelVardiff {
+            hash_rate,
+            target,
+            maximum_target: [255_u8; 32].into(),
+            shares: VecDeque::new(),
+            opened_at: now,
+            last_retarget: now,
+        };
+        self.channels.insert(channel_id, channel);
+    }
+
+    pub fn config(&self) -> &VardiffConfig {
+        &self.config
+    }
+
+    pub fn remove_channel(&mut self, channel_id: u32) {
+        self.channels.remove(&channel_id);
+    }
+
+    pub fn target(&self, channel_id: u32) -> Option<U256<'static>> {
+        self.channels.get(&channel_id).map(|c| c.target.clone())
+    }
+
+    /// Hash rate [H/s] used to compute the current target of the channel
+    pub fn hash_rate(&self, channel_id: u32) -> Option<f32> {
+        self.channels.get(&channel_id).map(|c| c.hash_rate)
+    }
+
+    /// Must be called for every share accepted for the channel. Return a SetTarget for the
+    /// downstream if the channel has been retargeted.
+    pub fn on_share_accepted(
+        &mut self,
+        channel_id: u32,
+        now: Instant,
+    ) -> Option<SetTarget<'static>> {
+        let config = &self.config;
+        let channel = self.channels.get_mut(&channel_id)?;
+        // Hashes per share is proportional to 1/target, a share per second at this target is
+        // found by a device with this hash rate
+        let hashes = hash_rate_from_target(channel.target.clone(), 60.0) as f64;
+        channel.shares.push_back((now, hashes));
+        Self::retarget(config, channel_id, channel, now)
+    }
+
+    /// The downstream declared a new hash rate with UpdateChannel: the channel is retargeted from
+    /// it and from now on its target is never greater than `maximum_target`. Return None if the
+    /// channel is not tracked.
+    pub fn update_channel(
+        &mut self,
+        channel_id: u32,
+        nominal_hash_rate: f32,
+        maximum_target: U256<'static>,
+        now: Instant,
+    ) -> Option<SetTarget<'static>> {
+        let config = &self.config;
+        let channel = self.channels.get_mut(&channel_id)?;
+        channel.maximum_target = maximum_target;
+        Self::set_hash_rate(
+            config,
+            channel,
+            nominal_hash_rate.max(config.min_hash_rate()),
+        );
+        channel.last_retarget = now;
+        Some(SetTarget {
+            channel_id,
+            maximum_target: channel.target.clone(),
+        })
+    }
+
+    /// Retarget the channels that have not been retargeted since retarget_interval, this is what
+    /// lower the difficulty of the channels that do not find shares
+    pub fn on_tick(&mut self, now: Instant) -> Vec<SetTarget<'static>> {
+        let config = &self.config;
+        self.channels
+            .iter_mut()
+            .filter_map(|(channel_id, channel)| Self::retarget(config, *channel_id, channel, now))
+            .collect()
+    }
+
+    fn retarget(
+        config: &VardiffConfig,
+        channel_id: u32,
+        channel: &mut ChannelVardiff,
+        now: Instant,
+    ) -> Option<SetTarget<'static>> {
+        if now.duration_since(channel.last_retarget) < config.retarget_interval {
+            return None;
+        }
+        while let Some((accepted_at, _)) = channel.shares.front() {
+            match now.duration_since(*accepted_at) > config.window {
+                true => channel.shares.pop_front(),
+                false => break,
+            };
+        }
+        let elapsed = now.duration_since(channel.opened_at).min(config.window);
+        let hashes: f64 = channel.shares.iter().map(|(_, hashes)| hashes).sum();
+        let estimated = (hashes / elapsed.as_secs_f64()) as f32;
+        let estimated = estimated
+            .max(channel.hash_rate / MAX_RETARGET_FACTOR)
+            .min(channel.hash_rate * MAX_RETARGET_FACTOR)
+            .max(config.min_hash_rate());
+        channel.last_retarget = now;
+        if (estimated / channel.hash_rate - 1.0).abs() <= config.tolerance {
+            return None;
+        }
+        let previous_target = channel.target.clone();
+        Self::set_hash_rate(config, channel, estimated);
+        if channel.target == previous_target {
+            return None;
+        }
+        Some(SetTarget {
+            channel_id,
+            maximum_target: channel.target.clone(),
+        })
+    }
+
+    fn set_hash_rate(config: &VardiffConfig, channel: &mut ChannelVardiff, hash_rate: f32) {
+        let target = target_from_hash_rate(hash_rate, config.shares_per_minute);
+        if Target::from(target.clone()) > Target::from(channel.maximum_target.clone()) {
+            channel.target = channel.maximum_target.clone();
+            channel.hash_rate =
+                hash_rate_from_target(channel.target.clone(), config.shares_per_minute);
+        } else {
+            channel.target = target;
+            channel.hash_rate = hash_rate;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TERA: f32 = 1_000_000_000_000.0;
+
+    fn vardiff() -> Vardiff {
+        Vardiff::new(VardiffConfig::new(
+            6.0,
+            Duration::from_secs(300),
+            Duration::from_secs(60),
+        ))
+    }
+
+    #[test]
+    fn sets_initial_target_from_nominal_hash_rate() {
+        let mut vardiff = vardiff();
+
+        let target = vardiff.add_channel(1, TERA, Instant::now());
+
+        assert_eq!(target, target_from_hash_rate(TERA, 6.0));
+        assert_eq!(vardiff.hash_rate(1), Some(TERA));
+    }
+
+    #[test]
+    fn does_not_retarget_before_retarget_interval() {
+        let mut vardiff = vardiff();
+        let start = Instant::now();
+        vardiff.add_channel(1, TERA, start);
+
+        for i in 0..100 {
+            let now = start + Duration::from_millis(i * 100);
+            assert!(vardiff.on_share_accepted(1, now).is_none());
+        }
+    }
+
+    #[test]
+    fn does_not_retarget_when_shares_match_the_target() {
+        let mut vardiff = vardiff();
+        let start = Instant::now();
+        vardiff.add_channel(1, TERA, start);
+
+        // 6 shares per minute
+        for i in 1..=60 {
+            let now = start + Duration::from_secs(i * 10);
+            assert!(vardiff.on_share_accepted(1, now).is_none());
+        }
+    }
+
+    #[test]
+    fn raises_difficulty_when_too_many_shares() {
+        let mut vardiff = vardiff();
+        let start = Instant::now();
+        vardiff.add_channel(1, TERA, start);
+
+        // 12 shares per minute, the channel has twice the declared hash rate
+        let mut set_target = None;
+        for i in 1..=12 {
+            let now = start + Duration::from_secs(i * 5);
+            set_target = set_target.or_else(|| vardiff.on_share_accepted(1, now));
+        }
+
+        let set_target = set_target.unwrap();
+        let hash_rate = vardiff.hash_rate(1).unwrap();
+        assert_eq!(set_target.channel_id, 1);
+        assert!((hash_rate / (2.0 * TERA) - 1.0).abs() < 0.01);
+        assert_eq!(
+            set_target.maximum_target,
+            target_from_hash_rate(hash_rate, 6.0)
+        );
+    }
+
+    #[test]
+    fn lowers_difficulty_when_no_shares() {
+        let mut vardiff = vardiff();
+        let start = Instant::now();
+        vardiff.add_channel(1, TERA, start);
+
+        assert!(vardiff.on_tick(start + Duration::from_secs(30)).is_empty());
+        let set_targets = vardiff.on_tick(start + Duration::from_secs(60));
+
+        assert_eq!(set_targets.len(), 1);
+        assert_eq!(vardiff.hash_rate(1), Some(TERA / MAX_RETARGET_FACTOR));
+    }
+
+    #[test]
+    fn retargets_from_updated_hash_rate() {
+        let mut vardiff = vardiff();
+        vardiff.add_channel(1, TERA, Instant::now());
+
+        let set_target = vardiff
+            .update_channel(1, 2.0 * TERA, [255_u8; 32].into(), Instant::now())
+            .unwrap();
+
+        assert_eq!(vardiff.hash_rate(1), Some(2.0 * TERA));
+        assert_eq!(
+            set_target.maximum_target,
+            target_from_hash_rate(2.0 * TERA, 6.0)
+        );
+        assert!(vardiff
+            .update_channel(2, TERA, [255_u8; 32].into(), Instant::now())
+            .is_none());
+    }
+
+    #[test]
+    fn never_exceeds_maximum_target() {
+        let mut vardiff = vardiff();
+        let start = Instant::now();
+        vardiff.add_channel(1, TERA, start);
+        let maximum_target = target_from_hash_rate(TERA, 6.0);
+
+        vardiff.update_channel(1, TERA / 2.0, maximum_target.clone(), start);
+        assert_eq!(vardiff.target(1), Some(maximum_target.clone()));
+
+        // No shares, without the maximum target the difficulty would be lowered
+        assert!(vardiff.on_tick(start + Duration::from_secs(60)).is_empty());
+        assert_eq!(vardiff.target(1), Some(maximum_target));
+    }
+
+    #[test]
+    fn estimates_hash_rate_of_channel_with_target() {
+        let mut vardiff = vardiff();
+        let target = target_from_hash_rate(TERA, 6.0);
+
+        vardiff.add_channel_with_target(1, target.clone(), Instant::now());
+
+        let hash_rate = vardiff.hash_rate(1).unwrap();
+        assert!((hash_rate / TERA - 1.0).abs() < 0.01);
+        assert_eq!(vardiff.target(1), Some(target));
+    }
+}
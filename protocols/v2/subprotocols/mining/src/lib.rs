@@ -150,12 +150,12 @@ pub struct Target {
     tail: u128,
 }
 
+/// Little endian as the targets sent on the wire, head is the most significant half
 impl From<[u8; 32]> for Target {
-    fn from(mut v: [u8; 32]) -> Self {
-        v.reverse();
+    fn from(v: [u8; 32]) -> Self {
         // below unwraps never panics
-        let head = u128::from_le_bytes(v[0..16].try_into().unwrap());
-        let tail = u128::from_le_bytes(v[16..32].try_into().unwrap());
+        let tail = u128::from_le_bytes(v[0..16].try_into().unwrap());
+        let head = u128::from_le_bytes(v[16..32].try_into().unwrap());
         Self { head, tail }
     }
 }
@@ -172,16 +172,16 @@ impl<'a> From<U256<'a>> for Target {
     fn from(v: U256<'a>) -> Self {
         let inner = v.inner_as_ref();
         // below unwraps never panics
-        let head = u128::from_le_bytes(inner[0..16].try_into().unwrap());
-        let tail = u128::from_le_bytes(inner[16..32].try_into().unwrap());
+        let tail = u128::from_le_bytes(inner[0..16].try_into().unwrap());
+        let head = u128::from_le_bytes(inner[16..32].try_into().unwrap());
         Self { head, tail }
     }
 }
 
 impl From<Target> for U256<'static> {
     fn from(v: Target) -> Self {
-        let mut inner = v.head.to_le_bytes().to_vec();
-        inner.extend_from_slice(&v.tail.to_le_bytes());
+        let mut inner = v.tail.to_le_bytes().to_vec();
+        inner.extend_from_slice(&v.head.to_le_bytes());
         // below unwraps never panics
         inner.try_into().unwrap()
     }
@@ -346,7 +346,7 @@ impl ExtendedExtranonce {
     }
 
     pub fn next_extended(&mut self, required_len: usize) -> Option<Extranonce> {
-        if required_len > self.range_2.start - self.range_2.end {
+        if required_len > self.range_2.end - self.range_2.start {
             return None;
         };
         let extended_part = &mut self.inner[self.range_1.start..self.range_1.end];
@@ -355,6 +355,43 @@ impl ExtendedExtranonce {
             Err(_) => None,
         }
     }
+
+    /// Used by a downstream that received an extranonce prefix from its upstream: the prefix is
+    /// copied into range_0 (that goes from 0 to prefix.len()) and is never changed.
+    pub fn from_upstream_prefix(
+        prefix: &[u8],
+        range_1: Range<usize>,
+        range_2: Range<usize>,
+    ) -> Option<Self> {
+        if prefix.len() != range_1.start
+            || range_1.end != range_2.start
+            || range_2.end != EXTRANONCE_LEN
+        {
+            return None;
+        };
+        let mut inner = [0; EXTRANONCE_LEN];
+        inner[0..prefix.len()].copy_from_slice(prefix);
+        Some(Self {
+            inner,
+            range_0: 0..prefix.len(),
+            range_1,
+            range_2,
+        })
+    }
+
+    /// Increment range_1 and return the bytes in range_0 and range_1 in the order in which they
+    /// are placed in the coinbase. The returned bytes are the extranonce prefix of a new
+    /// downstream, that will have range_2 (at least `required_len` bytes) to roll.
+    pub fn next_prefix_extended(&mut self, required_len: usize) -> Option<alloc::vec::Vec<u8>> {
+        if required_len > self.range_2.end - self.range_2.start {
+            return None;
+        };
+        let extended_part = &mut self.inner[self.range_1.start..self.range_1.end];
+        match increment_bytes_be(extended_part) {
+            Ok(_) => Some(self.inner[self.range_0.start..self.range_1.end].to_vec()),
+            Err(_) => None,
+        }
+    }
 }
 
 fn increment_bytes_be(bs: &mut [u8]) -> Result<(), ()> {
@@ -366,3 +403,49 @@ fn increment_bytes_be(bs: &mut [u8]) -> Result<(), ()> {
     }
     Err(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn bytes() -> Vec<u8> {
+        (0..32).collect()
+    }
+
+    #[test]
+    fn extranonce_u256_round_trip() {
+        let u256: U256 = bytes().try_into().unwrap();
+        let extranonce: Extranonce = u256.into();
+        let back: U256 = extranonce.into();
+        assert_eq!(back.to_vec(), bytes());
+    }
+
+    #[test]
+    fn extranonce_into_u256_and_vec_have_the_same_bytes() {
+        let u256: U256 = bytes().try_into().unwrap();
+        let extranonce: Extranonce = u256.into();
+        let as_vec: Vec<u8> = extranonce.clone().into();
+        let as_u256: U256 = extranonce.into();
+        assert_eq!(as_vec, as_u256.to_vec());
+    }
+
+    #[test]
+    fn target_u256_round_trip() {
+        let u256: U256 = bytes().try_into().unwrap();
+        let target: Target = u256.into();
+        let back: U256 = target.into();
+        assert_eq!(back.to_vec(), bytes());
+    }
+
+    #[test]
+    fn targets_are_compared_as_little_endian_numbers() {
+        let mut low = [0; 32];
+        low[0] = 0xff;
+        let mut high = [0; 32];
+        high[31] = 0x01;
+        assert!(Target::from(high) > Target::from(low));
+        let low_u256: U256 = low.into();
+        assert_eq!(Target::from(low_u256), Target::from(low));
+    }
+}
@@ -141,6 +141,18 @@ impl<'decoder> OpenExtendedMiningChannel<'decoder> {
     pub fn get_request_id_as_u32(&self) -> u32 {
         (&self.request_id).into()
     }
+
+    pub fn into_static_self(
+        s: OpenExtendedMiningChannel<'decoder>,
+    ) -> OpenExtendedMiningChannel<'static> {
+        OpenExtendedMiningChannel {
+            request_id: s.request_id.into_static(),
+            user_identity: s.user_identity.into_static(),
+            nominal_hash_rate: s.nominal_hash_rate,
+            max_target: s.max_target.into_static(),
+            min_extranonce_size: s.min_extranonce_size,
+        }
+    }
 }
 
 /// # OpenExtendedMiningChannel.Success (Server -> Client)
@@ -0,0 +1,116 @@
+use crate::{EitherFrame, StdFrame};
+use async_channel::{Receiver, Sender};
+use codec_sv2::{HandshakeRole, Responder};
+use network_helpers::noise_connection_tokio::Connection;
+use roles_logic_sv2::{
+    mining_sv2::SetCustomMiningJob,
+    parsers::{Mining, PoolMessages},
+    utils::Mutex,
+};
+use std::{convert::TryInto, net::SocketAddr, sync::Arc};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    task,
+};
+use tracing::{error, info, info_span, warn, Instrument};
+
+mod setup_connection;
+use setup_connection::SetupConnectionHandler;
+
+/// Mining proxies that receive the negotiated jobs
+pub struct Downstream {
+    senders: Vec<Sender<EitherFrame>>,
+    last_custom_job: Option<SetCustomMiningJob<'static>>,
+}
+
+impl Downstream {
+    pub async fn accept_connections(
+        address: SocketAddr,
+        custom_job_receiver: Receiver<SetCustomMiningJob<'static>>,
+    ) {
+        let self_ = Arc::new(Mutex::new(Self {
+            senders: Vec::new(),
+            last_custom_job: None,
+        }));
+        let cloned = self_.clone();
+        task::spawn(async { Self::on_new_custom_job(cloned, custom_job_receiver).await });
+
+        let listner = match TcpListener::bind(address).await {
+            Ok(listner) => listner,
+            Err(e) => {
+                error!(%address, "can not listen for downstreams: {}", e);
+                std::process::exit(1);
+            }
+        };
+        loop {
+            match listner.accept().await {
+                // The handshake and the setup connection are done in their own task so that a
+                // slow or malicious downstream do not block the other connections
+                Ok((stream, peer)) => {
+                    let span = info_span!("downstream", remote = %peer);
+                    task::spawn(Self::on_new_connection(self_.clone(), stream).instrument(span));
+                }
+                Err(e) => warn!("accept failed: {}", e),
+            }
+        }
+    }
+
+    async fn on_new_connection(self_: Arc<Mutex<Self>>, stream: TcpStream) {
+        // Is ok to unwrap the authority keys are valid constants
+        let responder = Responder::from_authority_kp(
+            &crate::AUTHORITY_PUBLIC_K[..],
+            &crate::AUTHORITY_PRIVATE_K[..],
+            crate::CERT_VALIDITY,
+        )
+        .unwrap();
+        let (mut receiver, mut sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
+            Connection::new(stream, HandshakeRole::Responder(responder)).await;
+        if SetupConnectionHandler::setup(&mut receiver, &mut sender)
+            .await
+            .is_err()
+        {
+            warn!("setup connection failed");
+            return;
+        }
+        info!("connected");
+
+        let last_custom_job = self_.safe_lock(|s| s.last_custom_job.clone()).unwrap();
+        if let Some(custom_job) = last_custom_job {
+            let sv2_frame: StdFrame = PoolMessages::Mining(Mining::SetCustomMiningJob(custom_job))
+                .try_into()
+                .unwrap();
+            if sender.send(sv2_frame.into()).await.is_err() {
+                info!("disconnected");
+                return;
+            }
+        }
+        self_.safe_lock(|s| s.senders.push(sender)).unwrap();
+
+        // Downstreams do not send messages to the Job Negotiator after the setup
+        while receiver.recv().await.is_ok() {}
+        info!("disconnected");
+    }
+
+    /// Send the job to every downstream, downstreams that are disconnected are removed
+    async fn on_new_custom_job(self_: Arc<Mutex<Self>>, rx: Receiver<SetCustomMiningJob<'static>>) {
+        while let Ok(custom_job) = rx.recv().await {
+            let senders = self_
+                .safe_lock(|s| {
+                    s.last_custom_job = Some(custom_job.clone());
+                    s.senders.clone()
+                })
+                .unwrap();
+            let mut connected = Vec::with_capacity(senders.len());
+            for sender in senders {
+                let sv2_frame: StdFrame =
+                    PoolMessages::Mining(Mining::SetCustomMiningJob(custom_job.clone()))
+                        .try_into()
+                        .unwrap();
+                if sender.send(sv2_frame.into()).await.is_ok() {
+                    connected.push(sender);
+                }
+            }
+            self_.safe_lock(|s| s.senders = connected).unwrap();
+        }
+    }
+}
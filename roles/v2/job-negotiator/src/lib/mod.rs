@@ -0,0 +1,4 @@
+pub mod downstream;
+mod setup_connection;
+pub mod template_receiver;
+pub mod upstream;
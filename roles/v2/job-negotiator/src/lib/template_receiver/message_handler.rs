@@ -0,0 +1,68 @@
+use super::TemplateRx;
+use roles_logic_sv2::{
+    errors::Error,
+    handlers::template_distribution::{ParseServerTemplateDistributionMessages, SendTo},
+    parsers::TemplateDistribution,
+    template_distribution_sv2::*,
+    utils::Mutex,
+};
+use std::sync::Arc;
+use tracing::warn;
+
+impl ParseServerTemplateDistributionMessages for TemplateRx {
+    /// The template is negotiated only when the transactions data are available
+    fn handle_new_template(&mut self, m: NewTemplate) -> Result<SendTo, Error> {
+        self.templates.insert(m.template_id, m.as_static());
+        Ok(SendTo::Respond(
+            TemplateDistribution::RequestTransactionData(RequestTransactionData {
+                template_id: m.template_id,
+            }),
+        ))
+    }
+
+    fn handle_set_new_prev_hash(&mut self, m: SetNewPrevHash) -> Result<SendTo, Error> {
+        let new_prev_hash = SetNewPrevHash {
+            template_id: m.template_id,
+            prev_hash: m.prev_hash.into_static(),
+            header_timestamp: m.header_timestamp,
+            n_bits: m.n_bits,
+            target: m.target.into_static(),
+        };
+        // Templates not yet negotiated that are not built on the new prev hash are stale
+        self.templates
+            .retain(|id, _| *id >= new_prev_hash.template_id);
+        let new_prev_hash = TemplateDistribution::SetNewPrevHash(new_prev_hash);
+        Ok(SendTo::RelayNewMessage(
+            Arc::new(Mutex::new(())),
+            new_prev_hash,
+        ))
+    }
+
+    fn handle_request_tx_data_success(
+        &mut self,
+        m: RequestTransactionDataSuccess,
+    ) -> Result<SendTo, Error> {
+        let success = RequestTransactionDataSuccess {
+            template_id: m.template_id,
+            excess_data: m.excess_data.into_static(),
+            transaction_list: m.transaction_list.into_static(),
+        };
+        Ok(SendTo::RelayNewMessage(
+            Arc::new(Mutex::new(())),
+            TemplateDistribution::RequestTransactionDataSuccess(success),
+        ))
+    }
+
+    fn handle_request_tx_data_error(
+        &mut self,
+        m: RequestTransactionDataError,
+    ) -> Result<SendTo, Error> {
+        warn!(
+            template_id = m.template_id,
+            error_code = %String::from_utf8_lossy(m.error_code.inner_as_ref()),
+            "template dropped, transactions data not available"
+        );
+        self.templates.remove(&m.template_id);
+        Ok(SendTo::None(None))
+    }
+}
@@ -0,0 +1,175 @@
+use crate::{lib::setup_connection::SetupConnectionHandler, EitherFrame, StdFrame};
+use async_channel::{Receiver, Sender};
+use codec_sv2::Frame;
+use network_helpers::plain_connection_tokio::PlainConnection;
+use roles_logic_sv2::{
+    bitcoin::consensus::serialize,
+    common_messages_sv2::Protocol,
+    errors::Error,
+    handlers::{template_distribution::ParseServerTemplateDistributionMessages, SendTo_},
+    parsers::{PoolMessages, TemplateDistribution},
+    template_distribution_sv2::{CoinbaseOutputDataSize, NewTemplate, SetNewPrevHash},
+    utils::Mutex,
+};
+use std::{collections::HashMap, convert::TryInto, net::SocketAddr, sync::Arc};
+use tokio::{net::TcpStream, task};
+use tracing::{error, info_span, warn, Instrument};
+
+mod message_handler;
+
+/// A template with the data of the transactions that it contains, in the same order of the block
+#[derive(Debug, Clone)]
+pub struct TemplateWithTransactions {
+    pub template: NewTemplate<'static>,
+    pub transactions: Vec<Vec<u8>>,
+}
+
+pub struct TemplateRx {
+    receiver: Receiver<EitherFrame>,
+    sender: Sender<EitherFrame>,
+    // template_id -> template waiting for RequestTransactionData.Success
+    templates: HashMap<u64, NewTemplate<'static>>,
+    new_job_sender: Sender<TemplateWithTransactions>,
+    new_prev_hash_sender: Sender<SetNewPrevHash<'static>>,
+}
+
+impl TemplateRx {
+    /// Fail if the Template Provider refuse the setup connection
+    pub async fn connect(
+        address: SocketAddr,
+        new_job_sender: Sender<TemplateWithTransactions>,
+        new_prev_hash_sender: Sender<SetNewPrevHash<'static>>,
+    ) -> Result<(), ()> {
+        let stream = TcpStream::connect(address).await.map_err(|e| {
+            error!(%address, "can not connect to the Template Provider: {}", e);
+        })?;
+
+        let (mut receiver, mut sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
+            PlainConnection::new(stream).await;
+
+        SetupConnectionHandler::setup(
+            &mut receiver,
+            &mut sender,
+            address,
+            Protocol::TemplateDistributionProtocol,
+        )
+        .await?;
+
+        let self_ = Arc::new(Mutex::new(Self {
+            receiver,
+            sender,
+            templates: HashMap::new(),
+            new_job_sender,
+            new_prev_hash_sender,
+        }));
+
+        // The Job Negotiator add only the pool output to the coinbase
+        let coinbase_output_data_size = CoinbaseOutputDataSize {
+            coinbase_output_max_additional_size: serialize(&crate::pool_output(0)).len() as u32,
+        };
+        let sv2_frame: StdFrame = PoolMessages::TemplateDistribution(
+            TemplateDistribution::CoinbaseOutputDataSize(coinbase_output_data_size),
+        )
+        .try_into()
+        .unwrap();
+        Self::send(self_.clone(), sv2_frame).await?;
+
+        // Every event of the Template Provider connection is in this span
+        let span = info_span!("template_provider", %address);
+        task::spawn(async { Self::start(self_).await }.instrument(span));
+        Ok(())
+    }
+
+    pub async fn start(self_: Arc<Mutex<Self>>) {
+        let (receiver, new_job_sender, new_prev_hash_sender) = self_
+            .safe_lock(|s| {
+                (
+                    s.receiver.clone(),
+                    s.new_job_sender.clone(),
+                    s.new_prev_hash_sender.clone(),
+                )
+            })
+            .unwrap();
+        while let Ok(message_from_tp) = receiver.recv().await {
+            let mut message_from_tp: StdFrame = match message_from_tp.try_into() {
+                Ok(frame) => frame,
+                Err(_) => {
+                    warn!("received an invalid frame");
+                    continue;
+                }
+            };
+            let message_type = match message_from_tp.get_header() {
+                Some(header) => header.msg_type(),
+                None => {
+                    warn!("received a frame without header");
+                    continue;
+                }
+            };
+            let payload = message_from_tp.payload();
+            let handled = info_span!("message", msg_type = message_type).in_scope(|| {
+                ParseServerTemplateDistributionMessages::handle_message_template_distribution(
+                    self_.clone(),
+                    message_type,
+                    payload,
+                )
+            });
+            let forwarded = match handled {
+                Ok(SendTo_::Respond(m)) => {
+                    let sv2_frame: StdFrame =
+                        PoolMessages::TemplateDistribution(m).try_into().unwrap();
+                    Self::send(self_.clone(), sv2_frame).await
+                }
+                Ok(SendTo_::RelayNewMessage(_, m)) => match m {
+                    TemplateDistribution::SetNewPrevHash(m) => {
+                        new_prev_hash_sender.send(m).await.map_err(|_| ())
+                    }
+                    TemplateDistribution::RequestTransactionDataSuccess(m) => {
+                        let template = self_
+                            .safe_lock(|s| s.templates.remove(&m.template_id))
+                            .unwrap();
+                        match template {
+                            Some(template) => {
+                                let job = TemplateWithTransactions {
+                                    template,
+                                    transactions: m.transaction_list.to_vec(),
+                                };
+                                new_job_sender.send(job).await.map_err(|_| ())
+                            }
+                            None => Ok(()),
+                        }
+                    }
+                    _ => {
+                        warn!(msg_type = message_type, "message can not be relayed");
+                        Ok(())
+                    }
+                },
+                Ok(SendTo_::None(_)) => Ok(()),
+                Ok(_) => {
+                    warn!(msg_type = message_type, "message can not be relayed");
+                    Ok(())
+                }
+                Err(Error::UnexpectedMessage) => {
+                    warn!(msg_type = message_type, "unexpected message");
+                    Ok(())
+                }
+                Err(e) => {
+                    warn!(msg_type = message_type, "invalid message: {}", e);
+                    Ok(())
+                }
+            };
+            // The Job Negotiator can not do anything without the Template Provider or the pool
+            if forwarded.is_err() {
+                error!("can not forward the Template Provider messages");
+                std::process::exit(1);
+            }
+        }
+        error!("Template Provider connection closed");
+        std::process::exit(1);
+    }
+
+    pub async fn send(self_: Arc<Mutex<Self>>, sv2_frame: StdFrame) -> Result<(), ()> {
+        let either_frame = sv2_frame.into();
+        let sender = self_.safe_lock(|self_| self_.sender.clone()).unwrap();
+        sender.send(either_frame).await.map_err(|_| ())
+    }
+}
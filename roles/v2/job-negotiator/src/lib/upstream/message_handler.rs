@@ -0,0 +1,87 @@
+use super::Upstream;
+use binary_sv2::{Seq064K, B016M, U256};
+use roles_logic_sv2::{
+    errors::Error,
+    handlers::job_negotiation::{ParseServerJobNegotiationMessages, SendTo},
+    job_negotiation_sv2::*,
+    parsers::JobNegotiation,
+};
+use std::convert::TryInto;
+use tracing::warn;
+
+impl ParseServerJobNegotiationMessages for Upstream {
+    fn handle_allocate_mining_job_token_success(
+        &mut self,
+        m: AllocateMiningJobTokenSuccess,
+    ) -> Result<SendTo, Error> {
+        match self.jobs.get(&m.request_id) {
+            Some(job) => Ok(SendTo::Respond(JobNegotiation::CommitMiningJob(
+                job.commit_mining_job(m.request_id, m.mining_job_token),
+            ))),
+            // The job is stale
+            None => Ok(SendTo::None(None)),
+        }
+    }
+
+    fn handle_commit_mining_job_success(
+        &mut self,
+        m: CommitMiningJobSuccess,
+    ) -> Result<SendTo, Error> {
+        if let Some(job) = self.jobs.get_mut(&m.request_id) {
+            job.mining_job_token = Some(m.new_mining_job_token);
+            self.on_job_committed(m.request_id);
+        }
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_commit_mining_job_error(&mut self, m: CommitMiningJobError) -> Result<SendTo, Error> {
+        warn!(
+            request_id = m.request_id,
+            error_code = %String::from_utf8_lossy(m.error_code.inner_as_ref()),
+            "job refused by the pool"
+        );
+        self.jobs.remove(&m.request_id);
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_identify_transactions(&mut self, m: IdentifyTransactions) -> Result<SendTo, Error> {
+        let job = match self.jobs.get(&m.request_id) {
+            Some(job) => job,
+            None => return Ok(SendTo::None(None)),
+        };
+        let tx_hash_list: Vec<U256<'static>> =
+            job.tx_hashes.iter().map(|hash| (*hash).into()).collect();
+        Ok(SendTo::Respond(
+            JobNegotiation::IdentifyTransactionsSuccess(IdentifyTransactionsSuccess {
+                request_id: m.request_id,
+                tx_hash_list: Seq064K::new(tx_hash_list).unwrap(),
+            }),
+        ))
+    }
+
+    fn handle_provide_missing_transactions(
+        &mut self,
+        m: ProvideMissingTransactions,
+    ) -> Result<SendTo, Error> {
+        let job = match self.jobs.get(&m.request_id) {
+            Some(job) => job,
+            None => return Ok(SendTo::None(None)),
+        };
+        let mut transaction_list: Vec<B016M<'static>> = Vec::new();
+        for position in m.unknown_tx_position_list.to_vec() {
+            match job.transactions.get(position as usize) {
+                Some(transaction) => transaction_list.push(transaction.clone().try_into().unwrap()),
+                None => warn!(
+                    request_id = m.request_id,
+                    position, "pool asked for a transaction that is not in the job"
+                ),
+            }
+        }
+        Ok(SendTo::Respond(
+            JobNegotiation::ProvideMissingTransactionsSuccess(ProvideMissingTransactionsSuccess {
+                request_id: m.request_id,
+                transaction_list: Seq064K::new(transaction_list).unwrap(),
+            }),
+        ))
+    }
+}
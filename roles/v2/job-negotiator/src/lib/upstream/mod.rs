@@ -0,0 +1,338 @@
+use crate::{
+    lib::{setup_connection::SetupConnectionHandler, template_receiver::TemplateWithTransactions},
+    EitherFrame, StdFrame,
+};
+use async_channel::{Receiver, Sender};
+use binary_sv2::{Seq064K, B064K};
+use codec_sv2::{Frame, HandshakeRole, Initiator};
+use network_helpers::noise_connection_tokio::Connection;
+use roles_logic_sv2::{
+    bitcoin::{consensus::serialize, TxOut},
+    common_messages_sv2::Protocol,
+    errors::Error,
+    handlers::job_negotiation::{ParseServerJobNegotiationMessages, SendTo},
+    job_creator::template_coinbase_outputs,
+    job_negotiation_sv2::{AllocateMiningJobToken, CommitMiningJob},
+    mining_sv2::SetCustomMiningJob,
+    parsers::{JobNegotiation, PoolMessages},
+    template_distribution_sv2::{NewTemplate, SetNewPrevHash},
+    utils::{tx_hash, tx_hash_list_hash, tx_short_hash, Id, Mutex},
+};
+use std::{collections::HashMap, convert::TryInto, net::SocketAddr, sync::Arc};
+use tokio::{net::TcpStream, task};
+use tracing::{error, info_span, warn, Instrument};
+
+mod message_handler;
+
+/// A template that is being negotiated with the pool
+#[derive(Debug)]
+struct Job {
+    template: NewTemplate<'static>,
+    transactions: Vec<Vec<u8>>,
+    tx_hashes: Vec<[u8; 32]>,
+    coinbase_tx_outputs: Vec<TxOut>,
+    // Some when the pool accepted the job
+    mining_job_token: Option<u32>,
+}
+
+impl Job {
+    /// Fail if the Template Provider sent invalid coinbase outputs
+    fn new(job: TemplateWithTransactions) -> Result<Self, Error> {
+        let tx_hashes = job.transactions.iter().map(|tx| tx_hash(tx)).collect();
+        // Pool output first then the outputs required by the Template Provider
+        let mut coinbase_tx_outputs =
+            vec![crate::pool_output(job.template.coinbase_tx_value_remaining)];
+        coinbase_tx_outputs.extend(template_coinbase_outputs(&job.template)?);
+        Ok(Self {
+            template: job.template,
+            transactions: job.transactions,
+            tx_hashes,
+            coinbase_tx_outputs,
+            mining_job_token: None,
+        })
+    }
+
+    fn serialized_outputs(&self) -> Seq064K<'static, B064K<'static>> {
+        let outputs: Vec<B064K<'static>> = self
+            .coinbase_tx_outputs
+            .iter()
+            .map(|output| serialize(output).try_into().unwrap())
+            .collect();
+        Seq064K::new(outputs).unwrap()
+    }
+
+    fn commit_mining_job(
+        &self,
+        request_id: u32,
+        mining_job_token: u32,
+    ) -> CommitMiningJob<'static> {
+        let tx_short_hash_nonce: u64 = rand::random();
+        let tx_short_hash_list: Vec<u64> = self
+            .tx_hashes
+            .iter()
+            .map(|hash| tx_short_hash(hash, tx_short_hash_nonce))
+            .collect();
+        CommitMiningJob {
+            request_id,
+            mining_job_token,
+            version: self.template.version,
+            coinbase_tx_version: self.template.coinbase_tx_version,
+            coinbase_prefix: self.template.coinbase_prefix.clone(),
+            coinbase_tx_input_n_sequence: self.template.coinbase_tx_input_sequence,
+            coinbase_tx_value_remaining: self.template.coinbase_tx_value_remaining,
+            coinbase_tx_outputs: self.serialized_outputs(),
+            coinbase_tx_locktime: self.template.coinbase_tx_locktime,
+            min_extranonce_size: crate::MIN_EXTRANONCE_SIZE,
+            tx_short_hash_nonce,
+            tx_short_hash_list: Seq064K::new(tx_short_hash_list).unwrap(),
+            tx_hash_list_hash: tx_hash_list_hash(&self.tx_hashes),
+            excess_data: Vec::new().try_into().unwrap(),
+        }
+    }
+
+    /// The channel id is set by the downstream, that is the only one that know the id of the
+    /// channel that will mine the job
+    fn set_custom_mining_job(
+        &self,
+        request_id: u32,
+        prev_hash: &SetNewPrevHash<'static>,
+    ) -> Option<SetCustomMiningJob<'static>> {
+        let mining_job_token = self.mining_job_token?.to_le_bytes().to_vec();
+        // NewTemplate.coinbase_prefix start with the script len followed by the BIP34 block
+        // height: 1 byte for the len and 3 bytes for the height, that is what fit in a u32
+        let mut coinbase_prefix = [0_u8; 4];
+        for (i, b) in self
+            .template
+            .coinbase_prefix
+            .inner_as_ref()
+            .iter()
+            .skip(1)
+            .take(4)
+            .enumerate()
+        {
+            coinbase_prefix[i] = *b;
+        }
+        Some(SetCustomMiningJob {
+            channel_id: 0,
+            request_id,
+            mining_job_token: mining_job_token.try_into().unwrap(),
+            version: self.template.version,
+            prev_hash: prev_hash.prev_hash.clone(),
+            min_ntime: prev_hash.header_timestamp,
+            nbits: prev_hash.n_bits,
+            coinbase_tx_version: self.template.coinbase_tx_version,
+            coinbase_prefix: u32::from_le_bytes(coinbase_prefix),
+            coinbase_tx_input_n_sequence: self.template.coinbase_tx_input_sequence,
+            coinbase_tx_value_remaining: self.template.coinbase_tx_value_remaining,
+            coinbase_tx_outputs: self.serialized_outputs(),
+            coinbase_tx_locktime: self.template.coinbase_tx_locktime,
+            merkle_path: self.template.merkle_path.clone(),
+            extranonce_size: crate::MIN_EXTRANONCE_SIZE,
+            future_job: false,
+        })
+    }
+}
+
+/// Job Negotiation connection with the pool
+pub struct Upstream {
+    receiver: Receiver<EitherFrame>,
+    sender: Sender<EitherFrame>,
+    request_ids: Id,
+    // request_id -> Job, the same request id is used for AllocateMiningJobToken and for
+    // CommitMiningJob
+    jobs: HashMap<u32, Job>,
+    last_prev_hash: Option<SetNewPrevHash<'static>>,
+    // Negotiated jobs ready to be sent to the downstreams
+    custom_jobs: Vec<SetCustomMiningJob<'static>>,
+    custom_job_sender: Sender<SetCustomMiningJob<'static>>,
+}
+
+impl Upstream {
+    /// Fail if the pool refuse the setup connection
+    pub async fn connect(
+        address: SocketAddr,
+        new_job_receiver: Receiver<TemplateWithTransactions>,
+        new_prev_hash_receiver: Receiver<SetNewPrevHash<'static>>,
+        custom_job_sender: Sender<SetCustomMiningJob<'static>>,
+    ) -> Result<(), ()> {
+        let stream = TcpStream::connect(address).await.map_err(|e| {
+            error!(%address, "can not connect to the pool: {}", e);
+        })?;
+        // Is ok to unwrap AUTHORITY_PUBLIC_K is a valid key
+        let initiator = Initiator::from_raw_k(crate::AUTHORITY_PUBLIC_K).unwrap();
+        let (mut receiver, mut sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
+            Connection::new(stream, HandshakeRole::Initiator(initiator)).await;
+
+        SetupConnectionHandler::setup(
+            &mut receiver,
+            &mut sender,
+            address,
+            Protocol::JobNegotiationProtocol,
+        )
+        .await?;
+
+        let self_ = Arc::new(Mutex::new(Self {
+            receiver,
+            sender,
+            request_ids: Id::new(),
+            jobs: HashMap::new(),
+            last_prev_hash: None,
+            custom_jobs: Vec::new(),
+            custom_job_sender,
+        }));
+
+        // Every event of the pool connection is in this span
+        let span = info_span!("upstream", %address);
+        let cloned = self_.clone();
+        task::spawn(async { Self::start(cloned).await }.instrument(span.clone()));
+        let cloned = self_.clone();
+        task::spawn(
+            async { Self::on_new_job(cloned, new_job_receiver).await }.instrument(span.clone()),
+        );
+        task::spawn(
+            async { Self::on_new_prev_hash(self_, new_prev_hash_receiver).await }.instrument(span),
+        );
+        Ok(())
+    }
+
+    pub async fn start(self_: Arc<Mutex<Self>>) {
+        let receiver = self_.safe_lock(|s| s.receiver.clone()).unwrap();
+        while let Ok(incoming) = receiver.recv().await {
+            let mut incoming: StdFrame = match incoming.try_into() {
+                Ok(frame) => frame,
+                Err(_) => {
+                    warn!("received an invalid frame");
+                    continue;
+                }
+            };
+            let message_type = match incoming.get_header() {
+                Some(header) => header.msg_type(),
+                None => {
+                    warn!("received a frame without header");
+                    continue;
+                }
+            };
+            let payload = incoming.payload();
+            let handled = info_span!("message", msg_type = message_type).in_scope(|| {
+                ParseServerJobNegotiationMessages::handle_message_job_negotiation(
+                    self_.clone(),
+                    message_type,
+                    payload,
+                )
+            });
+            match handled {
+                Ok(SendTo::Respond(message)) => {
+                    let sv2_frame: StdFrame =
+                        PoolMessages::JobNegotiation(message).try_into().unwrap();
+                    if Self::send(self_.clone(), sv2_frame).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(SendTo::None(_)) => (),
+                Ok(_) => warn!(msg_type = message_type, "message can not be relayed"),
+                Err(Error::UnexpectedMessage) => {
+                    warn!(msg_type = message_type, "unexpected message")
+                }
+                Err(e) => warn!(msg_type = message_type, "invalid message: {}", e),
+            }
+            Self::send_custom_jobs(self_.clone()).await;
+        }
+        // The Job Negotiator can not do anything without the pool
+        error!("pool connection closed");
+        std::process::exit(1);
+    }
+
+    pub async fn send(self_: Arc<Mutex<Self>>, sv2_frame: StdFrame) -> Result<(), ()> {
+        let either_frame = sv2_frame.into();
+        let sender = self_.safe_lock(|self_| self_.sender.clone()).unwrap();
+        sender.send(either_frame).await.map_err(|_| ())
+    }
+
+    async fn send_custom_jobs(self_: Arc<Mutex<Self>>) {
+        let (custom_jobs, sender) = self_
+            .safe_lock(|s| {
+                (
+                    std::mem::take(&mut s.custom_jobs),
+                    s.custom_job_sender.clone(),
+                )
+            })
+            .unwrap();
+        for custom_job in custom_jobs {
+            if sender.send(custom_job).await.is_err() {
+                error!("downstreams are not listening for custom jobs");
+                std::process::exit(1);
+            }
+        }
+    }
+
+    /// Every new template is negotiated with a new token
+    async fn on_new_job(self_: Arc<Mutex<Self>>, rx: Receiver<TemplateWithTransactions>) {
+        while let Ok(job) = rx.recv().await {
+            let template_id = job.template.template_id;
+            let job = match Job::new(job) {
+                Ok(job) => job,
+                Err(e) => {
+                    warn!(template_id, "template dropped: {}", e);
+                    continue;
+                }
+            };
+            let request_id = self_
+                .safe_lock(|s| {
+                    let request_id = s.request_ids.next();
+                    s.jobs.insert(request_id, job);
+                    request_id
+                })
+                .unwrap();
+            let allocate_token = AllocateMiningJobToken {
+                user_identifier: crate::USER_IDENTIFIER.to_string().try_into().unwrap(),
+                request_id,
+            };
+            let sv2_frame: StdFrame = PoolMessages::JobNegotiation(
+                JobNegotiation::AllocateMiningJobToken(allocate_token),
+            )
+            .try_into()
+            .unwrap();
+            // When the connection is closed the Job Negotiator is stopped by `start`
+            if Self::send(self_.clone(), sv2_frame).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    async fn on_new_prev_hash(self_: Arc<Mutex<Self>>, rx: Receiver<SetNewPrevHash<'static>>) {
+        while let Ok(prev_hash) = rx.recv().await {
+            self_
+                .safe_lock(|s| {
+                    // Jobs for templates older than the one of the prev hash are stale
+                    s.jobs
+                        .retain(|_, job| job.template.template_id >= prev_hash.template_id);
+                    for (request_id, job) in &s.jobs {
+                        if job.template.template_id == prev_hash.template_id {
+                            if let Some(custom_job) =
+                                job.set_custom_mining_job(*request_id, &prev_hash)
+                            {
+                                s.custom_jobs.push(custom_job);
+                            }
+                        }
+                    }
+                    s.last_prev_hash = Some(prev_hash);
+                })
+                .unwrap();
+            Self::send_custom_jobs(self_.clone()).await;
+        }
+    }
+
+    /// Called when the pool accept a job, if the job is for the current prev hash it can be
+    /// mined immediately, future jobs are sent when the relative prev hash is received
+    fn on_job_committed(&mut self, request_id: u32) {
+        let (job, prev_hash) = match (self.jobs.get(&request_id), &self.last_prev_hash) {
+            (Some(job), Some(prev_hash)) => (job, prev_hash),
+            _ => return,
+        };
+        if !job.template.future_template || job.template.template_id == prev_hash.template_id {
+            if let Some(custom_job) = job.set_custom_mining_job(request_id, prev_hash) {
+                self.custom_jobs.push(custom_job);
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! Job Negotiator
+//!
+//! Template Provider means the bitcoind node that select the transactions
+//! Upstream means the pool
+//! Downstream means a Sv2 mining proxy that open extended channels with the pool
+//!
+//! For every NewTemplate received from the Template Provider the Job Negotiator ask for the
+//! transactions data, allocate a mining job token with the pool (AllocateMiningJobToken) and
+//! commit the job (CommitMiningJob). When the pool accept the job, the job is handed to the
+//! downstreams as SetCustomMiningJob, the downstream set its own channel id and send it to the pool.
+//!
+use async_channel::bounded;
+use codec_sv2::{StandardEitherFrame, StandardSv2Frame};
+use network_helpers::logging;
+use roles_logic_sv2::{
+    bitcoin::{blockdata::script::Script, PublicKey, TxOut},
+    parsers::PoolMessages,
+};
+use tracing::{error, info};
+
+mod lib;
+
+use lib::{downstream::Downstream, template_receiver::TemplateRx, upstream::Upstream};
+
+pub type Message = PoolMessages<'static>;
+pub type StdFrame = StandardSv2Frame<Message>;
+pub type EitherFrame = StandardEitherFrame<Message>;
+
+const LISTEN_ADDR: &str = "0.0.0.0:34265";
+const POOL_ADDR: &str = "127.0.0.1:34264";
+const TP_ADDR: &str = "127.0.0.1:8442";
+
+/// Identifier sent to the pool in AllocateMiningJobToken
+const USER_IDENTIFIER: &str = "job-negotiator";
+
+/// Extranonce size that the downstreams must have available when mining a negotiated job
+const MIN_EXTRANONCE_SIZE: u16 = 16;
+
+/// Public key of the pool payout, every committed job pay the whole
+/// coinbase_tx_value_remaining to it
+const POOL_PUBLIC_KEY_BTC: [u8; 33] = [
+    2, 70, 109, 127, 202, 229, 99, 229, 203, 9, 160, 209, 135, 11, 181, 128, 52, 72, 4, 97, 120,
+    121, 161, 73, 73, 207, 34, 40, 95, 27, 174, 63, 39,
+];
+
+const AUTHORITY_PUBLIC_K: [u8; 32] = [
+    215, 11, 47, 78, 34, 232, 25, 192, 195, 168, 170, 209, 95, 181, 40, 114, 154, 226, 176, 190,
+    90, 169, 238, 89, 191, 183, 97, 63, 194, 119, 11, 31,
+];
+
+const AUTHORITY_PRIVATE_K: [u8; 32] = [
+    204, 93, 167, 220, 169, 204, 172, 35, 9, 84, 174, 208, 171, 89, 25, 53, 196, 209, 161, 148, 4,
+    5, 173, 0, 234, 59, 15, 127, 31, 160, 136, 131,
+];
+
+const CERT_VALIDITY: std::time::Duration = std::time::Duration::from_secs(3600);
+
+/// Output that pay the pool, the value is the coinbase_tx_value_remaining of the template
+fn pool_output(value: u64) -> TxOut {
+    // Is ok to unwrap POOL_PUBLIC_KEY_BTC is a valid compressed key
+    let pub_key = PublicKey::from_slice(&POOL_PUBLIC_KEY_BTC).unwrap();
+    let script_pubkey = Script::new_v0_wpkh(&pub_key.wpubkey_hash().unwrap());
+    TxOut {
+        value,
+        script_pubkey,
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // The level is set with RUST_LOG and the format with SV2_LOG_FORMAT
+    if let Err(e) = logging::init_from_env() {
+        eprintln!("JOB NEGOTIATOR: {}", e);
+        std::process::exit(1);
+    }
+    let (s_new_job, r_new_job) = bounded(10);
+    let (s_prev_hash, r_prev_hash) = bounded(10);
+    let (s_custom_job, r_custom_job) = bounded(10);
+    info!("initializing");
+    if TemplateRx::connect(TP_ADDR.parse().unwrap(), s_new_job, s_prev_hash)
+        .await
+        .is_err()
+    {
+        error!(
+            address = TP_ADDR,
+            "setup connection with the Template Provider failed"
+        );
+        std::process::exit(1);
+    }
+    if Upstream::connect(
+        POOL_ADDR.parse().unwrap(),
+        r_new_job,
+        r_prev_hash,
+        s_custom_job,
+    )
+    .await
+    .is_err()
+    {
+        error!(address = POOL_ADDR, "setup connection with the pool failed");
+        std::process::exit(1);
+    }
+    info!(listen_address = LISTEN_ADDR, "initialized");
+    Downstream::accept_connections(LISTEN_ADDR.parse().unwrap(), r_custom_job).await;
+}
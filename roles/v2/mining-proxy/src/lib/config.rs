@@ -0,0 +1,502 @@
+//! Proxy configuration
+//!
+//! The configuration is read once at startup from a TOML file (`proxy-config.toml` by default,
+//! see `--config`) and validated before the proxy connect to the upstreams, so that a bad
+//! configuration is reported with a clear error instead of a panic.
+use network_helpers::logging::{self, LogFormat};
+use roles_logic_sv2::vardiff::VardiffConfig;
+use serde::Deserialize;
+use std::{
+    fmt::{self, Display},
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+const DEFAULT_CONFIG_PATH: &str = "proxy-config.toml";
+
+/// Sv2 version supported when the config file do not set it
+const DEFAULT_SUPPORTED_VERSION: u16 = 2;
+
+pub const USAGE: &str = "Usage: mining-proxy [OPTIONS]
+
+Options:
+  -c, --config <FILE>    Configuration file [default: proxy-config.toml]
+  -h, --help             Print this message";
+
+#[derive(Debug)]
+pub enum Error {
+    HelpRequested,
+    InvalidArgument(String),
+    MissingArgumentValue(String),
+    ReadFile(PathBuf, std::io::Error),
+    InvalidToml(PathBuf, toml::de::Error),
+    NoUpstreams,
+    /// (host, port, error) of an upstream that can not be resolved
+    UnresolvedUpstream(String, u16, Option<std::io::Error>),
+    /// Every upstream has weight 0 with the weighted-hash-rate selection
+    NoWeightedUpstream,
+    InvalidListenAddress(String),
+    InvalidMetricsAddress(String),
+    InvalidSupportedVersions(u16, u16),
+    InvalidVardiff,
+    InvalidLogging(logging::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            HelpRequested => write!(f, "{}", USAGE),
+            InvalidArgument(arg) => write!(f, "Unknown argument `{}`\n\n{}", arg, USAGE),
+            MissingArgumentValue(arg) => write!(f, "Missing value for `{}`\n\n{}", arg, USAGE),
+            ReadFile(path, e) => write!(f, "Can not read {}: {}", path.display(), e),
+            InvalidToml(path, e) => write!(f, "Invalid config file {}: {}", path.display(), e),
+            NoUpstreams => write!(f, "At least one upstream is needed"),
+            UnresolvedUpstream(host, port, Some(e)) => {
+                write!(f, "Can not resolve upstream {}:{}: {}", host, port, e)
+            }
+            UnresolvedUpstream(host, port, None) => {
+                write!(
+                    f,
+                    "Upstream {}:{} do not resolve to any address",
+                    host, port
+                )
+            }
+            NoWeightedUpstream => write!(
+                f,
+                "With weighted-hash-rate at least one upstream must have a weight greater than 0"
+            ),
+            InvalidListenAddress(value) => {
+                write!(
+                    f,
+                    "Invalid listen_address `{}`, expected an IP address",
+                    value
+                )
+            }
+            InvalidMetricsAddress(value) => write!(
+                f,
+                "Invalid metrics_address `{}`, expected an IP address and a port",
+                value
+            ),
+            InvalidSupportedVersions(min, max) => write!(
+                f,
+                "Invalid supported versions {}..={}: min_supported_version must be at least 2 \
+                and not greater than max_supported_version",
+                min, max
+            ),
+            InvalidVardiff => write!(
+                f,
+                "shares_per_minute, window_sec and retarget_interval_sec of vardiff must be \
+                greater than 0"
+            ),
+            InvalidLogging(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// An upstream as it is written in the config file, `address` is an IP address or a hostname
+#[derive(Debug, Deserialize)]
+struct UpstreamFile {
+    address: String,
+    port: u16,
+    pub_key: [u8; 32],
+    /// Used by the weighted-hash-rate selection
+    #[serde(default = "default_weight")]
+    weight: u64,
+    /// Used by the priority-failover selection, lower is preferred, upstreams without priority
+    /// are used last in the order in which they are listed
+    priority: Option<u32>,
+}
+
+fn default_weight() -> u64 {
+    1
+}
+
+fn default_supported_version() -> u16 {
+    DEFAULT_SUPPORTED_VERSION
+}
+
+/// When set the proxy adjust the targets of the standard channels of the header only downstreams
+/// so that they submit shares_per_minute shares
+#[derive(Debug, Deserialize)]
+struct VardiffFile {
+    shares_per_minute: f32,
+    window_sec: u64,
+    retarget_interval_sec: u64,
+}
+
+/// The config file as it is written, values are validated in `Config::new`
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    upstreams: Vec<UpstreamFile>,
+    #[serde(default)]
+    upstream_selection: UpstreamSelection,
+    listen_address: String,
+    listen_mining_port: u16,
+    #[serde(default = "default_supported_version")]
+    max_supported_version: u16,
+    #[serde(default = "default_supported_version")]
+    min_supported_version: u16,
+    vardiff: Option<VardiffFile>,
+    /// Open the standard channels of the header only downstreams as sub-channels of one extended
+    /// channel with the upstream
+    #[serde(default)]
+    aggregate_header_only_channels: bool,
+    /// When set the metrics are served on http://<metrics_address>/metrics
+    metrics_address: Option<String>,
+    /// A level or directives as `info,mining_proxy=debug`, `RUST_LOG` replace it when set
+    #[serde(default = "default_log_level")]
+    log_level: String,
+    /// text or json
+    #[serde(default = "default_log_format")]
+    log_format: String,
+}
+
+fn default_log_level() -> String {
+    logging::DEFAULT_FILTER.to_string()
+}
+
+fn default_log_format() -> String {
+    "text".to_string()
+}
+
+/// How an upstream is selected between the ones that can be paired with a downstream
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UpstreamSelection {
+    LeastHashRate,
+    WeightedHashRate,
+    PriorityFailover,
+    RoundRobin,
+}
+
+// `#[default]` on enum variants is not available with the toolchain of the repo
+#[allow(clippy::derivable_impls)]
+impl Default for UpstreamSelection {
+    fn default() -> Self {
+        Self::LeastHashRate
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UpstreamConfig {
+    /// IP address or hostname as written in the config file
+    pub host: String,
+    /// Resolved when the config is loaded, the host is resolved again at every connection
+    pub address: SocketAddr,
+    pub pub_key: [u8; 32],
+    pub weight: u64,
+    pub priority: Option<u32>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Upstream ids are the positions of the upstreams in this list
+    pub upstreams: Vec<UpstreamConfig>,
+    pub upstream_selection: UpstreamSelection,
+    pub listen_address: SocketAddr,
+    pub min_supported_version: u16,
+    pub max_supported_version: u16,
+    pub vardiff: Option<VardiffConfig>,
+    pub aggregate_header_only_channels: bool,
+    pub metrics_address: Option<SocketAddr>,
+    pub log_level: String,
+    pub log_format: LogFormat,
+}
+
+impl Config {
+    /// Read the config file given with `--config`
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut config_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Err(Error::HelpRequested),
+                "-c" | "--config" => {
+                    config_path = args.next().ok_or(Error::MissingArgumentValue(arg))?.into();
+                }
+                _ => return Err(Error::InvalidArgument(arg)),
+            }
+        }
+        let config_file = std::fs::read_to_string(&config_path)
+            .map_err(|e| Error::ReadFile(config_path.clone(), e))?;
+        let file: ConfigFile =
+            toml::from_str(&config_file).map_err(|e| Error::InvalidToml(config_path, e))?;
+        Self::new(file)
+    }
+
+    fn new(file: ConfigFile) -> Result<Self, Error> {
+        if file.upstreams.is_empty() {
+            return Err(Error::NoUpstreams);
+        }
+        if matches!(file.upstream_selection, UpstreamSelection::WeightedHashRate)
+            && file.upstreams.iter().all(|upstream| upstream.weight == 0)
+        {
+            return Err(Error::NoWeightedUpstream);
+        }
+        let (min, max) = (file.min_supported_version, file.max_supported_version);
+        if min < DEFAULT_SUPPORTED_VERSION || min > max {
+            return Err(Error::InvalidSupportedVersions(min, max));
+        }
+        let listen_ip = IpAddr::from_str(&file.listen_address)
+            .map_err(|_| Error::InvalidListenAddress(file.listen_address.clone()))?;
+        let upstreams = file
+            .upstreams
+            .into_iter()
+            .map(parse_upstream)
+            .collect::<Result<Vec<UpstreamConfig>, Error>>()?;
+        let metrics_address = file
+            .metrics_address
+            .map(|address| {
+                SocketAddr::from_str(&address).map_err(|_| Error::InvalidMetricsAddress(address))
+            })
+            .transpose()?;
+        logging::validate_filter(&file.log_level).map_err(Error::InvalidLogging)?;
+        let log_format = file.log_format.parse().map_err(Error::InvalidLogging)?;
+        Ok(Self {
+            upstreams,
+            upstream_selection: file.upstream_selection,
+            listen_address: SocketAddr::new(listen_ip, file.listen_mining_port),
+            min_supported_version: min,
+            max_supported_version: max,
+            vardiff: file.vardiff.map(parse_vardiff).transpose()?,
+            aggregate_header_only_channels: file.aggregate_header_only_channels,
+            metrics_address,
+            log_level: file.log_level,
+            log_format,
+        })
+    }
+}
+
+/// The upstream address is resolved so that an host that do not exist is reported at startup, the
+/// first address returned is used
+fn parse_upstream(upstream: UpstreamFile) -> Result<UpstreamConfig, Error> {
+    let address = (upstream.address.as_str(), upstream.port)
+        .to_socket_addrs()
+        .map_err(|e| Error::UnresolvedUpstream(upstream.address.clone(), upstream.port, Some(e)))?
+        .next()
+        .ok_or_else(|| Error::UnresolvedUpstream(upstream.address.clone(), upstream.port, None))?;
+    Ok(UpstreamConfig {
+        host: upstream.address,
+        address,
+        pub_key: upstream.pub_key,
+        weight: upstream.weight,
+        priority: upstream.priority,
+    })
+}
+
+fn parse_vardiff(vardiff: VardiffFile) -> Result<VardiffConfig, Error> {
+    if vardiff.shares_per_minute <= 0.0
+        || vardiff.window_sec == 0
+        || vardiff.retarget_interval_sec == 0
+    {
+        return Err(Error::InvalidVardiff);
+    }
+    Ok(VardiffConfig::new(
+        vardiff.shares_per_minute,
+        Duration::from_secs(vardiff.window_sec),
+        Duration::from_secs(vardiff.retarget_interval_sec),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = include_str!("../../proxy-config.toml");
+    const SELECTION: &str = r#"upstream_selection = "least-hash-rate""#;
+
+    /// Replace `from`, that must be in the config, with `to`
+    fn edit(config: &str, from: &str, to: &str) -> String {
+        assert!(config.contains(from), "`{}` not in the config", from);
+        config.replace(from, to)
+    }
+
+    /// An upstream entry of the config file, `extra` is appended to its fields
+    fn upstream(address: &str, extra: &str) -> String {
+        format!(
+            r#"{{ address = "{}", port = 34254, pub_key = {:?}{} }}"#,
+            address, [1_u8; 32], extra
+        )
+    }
+
+    /// `config` with the given upstreams
+    fn with_upstreams(config: &str, upstreams: &[String]) -> String {
+        config
+            .lines()
+            .map(|line| match line.starts_with("upstreams = ") {
+                true => format!("upstreams = [{}]", upstreams.join(", ")),
+                false => line.to_string(),
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+
+    /// The sample config with vardiff enabled, the table is appended after the other keys
+    fn with_vardiff(shares_per_minute: f32, window_sec: u64, retarget_interval_sec: u64) -> String {
+        format!(
+            "{}\n[vardiff]\nshares_per_minute = {:?}\nwindow_sec = {}\nretarget_interval_sec = {}\n",
+            SAMPLE, shares_per_minute, window_sec, retarget_interval_sec
+        )
+    }
+
+    /// Write `config` in a directory of the test and load it
+    fn load(test: &str, config: &str) -> Result<Config, Error> {
+        let dir =
+            std::env::temp_dir().join(format!("proxy-config-{}-{}", std::process::id(), test));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("proxy-config.toml");
+        std::fs::write(&path, config).unwrap();
+        Config::from_args(vec!["--config".to_string(), path.display().to_string()].into_iter())
+    }
+
+    #[test]
+    fn loads_the_sample_config() {
+        let config = load("sample", SAMPLE).unwrap();
+
+        assert_eq!(config.upstreams.len(), 1);
+        assert_eq!(config.upstreams[0].host, "127.0.0.1");
+        assert_eq!(
+            config.upstreams[0].address,
+            "127.0.0.1:34254".parse().unwrap()
+        );
+        assert_eq!(config.upstreams[0].weight, 1);
+        assert!(config.upstreams[0].priority.is_none());
+        assert!(matches!(
+            config.upstream_selection,
+            UpstreamSelection::LeastHashRate
+        ));
+        assert_eq!(config.listen_address, "127.0.0.1:34255".parse().unwrap());
+        assert_eq!(
+            (config.min_supported_version, config.max_supported_version),
+            (2, 2)
+        );
+        assert!(config.vardiff.is_none());
+        assert!(!config.aggregate_header_only_channels);
+        assert!(config.metrics_address.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(matches!(
+            Config::from_args(vec!["--unknown".to_string()].into_iter()),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            Config::from_args(vec!["--config".to_string()].into_iter()),
+            Err(Error::MissingArgumentValue(_))
+        ));
+        assert!(matches!(
+            Config::from_args(vec!["--help".to_string()].into_iter()),
+            Err(Error::HelpRequested)
+        ));
+    }
+
+    #[test]
+    fn at_least_one_upstream_is_needed() {
+        assert!(matches!(
+            load("no-upstreams", &with_upstreams(SAMPLE, &[])),
+            Err(Error::NoUpstreams)
+        ));
+    }
+
+    #[test]
+    fn hostnames_are_resolved_when_the_config_is_loaded() {
+        let config = load(
+            "hostname",
+            &with_upstreams(
+                SAMPLE,
+                &[upstream("localhost", ", weight = 2, priority = 1")],
+            ),
+        )
+        .unwrap();
+        assert_eq!(config.upstreams[0].host, "localhost");
+        assert!(config.upstreams[0].address.ip().is_loopback());
+        assert_eq!(config.upstreams[0].address.port(), 34254);
+        assert_eq!(
+            (config.upstreams[0].weight, config.upstreams[0].priority),
+            (2, Some(1))
+        );
+    }
+
+    #[test]
+    fn rejects_an_upstream_that_can_not_be_resolved() {
+        // .invalid never resolves
+        let config = with_upstreams(SAMPLE, &[upstream("upstream.invalid", "")]);
+        match load("unresolved", &config) {
+            Err(Error::UnresolvedUpstream(host, port, _)) => {
+                assert_eq!((host.as_str(), port), ("upstream.invalid", 34254))
+            }
+            _ => panic!("unresolved upstream accepted"),
+        }
+    }
+
+    #[test]
+    fn weighted_selection_needs_an_upstream_with_weight() {
+        let weighted = edit(
+            SAMPLE,
+            SELECTION,
+            r#"upstream_selection = "weighted-hash-rate""#,
+        );
+        let no_weight = with_upstreams(
+            &weighted,
+            &[
+                upstream("127.0.0.1", ", weight = 0"),
+                upstream("127.0.0.2", ", weight = 0"),
+            ],
+        );
+        assert!(matches!(
+            load("no-weight", &no_weight),
+            Err(Error::NoWeightedUpstream)
+        ));
+        assert!(matches!(
+            load("weighted", &weighted).unwrap().upstream_selection,
+            UpstreamSelection::WeightedHashRate
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_supported_versions() {
+        let too_low = edit(
+            SAMPLE,
+            "min_supported_version = 2",
+            "min_supported_version = 1",
+        );
+        assert!(matches!(
+            load("version-too-low", &too_low),
+            Err(Error::InvalidSupportedVersions(1, 2))
+        ));
+        let min_above_max = edit(
+            SAMPLE,
+            "min_supported_version = 2",
+            "min_supported_version = 3",
+        );
+        assert!(matches!(
+            load("min-above-max", &min_above_max),
+            Err(Error::InvalidSupportedVersions(3, 2))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_vardiff() {
+        for (test, config) in [
+            ("no-shares", with_vardiff(0.0, 300, 60)),
+            ("no-window", with_vardiff(6.0, 0, 60)),
+            ("no-retarget", with_vardiff(6.0, 300, 0)),
+        ]
+        .iter()
+        {
+            assert!(
+                matches!(load(test, config), Err(Error::InvalidVardiff)),
+                "{} accepted",
+                test
+            );
+        }
+        assert!(load("vardiff", &with_vardiff(6.0, 300, 60))
+            .unwrap()
+            .vardiff
+            .is_some());
+    }
+}
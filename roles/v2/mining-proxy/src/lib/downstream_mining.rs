@@ -1,21 +1,26 @@
-use super::upstream_mining::{JobDispatcher, StdFrame as UpstreamFrame, UpstreamMiningNode};
+use super::{
+    extended_channel::share_error,
+    upstream_mining::{JobDispatcher, StdFrame as UpstreamFrame, UpstreamMiningNode},
+};
 use async_channel::{Receiver, SendError, Sender};
 use roles_logic_sv2::{
     common_messages_sv2::{SetupConnection, SetupConnectionSuccess},
     common_properties::{
-        CommonDownstreamData, DownstreamChannel, IsDownstream, IsMiningDownstream,
+        CommonDownstreamData, DownstreamChannel, IsDownstream, IsMiningDownstream, IsUpstream,
     },
     errors::Error,
     handlers::{
         common::{ParseDownstreamCommonMessages, SendTo as SendToCommon},
         mining::{ParseDownstreamMiningMessages, SendTo, SupportedChannelTypes},
     },
+    job_dispatcher::SendSharesResponse,
     mining_sv2::*,
     parsers::{Mining, MiningDeviceMessages, PoolMessages},
     routing_logic::MiningProxyRoutingLogic,
-    utils::Mutex,
+    utils::{Id, Mutex},
 };
 use std::collections::HashMap;
+use tracing::{info, info_span, warn, Instrument};
 
 use codec_sv2::{Frame, StandardEitherFrame, StandardSv2Frame};
 
@@ -33,6 +38,10 @@ pub struct DownstreamMiningNode {
     // channel_id/group_id -> group_id
     channel_id_to_group_id: HashMap<u32, u32>,
     pub prev_job_id: Option<u32>,
+    /// Upstream paired on SetupConnection, extended channels are opened with it
+    upstream: Option<Arc<Mutex<UpstreamMiningNode>>>,
+    /// upstream request_id -> nominal hash rate of the standard channels that are being opened
+    requested_hash_rates: HashMap<u32, f32>,
 }
 
 #[derive(Debug)]
@@ -70,10 +79,15 @@ impl DownstreamMiningNodeStatus {
         match self {
             DownstreamMiningNodeStatus::Initializing => panic!(),
             DownstreamMiningNodeStatus::Paired((_, channels)) => {
-                match channels.get_mut(&channel.group_id()) {
+                // Extended channels do not belong to a group
+                let id = match channel {
+                    DownstreamChannel::Extended(channel_id) => channel_id,
+                    _ => channel.group_id(),
+                };
+                match channels.get_mut(&id) {
                     Some(g) => g.push(channel),
                     None => {
-                        channels.insert(channel.group_id(), vec![channel]);
+                        channels.insert(id, vec![channel]);
                     }
                 };
             }
@@ -87,11 +101,59 @@ use tokio::task;
 
 impl DownstreamMiningNode {
     pub fn add_channel(&mut self, channel: DownstreamChannel) {
-        self.channel_id_to_group_id
-            .insert(channel.channel_id(), channel.group_id());
+        if !matches!(channel, DownstreamChannel::Extended(_)) {
+            self.channel_id_to_group_id
+                .insert(channel.channel_id(), channel.group_id());
+        }
+        crate::metrics()
+            .safe_lock(|metrics| metrics.on_channel_opened(&channel))
+            .unwrap();
         self.status.add_channel(channel);
     }
 
+    /// Forget a channel that has been closed, when the id is the id of a group the channels of
+    /// the group are forgotten
+    pub fn remove_channel(&mut self, channel_id: u32) {
+        self.channel_id_to_group_id.remove(&channel_id);
+        let channels = self.status.get_channels();
+        crate::metrics()
+            .safe_lock(|metrics| {
+                for group in channels.values_mut() {
+                    group.retain(|channel| {
+                        let removed = channel.channel_id() == channel_id;
+                        if removed {
+                            metrics.on_channel_closed(channel);
+                        }
+                        !removed
+                    });
+                }
+            })
+            .unwrap();
+        channels.retain(|_, group| !group.is_empty());
+    }
+
+    /// Prefix used to compute the jobs of a standard channel from now on
+    pub fn set_extranonce_prefix(&mut self, channel_id: u32, extranonce: Extranonce) {
+        for group in self.status.get_channels().values_mut() {
+            for channel in group {
+                if let DownstreamChannel::Standard(channel) = channel {
+                    if channel.channel_id == channel_id {
+                        channel.extranonce = extranonce.clone();
+                    }
+                }
+            }
+        }
+    }
+
+    fn has_extended_channel(&mut self, channel_id: u32) -> bool {
+        match self.status.get_channels().get(&channel_id) {
+            Some(channels) => channels
+                .iter()
+                .any(|channel| matches!(channel, DownstreamChannel::Extended(_))),
+            None => false,
+        }
+    }
+
     pub fn new(receiver: Receiver<EitherFrame>, sender: Sender<EitherFrame>) -> Self {
         Self {
             receiver,
@@ -99,9 +161,15 @@ impl DownstreamMiningNode {
             status: DownstreamMiningNodeStatus::Initializing,
             channel_id_to_group_id: HashMap::new(),
             prev_job_id: None,
+            upstream: None,
+            requested_hash_rates: HashMap::new(),
         }
     }
 
+    pub fn take_requested_hash_rate(&mut self, request_id: u32) -> Option<f32> {
+        self.requested_hash_rates.remove(&request_id)
+    }
+
     /// Send SetupConnectionSuccess to donwstream and start processing new messages coming from
     /// downstream
     pub async fn start(
@@ -124,22 +192,73 @@ impl DownstreamMiningNode {
             }
 
             // TODO levare questo task
-            let _ = task::spawn(async move {
-                loop {
-                    let receiver = self_mutex
-                        .safe_lock(|self_| self_.receiver.clone())
-                        .unwrap();
-                    let message = receiver.recv().await.unwrap();
-                    let incoming: StdFrame = message.try_into().unwrap();
-                    Self::next(self_mutex.clone(), incoming).await
+            let _ = task::spawn(
+                async move {
+                    loop {
+                        let receiver = self_mutex
+                            .safe_lock(|self_| self_.receiver.clone())
+                            .unwrap();
+                        let message = match receiver.recv().await {
+                            Ok(message) => message,
+                            Err(_) => {
+                                Self::on_disconnect(self_mutex.clone()).await;
+                                break;
+                            }
+                        };
+                        let incoming: StdFrame = message.try_into().unwrap();
+                        Self::next(self_mutex.clone(), incoming).await
+                    }
                 }
-            })
+                .in_current_span(),
+            )
             .await;
         } else {
             panic!()
         }
     }
 
+    /// The channels of a downstream that disconnected are closed upstream
+    async fn on_disconnect(self_mutex: Arc<Mutex<Self>>) {
+        info!("disconnected");
+        let upstream = self_mutex
+            .safe_lock(|self_| self_.upstream.clone())
+            .unwrap();
+        if let Some(upstream) = upstream {
+            UpstreamMiningNode::remove_downstream(upstream, self_mutex.clone()).await;
+        }
+        self_mutex
+            .safe_lock(|self_| {
+                let channels = self_.status.get_channels().values().flatten();
+                crate::metrics()
+                    .safe_lock(|metrics| metrics.on_downstream_disconnected(channels))
+                    .unwrap();
+            })
+            .unwrap();
+    }
+
+    /// Shares accepted or rejected by the proxy itself, without relaying them to the upstream
+    fn count_share_responses(send_to: &SendTo<UpstreamMiningNode>) {
+        match send_to {
+            SendTo::Respond(Mining::SubmitSharesSuccess(_)) => crate::metrics()
+                .safe_lock(|metrics| metrics.on_share_accepted_by_proxy())
+                .unwrap(),
+            SendTo::Respond(Mining::SubmitSharesError(m)) => {
+                let reason = String::from_utf8_lossy(m.error_code.inner_as_ref()).to_string();
+                warn!(
+                    channel_id = m.channel_id,
+                    sequence_number = m.sequence_number,
+                    reason = %reason,
+                    "share rejected"
+                );
+                crate::metrics()
+                    .safe_lock(|metrics| metrics.on_share_rejected(&reason))
+                    .unwrap();
+            }
+            SendTo::Multiple(sends_to) => sends_to.iter().for_each(Self::count_share_responses),
+            _ => (),
+        }
+    }
+
     /// Parse the received message and relay it to the right upstream
     pub async fn next(self_mutex: Arc<Mutex<Self>>, mut incoming: StdFrame) {
         let message_type = incoming.get_header().unwrap().msg_type();
@@ -147,27 +266,68 @@ impl DownstreamMiningNode {
 
         let routing_logic = crate::get_routing_logic();
 
-        let next_message_to_send = ParseDownstreamMiningMessages::handle_message_mining(
-            self_mutex.clone(),
-            message_type,
-            payload,
-            routing_logic,
-        );
+        let next_message_to_send = info_span!("message", msg_type = message_type).in_scope(|| {
+            ParseDownstreamMiningMessages::handle_message_mining(
+                self_mutex.clone(),
+                message_type,
+                payload,
+                routing_logic,
+            )
+        });
+        if let Ok(send_to) = &next_message_to_send {
+            Self::count_share_responses(send_to);
+        }
 
         match next_message_to_send {
+            // The channel is opened by the upstream node as it has to know the downstream
+            Ok(SendTo::RelayNewMessage(
+                upstream_mutex,
+                Mining::OpenExtendedMiningChannel(request),
+            )) => {
+                UpstreamMiningNode::open_extended_channel(
+                    upstream_mutex,
+                    self_mutex.clone(),
+                    request,
+                )
+                .await
+            }
+            // Same for the standard channels aggregated in the extended channel
+            Ok(SendTo::RelayNewMessage(
+                upstream_mutex,
+                Mining::OpenStandardMiningChannel(request),
+            )) => {
+                UpstreamMiningNode::open_aggregated_channel(
+                    upstream_mutex,
+                    self_mutex.clone(),
+                    request,
+                )
+                .await
+            }
             Ok(SendTo::RelaySameMessage(upstream_mutex)) => {
                 let sv2_frame: codec_sv2::Sv2Frame<PoolMessages, buffer_sv2::Slice> =
                     incoming.map(|payload| payload.try_into().unwrap());
-                UpstreamMiningNode::send(upstream_mutex.clone(), sv2_frame)
+                if UpstreamMiningNode::send(upstream_mutex.clone(), sv2_frame)
                     .await
-                    .unwrap();
+                    .is_err()
+                {
+                    warn!(
+                        msg_type = message_type,
+                        "upstream not connected, message dropped"
+                    );
+                }
             }
             Ok(SendTo::RelayNewMessage(upstream_mutex, message)) => {
                 let message = PoolMessages::Mining(message);
                 let frame: UpstreamFrame = message.try_into().unwrap();
-                UpstreamMiningNode::send(upstream_mutex.clone(), frame)
+                if UpstreamMiningNode::send(upstream_mutex.clone(), frame)
                     .await
-                    .unwrap();
+                    .is_err()
+                {
+                    warn!(
+                        msg_type = message_type,
+                        "upstream not connected, message dropped"
+                    );
+                }
             }
             Ok(SendTo::Respond(message)) => {
                 let message = MiningDeviceMessages::Mining(message);
@@ -176,12 +336,37 @@ impl DownstreamMiningNode {
                     .await
                     .unwrap();
             }
-            Ok(SendTo::Multiple(_sends_to)) => {
-                todo!();
+            Ok(SendTo::Multiple(sends_to)) => {
+                for send_to in sends_to {
+                    match send_to {
+                        SendTo::RelayNewMessage(upstream_mutex, message) => {
+                            let message = PoolMessages::Mining(message);
+                            let frame: UpstreamFrame = message.try_into().unwrap();
+                            if UpstreamMiningNode::send(upstream_mutex.clone(), frame)
+                                .await
+                                .is_err()
+                            {
+                                warn!(
+                                    msg_type = message_type,
+                                    "upstream not connected, message dropped"
+                                );
+                            }
+                        }
+                        SendTo::Respond(message) => {
+                            let message = MiningDeviceMessages::Mining(message);
+                            let frame: StdFrame = message.try_into().unwrap();
+                            DownstreamMiningNode::send(self_mutex.clone(), frame)
+                                .await
+                                .unwrap();
+                        }
+                        SendTo::None(_) => (),
+                        _ => panic!("Unsupported SendTo in SendTo::Multiple"),
+                    }
+                }
             }
             Ok(SendTo::None(_)) => (),
-            Err(Error::UnexpectedMessage) => todo!("148"),
-            Err(_) => todo!("149"),
+            Err(Error::UnexpectedMessage) => warn!(msg_type = message_type, "unexpected message"),
+            Err(e) => warn!(msg_type = message_type, "invalid message: {}", e),
         }
     }
 
@@ -189,18 +374,23 @@ impl DownstreamMiningNode {
     pub async fn send(
         self_mutex: Arc<Mutex<Self>>,
         sv2_frame: StdFrame,
-    ) -> Result<(), SendError<StdFrame>> {
+    ) -> Result<(), SendError<EitherFrame>> {
         let either_frame = sv2_frame.into();
         let sender = self_mutex.safe_lock(|self_| self_.sender.clone()).unwrap();
-        match sender.send(either_frame).await {
-            Ok(_) => Ok(()),
-            Err(_) => {
-                todo!("172")
-            }
-        }
+        sender.send(either_frame).await
     }
 }
 
+fn standard_share_error(m: &SubmitSharesStandard, error_code: &str) -> SendTo<UpstreamMiningNode> {
+    let error = SubmitSharesError {
+        channel_id: m.channel_id,
+        sequence_number: m.sequence_number,
+        // Safe unwrap error codes are shorter than 32 bytes
+        error_code: error_code.to_string().try_into().unwrap(),
+    };
+    SendTo::Respond(Mining::SubmitSharesError(error))
+}
+
 use super::upstream_mining::ProxyRemoteSelector;
 
 /// It impl UpstreamMining cause the proxy act as an upstream node for the DownstreamMiningNode
@@ -211,76 +401,225 @@ impl
         MiningProxyRoutingLogic<Self, UpstreamMiningNode, ProxyRemoteSelector>,
     > for DownstreamMiningNode
 {
+    /// Header only downstreams can only open standard channels
     fn get_channel_type(&self) -> SupportedChannelTypes {
-        SupportedChannelTypes::Group
+        if self.is_header_only() {
+            SupportedChannelTypes::Group
+        } else {
+            SupportedChannelTypes::GroupAndExtended
+        }
     }
 
     fn is_work_selection_enabled(&self) -> bool {
         false
     }
 
+    /// The nominal hash rate is kept until the channel is opened, then it is part of the hash
+    /// rate of the group. When the proxy aggregate the header only downstreams the channel is
+    /// opened by the proxy in the extended channel.
     fn handle_open_standard_mining_channel(
         &mut self,
-        _: OpenStandardMiningChannel,
+        m: OpenStandardMiningChannel,
         up: Option<Arc<Mutex<UpstreamMiningNode>>>,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        Ok(SendTo::RelaySameMessage(up.unwrap()))
+        let upstream = up.unwrap();
+        let aggregate = upstream
+            .safe_lock(|u| u.aggregates_header_only_channels())
+            .unwrap();
+        if aggregate && self.is_header_only() {
+            let message =
+                Mining::OpenStandardMiningChannel(OpenStandardMiningChannel::into_static_self(m));
+            return Ok(SendTo::RelayNewMessage(upstream, message));
+        }
+        // The request id has already been mapped to the upstream one
+        let request_id = upstream
+            .safe_lock(|u| {
+                u.get_mapper()
+                    .and_then(|mapper| mapper.get(m.get_request_id_as_u32()))
+            })
+            .unwrap();
+        if let Some(request_id) = request_id {
+            self.requested_hash_rates
+                .insert(request_id, m.nominal_hash_rate);
+        }
+        Ok(SendTo::RelaySameMessage(upstream))
     }
 
     fn handle_open_extended_mining_channel(
         &mut self,
-        _: OpenExtendedMiningChannel,
+        m: OpenExtendedMiningChannel,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        todo!()
+        let upstream = self.upstream.clone().ok_or(Error::NoUpstreamsConnected)?;
+        let message =
+            Mining::OpenExtendedMiningChannel(OpenExtendedMiningChannel::into_static_self(m));
+        Ok(SendTo::RelayNewMessage(upstream, message))
     }
 
+    /// The standard channels of the header only downstreams and the channels of the extended
+    /// channel are opened by the proxy that answer with the new target. The new hash rate of a
+    /// standard channel is aggregated in the hash rate of its group that is sent upstream. The
+    /// other channels are opened by the upstream.
     fn handle_update_channel(
         &mut self,
-        _: UpdateChannel,
+        m: UpdateChannel,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        todo!()
+        let upstream = self.upstream.clone().ok_or(Error::NoUpstreamsConnected)?;
+        if !self.is_header_only() && !self.has_extended_channel(m.channel_id) {
+            return Ok(SendTo::RelaySameMessage(upstream));
+        }
+        match upstream.safe_lock(|u| u.on_update_channel(&m)).unwrap() {
+            Ok((set_target, None)) => Ok(SendTo::Respond(Mining::SetTarget(set_target))),
+            Ok((set_target, Some(update_group))) => Ok(SendTo::Multiple(vec![
+                SendTo::Respond(Mining::SetTarget(set_target)),
+                SendTo::RelayNewMessage(upstream, Mining::UpdateChannel(update_group)),
+            ])),
+            Err(error_code) => {
+                let error = UpdateChannelError {
+                    channel_id: m.channel_id,
+                    // Safe unwrap error codes are shorter than 32 bytes
+                    error_code: error_code.to_string().try_into().unwrap(),
+                };
+                Ok(SendTo::Respond(Mining::UpdateChannelError(error)))
+            }
+        }
     }
 
+    /// Extended channels and the standard channels of the header only downstreams are closed by
+    /// the proxy: the upstream extended channel is closed with its last sub-channel and the new
+    /// hash rate of the group (or of the extended channel for aggregated channels) is sent with
+    /// the CloseChannel of a standard channel. The other channels are closed by the upstream.
+    fn handle_close_channel(
+        &mut self,
+        m: CloseChannel,
+    ) -> Result<SendTo<UpstreamMiningNode>, Error> {
+        let upstream = self.upstream.clone().ok_or(Error::NoUpstreamsConnected)?;
+        let is_extended = self.has_extended_channel(m.channel_id);
+        self.remove_channel(m.channel_id);
+        if is_extended {
+            return match upstream
+                .safe_lock(|u| u.close_extended_downstream_channel(m.channel_id))
+                .unwrap()
+            {
+                Some(close) => Ok(SendTo::RelayNewMessage(
+                    upstream,
+                    Mining::CloseChannel(close),
+                )),
+                None => Ok(SendTo::None(None)),
+            };
+        }
+        if !self.is_header_only() {
+            return Ok(SendTo::RelaySameMessage(upstream));
+        }
+        if let Some(message) = upstream
+            .safe_lock(|u| u.close_aggregated_channel(m.channel_id))
+            .unwrap()
+        {
+            return Ok(SendTo::RelayNewMessage(upstream, message));
+        }
+        let update_group = upstream
+            .safe_lock(|u| u.close_standard_channel(m.channel_id))
+            .unwrap();
+        let close = Mining::CloseChannel(CloseChannel {
+            channel_id: m.channel_id,
+            reason_code: m.reason_code.clone().into_static(),
+        });
+        let mut messages = vec![SendTo::RelayNewMessage(upstream.clone(), close)];
+        if let Some(update_group) = update_group {
+            let message = Mining::UpdateChannel(update_group);
+            messages.push(SendTo::RelayNewMessage(upstream, message));
+        }
+        Ok(SendTo::Multiple(messages))
+    }
+
+    /// The share is validated by the job dispatcher of the group, only the shares that meet the
+    /// upstream target are forwarded. The shares of the aggregated channels are forwarded as
+    /// shares of the extended channel. When vardiff retarget the channel the SetTarget is sent
+    /// with the response.
     fn handle_submit_shares_standard(
         &mut self,
         m: SubmitSharesStandard,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        println!("{:?}", m);
-        match self.channel_id_to_group_id.get(&m.channel_id) {
-            Some(group_id) => match crate::upstream_from_job_id(m.job_id) {
-                Some(remote) => {
-                    remote.safe_lock(|r| {
-                        match r.channel_id_to_job_dispatcher.get(group_id) {
-                            Some(JobDispatcher::Group(dispatcher)) => {
-                                match dispatcher.on_submit_shares(m) {
-                                    roles_logic_sv2::job_dispatcher::SendSharesResponse::Valid(m) => {
-                                        // This could just relay same message and change the
-                                        // job_id as we do for request_ids
-                                        let message = Mining::SubmitSharesStandard(m);
-                                        Ok(SendTo::RelayNewMessage(remote.clone(),message))
-                                    },
-                                    roles_logic_sv2::job_dispatcher::SendSharesResponse::Invalid(m) => {
-                                        let message = Mining::SubmitSharesError(m);
-                                        Ok(SendTo::Respond(message))
-                                    }
-                                }
-                            },
-                            Some(_) => todo!(),
-                            None => todo!(),
-                        }
-                    }).unwrap()
-                }
-                None => todo!(),
-            },
-            None => todo!(),
+        if let Some(upstream) = self.upstream.clone() {
+            let aggregated = upstream
+                .safe_lock(|u| u.on_submit_shares_aggregated(m.clone()))
+                .unwrap();
+            if let Some((response, set_target)) = aggregated {
+                let send_to = match response {
+                    Ok(share) => {
+                        SendTo::RelayNewMessage(upstream, Mining::SubmitSharesExtended(share))
+                    }
+                    Err(message) => SendTo::Respond(message),
+                };
+                return match set_target {
+                    Some(set_target) => Ok(SendTo::Multiple(vec![
+                        send_to,
+                        SendTo::Respond(Mining::SetTarget(set_target)),
+                    ])),
+                    None => Ok(send_to),
+                };
+            }
         }
+        let group_id = match self.channel_id_to_group_id.get(&m.channel_id) {
+            Some(group_id) => *group_id,
+            None => return Ok(standard_share_error(&m, "invalid-channel-id")),
+        };
+        let remote = match crate::upstream_from_job_id(m.job_id) {
+            Some(remote) => remote,
+            None => return Ok(standard_share_error(&m, "invalid-job-id")),
+        };
+        let channel_id = m.channel_id;
+        remote
+            .safe_lock(|r| {
+                let response = match r.channel_id_to_job_dispatcher.get_mut(&group_id) {
+                    Some(JobDispatcher::Group(dispatcher)) => dispatcher.on_submit_shares(m),
+                    _ => return Ok(standard_share_error(&m, "invalid-channel-id")),
+                };
+                let send_to = match response {
+                    SendSharesResponse::Valid(m) => {
+                        // This could just relay same message and change the
+                        // job_id as we do for request_ids
+                        let message = Mining::SubmitSharesStandard(m);
+                        SendTo::RelayNewMessage(remote.clone(), message)
+                    }
+                    SendSharesResponse::ValidForDownstream(m) => {
+                        SendTo::Respond(Mining::SubmitSharesSuccess(m))
+                    }
+                    SendSharesResponse::Invalid(m) => {
+                        return Ok(SendTo::Respond(Mining::SubmitSharesError(m)))
+                    }
+                };
+                match r.on_standard_share_accepted(channel_id) {
+                    Some(set_target) => Ok(SendTo::Multiple(vec![
+                        send_to,
+                        SendTo::Respond(Mining::SetTarget(set_target)),
+                    ])),
+                    None => Ok(send_to),
+                }
+            })
+            .unwrap()
     }
 
     fn handle_submit_shares_extended(
         &mut self,
-        _: SubmitSharesExtended,
+        m: SubmitSharesExtended,
     ) -> Result<SendTo<UpstreamMiningNode>, Error> {
-        todo!()
+        let upstream = match (self.has_extended_channel(m.channel_id), &self.upstream) {
+            (true, Some(upstream)) => upstream.clone(),
+            _ => {
+                let error = share_error(&m, "invalid-channel-id");
+                return Ok(SendTo::Respond(Mining::SubmitSharesError(error)));
+            }
+        };
+        match upstream
+            .safe_lock(|u| u.on_submit_shares_extended(m))
+            .unwrap()
+        {
+            Ok(share) => Ok(SendTo::RelayNewMessage(
+                upstream,
+                Mining::SubmitSharesExtended(share),
+            )),
+            Err(error) => Ok(SendTo::Respond(Mining::SubmitSharesError(error))),
+        }
     }
 
     fn handle_set_custom_mining_job(
@@ -316,36 +655,53 @@ use tokio::net::TcpListener;
 
 pub async fn listen_for_downstream_mining(address: SocketAddr) {
     let listner = TcpListener::bind(address).await.unwrap();
+    let mut connection_ids = Id::new();
 
-    while let Ok((stream, _)) = listner.accept().await {
+    while let Ok((stream, remote)) = listner.accept().await {
+        // Every event of the downstream is in this span
+        let span = info_span!("downstream", connection_id = connection_ids.next(), %remote);
+        span.in_scope(|| info!("connected"));
         let (receiver, sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
             PlainConnection::new(stream).await;
         let node = DownstreamMiningNode::new(receiver, sender);
 
-        task::spawn(async move {
-            let mut incoming: StdFrame = node.receiver.recv().await.unwrap().try_into().unwrap();
-            let message_type = incoming.get_header().unwrap().msg_type();
-            let payload = incoming.payload();
-            let routing_logic = crate::get_common_routing_logic();
-            let node = Arc::new(Mutex::new(node));
-
-            // Call handle_setup_connection or fail
-            match DownstreamMiningNode::handle_message_common(
-                node.clone(),
-                message_type,
-                payload,
-                routing_logic,
-            ) {
-                Ok(SendToCommon::RelayNewMessage(_, message)) => {
-                    let message = match message {
-                        roles_logic_sv2::parsers::CommonMessages::SetupConnectionSuccess(m) => m,
-                        _ => panic!(),
-                    };
-                    DownstreamMiningNode::start(node, message).await
+        task::spawn(
+            async move {
+                let mut incoming: StdFrame =
+                    node.receiver.recv().await.unwrap().try_into().unwrap();
+                let message_type = incoming.get_header().unwrap().msg_type();
+                let payload = incoming.payload();
+                let routing_logic = crate::get_common_routing_logic();
+                let node = Arc::new(Mutex::new(node));
+
+                // Call handle_setup_connection or fail
+                match DownstreamMiningNode::handle_message_common(
+                    node.clone(),
+                    message_type,
+                    payload,
+                    routing_logic,
+                ) {
+                    Ok(SendToCommon::RelayNewMessage(_, message)) => {
+                        let message = match message {
+                            roles_logic_sv2::parsers::CommonMessages::SetupConnectionSuccess(m) => {
+                                m
+                            }
+                            _ => panic!(),
+                        };
+                        let downstream_data =
+                            node.safe_lock(|n| n.get_downstream_mining_data()).unwrap();
+                        let upstream = crate::paired_upstream(&downstream_data);
+                        node.safe_lock(|n| n.upstream = upstream).unwrap();
+                        crate::metrics()
+                            .safe_lock(|metrics| metrics.on_downstream_connected())
+                            .unwrap();
+                        DownstreamMiningNode::start(node, message).await
+                    }
+                    _ => panic!(),
                 }
-                _ => panic!(),
             }
-        });
+            .instrument(span),
+        );
     }
 }
 
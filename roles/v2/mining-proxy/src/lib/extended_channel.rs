@@ -0,0 +1,1291 @@
+//! The proxy open one extended channel with each upstream that it is paired with a downstream
+//! that do not require standard jobs. Every extended channel opened by those downstreams is a
+//! sub-channel of it: the proxy reserve `PROXY_EXTRANONCE_LEN` bytes of the upstream extranonce
+//! and use them to give a different extranonce prefix to each downstream channel.
+//!
+//! Ids of the downstream channels are assigned by the proxy starting from u32::MAX and going
+//! down so that they never collide with the ids that the upstream assign to group and standard
+//! channels, that are relayed on the same downstream connections.
+//!
+//! As the downstream channel ids belong to the proxy the sub-channels survive the loss of the
+//! connection with the upstream: when the extended channel is opened again every downstream
+//! channel keep its id and receive a SetExtranoncePrefix with its new prefix.
+//!
+//! When the proxy aggregate the header only downstreams their standard channels are sub-channels
+//! of the extended channel too. The extranonce of a standard channel is its prefix followed by
+//! zeros, the jobs are computed from the extended jobs by a GroupChannelJobDispatcher and the
+//! shares that meet the upstream target are sent upstream as extended shares.
+use super::downstream_mining::DownstreamMiningNode;
+use binary_sv2::{B032, U256};
+use roles_logic_sv2::{
+    common_properties::{DownstreamChannel, StandardChannel},
+    handlers::mining::SendTo,
+    job_dispatcher::{GroupChannelJobDispatcher, SendSharesResponse},
+    mining_sv2::*,
+    parsers::Mining,
+    utils::{Id, Mutex},
+    vardiff::Vardiff,
+};
+use std::{collections::HashMap, convert::TryInto, sync::Arc, time::Instant};
+
+/// Bytes of the upstream extranonce used by the proxy to differentiate the downstream channels
+pub const PROXY_EXTRANONCE_LEN: usize = 4;
+
+// The Opening variant is short lived so the size difference do not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug)]
+pub enum ExtendedChannel {
+    /// OpenExtendedMiningChannel has been sent upstream, the requests of the downstreams are
+    /// answered when the channel is open.
+    Opening {
+        /// Sent again if the connection with the upstream is lost before the response
+        request: OpenExtendedMiningChannel<'static>,
+        /// (downstream, downstream request id, downstream min extranonce size)
+        pending: Vec<(Arc<Mutex<DownstreamMiningNode>>, u32, u16)>,
+        /// (downstream, downstream request id, nominal hash rate) of the standard channels
+        pending_standard: Vec<(Arc<Mutex<DownstreamMiningNode>>, u32, f32)>,
+        /// Channel that was open before the connection with the upstream was lost
+        previous: Option<OpenExtendedChannel>,
+    },
+    Open(OpenExtendedChannel),
+}
+
+impl ExtendedChannel {
+    /// Every downstream that has or is waiting for an extended channel, each one only once
+    pub fn into_downstreams(self) -> Vec<Arc<Mutex<DownstreamMiningNode>>> {
+        let all: Vec<Arc<Mutex<DownstreamMiningNode>>> = match self {
+            ExtendedChannel::Opening {
+                pending,
+                pending_standard,
+                previous,
+                ..
+            } => pending
+                .into_iter()
+                .map(|(downstream, _, _)| downstream)
+                .chain(pending_standard.into_iter().map(|(d, _, _)| d))
+                .chain(previous.into_iter().flat_map(|c| c.into_downstreams()))
+                .collect(),
+            ExtendedChannel::Open(channel) => channel.into_downstreams(),
+        };
+        let mut downstreams: Vec<Arc<Mutex<DownstreamMiningNode>>> = Vec::new();
+        for downstream in all {
+            if !downstreams.iter().any(|d| Arc::ptr_eq(d, &downstream)) {
+                downstreams.push(downstream);
+            }
+        }
+        downstreams
+    }
+
+    /// Forget every channel of a downstream that disconnected, return true if the channel is
+    /// open and no downstream channel is left
+    pub fn remove_downstream(&mut self, downstream: &Arc<Mutex<DownstreamMiningNode>>) -> bool {
+        match self {
+            ExtendedChannel::Opening {
+                pending,
+                pending_standard,
+                previous,
+                ..
+            } => {
+                pending.retain(|(d, _, _)| !Arc::ptr_eq(d, downstream));
+                pending_standard.retain(|(d, _, _)| !Arc::ptr_eq(d, downstream));
+                if let Some(previous) = previous {
+                    previous.remove_downstream(downstream);
+                }
+                false
+            }
+            ExtendedChannel::Open(channel) => {
+                channel.remove_downstream(downstream);
+                channel.is_empty()
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OpenExtendedChannel {
+    pub channel_id: u32,
+    /// Request that opened the channel, used to open it again after a reconnection
+    pub request: OpenExtendedMiningChannel<'static>,
+    target: U256<'static>,
+    extranonces: ExtendedExtranonce,
+    /// Length of the extranonce prefix assigned by the upstream
+    upstream_prefix_len: usize,
+    /// Bytes of the extranonce that the downstreams can roll
+    downstream_extranonce_size: u16,
+    /// downstream channel id -> (downstream, extranonce prefix), the prefix of a standard channel
+    /// do not include the zeros that complete its extranonce
+    downstreams: HashMap<u32, (Arc<Mutex<DownstreamMiningNode>>, Vec<u8>)>,
+    next_channel_id: u32,
+    sequence_numbers: Id,
+    /// upstream sequence number -> (downstream channel id, downstream sequence number)
+    shares: HashMap<u32, (u32, u32)>,
+    last_prev_hash: Option<SetNewPrevHash<'static>>,
+    active_job: Option<NewExtendedMiningJob<'static>>,
+    future_jobs: Vec<NewExtendedMiningJob<'static>>,
+    /// Standard sub-channels, they are in `downstreams` too
+    standard_channels: HashMap<u32, AggregatedChannel>,
+    /// Compute the jobs of the standard sub-channels and validate their shares
+    dispatcher: GroupChannelJobDispatcher,
+    job_ids: Arc<Mutex<Id>>,
+}
+
+#[derive(Debug)]
+struct AggregatedChannel {
+    channel: StandardChannel,
+    nominal_hash_rate: f32,
+}
+
+impl OpenExtendedChannel {
+    /// Return None if the upstream extranonce do not leave any byte to the downstreams
+    pub fn new(
+        m: &OpenExtendedMiningChannelSuccess,
+        request: OpenExtendedMiningChannel<'static>,
+        job_ids: Arc<Mutex<Id>>,
+    ) -> Option<Self> {
+        let prefix = m.extranonce_prefix.to_vec();
+        let extranonce_size = m.extranonce_size as usize;
+        if extranonce_size <= PROXY_EXTRANONCE_LEN || prefix.len() + extranonce_size > 32 {
+            return None;
+        }
+        let range_1 = prefix.len()..prefix.len() + PROXY_EXTRANONCE_LEN;
+        let range_2 = range_1.end..32;
+        let extranonces = ExtendedExtranonce::from_upstream_prefix(&prefix, range_1, range_2)?;
+        Some(Self {
+            channel_id: m.channel_id,
+            request,
+            target: m.target.clone().into_static(),
+            extranonces,
+            upstream_prefix_len: prefix.len(),
+            downstream_extranonce_size: (extranonce_size - PROXY_EXTRANONCE_LEN) as u16,
+            downstreams: HashMap::new(),
+            next_channel_id: u32::MAX,
+            sequence_numbers: Id::new(),
+            shares: HashMap::new(),
+            last_prev_hash: None,
+            active_job: None,
+            future_jobs: Vec::new(),
+            standard_channels: HashMap::new(),
+            dispatcher: GroupChannelJobDispatcher::new(job_ids.clone()),
+            job_ids,
+        })
+    }
+
+    /// Move the downstream channels of the channel that was open before the connection with the
+    /// upstream was lost in this channel. Every downstream channel keep its id and get a new
+    /// extranonce prefix, downstreams that can not keep rolling the same extranonce size are
+    /// asked to reconnect.
+    pub fn restore(&mut self, previous: OpenExtendedChannel) -> Vec<SendTo<DownstreamMiningNode>> {
+        self.next_channel_id = previous.next_channel_id;
+        let same_size = previous.downstream_extranonce_size == self.downstream_extranonce_size;
+        for (channel_id, mut standard) in previous.standard_channels {
+            standard.channel.group_id = self.channel_id;
+            standard.channel.target = self.target.clone().into();
+            self.dispatcher
+                .add_channel(channel_id, standard.channel.target.clone());
+            self.standard_channels.insert(channel_id, standard);
+        }
+        self.assign_prefixes(previous.downstreams, same_size)
+    }
+
+    /// The upstream changed the extranonce prefix of the channel: the prefixes of the downstream
+    /// channels are carved out of the new one and sent to every downstream
+    pub fn on_set_extranonce_prefix(
+        &mut self,
+        m: &SetExtranoncePrefix,
+    ) -> Vec<SendTo<DownstreamMiningNode>> {
+        let prefix = m.extranonce_prefix.to_vec();
+        let downstreams = std::mem::take(&mut self.downstreams);
+        let extranonces =
+            match prefix.len() + PROXY_EXTRANONCE_LEN + self.downstream_extranonce_size as usize
+                <= 32
+            {
+                true => {
+                    let range_1 = prefix.len()..prefix.len() + PROXY_EXTRANONCE_LEN;
+                    let range_2 = range_1.end..32;
+                    ExtendedExtranonce::from_upstream_prefix(&prefix, range_1, range_2)
+                }
+                false => None,
+            };
+        match extranonces {
+            Some(extranonces) => {
+                self.extranonces = extranonces;
+                self.upstream_prefix_len = prefix.len();
+                self.assign_prefixes(downstreams, true)
+            }
+            // The downstreams can not keep rolling the same extranonce size
+            None => {
+                for channel_id in downstreams.keys() {
+                    self.remove_downstream_channel(*channel_id);
+                }
+                downstreams
+                    .into_iter()
+                    .map(|(_, (downstream, _))| reconnect(downstream))
+                    .collect()
+            }
+        }
+    }
+
+    /// Give a new extranonce prefix to every downstream channel, the downstreams that can not keep
+    /// rolling the same extranonce size are asked to reconnect. The standard channels get the jobs
+    /// computed with the new extranonce.
+    fn assign_prefixes(
+        &mut self,
+        downstreams: HashMap<u32, (Arc<Mutex<DownstreamMiningNode>>, Vec<u8>)>,
+        same_size: bool,
+    ) -> Vec<SendTo<DownstreamMiningNode>> {
+        let mut messages = Vec::with_capacity(downstreams.len());
+        for (channel_id, (downstream, _)) in downstreams {
+            let prefix = match same_size {
+                true => self
+                    .extranonces
+                    .next_prefix_extended(self.downstream_extranonce_size as usize),
+                false => None,
+            };
+            let extranonce = match (&prefix, self.standard_channels.contains_key(&channel_id)) {
+                (Some(prefix), true) => self.standard_extranonce(prefix),
+                _ => None,
+            };
+            match (prefix, extranonce) {
+                (Some(prefix), Some(extranonce)) => {
+                    let extranonce_prefix: Vec<u8> = extranonce.clone().into();
+                    self.downstreams
+                        .insert(channel_id, (downstream.clone(), prefix));
+                    downstream
+                        .safe_lock(|d| d.set_extranonce_prefix(channel_id, extranonce.clone()))
+                        .unwrap();
+                    // Safe unwrap the channel is in standard_channels
+                    let standard = self.standard_channels.get_mut(&channel_id).unwrap();
+                    standard.channel.extranonce = extranonce;
+                    let jobs = self
+                        .dispatcher
+                        .on_set_extranonce_prefix(channel_id, &extranonce_prefix);
+                    let message = Mining::SetExtranoncePrefix(SetExtranoncePrefix {
+                        channel_id,
+                        // Safe unwrap the extranonce is 32 bytes long
+                        extranonce_prefix: extranonce_prefix.try_into().unwrap(),
+                    });
+                    messages.push(SendTo::RelayNewMessage(downstream.clone(), message));
+                    for job in jobs {
+                        let message = Mining::NewMiningJob(job);
+                        messages.push(SendTo::RelayNewMessage(downstream.clone(), message));
+                    }
+                }
+                (Some(prefix), None) if !self.standard_channels.contains_key(&channel_id) => {
+                    self.downstreams
+                        .insert(channel_id, (downstream.clone(), prefix.clone()));
+                    let message = Mining::SetExtranoncePrefix(SetExtranoncePrefix {
+                        channel_id,
+                        // Safe unwrap the prefix is at most 32 bytes long
+                        extranonce_prefix: prefix.try_into().unwrap(),
+                    });
+                    messages.push(SendTo::RelayNewMessage(downstream, message));
+                }
+                _ => {
+                    self.remove_downstream_channel(channel_id);
+                    messages.push(reconnect(downstream));
+                }
+            }
+        }
+        messages
+    }
+
+    pub fn into_downstreams(self) -> Vec<Arc<Mutex<DownstreamMiningNode>>> {
+        self.downstreams
+            .into_iter()
+            .map(|(_, (downstream, _))| downstream)
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.downstreams.is_empty()
+    }
+
+    /// Return false if the downstream channel is not a sub-channel of this channel
+    pub fn remove_downstream_channel(&mut self, channel_id: u32) -> bool {
+        self.shares.retain(|_, (id, _)| *id != channel_id);
+        if self.standard_channels.remove(&channel_id).is_some() {
+            self.dispatcher.remove_channel(channel_id);
+        }
+        self.downstreams.remove(&channel_id).is_some()
+    }
+
+    /// Forget a standard sub-channel and return its nominal hash rate, None if the channel is not
+    /// a standard sub-channel of this channel
+    pub fn remove_standard_channel(&mut self, channel_id: u32) -> Option<f32> {
+        let nominal_hash_rate = self.standard_channels.get(&channel_id)?.nominal_hash_rate;
+        self.remove_downstream_channel(channel_id);
+        Some(nominal_hash_rate)
+    }
+
+    pub fn is_standard_channel(&self, channel_id: u32) -> bool {
+        self.standard_channels.contains_key(&channel_id)
+    }
+
+    /// Ids of the standard sub-channels opened by the downstream
+    pub fn standard_channels_of(&self, downstream: &Arc<Mutex<DownstreamMiningNode>>) -> Vec<u32> {
+        self.standard_channels
+            .keys()
+            .filter(|channel_id| match self.downstreams.get(channel_id) {
+                Some((d, _)) => Arc::ptr_eq(d, downstream),
+                None => false,
+            })
+            .copied()
+            .collect()
+    }
+
+    pub fn downstream(&self, channel_id: u32) -> Option<Arc<Mutex<DownstreamMiningNode>>> {
+        self.downstreams
+            .get(&channel_id)
+            .map(|(downstream, _)| downstream.clone())
+    }
+
+    /// Sum of the nominal hash rates of the standard sub-channels
+    pub fn standard_hash_rate(&self) -> f32 {
+        self.standard_channels
+            .values()
+            .map(|standard| standard.nominal_hash_rate)
+            .sum()
+    }
+
+    /// (channel id, nominal hash rate) of the standard sub-channels
+    pub fn standard_hash_rates(&self) -> Vec<(u32, f32)> {
+        self.standard_channels
+            .iter()
+            .map(|(channel_id, standard)| (*channel_id, standard.nominal_hash_rate))
+            .collect()
+    }
+
+    /// Set the nominal hash rate of a standard sub-channel and return the previous one
+    pub fn set_standard_hash_rate(
+        &mut self,
+        channel_id: u32,
+        nominal_hash_rate: f32,
+    ) -> Option<f32> {
+        let standard = self.standard_channels.get_mut(&channel_id)?;
+        let old_hash_rate = standard.nominal_hash_rate;
+        standard.nominal_hash_rate = nominal_hash_rate;
+        Some(old_hash_rate)
+    }
+
+    /// Target that the shares of a standard sub-channel must meet to be valid
+    pub fn standard_downstream_target(&self, channel_id: u32) -> Option<Target> {
+        self.dispatcher.downstream_target(channel_id)
+    }
+
+    pub fn set_standard_downstream_target(&mut self, set_target: &SetTarget) {
+        let target = set_target.maximum_target.clone().into();
+        self.dispatcher
+            .set_downstream_target(set_target.channel_id, target);
+    }
+
+    /// UpdateChannel with the hash rate of the standard sub-channels
+    pub fn update_channel(&self) -> UpdateChannel<'static> {
+        UpdateChannel {
+            channel_id: self.channel_id,
+            nominal_hash_rate: self.standard_hash_rate(),
+            maximum_target: self.request.max_target.clone(),
+        }
+    }
+
+    /// Extranonce of a standard sub-channel: the prefix followed by zeros in place of the bytes
+    /// rolled by the extended downstreams. None if it is not 32 bytes long, as the jobs of the
+    /// standard channels require.
+    fn standard_extranonce(&self, prefix: &[u8]) -> Option<Extranonce> {
+        let mut extranonce = prefix.to_vec();
+        extranonce.resize(prefix.len() + self.downstream_extranonce_size as usize, 0);
+        match extranonce.len() == 32 {
+            true => {
+                // Safe unwrap the extranonce is 32 bytes long
+                let extranonce: B032 = extranonce.try_into().unwrap();
+                Some(extranonce.into())
+            }
+            false => None,
+        }
+    }
+
+    pub fn remove_downstream(&mut self, downstream: &Arc<Mutex<DownstreamMiningNode>>) {
+        let channel_ids: Vec<u32> = self
+            .downstreams
+            .iter()
+            .filter(|(_, (d, _))| Arc::ptr_eq(d, downstream))
+            .map(|(channel_id, _)| *channel_id)
+            .collect();
+        for channel_id in channel_ids {
+            self.remove_downstream_channel(channel_id);
+        }
+    }
+
+    /// The upstream closed the channel: every downstream channel is closed with the same reason
+    pub fn close(self, m: &CloseChannel) -> Vec<SendTo<DownstreamMiningNode>> {
+        self.downstreams
+            .into_iter()
+            .map(|(channel_id, (downstream, _))| {
+                downstream
+                    .safe_lock(|d| d.remove_channel(channel_id))
+                    .unwrap();
+                let close = Mining::CloseChannel(CloseChannel {
+                    channel_id,
+                    reason_code: m.reason_code.clone().into_static(),
+                });
+                SendTo::RelayNewMessage(downstream, close)
+            })
+            .collect()
+    }
+
+    /// Open a sub-channel for the downstream and return the messages that must be sent to it:
+    /// OpenExtendedMiningChannelSuccess followed by the current jobs and prev hash, or
+    /// OpenMiningChannelError.
+    pub fn open_downstream_channel(
+        &mut self,
+        downstream: Arc<Mutex<DownstreamMiningNode>>,
+        request_id: u32,
+        min_extranonce_size: u16,
+    ) -> Vec<SendTo<DownstreamMiningNode>> {
+        if min_extranonce_size > self.downstream_extranonce_size {
+            return vec![open_channel_error(
+                downstream,
+                request_id,
+                "min-extranonce-size-too-large",
+            )];
+        }
+        let prefix = match self
+            .extranonces
+            .next_prefix_extended(min_extranonce_size as usize)
+        {
+            Some(prefix) => prefix,
+            None => {
+                return vec![open_channel_error(
+                    downstream,
+                    request_id,
+                    "extranonce-prefixes-exhausted",
+                )]
+            }
+        };
+        let channel_id = self.next_channel_id;
+        self.next_channel_id -= 1;
+        self.downstreams
+            .insert(channel_id, (downstream.clone(), prefix.clone()));
+        downstream
+            .safe_lock(|d| d.add_channel(DownstreamChannel::Extended(channel_id)))
+            .unwrap();
+
+        let success = Mining::OpenExtendedMiningChannelSuccess(OpenExtendedMiningChannelSuccess {
+            request_id,
+            channel_id,
+            target: self.target.clone(),
+            extranonce_size: self.downstream_extranonce_size,
+            // Safe unwrap the prefix is at most 32 bytes long
+            extranonce_prefix: prefix.try_into().unwrap(),
+        });
+        let mut messages = vec![SendTo::RelayNewMessage(downstream.clone(), success)];
+        if let (Some(job), Some(prev_hash)) = (&self.active_job, &self.last_prev_hash) {
+            let mut job = job.clone();
+            job.channel_id = channel_id;
+            job.future_job = true;
+            let prev_hash = SetNewPrevHash {
+                channel_id,
+                job_id: job.job_id,
+                prev_hash: prev_hash.prev_hash.clone(),
+                min_ntime: prev_hash.min_ntime,
+                nbits: prev_hash.nbits,
+            };
+            messages.push(SendTo::RelayNewMessage(
+                downstream.clone(),
+                Mining::NewExtendedMiningJob(job),
+            ));
+            messages.push(SendTo::RelayNewMessage(
+                downstream.clone(),
+                Mining::SetNewPrevHash(prev_hash),
+            ));
+        }
+        for job in &self.future_jobs {
+            let mut job = job.clone();
+            job.channel_id = channel_id;
+            messages.push(SendTo::RelayNewMessage(
+                downstream.clone(),
+                Mining::NewExtendedMiningJob(job),
+            ));
+        }
+        messages
+    }
+
+    /// Open a standard sub-channel for an header only downstream and return the messages that
+    /// must be sent to it: OpenStandardMiningChannelSuccess followed by the current jobs and prev
+    /// hash. When vardiff is enabled the target of the channel is computed from its hash rate,
+    /// otherwise it is the target of the upstream channel. On error the OpenMiningChannelError
+    /// is returned.
+    #[allow(clippy::result_large_err)]
+    pub fn open_standard_channel(
+        &mut self,
+        downstream: Arc<Mutex<DownstreamMiningNode>>,
+        request_id: u32,
+        nominal_hash_rate: f32,
+        vardiff: Option<&mut Vardiff>,
+    ) -> Result<Vec<SendTo<DownstreamMiningNode>>, SendTo<DownstreamMiningNode>> {
+        let prefix = self
+            .extranonces
+            .next_prefix_extended(self.downstream_extranonce_size as usize)
+            .ok_or_else(|| {
+                open_channel_error(
+                    downstream.clone(),
+                    request_id,
+                    "extranonce-prefixes-exhausted",
+                )
+            })?;
+        let extranonce = self.standard_extranonce(&prefix).ok_or_else(|| {
+            open_channel_error(
+                downstream.clone(),
+                request_id,
+                "unsupported-extranonce-size",
+            )
+        })?;
+        let channel_id = self.next_channel_id;
+        self.next_channel_id -= 1;
+        let target = match vardiff {
+            Some(vardiff) => vardiff.add_channel(channel_id, nominal_hash_rate, Instant::now()),
+            None => self.target.clone(),
+        };
+        let channel = StandardChannel {
+            channel_id,
+            group_id: self.channel_id,
+            target: target.clone().into(),
+            extranonce: extranonce.clone(),
+        };
+        let extranonce_prefix: Vec<u8> = extranonce.into();
+        // The dispatcher learn the prev hash only with the jobs that it activates, so with the
+        // first channel it start again from the current job
+        let first = self.standard_channels.is_empty();
+        if first {
+            self.dispatcher = GroupChannelJobDispatcher::new(self.job_ids.clone());
+        }
+        self.dispatcher
+            .add_channel(channel_id, self.target.clone().into());
+        self.dispatcher
+            .set_downstream_target(channel_id, target.clone().into());
+        self.downstreams
+            .insert(channel_id, (downstream.clone(), prefix));
+        let standard = AggregatedChannel {
+            channel: channel.clone(),
+            nominal_hash_rate,
+        };
+        self.standard_channels.insert(channel_id, standard);
+        downstream
+            .safe_lock(|d| d.add_channel(DownstreamChannel::Standard(channel.clone())))
+            .unwrap();
+
+        let success = Mining::OpenStandardMiningChannelSuccess(OpenStandardMiningChannelSuccess {
+            request_id: request_id.into(),
+            channel_id,
+            target,
+            // Safe unwrap the extranonce is 32 bytes long
+            extranonce_prefix: extranonce_prefix.try_into().unwrap(),
+            group_channel_id: self.channel_id,
+        });
+        let mut messages = vec![SendTo::RelayNewMessage(downstream.clone(), success)];
+        if let (Some(job), Some(prev_hash)) = (&self.active_job, &self.last_prev_hash) {
+            let mut job = job.clone();
+            job.future_job = first;
+            if let Some(mut standard_job) =
+                self.dispatcher.on_new_extended_mining_job(&job, &channel)
+            {
+                if first {
+                    let mut prev_hash = prev_hash.clone();
+                    prev_hash.job_id = job.job_id;
+                    // Safe unwrap the job has just been added as a future job
+                    self.dispatcher.on_new_prev_hash(&prev_hash).unwrap();
+                }
+                standard_job.future_job = true;
+                let prev_hash = SetNewPrevHash {
+                    channel_id,
+                    job_id: standard_job.job_id,
+                    prev_hash: prev_hash.prev_hash.clone(),
+                    min_ntime: prev_hash.min_ntime,
+                    nbits: prev_hash.nbits,
+                };
+                messages.push(SendTo::RelayNewMessage(
+                    downstream.clone(),
+                    Mining::NewMiningJob(standard_job),
+                ));
+                messages.push(SendTo::RelayNewMessage(
+                    downstream.clone(),
+                    Mining::SetNewPrevHash(prev_hash),
+                ));
+            }
+        }
+        for job in &self.future_jobs {
+            if let Some(job) = self.dispatcher.on_new_extended_mining_job(job, &channel) {
+                messages.push(SendTo::RelayNewMessage(
+                    downstream.clone(),
+                    Mining::NewMiningJob(job),
+                ));
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Sub-channels that are not standard channels
+    fn extended_downstreams(
+        &self,
+    ) -> impl Iterator<Item = (&u32, &Arc<Mutex<DownstreamMiningNode>>)> {
+        let standard_channels = &self.standard_channels;
+        self.downstreams
+            .iter()
+            .filter(move |(channel_id, _)| !standard_channels.contains_key(channel_id))
+            .map(|(channel_id, (downstream, _))| (channel_id, downstream))
+    }
+
+    /// The coinbase of the downstream channels is the same of the upstream channel so the job is
+    /// relayed as it is, only the channel id change. The standard channels get the job computed
+    /// with their extranonce.
+    pub fn on_new_extended_mining_job(
+        &mut self,
+        m: &NewExtendedMiningJob,
+    ) -> Vec<SendTo<DownstreamMiningNode>> {
+        let job = m.as_static();
+        if job.future_job {
+            self.future_jobs.push(job.clone());
+        } else {
+            self.active_job = Some(job.clone());
+        }
+        let mut messages: Vec<SendTo<DownstreamMiningNode>> = self
+            .extended_downstreams()
+            .map(|(channel_id, downstream)| {
+                let mut job = job.clone();
+                job.channel_id = *channel_id;
+                SendTo::RelayNewMessage(downstream.clone(), Mining::NewExtendedMiningJob(job))
+            })
+            .collect();
+        for (channel_id, standard) in &self.standard_channels {
+            if let Some(standard_job) = self
+                .dispatcher
+                .on_new_extended_mining_job(&job, &standard.channel)
+            {
+                // Safe unwrap the standard channels are in downstreams
+                let (downstream, _) = self.downstreams.get(channel_id).unwrap();
+                let message = Mining::NewMiningJob(standard_job);
+                messages.push(SendTo::RelayNewMessage(downstream.clone(), message));
+            }
+        }
+        messages
+    }
+
+    pub fn on_set_new_prev_hash(
+        &mut self,
+        m: &SetNewPrevHash,
+    ) -> Vec<SendTo<DownstreamMiningNode>> {
+        let prev_hash = m.as_static();
+        if let Some(position) = self
+            .future_jobs
+            .iter()
+            .position(|job| job.job_id == m.job_id)
+        {
+            let mut job = self.future_jobs.remove(position);
+            job.future_job = false;
+            self.active_job = Some(job);
+        }
+        self.future_jobs.clear();
+        self.last_prev_hash = Some(prev_hash.clone());
+        let mut messages: Vec<SendTo<DownstreamMiningNode>> = self
+            .extended_downstreams()
+            .map(|(channel_id, downstream)| {
+                let mut prev_hash = prev_hash.clone();
+                prev_hash.channel_id = *channel_id;
+                SendTo::RelayNewMessage(downstream.clone(), Mining::SetNewPrevHash(prev_hash))
+            })
+            .collect();
+        if self.standard_channels.is_empty() {
+            return messages;
+        }
+        // Every standard channel received the future jobs so the dispatcher know the job
+        if let Ok(job_ids) = self.dispatcher.on_new_prev_hash(&prev_hash) {
+            for (channel_id, job_id) in job_ids {
+                if let Some((downstream, _)) = self.downstreams.get(&channel_id) {
+                    let mut prev_hash = prev_hash.clone();
+                    prev_hash.channel_id = channel_id;
+                    prev_hash.job_id = job_id;
+                    let message = Mining::SetNewPrevHash(prev_hash);
+                    messages.push(SendTo::RelayNewMessage(downstream.clone(), message));
+                }
+            }
+        }
+        messages
+    }
+
+    /// The extended downstream channels share the target of the upstream channel
+    pub fn downstream_target(&self, channel_id: u32) -> Option<U256<'static>> {
+        match self.downstreams.contains_key(&channel_id)
+            && !self.standard_channels.contains_key(&channel_id)
+        {
+            true => Some(self.target.clone()),
+            false => None,
+        }
+    }
+
+    /// Every share of the extended downstream channels is forwarded so they have the target of
+    /// the upstream channel. For the standard channels it is the target that a share must meet
+    /// to be forwarded, it is their target too when their targets are not set by vardiff.
+    pub fn on_set_target(
+        &mut self,
+        m: &SetTarget,
+        vardiff: bool,
+    ) -> Vec<SendTo<DownstreamMiningNode>> {
+        self.target = m.maximum_target.clone().into_static();
+        for channel_id in self.standard_channels.keys() {
+            self.dispatcher
+                .set_upstream_target(*channel_id, self.target.clone().into());
+            if !vardiff {
+                self.dispatcher
+                    .set_downstream_target(*channel_id, self.target.clone().into());
+            }
+        }
+        self.downstreams
+            .iter()
+            .filter(|(channel_id, _)| !vardiff || !self.standard_channels.contains_key(channel_id))
+            .map(|(channel_id, (downstream, _))| {
+                let set_target = SetTarget {
+                    channel_id: *channel_id,
+                    maximum_target: self.target.clone(),
+                };
+                SendTo::RelayNewMessage(downstream.clone(), Mining::SetTarget(set_target))
+            })
+            .collect()
+    }
+
+    /// Validate a share of a standard sub-channel. A share that meet the upstream target is
+    /// returned as a share of the upstream channel, otherwise the Err is the response for the
+    /// downstream.
+    #[allow(clippy::result_large_err)]
+    pub fn on_submit_shares_standard(
+        &mut self,
+        m: SubmitSharesStandard,
+    ) -> Result<SubmitSharesExtended<'static>, Mining<'static>> {
+        match self.dispatcher.on_submit_shares(m) {
+            SendSharesResponse::Valid(share) => {
+                let share = SubmitSharesExtended {
+                    channel_id: share.channel_id,
+                    sequence_number: share.sequence_number,
+                    job_id: share.job_id,
+                    nonce: share.nonce,
+                    ntime: share.ntime,
+                    version: share.version,
+                    // The bytes rolled by the extended downstreams are zero for standard channels
+                    // Safe unwrap the extranonce is at most 32 bytes long
+                    extranonce: vec![0; self.downstream_extranonce_size as usize]
+                        .try_into()
+                        .unwrap(),
+                };
+                self.on_submit_shares_extended(share)
+                    .map_err(Mining::SubmitSharesError)
+            }
+            SendSharesResponse::ValidForDownstream(success) => {
+                Err(Mining::SubmitSharesSuccess(success))
+            }
+            SendSharesResponse::Invalid(error) => Err(Mining::SubmitSharesError(error)),
+        }
+    }
+
+    /// Translate a share of a downstream channel in a share of the upstream channel: the
+    /// extranonce is the part of the downstream prefix that is not assigned by the upstream
+    /// followed by the extranonce rolled by the downstream.
+    pub fn on_submit_shares_extended(
+        &mut self,
+        m: SubmitSharesExtended,
+    ) -> Result<SubmitSharesExtended<'static>, SubmitSharesError<'static>> {
+        let prefix = match self.downstreams.get(&m.channel_id) {
+            Some((_, prefix)) => prefix,
+            None => return Err(share_error(&m, "invalid-channel-id")),
+        };
+        let downstream_extranonce = m.extranonce.to_vec();
+        if downstream_extranonce.len() != self.downstream_extranonce_size as usize {
+            return Err(share_error(&m, "invalid-extranonce-size"));
+        }
+        let mut extranonce = prefix[self.upstream_prefix_len..].to_vec();
+        extranonce.extend_from_slice(&downstream_extranonce);
+        let sequence_number = self.sequence_numbers.next();
+        self.shares
+            .insert(sequence_number, (m.channel_id, m.sequence_number));
+        Ok(SubmitSharesExtended {
+            channel_id: self.channel_id,
+            sequence_number,
+            job_id: m.job_id,
+            nonce: m.nonce,
+            ntime: m.ntime,
+            version: m.version,
+            // Safe unwrap the extranonce is at most 32 bytes long
+            extranonce: extranonce.try_into().unwrap(),
+        })
+    }
+
+    /// A success can acknowledge more than one share, every downstream that submitted one of
+    /// them receive a success for its last share. The new shares sum is split proportionally to
+    /// the number of shares.
+    pub fn on_submit_shares_success(
+        &mut self,
+        m: &SubmitSharesSuccess,
+    ) -> Vec<SendTo<DownstreamMiningNode>> {
+        let acknowledged: Vec<u32> = self
+            .shares
+            .keys()
+            .filter(|sequence_number| **sequence_number <= m.last_sequence_number)
+            .copied()
+            .collect();
+        // downstream channel id -> (last sequence number, accepted shares)
+        let mut accepted: HashMap<u32, (u32, u32)> = HashMap::new();
+        for sequence_number in &acknowledged {
+            // Safe unwrap the keys are taken from the map
+            let (channel_id, downstream_sequence_number) =
+                self.shares.remove(sequence_number).unwrap();
+            let entry = accepted.entry(channel_id).or_insert((0, 0));
+            entry.0 = entry.0.max(downstream_sequence_number);
+            entry.1 += 1;
+        }
+        let total = acknowledged.len() as u64;
+        accepted
+            .into_iter()
+            .filter_map(|(channel_id, (last_sequence_number, count))| {
+                let (downstream, _) = self.downstreams.get(&channel_id)?;
+                let success = SubmitSharesSuccess {
+                    channel_id,
+                    last_sequence_number,
+                    new_submits_accepted_count: count,
+                    new_shares_sum: m.new_shares_sum * count as u64 / total,
+                };
+                Some(SendTo::RelayNewMessage(
+                    downstream.clone(),
+                    Mining::SubmitSharesSuccess(success),
+                ))
+            })
+            .collect()
+    }
+
+    pub fn on_submit_shares_error(
+        &mut self,
+        m: &SubmitSharesError,
+    ) -> Option<SendTo<DownstreamMiningNode>> {
+        let (channel_id, sequence_number) = self.shares.remove(&m.sequence_number)?;
+        let (downstream, _) = self.downstreams.get(&channel_id)?;
+        let error = SubmitSharesError {
+            channel_id,
+            sequence_number,
+            error_code: m.error_code.clone().into_static(),
+        };
+        Some(SendTo::RelayNewMessage(
+            downstream.clone(),
+            Mining::SubmitSharesError(error),
+        ))
+    }
+}
+
+pub fn open_channel_error(
+    downstream: Arc<Mutex<DownstreamMiningNode>>,
+    request_id: u32,
+    error_code: &str,
+) -> SendTo<DownstreamMiningNode> {
+    let error = OpenMiningChannelError {
+        request_id,
+        // Safe unwrap error codes are shorter than 32 bytes
+        error_code: error_code.to_string().try_into().unwrap(),
+    };
+    SendTo::RelayNewMessage(downstream, Mining::OpenMiningChannelError(error))
+}
+
+/// Ask the downstream to reconnect to the proxy, it will be paired again with an upstream that is
+/// connected
+pub fn reconnect(downstream: Arc<Mutex<DownstreamMiningNode>>) -> SendTo<DownstreamMiningNode> {
+    let reconnect = Reconnect {
+        // An empty host means that the downstream must reconnect to the current host
+        // Safe unwrap the empty string is a valid Str0255
+        new_host: String::new().try_into().unwrap(),
+        new_port: 0,
+    };
+    SendTo::RelayNewMessage(downstream, Mining::Reconnect(reconnect))
+}
+
+pub fn close_channel(channel_id: u32, reason_code: &str) -> CloseChannel<'static> {
+    CloseChannel {
+        channel_id,
+        // Safe unwrap reason codes are shorter than 32 bytes
+        reason_code: reason_code.to_string().try_into().unwrap(),
+    }
+}
+
+pub fn share_error(m: &SubmitSharesExtended, error_code: &str) -> SubmitSharesError<'static> {
+    SubmitSharesError {
+        channel_id: m.channel_id,
+        sequence_number: m.sequence_number,
+        // Safe unwrap error codes are shorter than 32 bytes
+        error_code: error_code.to_string().try_into().unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::downstream_mining::DownstreamMiningNodeStatus;
+    use binary_sv2::Seq0255;
+    use roles_logic_sv2::{common_properties::CommonDownstreamData, utils::merkle_root_from_path};
+
+    const UPSTREAM_CHANNEL_ID: u32 = 7;
+    const UPSTREAM_PREFIX: [u8; 4] = [1, 2, 3, 4];
+    /// Extranonce size of the upstream channel, the downstreams can roll 12 bytes
+    const EXTRANONCE_SIZE: u16 = 16;
+
+    fn downstream() -> Arc<Mutex<DownstreamMiningNode>> {
+        let (sender, receiver) = async_channel::bounded(10);
+        let mut downstream = DownstreamMiningNode::new(receiver, sender);
+        let data = CommonDownstreamData {
+            header_only: false,
+            work_selection: false,
+            version_rolling: true,
+        };
+        downstream.status = DownstreamMiningNodeStatus::Paired((data, HashMap::new()));
+        Arc::new(Mutex::new(downstream))
+    }
+
+    fn channel() -> OpenExtendedChannel {
+        channel_with_prefix(&UPSTREAM_PREFIX)
+    }
+
+    fn channel_with_prefix(prefix: &[u8]) -> OpenExtendedChannel {
+        let request = OpenExtendedMiningChannel {
+            request_id: 1.into(),
+            user_identity: "proxy".to_string().try_into().unwrap(),
+            nominal_hash_rate: 1000.0,
+            max_target: [0xff; 32].into(),
+            min_extranonce_size: EXTRANONCE_SIZE,
+        };
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id: 1,
+            channel_id: UPSTREAM_CHANNEL_ID,
+            target: [0xff; 32].into(),
+            extranonce_size: EXTRANONCE_SIZE,
+            extranonce_prefix: prefix.to_vec().try_into().unwrap(),
+        };
+        OpenExtendedChannel::new(&success, request, Arc::new(Mutex::new(Id::new()))).unwrap()
+    }
+
+    /// Messages relayed to the downstreams, in order
+    fn relayed(
+        messages: Vec<SendTo<DownstreamMiningNode>>,
+    ) -> Vec<(Arc<Mutex<DownstreamMiningNode>>, Mining<'static>)> {
+        messages
+            .into_iter()
+            .map(|message| match message {
+                SendTo::RelayNewMessage(downstream, message) => (downstream, message),
+                _ => panic!("message not relayed to a downstream"),
+            })
+            .collect()
+    }
+
+    /// Open a sub-channel and return its (channel id, extranonce prefix)
+    fn open(
+        channel: &mut OpenExtendedChannel,
+        downstream: &Arc<Mutex<DownstreamMiningNode>>,
+    ) -> (u32, Vec<u8>) {
+        let messages = relayed(channel.open_downstream_channel(downstream.clone(), 1, 8));
+        match &messages[0].1 {
+            Mining::OpenExtendedMiningChannelSuccess(m) => {
+                assert_eq!(
+                    m.extranonce_size,
+                    EXTRANONCE_SIZE - PROXY_EXTRANONCE_LEN as u16
+                );
+                (m.channel_id, m.extranonce_prefix.to_vec())
+            }
+            _ => panic!("channel not opened"),
+        }
+    }
+
+    fn open_error(messages: Vec<SendTo<DownstreamMiningNode>>) -> String {
+        match &relayed(messages)[0].1 {
+            Mining::OpenMiningChannelError(m) => String::from_utf8(m.error_code.to_vec()).unwrap(),
+            _ => panic!("channel opened"),
+        }
+    }
+
+    fn job(job_id: u32, future_job: bool) -> NewExtendedMiningJob<'static> {
+        NewExtendedMiningJob {
+            channel_id: UPSTREAM_CHANNEL_ID,
+            job_id,
+            future_job,
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: Seq0255::new(Vec::new()).unwrap(),
+            coinbase_tx_prefix: vec![1, 2, 3].try_into().unwrap(),
+            coinbase_tx_suffix: vec![4, 5, 6].try_into().unwrap(),
+        }
+    }
+
+    /// Job with a coinbase that has the 32 bytes extranonce as input script, so that the
+    /// standard jobs can be computed from it
+    fn coinbase_job(job_id: u32, future_job: bool) -> NewExtendedMiningJob<'static> {
+        let mut coinbase_tx_prefix = vec![2, 0, 0, 0, 1];
+        coinbase_tx_prefix.extend_from_slice(&[0; 32]);
+        coinbase_tx_prefix.extend_from_slice(&[255, 255, 255, 255, 32]);
+        let mut coinbase_tx_suffix = vec![255, 255, 255, 255, 1];
+        coinbase_tx_suffix.extend_from_slice(&[0; 8]);
+        coinbase_tx_suffix.extend_from_slice(&[0, 0, 0, 0, 0]);
+        NewExtendedMiningJob {
+            coinbase_tx_prefix: coinbase_tx_prefix.try_into().unwrap(),
+            coinbase_tx_suffix: coinbase_tx_suffix.try_into().unwrap(),
+            ..job(job_id, future_job)
+        }
+    }
+
+    fn prev_hash(job_id: u32) -> SetNewPrevHash<'static> {
+        SetNewPrevHash {
+            channel_id: UPSTREAM_CHANNEL_ID,
+            job_id,
+            prev_hash: [3; 32].into(),
+            min_ntime: 1_650_000_000,
+            nbits: 0x1d00_ffff,
+        }
+    }
+
+    fn share(
+        channel_id: u32,
+        sequence_number: u32,
+        extranonce: Vec<u8>,
+    ) -> SubmitSharesExtended<'static> {
+        SubmitSharesExtended {
+            channel_id,
+            sequence_number,
+            job_id: 1,
+            nonce: 2,
+            ntime: 3,
+            version: 4,
+            extranonce: extranonce.try_into().unwrap(),
+        }
+    }
+
+    fn error_code(error: &SubmitSharesError) -> String {
+        String::from_utf8(error.error_code.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn downstream_channels_get_unique_prefixes_after_the_upstream_prefix() {
+        let mut channel = channel();
+        let downstream = downstream();
+        let opened: Vec<(u32, Vec<u8>)> = (0..3).map(|_| open(&mut channel, &downstream)).collect();
+        let channel_ids: Vec<u32> = opened.iter().map(|(id, _)| *id).collect();
+        assert_eq!(channel_ids, vec![u32::MAX, u32::MAX - 1, u32::MAX - 2]);
+        for (i, (_, prefix)) in opened.iter().enumerate() {
+            assert_eq!(prefix.len(), UPSTREAM_PREFIX.len() + PROXY_EXTRANONCE_LEN);
+            assert_eq!(prefix[..UPSTREAM_PREFIX.len()], UPSTREAM_PREFIX);
+            assert!(opened[i + 1..].iter().all(|(_, other)| other != prefix));
+        }
+    }
+
+    #[test]
+    fn min_extranonce_size_larger_than_the_downstream_part_is_refused() {
+        let mut channel = channel();
+        let min_extranonce_size = EXTRANONCE_SIZE - PROXY_EXTRANONCE_LEN as u16 + 1;
+        let messages = channel.open_downstream_channel(downstream(), 1, min_extranonce_size);
+        assert_eq!(open_error(messages), "min-extranonce-size-too-large");
+        assert!(channel.is_empty());
+    }
+
+    #[test]
+    fn no_channel_is_opened_when_the_prefixes_are_exhausted() {
+        let mut channel = channel();
+        // Every byte reserved to the proxy has already been used
+        let mut last = UPSTREAM_PREFIX.to_vec();
+        last.extend_from_slice(&[0xff; PROXY_EXTRANONCE_LEN]);
+        last.resize(32, 0);
+        // Extranonce read the halves of a B032 as little endian and ExtendedExtranonce write them
+        // back as big endian
+        last[..16].reverse();
+        last[16..].reverse();
+        let last: B032 = last.try_into().unwrap();
+        channel.extranonces = ExtendedExtranonce::from_extranonce(last.into(), 0..4, 4..8, 8..32);
+        let messages = channel.open_downstream_channel(downstream(), 1, 8);
+        assert_eq!(open_error(messages), "extranonce-prefixes-exhausted");
+        assert!(channel.is_empty());
+    }
+
+    #[test]
+    fn share_is_sent_upstream_with_the_downstream_prefix() {
+        let mut channel = channel();
+        let downstream = downstream();
+        let (_, _) = open(&mut channel, &downstream);
+        let (channel_id, prefix) = open(&mut channel, &downstream);
+        let rolled = vec![9; 12];
+        let upstream_share = channel
+            .on_submit_shares_extended(share(channel_id, 5, rolled.clone()))
+            .unwrap();
+        assert_eq!(upstream_share.channel_id, UPSTREAM_CHANNEL_ID);
+        let mut extranonce = prefix[UPSTREAM_PREFIX.len()..].to_vec();
+        extranonce.extend_from_slice(&rolled);
+        assert_eq!(upstream_share.extranonce.to_vec(), extranonce);
+        assert_eq!(
+            (
+                upstream_share.job_id,
+                upstream_share.nonce,
+                upstream_share.ntime,
+                upstream_share.version
+            ),
+            (1, 2, 3, 4)
+        );
+
+        let success = SubmitSharesSuccess {
+            channel_id: UPSTREAM_CHANNEL_ID,
+            last_sequence_number: upstream_share.sequence_number,
+            new_submits_accepted_count: 1,
+            new_shares_sum: 10,
+        };
+        let messages = relayed(channel.on_submit_shares_success(&success));
+        assert_eq!(messages.len(), 1);
+        assert!(Arc::ptr_eq(&messages[0].0, &downstream));
+        match &messages[0].1 {
+            Mining::SubmitSharesSuccess(m) => {
+                assert_eq!((m.channel_id, m.last_sequence_number), (channel_id, 5));
+                assert_eq!((m.new_submits_accepted_count, m.new_shares_sum), (1, 10));
+            }
+            _ => panic!("success not relayed"),
+        }
+    }
+
+    #[test]
+    fn share_error_is_relayed_to_the_downstream_channel() {
+        let mut channel = channel();
+        let downstream = downstream();
+        let (channel_id, _) = open(&mut channel, &downstream);
+        let upstream_share = channel
+            .on_submit_shares_extended(share(channel_id, 5, vec![9; 12]))
+            .unwrap();
+        let error = share_error(&upstream_share, "difficulty-too-low");
+        match channel.on_submit_shares_error(&error) {
+            Some(SendTo::RelayNewMessage(d, Mining::SubmitSharesError(m))) => {
+                assert!(Arc::ptr_eq(&d, &downstream));
+                assert_eq!((m.channel_id, m.sequence_number), (channel_id, 5));
+                assert_eq!(error_code(&m), "difficulty-too-low");
+            }
+            _ => panic!("error not relayed"),
+        }
+        // Every upstream share is answered once
+        assert!(channel.on_submit_shares_error(&error).is_none());
+    }
+
+    #[test]
+    fn invalid_shares_are_refused() {
+        let mut channel = channel();
+        let (channel_id, _) = open(&mut channel, &downstream());
+        let unknown_channel =
+            channel.on_submit_shares_extended(share(channel_id - 1, 1, vec![9; 12]));
+        assert_eq!(
+            error_code(&unknown_channel.unwrap_err()),
+            "invalid-channel-id"
+        );
+        let wrong_size = channel.on_submit_shares_extended(share(channel_id, 2, vec![9; 11]));
+        assert_eq!(
+            error_code(&wrong_size.unwrap_err()),
+            "invalid-extranonce-size"
+        );
+    }
+
+    #[test]
+    fn jobs_are_relayed_to_every_sub_channel() {
+        let mut channel = channel();
+        let first = downstream();
+        let second = downstream();
+        let (first_id, _) = open(&mut channel, &first);
+        let (second_id, _) = open(&mut channel, &second);
+        let messages = relayed(channel.on_new_extended_mining_job(&job(1, true)));
+        assert_eq!(messages.len(), 2);
+        for (downstream, message) in messages {
+            let expected_id = match Arc::ptr_eq(&downstream, &first) {
+                true => first_id,
+                false => second_id,
+            };
+            match message {
+                Mining::NewExtendedMiningJob(m) => {
+                    assert_eq!((m.channel_id, m.job_id), (expected_id, 1));
+                    assert!(m.future_job);
+                    assert_eq!(m.coinbase_tx_prefix.to_vec(), vec![1, 2, 3]);
+                }
+                _ => panic!("job not relayed"),
+            }
+        }
+        let messages = relayed(channel.on_set_new_prev_hash(&prev_hash(1)));
+        assert_eq!(messages.len(), 2);
+        assert!(messages
+            .iter()
+            .all(|(_, m)| matches!(m, Mining::SetNewPrevHash(m) if m.job_id == 1)));
+    }
+
+    #[test]
+    fn new_sub_channel_gets_the_active_job_and_the_future_jobs() {
+        let mut channel = channel();
+        channel.on_new_extended_mining_job(&job(1, true));
+        channel.on_set_new_prev_hash(&prev_hash(1));
+        channel.on_new_extended_mining_job(&job(2, true));
+        let downstream = downstream();
+        let messages = relayed(channel.open_downstream_channel(downstream, 1, 8));
+        let channel_id = match &messages[0].1 {
+            Mining::OpenExtendedMiningChannelSuccess(m) => m.channel_id,
+            _ => panic!("channel not opened"),
+        };
+        match (&messages[1].1, &messages[2].1, &messages[3].1) {
+            (
+                Mining::NewExtendedMiningJob(active),
+                Mining::SetNewPrevHash(prev_hash),
+                Mining::NewExtendedMiningJob(future),
+            ) => {
+                assert_eq!((active.channel_id, active.job_id), (channel_id, 1));
+                assert_eq!((prev_hash.channel_id, prev_hash.job_id), (channel_id, 1));
+                assert_eq!((future.channel_id, future.job_id), (channel_id, 2));
+                assert!(future.future_job);
+            }
+            _ => panic!("jobs not sent"),
+        }
+        assert_eq!(messages.len(), 4);
+    }
+
+    #[test]
+    fn standard_job_is_computed_with_the_extranonce_of_the_upstream_share() {
+        // The upstream prefix and the extranonce size fill the 32 bytes of a standard extranonce
+        let upstream_prefix: Vec<u8> = (1..=16).collect();
+        let mut channel = channel_with_prefix(&upstream_prefix);
+        let extended_job = coinbase_job(1, true);
+        channel.on_new_extended_mining_job(&extended_job);
+        channel.on_set_new_prev_hash(&prev_hash(1));
+        let messages = match channel.open_standard_channel(downstream(), 1, 10.0, None) {
+            Ok(messages) => relayed(messages),
+            Err(_) => panic!("channel not opened"),
+        };
+        let (channel_id, extranonce_prefix) = match &messages[0].1 {
+            Mining::OpenStandardMiningChannelSuccess(m) => {
+                assert_eq!(m.group_channel_id, UPSTREAM_CHANNEL_ID);
+                (m.channel_id, m.extranonce_prefix.to_vec())
+            }
+            _ => panic!("channel not opened"),
+        };
+        let job = match &messages[1].1 {
+            Mining::NewMiningJob(m) => m.as_static(),
+            _ => panic!("job not sent"),
+        };
+        assert!(matches!(&messages[2].1, Mining::SetNewPrevHash(m) if m.job_id == job.job_id));
+
+        let share = SubmitSharesStandard {
+            channel_id,
+            sequence_number: 1,
+            job_id: job.job_id,
+            nonce: 2,
+            ntime: 3,
+            version: 0x2000_0000,
+        };
+        let upstream_share = channel.on_submit_shares_standard(share).unwrap();
+        assert_eq!(upstream_share.channel_id, UPSTREAM_CHANNEL_ID);
+        assert_eq!(upstream_share.job_id, 1);
+        let mut extranonce = upstream_prefix;
+        extranonce.extend_from_slice(&upstream_share.extranonce.to_vec());
+        assert_eq!(extranonce, extranonce_prefix);
+        let merkle_root = merkle_root_from_path(
+            extended_job.coinbase_tx_prefix.inner_as_ref(),
+            extended_job.coinbase_tx_suffix.inner_as_ref(),
+            &extranonce,
+            &[] as &[&[u8]],
+        )
+        .unwrap();
+        assert_eq!(job.merkle_root.to_vec(), merkle_root);
+    }
+}
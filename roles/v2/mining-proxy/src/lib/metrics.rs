@@ -0,0 +1,362 @@
+//! Operational metrics of the proxy, exported in the Prometheus text format on `/metrics` when
+//! `metrics_address` is set in the config.
+//!
+//! Shares are counted when the proxy answer them: the ones accepted by the upstream and the ones
+//! that only meet the downstream target and are accepted by the proxy itself. The hash rate of the
+//! standard channels of the header only downstreams is estimated by vardiff when it is enabled,
+//! otherwise the nominal hash rate declared by the downstream is exported. Templates and blocks
+//! are only seen by the pool.
+use network_helpers::http_tokio::{self, Response};
+use roles_logic_sv2::common_properties::{DownstreamChannel, IsMiningUpstream};
+use std::{collections::HashMap, fmt::Write, net::SocketAddr, time::Duration};
+use tracing::error;
+
+const CHANNEL_TYPES: [&str; 3] = ["standard", "extended", "group"];
+
+/// (address, total nominal hash rate, (channel id, hash rate) of the standard channels)
+type UpstreamHashRates = (SocketAddr, u64, Vec<(u32, f32)>);
+
+fn channel_type(channel: &DownstreamChannel) -> &'static str {
+    match channel {
+        DownstreamChannel::Standard(_) => "standard",
+        DownstreamChannel::Extended(_) => "extended",
+        DownstreamChannel::Group(_) => "group",
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    downstreams: u64,
+    /// channel type -> open channels
+    channels: HashMap<&'static str, u64>,
+    shares_accepted_by_upstream: u64,
+    shares_accepted_by_proxy: u64,
+    /// reason -> shares
+    shares_rejected: HashMap<String, u64>,
+    jobs_received: u64,
+    /// Time to relay the last prev hash received from an upstream to the downstreams
+    last_prev_hash_latency: Option<Duration>,
+}
+
+impl Metrics {
+    pub fn on_downstream_connected(&mut self) {
+        self.downstreams += 1;
+    }
+
+    /// `channels` are the channels that the downstream had still open
+    pub fn on_downstream_disconnected<'a>(
+        &mut self,
+        channels: impl Iterator<Item = &'a DownstreamChannel>,
+    ) {
+        self.downstreams = self.downstreams.saturating_sub(1);
+        for channel in channels {
+            self.on_channel_closed(channel);
+        }
+    }
+
+    pub fn on_channel_opened(&mut self, channel: &DownstreamChannel) {
+        *self.channels.entry(channel_type(channel)).or_default() += 1;
+    }
+
+    pub fn on_channel_closed(&mut self, channel: &DownstreamChannel) {
+        if let Some(open) = self.channels.get_mut(channel_type(channel)) {
+            *open = open.saturating_sub(1);
+        }
+    }
+
+    pub fn on_shares_accepted_by_upstream(&mut self, shares: u32) {
+        self.shares_accepted_by_upstream += shares as u64;
+    }
+
+    pub fn on_share_accepted_by_proxy(&mut self) {
+        self.shares_accepted_by_proxy += 1;
+    }
+
+    pub fn on_share_rejected(&mut self, reason: &str) {
+        *self.shares_rejected.entry(reason.to_string()).or_default() += 1;
+    }
+
+    pub fn on_job_received(&mut self) {
+        self.jobs_received += 1;
+    }
+
+    pub fn on_prev_hash_relayed(&mut self, latency: Duration) {
+        self.last_prev_hash_latency = Some(latency);
+    }
+
+    /// Prometheus text format
+    fn encode(&self, upstreams: &[UpstreamHashRates]) -> String {
+        let mut out = String::new();
+        // Safe unwraps writing in a String never fail
+        header(
+            &mut out,
+            "sv2_proxy_downstreams",
+            "gauge",
+            "Connected downstreams",
+        );
+        writeln!(out, "sv2_proxy_downstreams {}", self.downstreams).unwrap();
+        header(
+            &mut out,
+            "sv2_proxy_channels",
+            "gauge",
+            "Open downstream channels by type",
+        );
+        for channel_type in CHANNEL_TYPES {
+            let open = self.channels.get(channel_type).copied().unwrap_or(0);
+            writeln!(
+                out,
+                "sv2_proxy_channels{{type=\"{}\"}} {}",
+                channel_type, open
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "sv2_proxy_shares_accepted_total",
+            "counter",
+            "Accepted shares by who accepted them",
+        );
+        writeln!(
+            out,
+            "sv2_proxy_shares_accepted_total{{by=\"upstream\"}} {}",
+            self.shares_accepted_by_upstream
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "sv2_proxy_shares_accepted_total{{by=\"proxy\"}} {}",
+            self.shares_accepted_by_proxy
+        )
+        .unwrap();
+        header(
+            &mut out,
+            "sv2_proxy_shares_rejected_total",
+            "counter",
+            "Rejected shares by reason",
+        );
+        for (reason, shares) in &self.shares_rejected {
+            writeln!(
+                out,
+                "sv2_proxy_shares_rejected_total{{reason=\"{}\"}} {}",
+                escape(reason),
+                shares
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "sv2_proxy_upstream_hashrate",
+            "gauge",
+            "Nominal hash rate of the downstream channels opened with the upstream",
+        );
+        for (address, total_hash_rate, _) in upstreams {
+            writeln!(
+                out,
+                "sv2_proxy_upstream_hashrate{{upstream=\"{}\"}} {}",
+                address, total_hash_rate
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "sv2_proxy_channel_hashrate",
+            "gauge",
+            "Hash rate of the standard channels of the header only downstreams",
+        );
+        for (address, _, hash_rates) in upstreams {
+            for (channel_id, hash_rate) in hash_rates {
+                writeln!(
+                    out,
+                    "sv2_proxy_channel_hashrate{{upstream=\"{}\",channel=\"{}\"}} {}",
+                    address, channel_id, hash_rate
+                )
+                .unwrap();
+            }
+        }
+        header(
+            &mut out,
+            "sv2_proxy_jobs_received_total",
+            "counter",
+            "Jobs received from the upstreams",
+        );
+        writeln!(out, "sv2_proxy_jobs_received_total {}", self.jobs_received).unwrap();
+        if let Some(latency) = self.last_prev_hash_latency {
+            header(
+                &mut out,
+                "sv2_proxy_prev_hash_latency_seconds",
+                "gauge",
+                "Time to relay the last prev hash to the downstreams",
+            );
+            writeln!(
+                out,
+                "sv2_proxy_prev_hash_latency_seconds {}",
+                latency.as_secs_f64()
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// Serve the metrics on `http://<address>/metrics`
+pub async fn serve(address: SocketAddr) {
+    let served = http_tokio::serve(address, |request| match request.path.as_str() {
+        "/metrics" => {
+            // Read before locking the metrics, the upstreams update the metrics while locked
+            let upstreams: Vec<UpstreamHashRates> = crate::upstream_nodes()
+                .iter()
+                .map(|upstream| {
+                    upstream
+                        .safe_lock(|u| (u.address(), u.total_hash_rate(), u.channel_hash_rates()))
+                        .unwrap()
+                })
+                .collect();
+            let body = crate::metrics()
+                .safe_lock(|m| m.encode(&upstreams))
+                .unwrap();
+            Response::new(200, "text/plain; version=0.0.4", body)
+        }
+        _ => Response::not_found(),
+    })
+    .await;
+    if let Err(e) = served {
+        error!(%address, "metrics not served: {}", e);
+    }
+}
+
+fn header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+/// Label values are quoted
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Value of the sample `name`, written with its labels as in the output
+    fn sample<'a>(out: &'a str, name: &str) -> Option<&'a str> {
+        out.lines()
+            .filter(|line| !line.starts_with('#'))
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+    }
+
+    fn upstreams() -> Vec<UpstreamHashRates> {
+        vec![("127.0.0.1:34254".parse().unwrap(), 100, vec![(3, 10.5)])]
+    }
+
+    #[test]
+    fn every_sample_follows_the_help_and_type_of_its_metric() {
+        let mut metrics = Metrics::default();
+        metrics.on_downstream_connected();
+        metrics.on_channel_opened(&DownstreamChannel::Group(1));
+        metrics.on_share_rejected("stale-share");
+        metrics.on_prev_hash_relayed(Duration::from_millis(1500));
+        let out = metrics.encode(&upstreams());
+
+        let mut described: Option<&str> = None;
+        let mut typed: Option<&str> = None;
+        for line in out.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                described = help.split(' ').next();
+            } else if let Some(metric_type) = line.strip_prefix("# TYPE ") {
+                let mut parts = metric_type.split(' ');
+                typed = parts.next();
+                assert_eq!(typed, described, "TYPE without HELP: {}", line);
+                assert!(matches!(parts.next(), Some("counter") | Some("gauge")));
+            } else {
+                let name = line.split(&['{', ' '][..]).next().unwrap();
+                assert_eq!(Some(name), typed, "sample without TYPE: {}", line);
+                let value = line.rsplit(' ').next().unwrap();
+                assert!(value.parse::<f64>().is_ok(), "invalid value: {}", line);
+                if name.ends_with("_total") {
+                    assert!(out.contains(&format!("# TYPE {} counter\n", name)));
+                }
+            }
+        }
+        assert!(out.ends_with('\n'));
+    }
+
+    #[test]
+    fn samples_follow_the_events() {
+        let mut metrics = Metrics::default();
+        metrics.on_downstream_connected();
+        metrics.on_downstream_connected();
+        metrics.on_channel_opened(&DownstreamChannel::Group(1));
+        metrics.on_channel_opened(&DownstreamChannel::Extended(2));
+        metrics.on_shares_accepted_by_upstream(3);
+        metrics.on_share_accepted_by_proxy();
+        metrics.on_share_rejected("stale-share");
+        metrics.on_share_rejected("stale-share");
+        metrics.on_job_received();
+        metrics.on_downstream_disconnected([DownstreamChannel::Group(1)].iter());
+        let out = metrics.encode(&upstreams());
+
+        assert_eq!(sample(&out, "sv2_proxy_downstreams"), Some("1"));
+        assert_eq!(
+            sample(&out, r#"sv2_proxy_channels{type="group"}"#),
+            Some("0")
+        );
+        assert_eq!(
+            sample(&out, r#"sv2_proxy_channels{type="extended"}"#),
+            Some("1")
+        );
+        assert_eq!(
+            sample(&out, r#"sv2_proxy_channels{type="standard"}"#),
+            Some("0")
+        );
+        assert_eq!(
+            sample(&out, r#"sv2_proxy_shares_accepted_total{by="upstream"}"#),
+            Some("3")
+        );
+        assert_eq!(
+            sample(&out, r#"sv2_proxy_shares_accepted_total{by="proxy"}"#),
+            Some("1")
+        );
+        assert_eq!(
+            sample(
+                &out,
+                r#"sv2_proxy_shares_rejected_total{reason="stale-share"}"#
+            ),
+            Some("2")
+        );
+        assert_eq!(
+            sample(
+                &out,
+                r#"sv2_proxy_upstream_hashrate{upstream="127.0.0.1:34254"}"#
+            ),
+            Some("100")
+        );
+        assert_eq!(
+            sample(
+                &out,
+                r#"sv2_proxy_channel_hashrate{upstream="127.0.0.1:34254",channel="3"}"#
+            ),
+            Some("10.5")
+        );
+        assert_eq!(sample(&out, "sv2_proxy_jobs_received_total"), Some("1"));
+        // Exported only once a prev hash has been relayed
+        assert!(!out.contains("sv2_proxy_prev_hash_latency_seconds"));
+        metrics.on_prev_hash_relayed(Duration::from_millis(1500));
+        assert_eq!(
+            sample(&metrics.encode(&[]), "sv2_proxy_prev_hash_latency_seconds"),
+            Some("1.5")
+        );
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let mut metrics = Metrics::default();
+        metrics.on_share_rejected("bad \"reason\"\\\n");
+        let out = metrics.encode(&[]);
+
+        assert!(out.contains(r#"reason="bad \"reason\"\\\n""#));
+    }
+}
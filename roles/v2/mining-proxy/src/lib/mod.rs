@@ -1,2 +1,5 @@
+pub mod config;
 pub mod downstream_mining;
+pub mod extended_channel;
+pub mod metrics;
 pub mod upstream_mining;
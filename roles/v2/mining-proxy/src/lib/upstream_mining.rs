@@ -1,4 +1,11 @@
-use super::downstream_mining::{DownstreamMiningNode, StdFrame as DownstreamFrame};
+use super::{
+    config::Config,
+    downstream_mining::{DownstreamMiningNode, StdFrame as DownstreamFrame},
+    extended_channel::{
+        close_channel, open_channel_error, reconnect, share_error, ExtendedChannel,
+        OpenExtendedChannel, PROXY_EXTRANONCE_LEN,
+    },
+};
 use async_channel::{Receiver, SendError, Sender};
 use async_recursion::async_recursion;
 use codec_sv2::{Frame, HandshakeRole, Initiator, StandardEitherFrame, StandardSv2Frame};
@@ -6,8 +13,8 @@ use network_helpers::noise_connection_tokio::Connection;
 use roles_logic_sv2::{
     common_messages_sv2::{Protocol, SetupConnection},
     common_properties::{
-        DownstreamChannel, IsMiningDownstream, IsMiningUpstream, IsUpstream, RequestIdMapper,
-        StandardChannel, UpstreamChannel,
+        DownstreamChannel, IsMiningDownstream, IsMiningUpstream, IsUpstream, PairSettings,
+        RequestIdMapper, StandardChannel, UpstreamChannel,
     },
     errors::Error,
     handlers::mining::{ParseUpstreamMiningMessages, SendTo, SupportedChannelTypes},
@@ -17,9 +24,14 @@ use roles_logic_sv2::{
     routing_logic::MiningProxyRoutingLogic,
     selectors::{DownstreamMiningSelector, ProxyDownstreamMiningSelector as Prs},
     utils::{Id, Mutex},
+    vardiff::Vardiff,
 };
 use std::{collections::HashMap, sync::Arc};
-use tokio::{net::TcpStream, task};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    task,
+};
+use tracing::{info, info_span, warn, Instrument};
 
 pub type Message = PoolMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
@@ -70,6 +82,10 @@ pub struct UpstreamMiningNode {
     id: u32,
     job_ids: Arc<Mutex<Id>>,
     total_hash_rate: u64,
+    /// IP address or hostname, resolved again at every connection so that the proxy follow the
+    /// changes of the DNS records
+    host: String,
+    /// Last resolved address, used when the host can not be resolved
     address: SocketAddr,
     //port: u32,
     connection: Option<UpstreamMiningConnection>,
@@ -84,19 +100,44 @@ pub struct UpstreamMiningNode {
     downstream_selector: ProxyRemoteSelector,
     last_prev_hash: Option<SetNewPrevHash<'static>>,
     last_extended_jobs: Vec<NewExtendedMiningJob<'static>>,
+    /// Extended channel shared by the downstreams that open extended channels
+    extended_channel: Option<ExtendedChannel>,
+    /// When enabled the proxy set the targets of the standard channels of the header only
+    /// downstreams, the upstream targets are only used to decide which shares are forwarded
+    vardiff: Option<Vardiff>,
+    /// channel_id -> (group_id, nominal hash rate) of the standard channels of the header only
+    /// downstreams
+    channel_hash_rates: HashMap<u32, (u32, f32)>,
+    /// When enabled the standard channels of the header only downstreams are sub-channels of the
+    /// extended channel instead of being opened with the upstream
+    aggregate_header_only_channels: bool,
+    /// Sent in SetupConnection
+    min_supported_version: u16,
+    max_supported_version: u16,
 }
 
-use crate::{max_supported_version, min_supported_version};
 use core::convert::TryInto;
-use std::net::SocketAddr;
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+/// Delay before retrying to connect to an upstream, doubled after every failed attempt
+const MIN_RECONNECT_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_BACKOFF: Duration = Duration::from_secs(60);
+/// Failed connection attempts after which the downstreams move to another upstream
+const FAILOVER_AFTER_ATTEMPTS: u32 = 3;
 
 /// It assume that endpoint NEVER change flags and version!
 impl UpstreamMiningNode {
     pub fn new(
         id: u32,
+        host: String,
         address: SocketAddr,
         authority_public_key: [u8; 32],
         job_ids: Arc<Mutex<Id>>,
+        config: &Config,
     ) -> Self {
         let request_id_mapper = RequestIdMapper::new();
         let downstream_selector = ProxyRemoteSelector::new();
@@ -104,6 +145,7 @@ impl UpstreamMiningNode {
             id,
             job_ids,
             total_hash_rate: 0,
+            host,
             address,
             connection: None,
             sv2_connection: None,
@@ -113,59 +155,540 @@ impl UpstreamMiningNode {
             downstream_selector,
             last_prev_hash: None,
             last_extended_jobs: Vec::new(),
+            extended_channel: None,
+            vardiff: config.vardiff.clone().map(Vardiff::new),
+            channel_hash_rates: HashMap::new(),
+            aggregate_header_only_channels: config.aggregate_header_only_channels,
+            min_supported_version: config.min_supported_version,
+            max_supported_version: config.max_supported_version,
+        }
+    }
+
+    pub fn aggregates_header_only_channels(&self) -> bool {
+        self.aggregate_header_only_channels
+    }
+
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+
+    /// (channel id, hash rate) of the standard channels of the header only downstreams, opened
+    /// with the upstream or aggregated in the extended channel. The hash rate is the vardiff
+    /// estimate when vardiff is enabled, the nominal hash rate otherwise.
+    pub fn channel_hash_rates(&self) -> Vec<(u32, f32)> {
+        let mut hash_rates: Vec<(u32, f32)> = self
+            .channel_hash_rates
+            .iter()
+            .map(|(channel_id, (_, hash_rate))| (*channel_id, *hash_rate))
+            .collect();
+        if let Some(ExtendedChannel::Open(channel)) = &self.extended_channel {
+            hash_rates.extend(channel.standard_hash_rates());
+        }
+        for (channel_id, hash_rate) in hash_rates.iter_mut() {
+            if let Some(estimate) = self.vardiff.as_ref().and_then(|v| v.hash_rate(*channel_id)) {
+                *hash_rate = estimate;
+            }
+        }
+        hash_rates
+    }
+
+    /// Open an extended channel for the downstream. The first request open the extended channel
+    /// of the proxy with the upstream, the downstreams are answered when it is open.
+    pub async fn open_extended_channel(
+        self_mutex: Arc<Mutex<Self>>,
+        downstream: Arc<Mutex<DownstreamMiningNode>>,
+        mut request: OpenExtendedMiningChannel<'static>,
+    ) {
+        let request_id = request.get_request_id_as_u32();
+        let min_extranonce_size = request.min_extranonce_size;
+        let sends_to = self_mutex
+            .safe_lock(|self_| match &mut self_.extended_channel {
+                None => {
+                    let upstream_request_id = self_.request_id_mapper.on_open_channel(request_id);
+                    request.request_id = upstream_request_id.into();
+                    request.min_extranonce_size += PROXY_EXTRANONCE_LEN as u16;
+                    self_.extended_channel = Some(ExtendedChannel::Opening {
+                        request: request.clone(),
+                        pending: vec![(downstream.clone(), request_id, min_extranonce_size)],
+                        pending_standard: Vec::new(),
+                        previous: None,
+                    });
+                    vec![SendTo::Respond(Mining::OpenExtendedMiningChannel(request))]
+                }
+                Some(ExtendedChannel::Opening { pending, .. }) => {
+                    pending.push((downstream.clone(), request_id, min_extranonce_size));
+                    vec![]
+                }
+                Some(ExtendedChannel::Open(channel)) => channel.open_downstream_channel(
+                    downstream.clone(),
+                    request_id,
+                    min_extranonce_size,
+                ),
+            })
+            .unwrap();
+        Self::send_all(self_mutex, sends_to).await;
+    }
+
+    /// Open a standard channel of an header only downstream as a sub-channel of the extended
+    /// channel, the first request open the extended channel. The routing logic already mapped the
+    /// request id and counted the hash rate as for a channel opened with the upstream.
+    pub async fn open_aggregated_channel(
+        self_mutex: Arc<Mutex<Self>>,
+        downstream: Arc<Mutex<DownstreamMiningNode>>,
+        request: OpenStandardMiningChannel<'static>,
+    ) {
+        let sends_to = self_mutex
+            .safe_lock(|self_| self_.open_aggregated(downstream, request))
+            .unwrap();
+        Self::send_all(self_mutex, sends_to).await;
+    }
+
+    fn open_aggregated(
+        &mut self,
+        downstream: Arc<Mutex<DownstreamMiningNode>>,
+        request: OpenStandardMiningChannel<'static>,
+    ) -> Vec<SendTo<DownstreamMiningNode>> {
+        let upstream_request_id = request.get_request_id_as_u32();
+        let nominal_hash_rate = request.nominal_hash_rate;
+        self.downstream_selector.remove_request(upstream_request_id);
+        let request_id = self
+            .request_id_mapper
+            .remove(upstream_request_id)
+            .unwrap_or(upstream_request_id);
+        match &mut self.extended_channel {
+            None => {
+                let upstream_request_id = self.request_id_mapper.on_open_channel(request_id);
+                let open = OpenExtendedMiningChannel {
+                    request_id: upstream_request_id.into(),
+                    user_identity: request.user_identity,
+                    nominal_hash_rate,
+                    max_target: request.max_target,
+                    min_extranonce_size: PROXY_EXTRANONCE_LEN as u16,
+                };
+                self.extended_channel = Some(ExtendedChannel::Opening {
+                    request: open.clone(),
+                    pending: Vec::new(),
+                    pending_standard: vec![(downstream, request_id, nominal_hash_rate)],
+                    previous: None,
+                });
+                vec![SendTo::Respond(Mining::OpenExtendedMiningChannel(open))]
+            }
+            Some(ExtendedChannel::Opening {
+                pending_standard, ..
+            }) => {
+                pending_standard.push((downstream, request_id, nominal_hash_rate));
+                vec![]
+            }
+            Some(ExtendedChannel::Open(channel)) => match channel.open_standard_channel(
+                downstream,
+                request_id,
+                nominal_hash_rate,
+                self.vardiff.as_mut(),
+            ) {
+                Ok(mut messages) => {
+                    let update_channel = Mining::UpdateChannel(channel.update_channel());
+                    messages.push(SendTo::Respond(update_channel));
+                    messages
+                }
+                Err(error) => {
+                    self.add_hash_rate(-(nominal_hash_rate as i64));
+                    vec![error]
+                }
+            },
+        }
+    }
+
+    pub fn is_aggregated_channel(&self, channel_id: u32) -> bool {
+        match &self.extended_channel {
+            Some(ExtendedChannel::Open(channel)) => channel.is_standard_channel(channel_id),
+            _ => false,
+        }
+    }
+
+    /// Validate a share of a standard channel aggregated in the extended channel. Return None if
+    /// the channel is not aggregated, otherwise the share for the upstream or the response for the
+    /// downstream and, when vardiff retarget the channel, the SetTarget for the downstream.
+    #[allow(clippy::type_complexity)]
+    pub fn on_submit_shares_aggregated(
+        &mut self,
+        m: SubmitSharesStandard,
+    ) -> Option<(
+        Result<SubmitSharesExtended<'static>, Mining<'static>>,
+        Option<SetTarget<'static>>,
+    )> {
+        let channel = match &mut self.extended_channel {
+            Some(ExtendedChannel::Open(channel)) if channel.is_standard_channel(m.channel_id) => {
+                channel
+            }
+            _ => return None,
+        };
+        let channel_id = m.channel_id;
+        let response = channel.on_submit_shares_standard(m);
+        let set_target = match response {
+            Ok(_) | Err(Mining::SubmitSharesSuccess(_)) => {
+                self.on_standard_share_accepted(channel_id)
+            }
+            _ => None,
+        };
+        Some((response, set_target))
+    }
+
+    /// Forget a standard channel aggregated in the extended channel. Return the UpdateChannel with
+    /// the new hash rate of the extended channel or, if it was the last sub-channel, the
+    /// CloseChannel of the extended channel. None if the channel is not aggregated.
+    pub fn close_aggregated_channel(&mut self, channel_id: u32) -> Option<Mining<'static>> {
+        let (hash_rate, message) = match &mut self.extended_channel {
+            Some(ExtendedChannel::Open(channel)) => {
+                let hash_rate = channel.remove_standard_channel(channel_id)?;
+                let message = match channel.is_empty() {
+                    true => {
+                        let close = close_channel(channel.channel_id, "no-downstream-channels");
+                        Mining::CloseChannel(close)
+                    }
+                    false => Mining::UpdateChannel(channel.update_channel()),
+                };
+                (hash_rate, message)
+            }
+            _ => return None,
+        };
+        if let Mining::CloseChannel(_) = message {
+            self.extended_channel = None;
+        }
+        self.add_hash_rate(-(hash_rate as i64));
+        if let Some(vardiff) = &mut self.vardiff {
+            vardiff.remove_channel(channel_id);
+        }
+        Some(message)
+    }
+
+    /// Downstream that opened a standard channel
+    fn downstream_from_channel_id(
+        &self,
+        channel_id: u32,
+    ) -> Option<Arc<Mutex<DownstreamMiningNode>>> {
+        match &self.extended_channel {
+            Some(ExtendedChannel::Open(channel)) if channel.is_standard_channel(channel_id) => {
+                channel.downstream(channel_id)
+            }
+            _ => self
+                .downstream_selector
+                .downstream_from_channel_id(channel_id),
+        }
+    }
+
+    /// Translate a share of a downstream extended channel in a share of the upstream extended
+    /// channel
+    pub fn on_submit_shares_extended(
+        &mut self,
+        m: SubmitSharesExtended,
+    ) -> Result<SubmitSharesExtended<'static>, SubmitSharesError<'static>> {
+        match &mut self.extended_channel {
+            Some(ExtendedChannel::Open(channel)) => channel.on_submit_shares_extended(m),
+            _ => Err(share_error(&m, "invalid-channel-id")),
+        }
+    }
+
+    fn open_extended_channel_with_id(
+        &mut self,
+        channel_id: u32,
+    ) -> Option<&mut OpenExtendedChannel> {
+        match &mut self.extended_channel {
+            Some(ExtendedChannel::Open(channel)) if channel.channel_id == channel_id => {
+                Some(channel)
+            }
+            _ => None,
+        }
+    }
+
+    /// Must be called for every accepted share of a standard channel, if vardiff retarget the
+    /// channel the new target is used to validate the next shares and the SetTarget for the
+    /// downstream is returned
+    pub fn on_standard_share_accepted(&mut self, channel_id: u32) -> Option<SetTarget<'static>> {
+        let set_target = self
+            .vardiff
+            .as_mut()?
+            .on_share_accepted(channel_id, Instant::now())?;
+        self.set_downstream_target(&set_target);
+        Some(set_target)
+    }
+
+    /// Handle an UpdateChannel of a downstream channel opened by the proxy. Return the SetTarget
+    /// for the downstream and, for standard channels, the UpdateChannel with the new hash rate of
+    /// the group for the upstream.
+    pub fn on_update_channel(
+        &mut self,
+        m: &UpdateChannel,
+    ) -> Result<(SetTarget<'static>, Option<UpdateChannel<'static>>), &'static str> {
+        // No share can meet it
+        if m.maximum_target.inner_as_ref().iter().all(|b| *b == 0) {
+            return Err("max-target-out-of-range");
+        }
+        let maximum_target: Target = m.maximum_target.clone().into();
+        if let Some(ExtendedChannel::Open(channel)) = &mut self.extended_channel {
+            if let Some(old_hash_rate) =
+                channel.set_standard_hash_rate(m.channel_id, m.nominal_hash_rate)
+            {
+                let vardiff_set_target = self.vardiff.as_mut().and_then(|vardiff| {
+                    let maximum_target = m.maximum_target.clone().into_static();
+                    vardiff.update_channel(
+                        m.channel_id,
+                        m.nominal_hash_rate,
+                        maximum_target,
+                        Instant::now(),
+                    )
+                });
+                let set_target = match vardiff_set_target {
+                    Some(set_target) => set_target,
+                    None => {
+                        let target = channel
+                            .standard_downstream_target(m.channel_id)
+                            .ok_or("invalid-channel-id")?;
+                        SetTarget {
+                            channel_id: m.channel_id,
+                            maximum_target: target.min(maximum_target).into(),
+                        }
+                    }
+                };
+                channel.set_standard_downstream_target(&set_target);
+                let update_channel = channel.update_channel();
+                self.add_hash_rate(m.nominal_hash_rate as i64 - old_hash_rate as i64);
+                return Ok((set_target, Some(update_channel)));
+            }
+        }
+        if let Some(ExtendedChannel::Open(channel)) = &self.extended_channel {
+            if let Some(target) = channel.downstream_target(m.channel_id) {
+                // The target is shared with the other downstreams and can not be lowered
+                if maximum_target < target.clone().into() {
+                    return Err("max-target-out-of-range");
+                }
+                let set_target = SetTarget {
+                    channel_id: m.channel_id,
+                    maximum_target: target,
+                };
+                return Ok((set_target, None));
+            }
+        }
+        let (group_id, old_hash_rate) = match self.channel_hash_rates.get_mut(&m.channel_id) {
+            Some((group_id, hash_rate)) => {
+                let old_hash_rate = *hash_rate;
+                *hash_rate = m.nominal_hash_rate;
+                (*group_id, old_hash_rate)
+            }
+            None => return Err("invalid-channel-id"),
+        };
+        self.add_hash_rate(m.nominal_hash_rate as i64 - old_hash_rate as i64);
+        let vardiff_set_target = self.vardiff.as_mut().and_then(|vardiff| {
+            let maximum_target = m.maximum_target.clone().into_static();
+            vardiff.update_channel(
+                m.channel_id,
+                m.nominal_hash_rate,
+                maximum_target,
+                Instant::now(),
+            )
+        });
+        let set_target = match vardiff_set_target {
+            Some(set_target) => set_target,
+            None => {
+                let target = match self.channel_id_to_job_dispatcher.get(&group_id) {
+                    Some(JobDispatcher::Group(dispatcher)) => {
+                        dispatcher.downstream_target(m.channel_id)
+                    }
+                    _ => None,
+                }
+                .ok_or("invalid-channel-id")?;
+                SetTarget {
+                    channel_id: m.channel_id,
+                    maximum_target: target.min(maximum_target).into(),
+                }
+            }
+        };
+        self.set_downstream_target(&set_target);
+        Ok((set_target, Some(self.update_group(group_id))))
+    }
+
+    /// UpdateChannel with the hash rate of all the standard channels of the group
+    fn update_group(&self, group_id: u32) -> UpdateChannel<'static> {
+        UpdateChannel {
+            channel_id: group_id,
+            nominal_hash_rate: self
+                .channel_hash_rates
+                .values()
+                .filter(|(channel_group_id, _)| *channel_group_id == group_id)
+                .map(|(_, hash_rate)| hash_rate)
+                .sum(),
+            // The channels of the group are retargeted by the proxy
+            maximum_target: [255_u8; 32].into(),
+        }
+    }
+
+    /// Forget a standard channel of a downstream and return the UpdateChannel with the new hash
+    /// rate of its group, if the channel was opened by an header only downstream
+    pub fn close_standard_channel(&mut self, channel_id: u32) -> Option<UpdateChannel<'static>> {
+        let (group_id, hash_rate) = self.channel_hash_rates.remove(&channel_id)?;
+        self.add_hash_rate(-(hash_rate as i64));
+        self.downstream_selector
+            .remove_channel(group_id, channel_id);
+        if let Some(vardiff) = &mut self.vardiff {
+            vardiff.remove_channel(channel_id);
+        }
+        if let Some(JobDispatcher::Group(dispatcher)) =
+            self.channel_id_to_job_dispatcher.get_mut(&group_id)
+        {
+            dispatcher.remove_channel(channel_id);
+        }
+        Some(self.update_group(group_id))
+    }
+
+    /// Forget a sub-channel of the extended channel, when the last one is closed the extended
+    /// channel is closed too and the CloseChannel for the upstream is returned
+    pub fn close_extended_downstream_channel(
+        &mut self,
+        channel_id: u32,
+    ) -> Option<CloseChannel<'static>> {
+        let channel = match &mut self.extended_channel {
+            Some(ExtendedChannel::Open(channel)) => channel,
+            _ => return None,
+        };
+        if !channel.remove_downstream_channel(channel_id) || !channel.is_empty() {
+            return None;
+        }
+        let upstream_channel_id = channel.channel_id;
+        self.extended_channel = None;
+        Some(close_channel(upstream_channel_id, "no-downstream-channels"))
+    }
+
+    /// Close every channel of a downstream that disconnected and tell the upstream
+    pub async fn remove_downstream(
+        self_mutex: Arc<Mutex<Self>>,
+        downstream: Arc<Mutex<DownstreamMiningNode>>,
+    ) {
+        let messages = self_mutex
+            .safe_lock(|self_| self_.close_downstream_channels(&downstream))
+            .unwrap();
+        Self::send_all(self_mutex, messages).await;
+    }
+
+    fn close_downstream_channels(
+        &mut self,
+        downstream: &Arc<Mutex<DownstreamMiningNode>>,
+    ) -> Vec<SendTo<DownstreamMiningNode>> {
+        let mut messages = Vec::new();
+        let mut update_groups: HashMap<u32, UpdateChannel<'static>> = HashMap::new();
+        for channel_id in self.downstream_selector.remove_downstream(downstream) {
+            let close = close_channel(channel_id, "downstream-disconnected");
+            messages.push(SendTo::Respond(Mining::CloseChannel(close)));
+            if let Some(update_group) = self.close_standard_channel(channel_id) {
+                update_groups.insert(update_group.channel_id, update_group);
+            }
+        }
+        messages.extend(
+            update_groups
+                .into_values()
+                .map(|update_group| SendTo::Respond(Mining::UpdateChannel(update_group))),
+        );
+        let aggregated = match &self.extended_channel {
+            Some(ExtendedChannel::Open(channel)) => channel.standard_channels_of(downstream),
+            _ => Vec::new(),
+        };
+        // Only the message sent with the last channel is relevant for the upstream
+        let mut last_message = None;
+        for channel_id in aggregated {
+            if let Some(message) = self.close_aggregated_channel(channel_id) {
+                last_message = Some(message);
+            }
+        }
+        if let Some(message) = last_message {
+            messages.push(SendTo::Respond(message));
+        }
+        let empty = match &mut self.extended_channel {
+            Some(extended_channel) => extended_channel.remove_downstream(downstream),
+            None => false,
+        };
+        if let (true, Some(ExtendedChannel::Open(channel))) = (empty, &self.extended_channel) {
+            let close = close_channel(channel.channel_id, "no-downstream-channels");
+            messages.push(SendTo::Respond(Mining::CloseChannel(close)));
+            self.extended_channel = None;
+        }
+        messages
+    }
+
+    fn set_downstream_target(&mut self, set_target: &SetTarget) {
+        for dispatcher in self.channel_id_to_job_dispatcher.values_mut() {
+            if let JobDispatcher::Group(dispatcher) = dispatcher {
+                let target = set_target.maximum_target.clone().into();
+                dispatcher.set_downstream_target(set_target.channel_id, target);
+            }
+        }
+        if let Some(ExtendedChannel::Open(channel)) = &mut self.extended_channel {
+            channel.set_standard_downstream_target(set_target);
+        }
+    }
+
+    /// Retarget the standard channels that do not submit enough shares
+    async fn retarget_periodically(self_mutex: Arc<Mutex<Self>>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let messages = self_mutex
+                .safe_lock(|self_| {
+                    let set_targets = match &mut self_.vardiff {
+                        Some(vardiff) => vardiff.on_tick(Instant::now()),
+                        None => Vec::new(),
+                    };
+                    let mut messages = Vec::with_capacity(set_targets.len());
+                    for set_target in set_targets {
+                        self_.set_downstream_target(&set_target);
+                        if let Some(downstream) =
+                            self_.downstream_from_channel_id(set_target.channel_id)
+                        {
+                            let message = Mining::SetTarget(set_target);
+                            messages.push(SendTo::RelayNewMessage(downstream, message));
+                        }
+                    }
+                    messages
+                })
+                .unwrap();
+            Self::send_all(self_mutex.clone(), messages).await;
         }
     }
 
-    /// Try send a message to the upstream node.
-    /// If the node is connected and there are no error return Ok(())
-    /// If the node is connected and there is an error the message is not sent and an error is
-    ///     returned and the upstream is marked as not connected.
-    /// If the node is not connected it try to connect and send the message and everything is ok
-    ///     the upstream is marked as connected and Ok(()) is returned if not an error is returned.
+    /// Send a message to the upstream node. If the node is not connected the message is dropped
+    /// and an error is returned: the connection is restored by the task that relay the incoming
+    /// messages as soon as it see that the connection is lost.
     pub async fn send(
         self_mutex: Arc<Mutex<Self>>,
         sv2_frame: StdFrame,
     ) -> Result<(), SendError<EitherFrame>> {
-        let (has_sv2_connetcion, mut connection) = self_mutex
-            .safe_lock(|self_| (self_.sv2_connection.is_some(), self_.connection.clone()))
+        let connection = self_mutex
+            .safe_lock(|self_| self_.connection.clone())
             .unwrap();
-        //let mut self_ = self_mutex.lock().await;
-
-        match (connection.as_mut(), has_sv2_connetcion) {
-            (Some(connection), true) => match connection.send(sv2_frame).await {
-                Ok(_) => Ok(()),
-                Err(_e) => {
-                    Self::connect(self_mutex.clone()).await.unwrap();
-                    // It assume that enpoint NEVER change flags and version!
-                    match Self::setup_connection(self_mutex).await {
-                        Ok(()) => Ok(()),
-                        Err(()) => panic!(),
-                    }
+        match connection {
+            Some(mut connection) => connection.send(sv2_frame).await,
+            None => Err(SendError(sv2_frame.into())),
+        }
+    }
+
+    /// Send the messages that are not a response to an incoming message: RelayNewMessage are sent
+    /// to the downstreams and Respond to the upstream
+    async fn send_all(self_mutex: Arc<Mutex<Self>>, sends_to: Vec<SendTo<DownstreamMiningNode>>) {
+        for send_to in sends_to {
+            match send_to {
+                SendTo::RelayNewMessage(downstream_mutex, message) => {
+                    let message = MiningDeviceMessages::Mining(message);
+                    let frame: DownstreamFrame = message.try_into().unwrap();
+                    // A downstream that is gone do not need the message
+                    let _ = DownstreamMiningNode::send(downstream_mutex, frame).await;
                 }
-            },
-            // It assume that no downstream try to send messages before that the upstream is
-            // initialized. This assumption is enforced by the fact that
-            // UpstreamMiningNode::pair only pair downstream noder with already
-            // initialized upstream nodes!
-            (Some(connection), false) => match connection.send(sv2_frame).await {
-                Ok(_) => Ok(()),
-                Err(e) => Err(e),
-            },
-            (None, _) => {
-                Self::connect(self_mutex.clone()).await.unwrap();
-                let mut connection = self_mutex
-                    .safe_lock(|self_| self_.connection.clone())
-                    .unwrap();
-                match connection.as_mut().unwrap().send(sv2_frame).await {
-                    Ok(_) => match Self::setup_connection(self_mutex).await {
-                        Ok(()) => Ok(()),
-                        Err(()) => panic!(),
-                    },
-                    Err(e) => {
-                        //Self::connect(self_mutex.clone()).await.unwrap();
-                        Err(e)
+                SendTo::Respond(message) => {
+                    let message = PoolMessages::Mining(message);
+                    let frame: StdFrame = message.try_into().unwrap();
+                    if UpstreamMiningNode::send(self_mutex.clone(), frame)
+                        .await
+                        .is_err()
+                    {
+                        warn!("not connected, message dropped");
                     }
                 }
+                _ => unreachable!(),
             }
         }
     }
@@ -177,12 +700,9 @@ impl UpstreamMiningNode {
         match connection.as_mut() {
             Some(connection) => match connection.receiver.recv().await {
                 Ok(m) => Ok(m.try_into()?),
-                Err(_) => {
-                    Self::connect(self_mutex).await?;
-                    Err(())
-                }
+                Err(_) => Err(()),
             },
-            None => todo!("177"),
+            None => Err(()),
         }
     }
 
@@ -193,13 +713,23 @@ impl UpstreamMiningNode {
         match has_connection {
             true => Ok(()),
             false => {
-                let (address, authority_public_key) = self_mutex
-                    .safe_lock(|self_| (self_.address, self_.authority_public_key))
+                let address = Self::resolve(self_mutex.clone()).await;
+                let authority_public_key = self_mutex
+                    .safe_lock(|self_| self_.authority_public_key)
                     .unwrap();
                 let socket = TcpStream::connect(address).await.map_err(|_| ())?;
                 let initiator = Initiator::from_raw_k(authority_public_key).unwrap();
+                // The handshake panic if the upstream close the connection, it is done in its own
+                // task so that a failed handshake is just a failed connection attempt
                 let (receiver, sender) =
-                    Connection::new(socket, HandshakeRole::Initiator(initiator)).await;
+                    task::spawn(
+                        async move {
+                            Connection::new(socket, HandshakeRole::Initiator(initiator)).await
+                        }
+                        .in_current_span(),
+                    )
+                    .await
+                    .map_err(|_| ())?;
                 let connection = UpstreamMiningConnection { receiver, sender };
                 self_mutex
                     .safe_lock(|self_| {
@@ -211,40 +741,29 @@ impl UpstreamMiningNode {
         }
     }
 
-    #[async_recursion]
-    async fn setup_connection(self_mutex: Arc<Mutex<Self>>) -> Result<(), ()> {
-        let sv2_connection = self_mutex.safe_lock(|self_| self_.sv2_connection).unwrap();
-
-        match sv2_connection {
-            None => Ok(()),
-            Some(sv2_connection) => {
-                let flags = sv2_connection.setup_connection_flags;
-                let version = sv2_connection.version;
-                let frame = self_mutex
-                    .safe_lock(|self_| self_.new_setup_connection_frame(flags, version, version))
-                    .unwrap();
-                Self::send(self_mutex.clone(), frame)
-                    .await
-                    .map_err(|_| ())?;
-
-                let cloned = self_mutex.clone();
-                let mut response = task::spawn(async { Self::receive(cloned).await })
-                    .await
-                    .unwrap()
+    /// Resolve the host and remember the address, the last address is returned when the host can
+    /// not be resolved
+    async fn resolve(self_mutex: Arc<Mutex<Self>>) -> SocketAddr {
+        let (host, address) = self_mutex
+            .safe_lock(|self_| (self_.host.clone(), self_.address))
+            .unwrap();
+        let resolved = lookup_host((host.as_str(), address.port()))
+            .await
+            .map(|mut addresses| addresses.next());
+        match resolved {
+            Ok(Some(resolved)) => {
+                self_mutex
+                    .safe_lock(|self_| self_.address = resolved)
                     .unwrap();
-
-                let message_type = response.get_header().unwrap().msg_type();
-                let payload = response.payload();
-                match (message_type, payload).try_into() {
-                    Ok(CommonMessages::SetupConnectionSuccess(_)) => {
-                        let receiver = self_mutex
-                            .safe_lock(|self_| self_.connection.clone().unwrap().receiver)
-                            .unwrap();
-                        Self::relay_incoming_messages(self_mutex, receiver);
-                        Ok(())
-                    }
-                    _ => panic!(),
-                }
+                resolved
+            }
+            Ok(None) => {
+                warn!(host = %host, "host do not resolve to any address, using {}", address);
+                address
+            }
+            Err(e) => {
+                warn!(host = %host, "can not resolve host, using {}: {}", address, e);
+                address
             }
         }
     }
@@ -254,47 +773,155 @@ impl UpstreamMiningNode {
         //_downstreams: HashMap<u32, Downstream>,
         receiver: Receiver<EitherFrame>,
     ) {
-        task::spawn(async move {
-            loop {
-                let message = receiver.recv().await.unwrap();
-                let incoming: StdFrame = message.try_into().unwrap();
-                Self::next(self_.clone(), incoming).await;
+        task::spawn(
+            async move {
+                while let Ok(message) = receiver.recv().await {
+                    let incoming: StdFrame = message.try_into().unwrap();
+                    Self::next(self_.clone(), incoming).await;
+                }
+                Self::on_disconnect(self_).await;
             }
-        });
+            .in_current_span(),
+        );
+    }
+
+    /// The connection with the upstream has been lost (or closed on Reconnect). Only the channels
+    /// that belong to the proxy are opened again transparently: the extended sub-channels and the
+    /// aggregated standard channels keep their ids as they are sub-channels of the extended
+    /// channel. The ids of the group and standard channels relayed as they are were assigned by
+    /// the upstream, they are lost with the connection so the downstreams that use them are asked
+    /// to reconnect, then the proxy connect again to the upstream.
+    async fn on_disconnect(self_mutex: Arc<Mutex<Self>>) {
+        let (address, downstreams) = self_mutex
+            .safe_lock(|self_| (self_.address, self_.on_connection_lost()))
+            .unwrap();
+        warn!(%address, downstreams = downstreams.len(), "connection lost");
+        let messages = downstreams.into_iter().map(reconnect).collect();
+        Self::send_all(self_mutex.clone(), messages).await;
+        Self::reconnect(self_mutex).await;
+    }
+
+    /// Connect to the upstream retrying with exponential backoff. After FAILOVER_AFTER_ATTEMPTS
+    /// failed attempts the downstreams that use the extended channel are asked to reconnect so
+    /// that they are paired with another upstream. The upstream is retried forever so that new
+    /// downstreams can be paired with it when it is back.
+    async fn reconnect(self_mutex: Arc<Mutex<Self>>) {
+        let mut backoff = MIN_RECONNECT_BACKOFF;
+        let mut attempts = 0;
+        while Self::setup_flag_and_version(self_mutex.clone(), None)
+            .await
+            .is_err()
+        {
+            let (address, failed_over) = self_mutex
+                .safe_lock(|self_| {
+                    self_.connection = None;
+                    let failed_over = match attempts + 1 == FAILOVER_AFTER_ATTEMPTS {
+                        true => self_.extended_channel.take().map(|c| c.into_downstreams()),
+                        false => None,
+                    };
+                    (self_.address, failed_over)
+                })
+                .unwrap();
+            attempts += 1;
+            if let Some(downstreams) = failed_over {
+                warn!(
+                    %address,
+                    downstreams = downstreams.len(),
+                    "unreachable, downstreams asked to reconnect"
+                );
+                let messages = downstreams.into_iter().map(reconnect).collect();
+                Self::send_all(self_mutex.clone(), messages).await;
+            }
+            warn!(
+                %address,
+                retry_in_sec = backoff.as_secs(),
+                "connection failed"
+            );
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_RECONNECT_BACKOFF);
+        }
+        let (address, messages) = self_mutex
+            .safe_lock(|self_| (self_.address, self_.reopen_extended_channel()))
+            .unwrap();
+        info!(%address, "connected");
+        Self::send_all(self_mutex, messages).await;
+    }
+
+    /// Forget everything that is bound to the lost connection and return the downstreams that
+    /// use the channels opened by the upstream. The extended channel is kept so that it can be
+    /// opened again with the next connection.
+    fn on_connection_lost(&mut self) -> Vec<Arc<Mutex<DownstreamMiningNode>>> {
+        self.connection = None;
+        self.sv2_connection = None;
+        self.request_id_mapper = RequestIdMapper::new();
+        self.channel_id_to_job_dispatcher.clear();
+        self.last_prev_hash = None;
+        self.last_extended_jobs.clear();
+        self.channel_hash_rates.clear();
+        self.total_hash_rate = 0;
+        if let Some(vardiff) = &mut self.vardiff {
+            *vardiff = Vardiff::new(vardiff.config().clone());
+        }
+        self.extended_channel = match self.extended_channel.take() {
+            Some(ExtendedChannel::Open(channel)) => Some(ExtendedChannel::Opening {
+                request: channel.request.clone(),
+                pending: Vec::new(),
+                pending_standard: Vec::new(),
+                previous: Some(channel),
+            }),
+            extended_channel => extended_channel,
+        };
+        self.downstream_selector.remove_downstreams()
+    }
+
+    /// If there is an extended channel to open return the OpenExtendedMiningChannel to send
+    fn reopen_extended_channel(&mut self) -> Vec<SendTo<DownstreamMiningNode>> {
+        match &mut self.extended_channel {
+            Some(ExtendedChannel::Opening { request, .. }) => {
+                let request_id = self
+                    .request_id_mapper
+                    .on_open_channel(request.get_request_id_as_u32());
+                request.request_id = request_id.into();
+                let message = Mining::OpenExtendedMiningChannel(request.clone());
+                vec![SendTo::Respond(message)]
+            }
+            _ => Vec::new(),
+        }
     }
 
     pub async fn next(self_mutex: Arc<Mutex<Self>>, mut incoming: StdFrame) {
+        let received = Instant::now();
         let message_type = incoming.get_header().unwrap().msg_type();
         let payload = incoming.payload();
 
         let routing_logic = crate::get_routing_logic();
 
-        let next_message_to_send = UpstreamMiningNode::handle_message_mining(
-            self_mutex.clone(),
-            message_type,
-            payload,
-            routing_logic,
-        );
+        let next_message_to_send = info_span!("message", msg_type = message_type).in_scope(|| {
+            UpstreamMiningNode::handle_message_mining(
+                self_mutex.clone(),
+                message_type,
+                payload,
+                routing_logic,
+            )
+        });
         match next_message_to_send {
             Ok(SendTo::RelaySameMessage(downstream)) => {
                 let sv2_frame: codec_sv2::Sv2Frame<MiningDeviceMessages, buffer_sv2::Slice> =
                     incoming.map(|payload| payload.try_into().unwrap());
 
-                DownstreamMiningNode::send(downstream.clone(), sv2_frame)
-                    .await
-                    .unwrap();
+                let _ = DownstreamMiningNode::send(downstream.clone(), sv2_frame).await;
             }
             Ok(SendTo::RelayNewMessage(downstream_mutex, message)) => {
                 let message = MiningDeviceMessages::Mining(message);
                 let frame: DownstreamFrame = message.try_into().unwrap();
-                DownstreamMiningNode::send(downstream_mutex, frame)
-                    .await
-                    .unwrap();
+                let _ = DownstreamMiningNode::send(downstream_mutex, frame).await;
             }
             Ok(SendTo::Respond(message)) => {
                 let message = PoolMessages::Mining(message);
                 let frame: StdFrame = message.try_into().unwrap();
-                UpstreamMiningNode::send(self_mutex, frame).await.unwrap();
+                if UpstreamMiningNode::send(self_mutex, frame).await.is_err() {
+                    warn!("not connected, message dropped");
+                }
             }
             Ok(SendTo::Multiple(sends_to)) => {
                 for send_to in sends_to {
@@ -302,25 +929,24 @@ impl UpstreamMiningNode {
                         SendTo::RelayNewMessage(downstream_mutex, message) => {
                             let message = MiningDeviceMessages::Mining(message);
                             let frame: DownstreamFrame = message.try_into().unwrap();
-                            DownstreamMiningNode::send(downstream_mutex, frame)
-                                .await
-                                .unwrap();
+                            let _ = DownstreamMiningNode::send(downstream_mutex, frame).await;
                         }
                         SendTo::RelaySameMessage(downstream_mutex) => {
                             let frame: codec_sv2::Sv2Frame<
                                 MiningDeviceMessages,
                                 buffer_sv2::Slice,
                             > = incoming.clone().map(|payload| payload.try_into().unwrap());
-                            DownstreamMiningNode::send(downstream_mutex, frame)
-                                .await
-                                .unwrap();
+                            let _ = DownstreamMiningNode::send(downstream_mutex, frame).await;
                         }
                         SendTo::Respond(message) => {
                             let message = PoolMessages::Mining(message);
                             let frame: StdFrame = message.try_into().unwrap();
-                            UpstreamMiningNode::send(self_mutex.clone(), frame)
+                            if UpstreamMiningNode::send(self_mutex.clone(), frame)
                                 .await
-                                .unwrap();
+                                .is_err()
+                            {
+                                warn!("not connected, message dropped");
+                            }
                         }
                         SendTo::None(_) => (),
                         SendTo::Multiple(_) => panic!("Nested SendTo::Multiple not supported"),
@@ -329,8 +955,13 @@ impl UpstreamMiningNode {
             }
             Ok(SendTo::None(_)) => (),
             Err(Error::NoDownstreamsConnected) => (),
-            Err(Error::UnexpectedMessage) => todo!(),
-            Err(_) => todo!(),
+            Err(Error::UnexpectedMessage) => warn!(msg_type = message_type, "unexpected message"),
+            Err(e) => warn!(msg_type = message_type, "invalid message: {}", e),
+        }
+        if message_type == const_sv2::MESSAGE_TYPE_MINING_SET_NEW_PREV_HASH {
+            crate::metrics()
+                .safe_lock(|m| m.on_prev_hash_relayed(received.elapsed()))
+                .unwrap();
         }
     }
 
@@ -340,10 +971,13 @@ impl UpstreamMiningNode {
         flags: Option<u32>,
     ) -> Result<(), ()> {
         let flags = flags.unwrap_or(0b0111_0000_0000_0000_0000_0000_0000_0000);
-        let min_version = min_supported_version();
-        let max_version = max_supported_version();
+        Self::connect(self_mutex.clone()).await?;
         let frame = self_mutex
-            .safe_lock(|self_| self_.new_setup_connection_frame(flags, min_version, max_version))
+            .safe_lock(|self_| {
+                let (min_version, max_version) =
+                    (self_.min_supported_version, self_.max_supported_version);
+                self_.new_setup_connection_frame(flags, min_version, max_version)
+            })
             .unwrap();
         Self::send(self_mutex.clone(), frame)
             .await
@@ -352,10 +986,9 @@ impl UpstreamMiningNode {
         let cloned = self_mutex.clone();
         let mut response = task::spawn(async { Self::receive(cloned).await })
             .await
-            .unwrap()
-            .unwrap();
+            .map_err(|_| ())??;
 
-        let message_type = response.get_header().unwrap().msg_type();
+        let message_type = response.get_header().ok_or(())?.msg_type();
         let payload = response.payload();
         match (message_type, payload).try_into() {
             Ok(CommonMessages::SetupConnectionSuccess(m)) => {
@@ -383,8 +1016,20 @@ impl UpstreamMiningNode {
                     Err(())
                 }
             }
-            Ok(_) => todo!("356"),
-            Err(_) => todo!("357"),
+            Ok(_) => {
+                warn!(
+                    msg_type = message_type,
+                    "unexpected response to SetupConnection"
+                );
+                Err(())
+            }
+            Err(e) => {
+                warn!(
+                    msg_type = message_type,
+                    "invalid response to SetupConnection: {}", e
+                );
+                Err(())
+            }
         }
     }
 
@@ -454,7 +1099,7 @@ impl
     > for UpstreamMiningNode
 {
     fn get_channel_type(&self) -> SupportedChannelTypes {
-        SupportedChannelTypes::Group
+        SupportedChannelTypes::GroupAndExtended
     }
 
     fn is_work_selection_enabled(&self) -> bool {
@@ -472,6 +1117,16 @@ impl
             .safe_lock(|remote| remote.is_header_only())
             .unwrap();
         let up_is_header_only = self.is_header_only();
+        if down_is_header_only {
+            let hash_rate = remote
+                .as_ref()
+                .unwrap()
+                .safe_lock(|r| r.take_requested_hash_rate(m.get_request_id_as_u32()))
+                .unwrap()
+                .unwrap_or(0.0);
+            self.channel_hash_rates
+                .insert(m.channel_id, (m.group_channel_id, hash_rate));
+        }
         match (down_is_header_only, up_is_header_only) {
             (true, true) => {
                 let channel = DownstreamChannel::Standard(StandardChannel {
@@ -490,7 +1145,7 @@ impl
                 let channel = DownstreamChannel::Standard(StandardChannel {
                     channel_id: m.channel_id,
                     group_id: m.group_channel_id,
-                    target: m.target.into(),
+                    target: m.target.clone().into(),
                     extranonce: m.extranonce_prefix.into(),
                 });
                 if self
@@ -502,6 +1157,16 @@ impl
                     self.channel_id_to_job_dispatcher
                         .insert(m.group_channel_id, JobDispatcher::Group(dispatcher));
                 }
+                if let Some(JobDispatcher::Group(dispatcher)) = self
+                    .channel_id_to_job_dispatcher
+                    .get_mut(&m.group_channel_id)
+                {
+                    dispatcher.add_channel(m.channel_id, m.target.clone().into());
+                }
+                if let Some(vardiff) = &mut self.vardiff {
+                    let target = m.target.clone().into_static();
+                    vardiff.add_channel_with_target(m.channel_id, target, Instant::now());
+                }
                 remote
                     .as_ref()
                     .unwrap()
@@ -558,43 +1223,260 @@ impl
 
     fn handle_open_extended_mining_channel_success(
         &mut self,
-        _m: OpenExtendedMiningChannelSuccess,
+        m: OpenExtendedMiningChannelSuccess,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("450")
+        let (request, pending, pending_standard, previous) = match self.extended_channel.take() {
+            Some(ExtendedChannel::Opening {
+                request,
+                pending,
+                pending_standard,
+                previous,
+            }) if request.get_request_id_as_u32() == m.request_id => {
+                (request, pending, pending_standard, previous)
+            }
+            extended_channel => {
+                self.extended_channel = extended_channel;
+                return Err(Error::UnknownRequestId(m.request_id));
+            }
+        };
+        self.request_id_mapper.remove(m.request_id);
+        match OpenExtendedChannel::new(&m, request, self.job_ids.clone()) {
+            Some(mut channel) => {
+                let mut messages = match previous {
+                    Some(previous) => {
+                        let messages = channel.restore(previous);
+                        // The hash rate is forgotten when the connection is lost
+                        self.add_hash_rate(channel.standard_hash_rate() as i64);
+                        messages
+                    }
+                    None => Vec::new(),
+                };
+                for (downstream, request_id, min_extranonce_size) in pending {
+                    messages.append(&mut channel.open_downstream_channel(
+                        downstream,
+                        request_id,
+                        min_extranonce_size,
+                    ));
+                }
+                for (downstream, request_id, nominal_hash_rate) in pending_standard {
+                    match channel.open_standard_channel(
+                        downstream,
+                        request_id,
+                        nominal_hash_rate,
+                        self.vardiff.as_mut(),
+                    ) {
+                        Ok(mut sends_to) => messages.append(&mut sends_to),
+                        Err(error) => {
+                            self.add_hash_rate(-(nominal_hash_rate as i64));
+                            messages.push(error);
+                        }
+                    }
+                }
+                if channel.standard_hash_rate() > 0.0 {
+                    let update_channel = Mining::UpdateChannel(channel.update_channel());
+                    messages.push(SendTo::Respond(update_channel));
+                }
+                self.extended_channel = Some(ExtendedChannel::Open(channel));
+                Ok(SendTo::Multiple(messages))
+            }
+            None => {
+                warn!(
+                    channel_id = m.channel_id,
+                    "extended channel has an extranonce too small to be shared"
+                );
+                let mut messages: Vec<SendTo<DownstreamMiningNode>> = pending
+                    .into_iter()
+                    .map(|(downstream, request_id, _)| {
+                        open_channel_error(downstream, request_id, "min-extranonce-size-too-large")
+                    })
+                    .collect();
+                for (downstream, request_id, nominal_hash_rate) in pending_standard {
+                    self.add_hash_rate(-(nominal_hash_rate as i64));
+                    let error_code = "unsupported-extranonce-size";
+                    messages.push(open_channel_error(downstream, request_id, error_code));
+                }
+                let previous = previous.into_iter().flat_map(|c| c.into_downstreams());
+                messages.extend(previous.map(reconnect));
+                Ok(SendTo::Multiple(messages))
+            }
+        }
     }
 
     fn handle_open_mining_channel_error(
         &mut self,
-        _m: OpenMiningChannelError,
+        m: OpenMiningChannelError,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("460")
+        let error_code = String::from_utf8_lossy(m.error_code.inner_as_ref()).to_string();
+        match self.extended_channel.take() {
+            Some(ExtendedChannel::Opening {
+                request,
+                pending,
+                pending_standard,
+                previous,
+            }) if request.get_request_id_as_u32() == m.request_id => {
+                self.request_id_mapper.remove(m.request_id);
+                let mut messages: Vec<SendTo<DownstreamMiningNode>> = pending
+                    .into_iter()
+                    .map(|(downstream, request_id, _)| {
+                        open_channel_error(downstream, request_id, &error_code)
+                    })
+                    .collect();
+                for (downstream, request_id, nominal_hash_rate) in pending_standard {
+                    self.add_hash_rate(-(nominal_hash_rate as i64));
+                    messages.push(open_channel_error(downstream, request_id, &error_code));
+                }
+                let previous = previous.into_iter().flat_map(|c| c.into_downstreams());
+                messages.extend(previous.map(reconnect));
+                Ok(SendTo::Multiple(messages))
+            }
+            extended_channel => {
+                self.extended_channel = extended_channel;
+                // A standard channel request relayed to the upstream, the error go to the
+                // downstream that sent it with its original request id as the success do
+                let upstream_request_id = m.request_id;
+                let downstream = self.downstream_selector.remove_request(upstream_request_id);
+                let request_id = self.request_id_mapper.remove(upstream_request_id);
+                let (downstream, request_id) = match (downstream, request_id) {
+                    (Some(downstream), Some(request_id)) => (downstream, request_id),
+                    _ => return Err(Error::RequestIdNotMapped(upstream_request_id)),
+                };
+                let hash_rate = downstream
+                    .safe_lock(|d| d.take_requested_hash_rate(upstream_request_id))
+                    .unwrap()
+                    .unwrap_or(0.0);
+                self.add_hash_rate(-(hash_rate as i64));
+                Ok(open_channel_error(downstream, request_id, &error_code))
+            }
+        }
     }
 
+    /// Only the proxy send UpdateChannel for the group channels, the error is not relayed
     fn handle_update_channel_error(
         &mut self,
-        _m: UpdateChannelError,
+        m: UpdateChannelError,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("470")
+        warn!(
+            channel_id = m.channel_id,
+            error_code = %String::from_utf8_lossy(m.error_code.inner_as_ref()),
+            "channel update refused"
+        );
+        Ok(SendTo::None(None))
     }
 
+    /// The upstream can close the extended channel, a group channel or a standard channel: every
+    /// downstream channel that use it is closed with the same reason
     fn handle_close_channel(
         &mut self,
-        _m: CloseChannel,
+        m: CloseChannel,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("480")
+        if self.open_extended_channel_with_id(m.channel_id).is_some() {
+            return match self.extended_channel.take() {
+                Some(ExtendedChannel::Open(channel)) => Ok(SendTo::Multiple(channel.close(&m))),
+                _ => unreachable!(),
+            };
+        }
+        let channel_ids: Vec<u32> = match self.channel_hash_rates.get(&m.channel_id) {
+            Some(_) => vec![m.channel_id],
+            None => {
+                self.channel_id_to_job_dispatcher.remove(&m.channel_id);
+                self.channel_hash_rates
+                    .iter()
+                    .filter(|(_, (group_id, _))| *group_id == m.channel_id)
+                    .map(|(channel_id, _)| *channel_id)
+                    .collect()
+            }
+        };
+        let mut messages = Vec::with_capacity(channel_ids.len());
+        for channel_id in channel_ids {
+            if let Some(downstream) = self
+                .downstream_selector
+                .downstream_from_channel_id(channel_id)
+            {
+                self.close_standard_channel(channel_id);
+                downstream
+                    .safe_lock(|d| d.remove_channel(channel_id))
+                    .unwrap();
+                let close = Mining::CloseChannel(CloseChannel {
+                    channel_id,
+                    reason_code: m.reason_code.clone().into_static(),
+                });
+                messages.push(SendTo::RelayNewMessage(downstream, close));
+            }
+        }
+        // Downstreams that are not header only have the group channel
+        if let Some(downstreams) = self
+            .downstream_selector
+            .get_downstreams_in_channel(m.channel_id)
+            .cloned()
+        {
+            for downstream in downstreams {
+                downstream
+                    .safe_lock(|d| d.remove_channel(m.channel_id))
+                    .unwrap();
+                let close = Mining::CloseChannel(CloseChannel {
+                    channel_id: m.channel_id,
+                    reason_code: m.reason_code.clone().into_static(),
+                });
+                messages.push(SendTo::RelayNewMessage(downstream, close));
+            }
+        }
+        Ok(SendTo::Multiple(messages))
     }
 
+    /// A new prefix of the extended channel is split between its downstream channels. The jobs
+    /// of the standard channels of the header only downstreams are computed again with the new
+    /// prefix and sent after the message, the other downstreams compute their jobs.
     fn handle_set_extranonce_prefix(
         &mut self,
-        _m: SetExtranoncePrefix,
+        m: SetExtranoncePrefix,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("490")
+        if let Some(channel) = self.open_extended_channel_with_id(m.channel_id) {
+            return Ok(SendTo::Multiple(channel.on_set_extranonce_prefix(&m)));
+        }
+        let downstream = self
+            .downstream_selector
+            .downstream_from_channel_id(m.channel_id)
+            .ok_or(Error::NoDownstreamsConnected)?;
+        let group_id = match self.channel_hash_rates.get(&m.channel_id) {
+            Some((group_id, _)) => *group_id,
+            None => return Ok(SendTo::RelaySameMessage(downstream)),
+        };
+        let extranonce: Extranonce = m.extranonce_prefix.clone().into();
+        // The same conversion used when the jobs of the channel are computed
+        let prefix: Vec<u8> = extranonce.clone().into();
+        let prev_job_id = downstream
+            .safe_lock(|d| {
+                d.set_extranonce_prefix(m.channel_id, extranonce);
+                d.prev_job_id
+            })
+            .unwrap();
+        let jobs = match self.channel_id_to_job_dispatcher.get_mut(&group_id) {
+            Some(JobDispatcher::Group(dispatcher)) => {
+                dispatcher.on_set_extranonce_prefix(m.channel_id, &prefix)
+            }
+            _ => Vec::new(),
+        };
+        let mut messages = vec![SendTo::RelaySameMessage(downstream.clone())];
+        for job in jobs {
+            crate::add_job_id(job.job_id, self.id, prev_job_id);
+            let message = Mining::NewMiningJob(job);
+            messages.push(SendTo::RelayNewMessage(downstream.clone(), message));
+        }
+        Ok(SendTo::Multiple(messages))
     }
 
     fn handle_submit_shares_success(
         &mut self,
         m: SubmitSharesSuccess,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        crate::metrics()
+            .safe_lock(|metrics| {
+                metrics.on_shares_accepted_by_upstream(m.new_submits_accepted_count)
+            })
+            .unwrap();
+        if let Some(channel) = self.open_extended_channel_with_id(m.channel_id) {
+            return Ok(SendTo::Multiple(channel.on_submit_shares_success(&m)));
+        }
         match &self
             .downstream_selector
             .downstream_from_channel_id(m.channel_id)
@@ -606,8 +1488,17 @@ impl
 
     fn handle_submit_shares_error(
         &mut self,
-        _m: SubmitSharesError,
+        m: SubmitSharesError,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        let reason = String::from_utf8_lossy(m.error_code.inner_as_ref()).to_string();
+        crate::metrics()
+            .safe_lock(|metrics| metrics.on_share_rejected(&reason))
+            .unwrap();
+        if let Some(channel) = self.open_extended_channel_with_id(m.channel_id) {
+            if let Some(send_to) = channel.on_submit_shares_error(&m) {
+                return Ok(send_to);
+            }
+        }
         Ok(SendTo::None(None))
     }
 
@@ -615,6 +1506,16 @@ impl
         &mut self,
         m: NewMiningJob,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        crate::metrics()
+            .safe_lock(|metrics| metrics.on_job_received())
+            .unwrap();
+        // The jobs of the standard channels in a group are derived by the job dispatcher, the
+        // upstream send one after a SetExtranoncePrefix and the dispatcher has already computed it
+        if let Some((group_id, _)) = self.channel_hash_rates.get(&m.channel_id) {
+            if let Some(JobDispatcher::Group(_)) = self.channel_id_to_job_dispatcher.get(group_id) {
+                return Ok(SendTo::None(None));
+            }
+        }
         // One and only one downstream cause the message is not extended
         match &self
             .downstream_selector
@@ -637,6 +1538,12 @@ impl
         &mut self,
         m: NewExtendedMiningJob,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        crate::metrics()
+            .safe_lock(|metrics| metrics.on_job_received())
+            .unwrap();
+        if let Some(channel) = self.open_extended_channel_with_id(m.channel_id) {
+            return Ok(SendTo::Multiple(channel.on_new_extended_mining_job(&m)));
+        }
         self.last_extended_jobs.push(m.as_static());
         let id = self.id;
         let downstreams = self
@@ -658,6 +1565,9 @@ impl
         &mut self,
         m: SetNewPrevHash,
     ) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        if let Some(channel) = self.open_extended_channel_with_id(m.channel_id) {
+            return Ok(SendTo::Multiple(channel.on_set_new_prev_hash(&m)));
+        }
         self.last_prev_hash = Some(m.as_static());
         self.last_extended_jobs = self
             .last_extended_jobs
@@ -690,8 +1600,12 @@ impl
                         .safe_lock(|d| {
                             for channel in d.status.get_channels().get_mut(&m.channel_id).unwrap() {
                                 match channel {
-                                    DownstreamChannel::Extended(_) => todo!(),
-                                    DownstreamChannel::Group(_) => todo!(),
+                                    // Extended sub-channels get the prev hash from their
+                                    // OpenExtendedChannel, that is never a group channel
+                                    DownstreamChannel::Extended(_) => (),
+                                    DownstreamChannel::Group(_) => {
+                                        messages.push(SendTo::RelaySameMessage(downstream.clone()))
+                                    }
                                     DownstreamChannel::Standard(channel) => {
                                         let new_prev_hash = SetNewPrevHash {
                                             channel_id: channel.channel_id,
@@ -734,12 +1648,59 @@ impl
         todo!("560")
     }
 
-    fn handle_set_target(&mut self, _m: SetTarget) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("570")
+    /// The target of the extended channel is forwarded to every downstream channel. For the
+    /// standard channels of the header only downstreams it is the target that a share must meet
+    /// to be forwarded, it is relayed to the downstream only if the proxy do not run vardiff.
+    fn handle_set_target(&mut self, m: SetTarget) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        let vardiff = self.vardiff.is_some();
+        if let Some(channel) = self.open_extended_channel_with_id(m.channel_id) {
+            return Ok(SendTo::Multiple(channel.on_set_target(&m, vardiff)));
+        }
+        let downstream = self
+            .downstream_selector
+            .downstream_from_channel_id(m.channel_id)
+            .ok_or(Error::NoDownstreamsConnected)?;
+        for dispatcher in self.channel_id_to_job_dispatcher.values_mut() {
+            if let JobDispatcher::Group(dispatcher) = dispatcher {
+                let target = m.maximum_target.clone().into();
+                dispatcher.set_upstream_target(m.channel_id, target);
+            }
+        }
+        match self.vardiff {
+            Some(_) => Ok(SendTo::None(None)),
+            None => {
+                self.set_downstream_target(&m);
+                Ok(SendTo::RelaySameMessage(downstream))
+            }
+        }
     }
 
-    fn handle_reconnect(&mut self, _m: Reconnect) -> Result<SendTo<DownstreamMiningNode>, Error> {
-        todo!("580")
+    /// The connection is closed, the task that relay the incoming messages see it and connect to
+    /// the new host as it does when the connection is lost, a hostname is resolved then. A
+    /// Reconnect to a host that is neither an IP address nor a hostname is ignored.
+    fn handle_reconnect(&mut self, m: Reconnect) -> Result<SendTo<DownstreamMiningNode>, Error> {
+        let new_host = String::from_utf8_lossy(m.new_host.inner_as_ref()).to_string();
+        // An empty host and a 0 port mean that the current ones must be used
+        if !new_host.is_empty() {
+            match IpAddr::from_str(&new_host) {
+                Ok(ip) => self.address.set_ip(ip),
+                Err(_) if is_hostname(&new_host) => (),
+                Err(_) => {
+                    warn!(new_host = %new_host, "reconnect ignored, the host is not valid");
+                    return Ok(SendTo::None(None));
+                }
+            }
+            self.host = new_host;
+        }
+        if m.new_port != 0 {
+            self.address.set_port(m.new_port);
+        }
+        info!(host = %self.host, port = self.address.port(), "asked to reconnect");
+        if let Some(connection) = &self.connection {
+            connection.receiver.close();
+            connection.sender.close();
+        }
+        Ok(SendTo::None(None))
     }
 
     fn get_request_id_mapper(&mut self) -> Option<Arc<Mutex<RequestIdMapper>>> {
@@ -747,16 +1708,47 @@ impl
     }
 }
 
+/// Labels of letters, digits and hyphens separated by dots
+fn is_hostname(host: &str) -> bool {
+    host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
 pub async fn scan(nodes: Vec<Arc<Mutex<UpstreamMiningNode>>>) {
     let spawn_tasks: Vec<task::JoinHandle<()>> = nodes
         .iter()
         .map(|node| {
             let node = node.clone();
-            task::spawn(async move {
-                UpstreamMiningNode::setup_flag_and_version(node, None)
-                    .await
-                    .unwrap();
-            })
+            // Every event of the upstream is in this span, the address is the configured one
+            let span = node
+                .safe_lock(|n| info_span!("upstream", upstream_id = n.id, address = %n.address))
+                .unwrap();
+            task::spawn(
+                async move {
+                    if UpstreamMiningNode::setup_flag_and_version(node.clone(), None)
+                        .await
+                        .is_err()
+                    {
+                        node.safe_lock(|n| n.connection = None).unwrap();
+                        // Downstreams are paired only with connected upstreams
+                        task::spawn(UpstreamMiningNode::reconnect(node.clone()).in_current_span());
+                    }
+                    let retarget_interval = node
+                        .safe_lock(|n| n.vardiff.as_ref().map(|v| v.config().retarget_interval))
+                        .unwrap();
+                    if let Some(interval) = retarget_interval {
+                        task::spawn(
+                            UpstreamMiningNode::retarget_periodically(node, interval)
+                                .in_current_span(),
+                        );
+                    }
+                }
+                .instrument(span),
+            )
         })
         .collect();
     for task in spawn_tasks {
@@ -773,6 +1765,24 @@ impl IsUpstream<DownstreamMiningNode, ProxyRemoteSelector> for UpstreamMiningNod
         self.sv2_connection.unwrap().setup_connection_flags
     }
 
+    /// An upstream that is not connected can not be paired
+    fn is_pairable(&self, pair_settings: &PairSettings) -> bool {
+        match (&self.connection, self.sv2_connection) {
+            (Some(_), Some(sv2_connection)) => {
+                let version = sv2_connection.version;
+                let check_version =
+                    version >= pair_settings.min_v && version <= pair_settings.max_v;
+                let check_flags = SetupConnection::check_flags(
+                    pair_settings.protocol,
+                    pair_settings.flags,
+                    sv2_connection.setup_connection_flags,
+                );
+                check_version && check_flags
+            }
+            _ => false,
+        }
+    }
+
     fn get_supported_protocols(&self) -> Vec<Protocol> {
         vec![Protocol::MiningProtocol]
     }
@@ -793,8 +1803,8 @@ impl IsMiningUpstream<DownstreamMiningNode, ProxyRemoteSelector> for UpstreamMin
     fn total_hash_rate(&self) -> u64 {
         self.total_hash_rate
     }
-    fn add_hash_rate(&mut self, to_add: u64) {
-        self.total_hash_rate += to_add;
+    fn add_hash_rate(&mut self, to_add: i64) {
+        self.total_hash_rate = (self.total_hash_rate as i64 + to_add).max(0) as u64;
     }
     fn get_opened_channels(&mut self) -> &mut Vec<UpstreamChannel> {
         todo!()
@@ -817,7 +1827,9 @@ fn jobs_to_relay(
                 let prev_id = d.prev_job_id;
                 for channel in d.status.get_channels().get_mut(&m.channel_id).unwrap() {
                     match channel {
-                        DownstreamChannel::Extended(_) => todo!(),
+                        // Extended sub-channels get the jobs from their OpenExtendedChannel,
+                        // that is never a group channel
+                        DownstreamChannel::Extended(_) => (),
                         DownstreamChannel::Group(_) => {
                             crate::add_job_id(m.job_id, id, prev_id);
                             messages.push(SendTo::RelaySameMessage(downstream.clone()))
@@ -842,18 +1854,170 @@ fn jobs_to_relay(
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::lib::{config::UpstreamSelection, downstream_mining::DownstreamMiningNodeStatus};
+    use binary_sv2::Seq0255;
+    use roles_logic_sv2::{common_properties::CommonDownstreamData, utils::merkle_root_from_path};
     use std::net::{IpAddr, Ipv4Addr};
 
+    const AUTHORITY_PUBLIC_KEY: [u8; 32] = [
+        215, 11, 47, 78, 34, 232, 25, 192, 195, 168, 170, 209, 95, 181, 40, 114, 154, 226, 176,
+        190, 90, 169, 238, 89, 191, 183, 97, 63, 194, 119, 11, 31,
+    ];
+
+    fn config(address: SocketAddr) -> Config {
+        Config {
+            upstreams: Vec::new(),
+            upstream_selection: UpstreamSelection::default(),
+            listen_address: address,
+            min_supported_version: 2,
+            max_supported_version: 2,
+            vardiff: None,
+            aggregate_header_only_channels: false,
+            metrics_address: None,
+            log_level: network_helpers::logging::DEFAULT_FILTER.to_string(),
+            log_format: network_helpers::logging::LogFormat::default(),
+        }
+    }
+
+    fn upstream(address: SocketAddr) -> UpstreamMiningNode {
+        let job_ids = Arc::new(Mutex::new(Id::new()));
+        let host = address.ip().to_string();
+        UpstreamMiningNode::new(
+            0,
+            host,
+            address,
+            AUTHORITY_PUBLIC_KEY,
+            job_ids,
+            &config(address),
+        )
+    }
+
+    fn reconnect_to(new_host: &str, new_port: u16) -> Reconnect<'static> {
+        Reconnect {
+            new_host: new_host.to_string().try_into().unwrap(),
+            new_port,
+        }
+    }
+
+    fn open_extended_channel(upstream: &mut UpstreamMiningNode, prefix: Vec<u8>) {
+        let request = OpenExtendedMiningChannel {
+            request_id: 1.into(),
+            user_identity: "proxy".to_string().try_into().unwrap(),
+            nominal_hash_rate: 1000.0,
+            max_target: [0xff; 32].into(),
+            min_extranonce_size: 16,
+        };
+        let success = OpenExtendedMiningChannelSuccess {
+            request_id: 1,
+            channel_id: 7,
+            target: [0xff; 32].into(),
+            extranonce_size: 16,
+            extranonce_prefix: prefix.try_into().unwrap(),
+        };
+        let channel =
+            OpenExtendedChannel::new(&success, request, upstream.job_ids.clone()).unwrap();
+        upstream.extended_channel = Some(ExtendedChannel::Open(channel));
+    }
+
+    fn header_only_downstream() -> Arc<Mutex<DownstreamMiningNode>> {
+        let (sender, receiver) = async_channel::bounded(10);
+        let mut downstream = DownstreamMiningNode::new(receiver, sender);
+        let data = CommonDownstreamData {
+            header_only: true,
+            work_selection: false,
+            version_rolling: true,
+        };
+        downstream.status = DownstreamMiningNodeStatus::Paired((data, HashMap::new()));
+        Arc::new(Mutex::new(downstream))
+    }
+
+    fn open_standard(
+        request_id: u32,
+        nominal_hash_rate: f32,
+    ) -> OpenStandardMiningChannel<'static> {
+        OpenStandardMiningChannel {
+            request_id: request_id.into(),
+            user_identity: "miner".to_string().try_into().unwrap(),
+            nominal_hash_rate,
+            max_target: [0xff; 32].into(),
+        }
+    }
+
+    /// Extended job with a coinbase that has the 32 bytes extranonce as input script
+    fn extended_job(job_id: u32, future_job: bool) -> NewExtendedMiningJob<'static> {
+        let mut coinbase_tx_prefix = vec![2, 0, 0, 0, 1];
+        coinbase_tx_prefix.extend_from_slice(&[0; 32]);
+        coinbase_tx_prefix.extend_from_slice(&[255, 255, 255, 255, 32]);
+        let mut coinbase_tx_suffix = vec![255, 255, 255, 255, 1];
+        coinbase_tx_suffix.extend_from_slice(&[0; 8]);
+        coinbase_tx_suffix.extend_from_slice(&[0, 0, 0, 0, 0]);
+        NewExtendedMiningJob {
+            channel_id: 7,
+            job_id,
+            future_job,
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: Seq0255::new(Vec::new()).unwrap(),
+            coinbase_tx_prefix: coinbase_tx_prefix.try_into().unwrap(),
+            coinbase_tx_suffix: coinbase_tx_suffix.try_into().unwrap(),
+        }
+    }
+
+    /// Upstream with an open extended channel that has an active job, the 16 bytes prefix and
+    /// the 16 bytes extranonce size fill the extranonce of the standard channels
+    fn upstream_with_job(upstream_prefix: Vec<u8>) -> UpstreamMiningNode {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut upstream = upstream(address);
+        open_extended_channel(&mut upstream, upstream_prefix);
+        let prev_hash = SetNewPrevHash {
+            channel_id: 7,
+            job_id: 1,
+            prev_hash: [3; 32].into(),
+            min_ntime: 1_650_000_000,
+            nbits: 0x1d00_ffff,
+        };
+        match &mut upstream.extended_channel {
+            Some(ExtendedChannel::Open(channel)) => {
+                channel.on_new_extended_mining_job(&extended_job(1, true));
+                channel.on_set_new_prev_hash(&prev_hash);
+            }
+            _ => unreachable!(),
+        }
+        upstream
+    }
+
+    /// Open an aggregated channel and return the messages relayed to the downstream and the
+    /// messages for the upstream
+    fn open_aggregated(
+        upstream: &mut UpstreamMiningNode,
+        downstream: &Arc<Mutex<DownstreamMiningNode>>,
+        request: OpenStandardMiningChannel<'static>,
+    ) -> (Vec<Mining<'static>>, Vec<Mining<'static>>) {
+        let mut relayed = Vec::new();
+        let mut responses = Vec::new();
+        for message in upstream.open_aggregated(downstream.clone(), request) {
+            match message {
+                SendTo::RelayNewMessage(d, message) => {
+                    assert!(Arc::ptr_eq(&d, downstream));
+                    relayed.push(message);
+                }
+                SendTo::Respond(message) => responses.push(message),
+                _ => panic!("unexpected message"),
+            }
+        }
+        (relayed, responses)
+    }
+
     #[test]
     fn new_upstream_minining_node() {
         let id = 0;
         let job_ids = Arc::new(Mutex::new(Id::new()));
         let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
-        let authority_public_key = [
-            215, 11, 47, 78, 34, 232, 25, 192, 195, 168, 170, 209, 95, 181, 40, 114, 154, 226, 176,
-            190, 90, 169, 238, 89, 191, 183, 97, 63, 194, 119, 11, 31,
-        ];
-        let actual = UpstreamMiningNode::new(id, address, authority_public_key, job_ids);
+        let authority_public_key = AUTHORITY_PUBLIC_KEY;
+        let config = config(address);
+        let host = "127.0.0.1".to_string();
+        let actual =
+            UpstreamMiningNode::new(id, host, address, authority_public_key, job_ids, &config);
 
         assert_eq!(actual.id, id);
 
@@ -880,4 +2044,196 @@ mod tests {
         assert!(actual.last_prev_hash.is_none());
         assert!(actual.last_extended_jobs.is_empty());
     }
+
+    #[test]
+    fn reconnect_changes_the_address() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut upstream = upstream(address);
+        upstream
+            .handle_reconnect(reconnect_to("10.0.0.2", 3333))
+            .unwrap();
+        assert_eq!(upstream.address, "10.0.0.2:3333".parse().unwrap());
+        assert_eq!(upstream.host, "10.0.0.2");
+    }
+
+    #[test]
+    fn reconnect_without_host_and_port_keeps_the_address() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut upstream = upstream(address);
+        upstream.handle_reconnect(reconnect_to("", 0)).unwrap();
+        assert_eq!(upstream.address, address);
+        upstream.handle_reconnect(reconnect_to("", 3333)).unwrap();
+        assert_eq!(upstream.address, "127.0.0.1:3333".parse().unwrap());
+    }
+
+    #[test]
+    fn reconnect_to_an_invalid_host_is_ignored() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut upstream = upstream(address);
+        upstream
+            .handle_reconnect(reconnect_to("not an address", 3333))
+            .unwrap();
+        assert_eq!(upstream.address, address);
+        assert_eq!(upstream.host, "127.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn reconnect_to_a_hostname_is_resolved_when_connecting() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 8080);
+        let mut upstream = upstream(address);
+        upstream
+            .handle_reconnect(reconnect_to("localhost", 3333))
+            .unwrap();
+        assert_eq!(upstream.host, "localhost");
+
+        let upstream = Arc::new(Mutex::new(upstream));
+        let resolved = UpstreamMiningNode::resolve(upstream.clone()).await;
+        assert!(resolved.ip().is_loopback());
+        assert_eq!(resolved.port(), 3333);
+        assert_eq!(upstream.safe_lock(|u| u.address).unwrap(), resolved);
+    }
+
+    #[tokio::test]
+    async fn last_address_is_used_when_the_host_can_not_be_resolved() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 8080);
+        let mut upstream = upstream(address);
+        // .invalid never resolves
+        upstream.host = "upstream.invalid".to_string();
+        let upstream = Arc::new(Mutex::new(upstream));
+        assert_eq!(UpstreamMiningNode::resolve(upstream).await, address);
+    }
+
+    #[test]
+    fn extended_channel_is_opened_again_after_the_connection_is_lost() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut upstream = upstream(address);
+        open_extended_channel(&mut upstream, vec![1, 2, 3, 4]);
+        upstream.channel_hash_rates.insert(1, (1, 10.0));
+        upstream.total_hash_rate = 10;
+
+        assert!(upstream.on_connection_lost().is_empty());
+        assert!(upstream.channel_hash_rates.is_empty());
+        assert_eq!(upstream.total_hash_rate, 0);
+        match &upstream.extended_channel {
+            Some(ExtendedChannel::Opening {
+                previous: Some(previous),
+                ..
+            }) => assert_eq!(previous.channel_id, 7),
+            _ => panic!("the channel is not being opened again"),
+        }
+
+        let messages = upstream.reopen_extended_channel();
+        match &messages[..] {
+            [SendTo::Respond(Mining::OpenExtendedMiningChannel(m))] => {
+                assert_eq!(m.min_extranonce_size, 16);
+                assert_eq!(m.user_identity.to_vec(), b"proxy".to_vec());
+            }
+            _ => panic!("OpenExtendedMiningChannel not sent"),
+        }
+    }
+
+    #[test]
+    fn nothing_is_opened_again_without_extended_channel() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 8080);
+        let mut upstream = upstream(address);
+        assert!(upstream.on_connection_lost().is_empty());
+        assert!(upstream.reopen_extended_channel().is_empty());
+    }
+
+    #[test]
+    fn aggregated_job_is_computed_with_the_extranonce_of_the_upstream_share() {
+        let upstream_prefix: Vec<u8> = (1..=16).collect();
+        let mut upstream = upstream_with_job(upstream_prefix.clone());
+        let downstream = header_only_downstream();
+        let (relayed, responses) =
+            open_aggregated(&mut upstream, &downstream, open_standard(1, 10.0));
+        let (channel_id, extranonce_prefix) = match &relayed[0] {
+            Mining::OpenStandardMiningChannelSuccess(m) => {
+                assert_eq!((m.get_request_id_as_u32(), m.group_channel_id), (1, 7));
+                (m.channel_id, m.extranonce_prefix.to_vec())
+            }
+            _ => panic!("channel not opened"),
+        };
+        let job = match &relayed[1] {
+            Mining::NewMiningJob(m) => m.as_static(),
+            _ => panic!("job not sent"),
+        };
+        assert!(matches!(&relayed[2], Mining::SetNewPrevHash(m) if m.job_id == job.job_id));
+        match &responses[..] {
+            [Mining::UpdateChannel(m)] => {
+                assert_eq!((m.channel_id, m.nominal_hash_rate), (7, 10.0))
+            }
+            _ => panic!("UpdateChannel not sent"),
+        }
+        assert!(upstream.is_aggregated_channel(channel_id));
+
+        let share = SubmitSharesStandard {
+            channel_id,
+            sequence_number: 1,
+            job_id: job.job_id,
+            nonce: 2,
+            ntime: 3,
+            version: 0x2000_0000,
+        };
+        let upstream_share = match upstream.on_submit_shares_aggregated(share) {
+            Some((Ok(share), None)) => share,
+            _ => panic!("share not sent upstream"),
+        };
+        assert_eq!((upstream_share.channel_id, upstream_share.job_id), (7, 1));
+        let mut extranonce = upstream_prefix;
+        extranonce.extend_from_slice(&upstream_share.extranonce.to_vec());
+        assert_eq!(extranonce, extranonce_prefix);
+        let extended_job = extended_job(1, true);
+        let merkle_root = merkle_root_from_path(
+            extended_job.coinbase_tx_prefix.inner_as_ref(),
+            extended_job.coinbase_tx_suffix.inner_as_ref(),
+            &extranonce,
+            &[] as &[&[u8]],
+        )
+        .unwrap();
+        assert_eq!(job.merkle_root.to_vec(), merkle_root);
+    }
+
+    #[test]
+    fn closing_the_last_aggregated_channel_closes_the_extended_channel() {
+        let mut upstream = upstream_with_job((1..=16).collect());
+        let downstream = header_only_downstream();
+        let mut channel_ids = Vec::new();
+        for request_id in 1..=2 {
+            let (relayed, _) =
+                open_aggregated(&mut upstream, &downstream, open_standard(request_id, 10.0));
+            match &relayed[0] {
+                Mining::OpenStandardMiningChannelSuccess(m) => channel_ids.push(m.channel_id),
+                _ => panic!("channel not opened"),
+            }
+        }
+
+        match upstream.close_aggregated_channel(channel_ids[0]) {
+            Some(Mining::UpdateChannel(m)) => {
+                assert_eq!((m.channel_id, m.nominal_hash_rate), (7, 10.0))
+            }
+            _ => panic!("UpdateChannel not sent"),
+        }
+        assert!(upstream.close_aggregated_channel(channel_ids[0]).is_none());
+        match upstream.close_aggregated_channel(channel_ids[1]) {
+            Some(Mining::CloseChannel(m)) => assert_eq!(m.channel_id, 7),
+            _ => panic!("CloseChannel not sent"),
+        }
+        assert!(upstream.extended_channel.is_none());
+    }
+
+    #[test]
+    fn extended_channel_is_closed_when_the_last_aggregated_downstream_disconnects() {
+        let mut upstream = upstream_with_job((1..=16).collect());
+        let downstream = header_only_downstream();
+        open_aggregated(&mut upstream, &downstream, open_standard(1, 10.0));
+        open_aggregated(&mut upstream, &downstream, open_standard(2, 10.0));
+
+        let messages = upstream.close_downstream_channels(&downstream);
+        match &messages[..] {
+            [SendTo::Respond(Mining::CloseChannel(m))] => assert_eq!(m.channel_id, 7),
+            _ => panic!("CloseChannel not sent"),
+        }
+        assert!(upstream.extended_channel.is_none());
+    }
 }
@@ -18,19 +18,26 @@
 //! A Downstream that signal the incapacity to handle group channels can open only one channel.
 //!
 mod lib;
-use std::net::{IpAddr, SocketAddr};
 
-use lib::upstream_mining::UpstreamMiningNode;
-use once_cell::sync::Lazy;
-use serde::Deserialize;
-use std::str::FromStr;
+use lib::{
+    config::{Config, Error, UpstreamConfig, UpstreamSelection},
+    metrics::Metrics,
+    upstream_mining::UpstreamMiningNode,
+};
+use network_helpers::logging;
+use once_cell::sync::{Lazy, OnceCell};
 
 use roles_logic_sv2::{
-    routing_logic::{CommonRoutingLogic, MiningProxyRoutingLogic, MiningRoutingLogic},
+    common_properties::CommonDownstreamData,
+    routing_logic::{
+        CommonRoutingLogic, LeastHashRate, MiningProxyRoutingLogic, MiningRoutingLogic,
+        PriorityFailover, RoundRobin, UpstreamSelectionLogic, WeightedHashRate,
+    },
     selectors::{GeneralMiningSelector, UpstreamMiningSelctor},
     utils::{Id, Mutex},
 };
 use std::{collections::HashMap, sync::Arc};
+use tracing::info;
 
 type RLogic = MiningProxyRoutingLogic<
     crate::lib::downstream_mining::DownstreamMiningNode,
@@ -38,17 +45,6 @@ type RLogic = MiningProxyRoutingLogic<
     crate::lib::upstream_mining::ProxyRemoteSelector,
 >;
 
-pub fn max_supported_version() -> u16 {
-    let config_file = std::fs::read_to_string("proxy-config.toml").unwrap();
-    let config: Config = toml::from_str(&config_file).unwrap();
-    config.max_supported_version
-}
-pub fn min_supported_version() -> u16 {
-    let config_file = std::fs::read_to_string("proxy-config.toml").unwrap();
-    let config: Config = toml::from_str(&config_file).unwrap();
-    config.min_supported_version
-}
-
 /// Panic whene we are looking one of this 2 global mutex would force the proxy to go down as every
 /// part of the program depend on them.
 /// SAFTEY note: we use global mutable memory instead of a dedicated struct that use a dedicated
@@ -56,15 +52,30 @@ pub fn min_supported_version() -> u16 {
 /// messages cause it is impossible for a task to panic while is using one of the two below Mutex.
 /// So it make sense to use shared mutable memory to lower the complexity of the codebase and to
 /// have some performance gain.
-static ROUTING_LOGIC: Lazy<Mutex<RLogic>> = Lazy::new(|| Mutex::new(initialize_r_logic()));
+/// The routing logic is set in main from the config before anything use it.
+static ROUTING_LOGIC: OnceCell<Mutex<RLogic>> = OnceCell::new();
 static JOB_ID_TO_UPSTREAM_ID: Lazy<Mutex<HashMap<u32, u32>>> =
     Lazy::new(|| Mutex::new(HashMap::new()));
+/// Only counters, it is always locked last
+static METRICS: Lazy<Mutex<Metrics>> = Lazy::new(|| Mutex::new(Metrics::default()));
 
-async fn initialize_upstreams() {
-    let upstreams = ROUTING_LOGIC
+fn routing_logic() -> &'static Mutex<RLogic> {
+    // Safe unwrap the routing logic is set in main
+    ROUTING_LOGIC.get().unwrap()
+}
+
+pub fn metrics() -> &'static Mutex<Metrics> {
+    &METRICS
+}
+
+pub fn upstream_nodes() -> Vec<Arc<Mutex<UpstreamMiningNode>>> {
+    routing_logic()
         .safe_lock(|r_logic| r_logic.upstream_selector.upstreams.clone())
-        .unwrap();
-    crate::lib::upstream_mining::scan(upstreams).await;
+        .unwrap()
+}
+
+async fn initialize_upstreams() {
+    crate::lib::upstream_mining::scan(upstream_nodes()).await;
 }
 
 pub fn get_routing_logic() -> MiningRoutingLogic<
@@ -73,10 +84,10 @@ pub fn get_routing_logic() -> MiningRoutingLogic<
     crate::lib::upstream_mining::ProxyRemoteSelector,
     RLogic,
 > {
-    MiningRoutingLogic::Proxy(&ROUTING_LOGIC)
+    MiningRoutingLogic::Proxy(routing_logic())
 }
 pub fn get_common_routing_logic() -> CommonRoutingLogic<RLogic> {
-    CommonRoutingLogic::Proxy(&ROUTING_LOGIC)
+    CommonRoutingLogic::Proxy(routing_logic())
 }
 
 pub fn upstream_from_job_id(job_id: u32) -> Option<Arc<Mutex<UpstreamMiningNode>>> {
@@ -84,11 +95,25 @@ pub fn upstream_from_job_id(job_id: u32) -> Option<Arc<Mutex<UpstreamMiningNode>
     upstream_id = JOB_ID_TO_UPSTREAM_ID
         .safe_lock(|x| *x.get(&job_id).unwrap())
         .unwrap();
-    ROUTING_LOGIC
+    routing_logic()
         .safe_lock(|rlogic| rlogic.upstream_selector.get_upstream(upstream_id))
         .unwrap()
 }
 
+/// Upstream paired with the downstreams that have `downstream_data` on SetupConnection
+pub fn paired_upstream(
+    downstream_data: &CommonDownstreamData,
+) -> Option<Arc<Mutex<UpstreamMiningNode>>> {
+    routing_logic()
+        .safe_lock(|rlogic| {
+            rlogic
+                .downstream_to_upstream_map
+                .get(downstream_data)
+                .and_then(|upstreams| upstreams.first().cloned())
+        })
+        .unwrap()
+}
+
 pub fn add_job_id(job_id: u32, up_id: u32, prev_job_id: Option<u32>) {
     if let Some(prev_job_id) = prev_job_id {
         JOB_ID_TO_UPSTREAM_ID
@@ -100,38 +125,20 @@ pub fn add_job_id(job_id: u32, up_id: u32, prev_job_id: Option<u32>) {
         .unwrap();
 }
 
-#[derive(Debug, Deserialize)]
-pub struct UpstreamValues {
-    address: String,
-    port: u16,
-    pub_key: [u8; 32],
-}
-
-#[derive(Debug, Deserialize)]
-pub struct Config {
-    upstreams: Vec<UpstreamValues>,
-    listen_address: String,
-    listen_mining_port: u16,
-    max_supported_version: u16,
-    min_supported_version: u16,
-}
-
-pub fn initialize_r_logic() -> RLogic {
-    let config_file = std::fs::read_to_string("proxy-config.toml").unwrap();
-    let config: Config = toml::from_str(&config_file).unwrap();
-    let upstreams = config.upstreams;
+pub fn initialize_r_logic(config: &Config) -> RLogic {
     let job_ids = Arc::new(Mutex::new(Id::new()));
-    let upstream_mining_nodes: Vec<Arc<Mutex<UpstreamMiningNode>>> = upstreams
+    let upstream_mining_nodes: Vec<Arc<Mutex<UpstreamMiningNode>>> = config
+        .upstreams
         .iter()
         .enumerate()
         .map(|(index, upstream)| {
-            let socket =
-                SocketAddr::new(IpAddr::from_str(&upstream.address).unwrap(), upstream.port);
             Arc::new(Mutex::new(UpstreamMiningNode::new(
                 index as u32,
-                socket,
+                upstream.host.clone(),
+                upstream.address,
                 upstream.pub_key,
                 job_ids.clone(),
+                config,
             )))
         })
         .collect();
@@ -141,6 +148,41 @@ pub fn initialize_r_logic() -> RLogic {
         upstream_selector,
         downstream_id_generator: Id::new(),
         downstream_to_upstream_map: std::collections::HashMap::new(),
+        upstream_selection: upstream_selection(&config.upstream_selection, &config.upstreams),
+    }
+}
+
+/// Upstream ids are the positions of the upstreams in the config
+fn upstream_selection(
+    selection: &UpstreamSelection,
+    upstreams: &[UpstreamConfig],
+) -> Box<
+    dyn UpstreamSelectionLogic<
+        crate::lib::downstream_mining::DownstreamMiningNode,
+        crate::lib::upstream_mining::UpstreamMiningNode,
+        crate::lib::upstream_mining::ProxyRemoteSelector,
+    >,
+> {
+    match selection {
+        UpstreamSelection::LeastHashRate => Box::new(LeastHashRate()),
+        UpstreamSelection::WeightedHashRate => Box::new(WeightedHashRate {
+            weights: upstreams
+                .iter()
+                .enumerate()
+                .map(|(index, upstream)| (index as u32, upstream.weight))
+                .collect(),
+        }),
+        UpstreamSelection::PriorityFailover => {
+            let mut priorities: Vec<u32> = (0..upstreams.len() as u32).collect();
+            priorities.sort_by_key(|index| {
+                (
+                    upstreams[*index as usize].priority.unwrap_or(u32::MAX),
+                    *index,
+                )
+            });
+            Box::new(PriorityFailover { priorities })
+        }
+        UpstreamSelection::RoundRobin => Box::new(RoundRobin::default()),
     }
 }
 
@@ -156,17 +198,33 @@ pub fn initialize_r_logic() -> RLogic {
 ///    upstream_mining::UpstreamMiningNode begin
 #[tokio::main]
 async fn main() {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(Error::HelpRequested) => {
+            println!("{}", Error::HelpRequested);
+            return;
+        }
+        Err(e) => {
+            eprintln!("PROXY: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = logging::init(&config.log_level, config.log_format) {
+        eprintln!("PROXY: {}", e);
+        std::process::exit(1);
+    }
+    // main is the only place where the routing logic is set
+    let _ = ROUTING_LOGIC.set(Mutex::new(initialize_r_logic(&config)));
+
+    if let Some(address) = config.metrics_address {
+        tokio::task::spawn(crate::lib::metrics::serve(address));
+    }
+
     // Scan all the upstreams and map them
-    let config_file = std::fs::read_to_string("proxy-config.toml").unwrap();
-    let config: Config = toml::from_str(&config_file).unwrap();
-    println!("PROXY INITIALIZING");
+    info!("initializing");
     initialize_upstreams().await;
 
     // Wait for downstream connection
-    let socket = SocketAddr::new(
-        IpAddr::from_str(&config.listen_address).unwrap(),
-        config.listen_mining_port,
-    );
-    println!("PROXY INITIALIZED");
-    crate::lib::downstream_mining::listen_for_downstream_mining(socket).await
+    info!(listen_address = %config.listen_address, "initialized");
+    crate::lib::downstream_mining::listen_for_downstream_mining(config.listen_address).await
 }
@@ -0,0 +1,581 @@
+//! Admin API of the pool, served as JSON when `admin_address` is set in the config.
+//!
+//! - `GET /downstreams`: connected downstreams with the target and hash rate of their channels
+//! - `GET /downstreams/<id>`: one downstream
+//! - `GET /template`: last template and prev hash received from the Template Provider
+//! - `POST /downstreams/<id>/channels/<channel_id>/target` with `{"target": "<hex>"}`: send
+//!   SetTarget, the target is 32 bytes big endian as `channel.initial_target`
+//! - `POST /downstreams/<id>/channels/<channel_id>/close` with an optional
+//!   `{"reason_code": "..."}`: send CloseChannel, closing the group channel close the whole group
+//! - `POST /downstreams/<id>/reconnect` with an optional `{"host": "...", "port": 0}`: send
+//!   Reconnect, an empty host and a 0 port mean the current ones
+//!
+//! When `admin_token` is set every request must have an `Authorization: Bearer <admin_token>`
+//! header, the token is required when `admin_address` is not a loopback address.
+use crate::lib::{
+    metrics::Metrics,
+    mining_pool::{
+        message_handler::{target_from_hex, target_to_hex},
+        u256_to_block_hash, Downstream, Pool,
+    },
+};
+use network_helpers::http_tokio::{self, Request, Response};
+use roles_logic_sv2::{
+    mining_sv2::{CloseChannel, Reconnect, SetTarget},
+    parsers::Mining,
+    utils::Mutex,
+};
+use serde::{Deserialize, Serialize};
+use std::{convert::TryInto, net::SocketAddr, sync::Arc};
+use tracing::{error, info};
+
+/// Sent with CloseChannel when the request do not give a reason
+const DEFAULT_CLOSE_REASON: &str = "closed-by-pool";
+
+#[derive(Debug, Serialize)]
+struct ChannelView {
+    channel_id: u32,
+    #[serde(rename = "type")]
+    channel_type: &'static str,
+    user_identity: Option<String>,
+    /// Big endian hex
+    target: String,
+    /// Hashes per second estimated from the shares accepted recently
+    hash_rate: Option<f64>,
+}
+
+#[derive(Debug, Serialize)]
+struct DownstreamView {
+    id: u32,
+    remote: String,
+    header_only: bool,
+    channels: Vec<ChannelView>,
+}
+
+#[derive(Debug, Serialize)]
+struct TemplateView {
+    template_id: u64,
+    future_template: bool,
+    version: u32,
+    coinbase_tx_value_remaining: u64,
+    coinbase_tx_outputs_count: u32,
+    merkle_path_length: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct PrevHashView {
+    template_id: u64,
+    prev_hash: String,
+    header_timestamp: u32,
+    n_bits: u32,
+    /// Big endian hex
+    target: String,
+}
+
+#[derive(Debug, Serialize)]
+struct CurrentWorkView {
+    template: Option<TemplateView>,
+    prev_hash: Option<PrevHashView>,
+}
+
+#[derive(Debug, Serialize)]
+struct Sent {
+    sent: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorView {
+    error: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetTargetRequest {
+    target: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct CloseChannelRequest {
+    reason_code: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq, Deserialize)]
+#[serde(default)]
+struct ReconnectRequest {
+    host: String,
+    port: u16,
+}
+
+/// An API request with its validated parameters
+#[derive(Debug, PartialEq)]
+enum Route {
+    Downstreams,
+    Downstream(u32),
+    Template,
+    SetTarget {
+        id: u32,
+        channel_id: u32,
+        target: String,
+    },
+    CloseChannel {
+        id: u32,
+        channel_id: u32,
+        reason_code: Option<String>,
+    },
+    Reconnect {
+        id: u32,
+        request: ReconnectRequest,
+    },
+}
+
+/// Serve the admin API on `http://<address>/`
+pub async fn serve(
+    pool: Arc<Mutex<Pool>>,
+    metrics: Arc<Mutex<Metrics>>,
+    address: SocketAddr,
+    token: Option<String>,
+) {
+    let served = http_tokio::serve(address, move |request| {
+        handle(&pool, &metrics, token.as_deref(), request).unwrap_or_else(|response| response)
+    })
+    .await;
+    if let Err(e) = served {
+        error!(%address, "admin API not served: {}", e);
+    }
+}
+
+fn handle(
+    pool: &Arc<Mutex<Pool>>,
+    metrics: &Arc<Mutex<Metrics>>,
+    token: Option<&str>,
+    request: Request,
+) -> Result<Response, Response> {
+    authorize(&request, token)?;
+    match route(&request)? {
+        Route::Downstreams => {
+            // The pool is not locked while the downstreams are locked
+            let downstreams = pool.safe_lock(|p| p.downstreams()).unwrap();
+            let views: Vec<DownstreamView> = downstreams
+                .iter()
+                .map(|downstream| downstream_view(downstream, metrics))
+                .collect();
+            Ok(json(200, &views))
+        }
+        Route::Downstream(id) => {
+            let downstream = find_downstream(pool, id)?;
+            Ok(json(200, &downstream_view(&downstream, metrics)))
+        }
+        Route::Template => Ok(json(200, &current_work(pool))),
+        Route::SetTarget {
+            id,
+            channel_id,
+            target,
+        } => set_target(&find_downstream(pool, id)?, channel_id, &target),
+        Route::CloseChannel {
+            id,
+            channel_id,
+            reason_code,
+        } => close_channel(&find_downstream(pool, id)?, channel_id, reason_code),
+        Route::Reconnect { id, request } => reconnect(&find_downstream(pool, id)?, request),
+    }
+}
+
+/// Without token every request is authorized
+fn authorize(request: &Request, token: Option<&str>) -> Result<(), Response> {
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(()),
+    };
+    let given = request
+        .header("authorization")
+        .and_then(|value| value.split_once(' '))
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("bearer"))
+        .map(|(_, given)| given.trim());
+    match given {
+        Some(given) if constant_time_eq(given.as_bytes(), token.as_bytes()) => Ok(()),
+        _ => Err(error(401, "missing or invalid bearer token")),
+    }
+}
+
+/// Every byte is compared so that the time taken do not tell how much of the token is right
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+fn route(request: &Request) -> Result<Route, Response> {
+    let segments: Vec<&str> = request.path.trim_matches('/').split('/').collect();
+    match (request.method.as_str(), segments.as_slice()) {
+        ("GET", ["downstreams"]) => Ok(Route::Downstreams),
+        ("GET", ["downstreams", id]) => Ok(Route::Downstream(parse_id(id)?)),
+        ("GET", ["template"]) => Ok(Route::Template),
+        ("POST", ["downstreams", id, "channels", channel_id, "target"]) => {
+            let (id, channel_id) = (parse_id(id)?, parse_id(channel_id)?);
+            let body: SetTargetRequest = parse_body(&request.body)?;
+            Ok(Route::SetTarget {
+                id,
+                channel_id,
+                target: body.target,
+            })
+        }
+        ("POST", ["downstreams", id, "channels", channel_id, "close"]) => {
+            let (id, channel_id) = (parse_id(id)?, parse_id(channel_id)?);
+            let body: CloseChannelRequest = parse_optional_body(&request.body)?;
+            Ok(Route::CloseChannel {
+                id,
+                channel_id,
+                reason_code: body.reason_code,
+            })
+        }
+        ("POST", ["downstreams", id, "reconnect"]) => Ok(Route::Reconnect {
+            id: parse_id(id)?,
+            request: parse_optional_body(&request.body)?,
+        }),
+        (_, ["downstreams"]) | (_, ["downstreams", _]) | (_, ["template"]) => {
+            Err(error(405, "method not allowed"))
+        }
+        (_, ["downstreams", _, "channels", _, "target"])
+        | (_, ["downstreams", _, "channels", _, "close"])
+        | (_, ["downstreams", _, "reconnect"]) => Err(error(405, "method not allowed")),
+        _ => Err(error(404, "not found")),
+    }
+}
+
+fn downstream_view(
+    downstream: &Arc<Mutex<Downstream>>,
+    metrics: &Arc<Mutex<Metrics>>,
+) -> DownstreamView {
+    let (id, remote, header_only, channels) = downstream
+        .safe_lock(|d| (d.id(), d.remote(), d.is_header_only(), d.channels()))
+        .unwrap();
+    let channels = channels
+        .into_iter()
+        .map(|(channel_id, channel_type, target)| {
+            let hash_rate = metrics
+                .safe_lock(|m| m.channel_hash_rate((id, channel_id)))
+                .unwrap();
+            ChannelView {
+                channel_id,
+                channel_type: channel_type.label(),
+                user_identity: hash_rate.as_ref().map(|(user, _)| user.clone()),
+                target: target_to_hex(&target),
+                hash_rate: hash_rate.map(|(_, hash_rate)| hash_rate),
+            }
+        })
+        .collect();
+    DownstreamView {
+        id,
+        remote: remote.to_string(),
+        header_only,
+        channels,
+    }
+}
+
+fn current_work(pool: &Arc<Mutex<Pool>>) -> CurrentWorkView {
+    let (template, prev_hash) = pool
+        .safe_lock(|p| (p.last_template(), p.last_new_prev_hash()))
+        .unwrap();
+    CurrentWorkView {
+        template: template.map(|t| TemplateView {
+            template_id: t.template_id,
+            future_template: t.future_template,
+            version: t.version,
+            coinbase_tx_value_remaining: t.coinbase_tx_value_remaining,
+            coinbase_tx_outputs_count: t.coinbase_tx_outputs_count,
+            merkle_path_length: t.merkle_path.to_vec().len(),
+        }),
+        prev_hash: prev_hash.map(|p| PrevHashView {
+            template_id: p.template_id,
+            prev_hash: u256_to_block_hash(p.prev_hash.clone()).to_string(),
+            header_timestamp: p.header_timestamp,
+            n_bits: p.n_bits,
+            target: target_to_hex(&p.target),
+        }),
+    }
+}
+
+fn set_target(
+    downstream: &Arc<Mutex<Downstream>>,
+    channel_id: u32,
+    target: &str,
+) -> Result<Response, Response> {
+    let target =
+        target_from_hex(target).ok_or_else(|| error(400, "target must be 32 bytes hex encoded"))?;
+    let set_target: SetTarget<'static> = downstream
+        .safe_lock(|d| d.force_target(channel_id, target))
+        .unwrap()
+        .ok_or_else(|| error(404, "channel not found"))?;
+    info!(
+        channel_id,
+        target = %target_to_hex(&set_target.maximum_target),
+        "target set by the admin API"
+    );
+    send(downstream, Mining::SetTarget(set_target))?;
+    Ok(json(200, &Sent { sent: "SetTarget" }))
+}
+
+fn close_channel(
+    downstream: &Arc<Mutex<Downstream>>,
+    channel_id: u32,
+    reason_code: Option<String>,
+) -> Result<Response, Response> {
+    let reason_code = reason_code.unwrap_or_else(|| DEFAULT_CLOSE_REASON.to_string());
+    let close = CloseChannel {
+        channel_id,
+        reason_code: reason_code
+            .try_into()
+            .map_err(|_| error(400, "reason_code must be at most 255 bytes"))?,
+    };
+    if !downstream.safe_lock(|d| d.has_channel(channel_id)).unwrap() {
+        return Err(error(404, "channel not found"));
+    }
+    info!(channel_id, "channel closed by the admin API");
+    send(downstream, Mining::CloseChannel(close))?;
+    downstream
+        .safe_lock(|d| d.close_channel(channel_id))
+        .unwrap();
+    Ok(json(
+        200,
+        &Sent {
+            sent: "CloseChannel",
+        },
+    ))
+}
+
+fn reconnect(
+    downstream: &Arc<Mutex<Downstream>>,
+    request: ReconnectRequest,
+) -> Result<Response, Response> {
+    info!(
+        new_host = %request.host,
+        new_port = request.port,
+        "reconnect sent by the admin API"
+    );
+    let reconnect = Reconnect {
+        new_host: request
+            .host
+            .try_into()
+            .map_err(|_| error(400, "host must be at most 255 bytes"))?,
+        new_port: request.port,
+    };
+    send(downstream, Mining::Reconnect(reconnect))?;
+    Ok(json(200, &Sent { sent: "Reconnect" }))
+}
+
+fn send(downstream: &Arc<Mutex<Downstream>>, message: Mining<'static>) -> Result<(), Response> {
+    downstream
+        .safe_lock(|d| d.try_send(message))
+        .unwrap()
+        .map_err(|_| error(503, "downstream disconnected or not reading its messages"))
+}
+
+fn find_downstream(pool: &Arc<Mutex<Pool>>, id: u32) -> Result<Arc<Mutex<Downstream>>, Response> {
+    pool.safe_lock(|p| p.downstream(id))
+        .unwrap()
+        .ok_or_else(|| error(404, "downstream not found"))
+}
+
+fn parse_id(id: &str) -> Result<u32, Response> {
+    id.parse()
+        .map_err(|_| error(400, &format!("invalid id `{}`", id)))
+}
+
+fn parse_body<'a, T: Deserialize<'a>>(body: &'a [u8]) -> Result<T, Response> {
+    serde_json::from_slice(body).map_err(|e| error(400, &format!("invalid body: {}", e)))
+}
+
+/// An empty body is a request with the default values
+fn parse_optional_body<'a, T: Deserialize<'a> + Default>(body: &'a [u8]) -> Result<T, Response> {
+    match body.is_empty() {
+        true => Ok(T::default()),
+        false => parse_body(body),
+    }
+}
+
+fn json<T: Serialize>(status: u16, body: &T) -> Response {
+    // Safe unwrap the views are always serializable
+    let mut body = serde_json::to_string(body).unwrap();
+    body.push('\n');
+    Response::new(status, "application/json", body)
+}
+
+fn error(status: u16, message: &str) -> Response {
+    json(
+        status,
+        &ErrorView {
+            error: message.to_string(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: &str, path: &str, body: &str) -> Request {
+        Request {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    fn authorization(value: &str) -> Request {
+        let mut request = request("GET", "/downstreams", "");
+        request
+            .headers
+            .push(("Authorization".to_string(), value.to_string()));
+        request
+    }
+
+    fn status<T: std::fmt::Debug>(result: Result<T, Response>) -> u16 {
+        result.unwrap_err().status
+    }
+
+    #[test]
+    fn requests_are_routed_with_their_parameters() {
+        let routes = [
+            (request("GET", "/downstreams", ""), Route::Downstreams),
+            (request("GET", "/downstreams/3/", ""), Route::Downstream(3)),
+            (request("GET", "/template", ""), Route::Template),
+            (
+                request(
+                    "POST",
+                    "/downstreams/3/channels/4/target",
+                    r#"{"target": "ff"}"#,
+                ),
+                Route::SetTarget {
+                    id: 3,
+                    channel_id: 4,
+                    target: "ff".to_string(),
+                },
+            ),
+            (
+                request("POST", "/downstreams/3/channels/4/close", ""),
+                Route::CloseChannel {
+                    id: 3,
+                    channel_id: 4,
+                    reason_code: None,
+                },
+            ),
+            (
+                request(
+                    "POST",
+                    "/downstreams/3/channels/4/close",
+                    r#"{"reason_code": "maintenance"}"#,
+                ),
+                Route::CloseChannel {
+                    id: 3,
+                    channel_id: 4,
+                    reason_code: Some("maintenance".to_string()),
+                },
+            ),
+            (
+                request("POST", "/downstreams/3/reconnect", ""),
+                Route::Reconnect {
+                    id: 3,
+                    request: ReconnectRequest::default(),
+                },
+            ),
+            (
+                request(
+                    "POST",
+                    "/downstreams/3/reconnect",
+                    r#"{"host": "10.0.0.2", "port": 3333}"#,
+                ),
+                Route::Reconnect {
+                    id: 3,
+                    request: ReconnectRequest {
+                        host: "10.0.0.2".to_string(),
+                        port: 3333,
+                    },
+                },
+            ),
+        ];
+        for (request, expected) in routes.iter() {
+            assert_eq!(&route(request).unwrap(), expected, "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn invalid_ids_and_bodies_are_bad_requests() {
+        let requests = [
+            request("GET", "/downstreams/x", ""),
+            request(
+                "POST",
+                "/downstreams/3/channels/-1/target",
+                r#"{"target": "ff"}"#,
+            ),
+            request("POST", "/downstreams/3/channels/4/target", ""),
+            request("POST", "/downstreams/3/channels/4/target", r#"{"tar"#),
+            request(
+                "POST",
+                "/downstreams/3/channels/4/close",
+                r#"{"reason_code": 1}"#,
+            ),
+            request("POST", "/downstreams/3/reconnect", r#"{"port": 70000}"#),
+        ];
+        for request in requests.iter() {
+            assert_eq!(status(route(request)), 400, "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn unknown_paths_are_not_found() {
+        let requests = [
+            request("GET", "/", ""),
+            request("GET", "/metrics", ""),
+            request("GET", "/downstreams/3/channels", ""),
+            request("POST", "/downstreams/3/channels/4", ""),
+        ];
+        for request in requests.iter() {
+            assert_eq!(status(route(request)), 404, "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn wrong_methods_are_not_allowed() {
+        let requests = [
+            request("POST", "/downstreams", ""),
+            request("DELETE", "/downstreams/3", ""),
+            request("PUT", "/template", ""),
+            request("GET", "/downstreams/3/channels/4/target", ""),
+            request("GET", "/downstreams/3/channels/4/close", ""),
+            request("GET", "/downstreams/3/reconnect", ""),
+        ];
+        for request in requests.iter() {
+            assert_eq!(status(route(request)), 405, "{:?}", request);
+        }
+    }
+
+    #[test]
+    fn requests_need_the_bearer_token_when_it_is_set() {
+        assert!(authorize(&request("GET", "/downstreams", ""), None).is_ok());
+        assert!(authorize(&authorization("Bearer secret"), Some("secret")).is_ok());
+        assert!(authorize(&authorization("bearer secret"), Some("secret")).is_ok());
+
+        assert_eq!(
+            status(authorize(
+                &request("GET", "/downstreams", ""),
+                Some("secret")
+            )),
+            401
+        );
+        for value in [
+            "Bearer wrong",
+            "Bearer secre",
+            "Bearer ",
+            "Basic secret",
+            "secret",
+        ]
+        .iter()
+        {
+            assert_eq!(
+                status(authorize(&authorization(value), Some("secret"))),
+                401,
+                "{}",
+                value
+            );
+        }
+    }
+}
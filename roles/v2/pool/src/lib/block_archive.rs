@@ -0,0 +1,187 @@
+//! Every block found by the pool is written in a local directory, so that it is not lost if the
+//! Template Provider can not be reached when the solution is found.
+use bitcoin::{
+    blockdata::block::{Block, BlockHeader},
+    consensus::{deserialize, serialize},
+    hashes::hex::ToHex,
+    Transaction,
+};
+use std::{
+    fmt::{self, Display},
+    fs::{self, File},
+    io::Write,
+    path::PathBuf,
+};
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidCoinbase,
+    /// Position of the transaction in the template
+    InvalidTransaction(usize),
+    /// The merkle root of the assembled block is not the one of the header that met the target
+    InvalidMerkleRoot,
+    Io(PathBuf, std::io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            InvalidCoinbase => write!(f, "Invalid coinbase"),
+            InvalidTransaction(position) => write!(f, "Invalid transaction at {}", position),
+            InvalidMerkleRoot => write!(f, "Merkle root do not match the transactions"),
+            Io(path, e) => write!(f, "Can not write {}: {}", path.display(), e),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockArchive {
+    dir: PathBuf,
+}
+
+impl BlockArchive {
+    pub fn new(dir: PathBuf) -> Result<Self, Error> {
+        fs::create_dir_all(&dir).map_err(|e| Error::Io(dir.clone(), e))?;
+        Ok(Self { dir })
+    }
+
+    /// Assemble the block from the header that met the bitcoin target, the coinbase and the
+    /// transactions of the template (coinbase excluded), check the merkle root and write the
+    /// serialized block in `<dir>/<block hash>.hex`. Return the path of the written file.
+    pub fn archive(
+        &self,
+        header: BlockHeader,
+        coinbase: &[u8],
+        transactions: &[Vec<u8>],
+    ) -> Result<PathBuf, Error> {
+        let block = Self::assemble(header, coinbase, transactions)?;
+        let path = self.dir.join(format!("{}.hex", block.block_hash()));
+        let mut file = File::create(&path).map_err(|e| Error::Io(path.clone(), e))?;
+        file.write_all(serialize(&block).to_hex().as_bytes())
+            .and_then(|_| file.sync_all())
+            .map_err(|e| Error::Io(path.clone(), e))?;
+        Ok(path)
+    }
+
+    fn assemble(
+        header: BlockHeader,
+        coinbase: &[u8],
+        transactions: &[Vec<u8>],
+    ) -> Result<Block, Error> {
+        let mut txdata = Vec::with_capacity(transactions.len() + 1);
+        txdata.push(deserialize::<Transaction>(coinbase).map_err(|_| Error::InvalidCoinbase)?);
+        for (position, transaction) in transactions.iter().enumerate() {
+            txdata.push(deserialize(transaction).map_err(|_| Error::InvalidTransaction(position))?);
+        }
+        let block = Block { header, txdata };
+        if !block.check_merkle_root() {
+            return Err(Error::InvalidMerkleRoot);
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::{
+        hashes::{sha256d, Hash},
+        OutPoint, Script, TxIn, TxMerkleNode, TxOut,
+    };
+
+    fn transaction(script_sig: Vec<u8>, value: u64) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from(script_sig),
+                sequence: u32::MAX,
+                witness: Default::default(),
+            }],
+            output: vec![TxOut {
+                value,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        }
+    }
+
+    /// Merkle root of a block with two transactions
+    fn merkle_root(coinbase: &Transaction, transaction: &Transaction) -> TxMerkleNode {
+        let mut leaves = coinbase.txid()[..].to_vec();
+        leaves.extend_from_slice(&transaction.txid()[..]);
+        TxMerkleNode::from_slice(&sha256d::Hash::hash(&leaves)[..]).unwrap()
+    }
+
+    fn header(merkle_root: TxMerkleNode) -> BlockHeader {
+        BlockHeader {
+            version: 0x2000_0000,
+            prev_blockhash: Default::default(),
+            merkle_root,
+            time: 1_650_000_000,
+            bits: 0x1d00_ffff,
+            nonce: 1,
+        }
+    }
+
+    fn archive(test: &str) -> BlockArchive {
+        let dir = std::env::temp_dir().join(format!("pool-blocks-{}-{}", std::process::id(), test));
+        BlockArchive::new(dir).unwrap()
+    }
+
+    #[test]
+    fn block_is_assembled_with_the_coinbase_first() {
+        let coinbase = transaction(vec![3, 1, 0, 0], 625_000_000_000);
+        let transaction = transaction(vec![1], 1);
+        let header = header(merkle_root(&coinbase, &transaction));
+        let block =
+            BlockArchive::assemble(header, &serialize(&coinbase), &[serialize(&transaction)])
+                .unwrap();
+        assert_eq!(block.txdata, vec![coinbase, transaction]);
+        assert_eq!(block.header, header);
+    }
+
+    #[test]
+    fn block_with_a_wrong_merkle_root_is_not_assembled() {
+        let coinbase = transaction(vec![3, 1, 0, 0], 625_000_000_000);
+        let transaction = transaction(vec![1], 1);
+        // Transactions in the wrong order
+        let header = header(merkle_root(&transaction, &coinbase));
+        let assembled =
+            BlockArchive::assemble(header, &serialize(&coinbase), &[serialize(&transaction)]);
+        assert!(matches!(assembled, Err(Error::InvalidMerkleRoot)));
+    }
+
+    #[test]
+    fn invalid_transactions_are_not_assembled() {
+        let coinbase = transaction(vec![3, 1, 0, 0], 625_000_000_000);
+        let transaction = transaction(vec![1], 1);
+        let header = header(merkle_root(&coinbase, &transaction));
+        let assembled = BlockArchive::assemble(header, &[0], &[serialize(&transaction)]);
+        assert!(matches!(assembled, Err(Error::InvalidCoinbase)));
+        let assembled = BlockArchive::assemble(header, &serialize(&coinbase), &[vec![0]]);
+        assert!(matches!(assembled, Err(Error::InvalidTransaction(0))));
+    }
+
+    #[test]
+    fn archived_block_is_written_in_hex() {
+        let archive = archive("archived_block_is_written_in_hex");
+        let coinbase = transaction(vec![3, 1, 0, 0], 625_000_000_000);
+        let transaction = transaction(vec![1], 1);
+        let header = header(merkle_root(&coinbase, &transaction));
+        let path = archive
+            .archive(header, &serialize(&coinbase), &[serialize(&transaction)])
+            .unwrap();
+        assert_eq!(
+            path.file_name().unwrap().to_str().unwrap(),
+            format!("{}.hex", header.block_hash())
+        );
+        let written = fs::read_to_string(&path).unwrap();
+        let block = Block {
+            header,
+            txdata: vec![coinbase, transaction],
+        };
+        assert_eq!(written, serialize(&block).to_hex());
+    }
+}
@@ -0,0 +1,898 @@
+//! Pool configuration
+//!
+//! The configuration is read from a TOML file (`pool-config.toml` by default, see
+//! `--config`) and every field can be overridden from the command line. Everything is validated
+//! before the pool start so that a bad configuration is reported with a clear error instead of a
+//! panic in the middle of a connection.
+use crate::lib::mining_pool::message_handler::target_from_hex;
+use binary_sv2::U256;
+use bitcoin::{hashes::hex::FromHex, util::address::Address, Network, Script};
+use codec_sv2::Responder;
+use network_helpers::logging::{self, LogFormat};
+use roles_logic_sv2::{job_creator::JobsCreators, vardiff::VardiffConfig};
+use serde::Deserialize;
+use std::{
+    convert::TryInto,
+    fmt::{self, Display},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+const DEFAULT_CONFIG_PATH: &str = "pool-config.toml";
+
+pub const USAGE: &str = "Usage: pool [OPTIONS]
+
+Options:
+  -c, --config <FILE>                       Configuration file [default: pool-config.toml]
+      --listen-address <ADDRESS>            Address where downstreams connect
+      --tp-address <ADDRESS>                Address of the Template Provider
+      --jn-address <ADDRESS>                Address where Job Negotiators connect
+      --network <NETWORK>                   bitcoin, testnet, signet or regtest
+      --coinbase-address <ADDRESS>          Address that receive the whole block reward
+      --coinbase-script-pubkey <HEX>        Script that receive the whole block reward
+      --authority-public-key-file <FILE>    Hex encoded authority public key
+      --authority-private-key-file <FILE>   Hex encoded authority private key
+      --cert-validity-sec <SECONDS>         Validity of the noise certificates
+      --channel-initial-target <HEX>        Target of new channels (big endian)
+      --block-archive-dir <DIR>             Directory where the blocks found are written
+      --share-log-dir <DIR>                 Directory where the accepted shares are logged
+      --metrics-address <ADDRESS>           Address where the Prometheus metrics are served
+      --admin-address <ADDRESS>             Address where the admin API is served
+      --log-level <FILTER>                  Level or directives as info,pool=debug [default: info]
+      --log-format <FORMAT>                 text or json [default: text]
+  -h, --help                                Print this message";
+
+#[derive(Debug)]
+pub enum Error {
+    HelpRequested,
+    InvalidArgument(String),
+    MissingArgumentValue(String),
+    ReadFile(PathBuf, std::io::Error),
+    InvalidToml(PathBuf, toml::de::Error),
+    InvalidSocketAddress(&'static str, String),
+    InvalidNetwork(String),
+    InvalidCoinbaseAddress(String),
+    CoinbaseAddressNetwork(String, Network),
+    InvalidCoinbaseScriptPubkey(String),
+    /// Exactly one of address and script_pubkey must be set in a coinbase output
+    CoinbaseOutput,
+    InvalidCoinbaseShares,
+    InvalidKeyFile(PathBuf),
+    InvalidAuthorityKeys,
+    InvalidCertValidity,
+    InvalidTarget(String),
+    InvalidVardiff,
+    InvalidExtranoncePrefixRotation,
+    InvalidPayoutScheme(String),
+    InvalidPplnsWindow,
+    InvalidPoolFee,
+    InvalidShareLog,
+    /// The admin API would be reachable from other hosts without a token
+    UnauthenticatedAdmin(SocketAddr),
+    InvalidAdminToken,
+    InvalidLogging(logging::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+        match self {
+            HelpRequested => write!(f, "{}", USAGE),
+            InvalidArgument(arg) => write!(f, "Unknown argument `{}`\n\n{}", arg, USAGE),
+            MissingArgumentValue(arg) => write!(f, "Missing value for `{}`\n\n{}", arg, USAGE),
+            ReadFile(path, e) => write!(f, "Can not read {}: {}", path.display(), e),
+            InvalidToml(path, e) => write!(f, "Invalid config file {}: {}", path.display(), e),
+            InvalidSocketAddress(field, value) => {
+                write!(f, "Invalid {}: `{}` is not a socket address", field, value)
+            }
+            InvalidNetwork(value) => write!(
+                f,
+                "Invalid network `{}`, expected bitcoin, testnet, signet or regtest",
+                value
+            ),
+            InvalidCoinbaseAddress(value) => write!(f, "Invalid coinbase address `{}`", value),
+            CoinbaseAddressNetwork(value, network) => write!(
+                f,
+                "Coinbase address `{}` is not valid for network {}",
+                value, network
+            ),
+            InvalidCoinbaseScriptPubkey(value) => {
+                write!(
+                    f,
+                    "Invalid coinbase script_pubkey `{}`, expected hex",
+                    value
+                )
+            }
+            CoinbaseOutput => write!(
+                f,
+                "Exactly one of address and script_pubkey must be set in every coinbase output"
+            ),
+            InvalidCoinbaseShares => write!(
+                f,
+                "At least one coinbase output is needed and the sum of the shares must be \
+                greater than 0 and fit in a u64"
+            ),
+            InvalidKeyFile(path) => write!(
+                f,
+                "Invalid key file {}: expected a 32 bytes hex encoded key",
+                path.display()
+            ),
+            InvalidAuthorityKeys => write!(f, "Authority public and private keys do not match"),
+            InvalidCertValidity => write!(f, "cert_validity_sec must be greater than 0"),
+            InvalidTarget(value) => write!(
+                f,
+                "Invalid channel initial_target `{}`, expected 32 bytes hex encoded",
+                value
+            ),
+            InvalidVardiff => write!(
+                f,
+                "shares_per_minute, window_sec and retarget_interval_sec of channel.vardiff must \
+                be greater than 0"
+            ),
+            InvalidExtranoncePrefixRotation => write!(
+                f,
+                "channel.extranonce_prefix_rotation_sec must be greater than 0"
+            ),
+            InvalidPayoutScheme(value) => {
+                write!(
+                    f,
+                    "Invalid payout scheme `{}`, expected pplns or pps",
+                    value
+                )
+            }
+            InvalidPplnsWindow => write!(
+                f,
+                "payout.pplns_window must be set and greater than 0 with the pplns scheme"
+            ),
+            InvalidPoolFee => write!(f, "payout.fee_percent must be between 0 and 100"),
+            InvalidShareLog => write!(
+                f,
+                "share_log.fsync_interval_ms and share_log.rotate_size_mb must be greater than 0"
+            ),
+            UnauthenticatedAdmin(address) => write!(
+                f,
+                "admin_token must be set to serve the admin API on {}, that is not a loopback \
+                address",
+                address
+            ),
+            InvalidAdminToken => write!(f, "admin_token must not be empty"),
+            InvalidLogging(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+/// Defaults for the channels opened by the downstreams
+#[derive(Debug, Deserialize)]
+struct ChannelFile {
+    initial_target: String,
+    vardiff: Option<VardiffFile>,
+    extranonce_prefix_rotation_sec: Option<u64>,
+}
+
+/// When set the target of every channel is adjusted so that it submit shares_per_minute shares
+#[derive(Debug, Deserialize)]
+struct VardiffFile {
+    shares_per_minute: f32,
+    window_sec: u64,
+    retarget_interval_sec: u64,
+}
+
+/// How the users are paid for the shares that they submit
+#[derive(Debug, Deserialize)]
+struct PayoutFile {
+    scheme: String,
+    /// Used by pplns, in multiples of the network difficulty
+    pplns_window: Option<f64>,
+    #[serde(default)]
+    fee_percent: f64,
+}
+
+/// Every accepted share is appended to a log in `dir`
+#[derive(Debug, Deserialize)]
+struct ShareLogFile {
+    dir: PathBuf,
+    fsync_interval_ms: u64,
+    rotate_size_mb: u64,
+}
+
+/// The block reward is split between the coinbase outputs proportionally to the shares
+#[derive(Debug, Deserialize)]
+struct CoinbaseOutputFile {
+    address: Option<String>,
+    script_pubkey: Option<String>,
+    share: u64,
+}
+
+/// The config file as it is written, values are validated in `Config::new`
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    listen_address: String,
+    tp_address: String,
+    jn_address: String,
+    network: String,
+    coinbase_outputs: Vec<CoinbaseOutputFile>,
+    authority_public_key_file: PathBuf,
+    authority_private_key_file: PathBuf,
+    cert_validity_sec: u64,
+    block_archive_dir: PathBuf,
+    /// When set the metrics are served on http://<metrics_address>/metrics
+    metrics_address: Option<String>,
+    /// When set the admin API is served on http://<admin_address>/
+    admin_address: Option<String>,
+    /// Bearer token required by the admin API, needed when admin_address is not a loopback
+    /// address. It can not be given on the command line where every user could read it.
+    admin_token: Option<String>,
+    /// A level or directives as `info,pool=debug`, `RUST_LOG` replace it when set
+    #[serde(default = "default_log_level")]
+    log_level: String,
+    /// text or json
+    #[serde(default = "default_log_format")]
+    log_format: String,
+    channel: ChannelFile,
+    payout: PayoutFile,
+    share_log: ShareLogFile,
+}
+
+fn default_log_level() -> String {
+    logging::DEFAULT_FILTER.to_string()
+}
+
+fn default_log_format() -> String {
+    "text".to_string()
+}
+
+#[derive(Debug, Clone)]
+pub struct ChannelConfig {
+    /// Target of the new channels when vardiff is not enabled
+    pub initial_target: U256<'static>,
+    pub vardiff: Option<VardiffConfig>,
+    /// When set every channel get a new extranonce prefix at this interval, see
+    /// `extranonce_prefix_rotation_sec` in pool-config.toml for why it is on a timer
+    pub extranonce_prefix_rotation: Option<Duration>,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum PayoutScheme {
+    /// The reward of a block is split between the last shares that sum up to `window` times the
+    /// network difficulty
+    Pplns { window: f64 },
+    /// Every share is paid when it is accepted
+    Pps,
+}
+
+#[derive(Debug, Clone)]
+pub struct PayoutConfig {
+    pub scheme: PayoutScheme,
+    /// Fraction of the rewards kept by the pool, between 0 and 1
+    pub fee: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShareLogConfig {
+    pub dir: PathBuf,
+    /// Logged shares are written to disk at this interval
+    pub fsync_interval: Duration,
+    /// A new file is started when the current one is bigger than this many bytes
+    pub rotate_size: u64,
+}
+
+#[derive(Clone)]
+pub struct Config {
+    pub listen_address: SocketAddr,
+    pub tp_address: SocketAddr,
+    pub jn_address: SocketAddr,
+    /// (script_pubkey, share)
+    pub coinbase_outputs: Vec<(Script, u64)>,
+    pub authority_public_key: [u8; 32],
+    pub authority_private_key: [u8; 32],
+    pub cert_validity: Duration,
+    pub block_archive_dir: PathBuf,
+    pub metrics_address: Option<SocketAddr>,
+    pub admin_address: Option<SocketAddr>,
+    /// When set the admin requests must have an `Authorization: Bearer <admin_token>` header
+    pub admin_token: Option<String>,
+    pub log_level: String,
+    pub log_format: LogFormat,
+    pub channel: ChannelConfig,
+    pub payout: PayoutConfig,
+    pub share_log: ShareLogConfig,
+}
+
+impl Config {
+    /// Read the config file and apply the command line overrides
+    pub fn from_args(args: impl Iterator<Item = String>) -> Result<Self, Error> {
+        let mut config_path = PathBuf::from(DEFAULT_CONFIG_PATH);
+        let mut overrides = Vec::new();
+        let mut args = args;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "-h" | "--help" => return Err(Error::HelpRequested),
+                "-c" | "--config" => {
+                    config_path = args.next().ok_or(Error::MissingArgumentValue(arg))?.into();
+                }
+                _ if arg.starts_with("--") => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| Error::MissingArgumentValue(arg.clone()))?;
+                    overrides.push((arg, value));
+                }
+                _ => return Err(Error::InvalidArgument(arg)),
+            }
+        }
+
+        let config_file = std::fs::read_to_string(&config_path)
+            .map_err(|e| Error::ReadFile(config_path.clone(), e))?;
+        let mut file: ConfigFile =
+            toml::from_str(&config_file).map_err(|e| Error::InvalidToml(config_path.clone(), e))?;
+
+        // Paths in the config file are relative to the config file
+        let config_dir = config_path.parent().unwrap_or_else(|| Path::new(""));
+        file.authority_public_key_file = config_dir.join(&file.authority_public_key_file);
+        file.authority_private_key_file = config_dir.join(&file.authority_private_key_file);
+        file.block_archive_dir = config_dir.join(&file.block_archive_dir);
+        file.share_log.dir = config_dir.join(&file.share_log.dir);
+
+        for (arg, value) in overrides {
+            match arg.as_str() {
+                "--listen-address" => file.listen_address = value,
+                "--tp-address" => file.tp_address = value,
+                "--jn-address" => file.jn_address = value,
+                "--network" => file.network = value,
+                // A coinbase output from the command line replace the ones in the config file
+                "--coinbase-address" => {
+                    file.coinbase_outputs = vec![CoinbaseOutputFile {
+                        address: Some(value),
+                        script_pubkey: None,
+                        share: 1,
+                    }]
+                }
+                "--coinbase-script-pubkey" => {
+                    file.coinbase_outputs = vec![CoinbaseOutputFile {
+                        address: None,
+                        script_pubkey: Some(value),
+                        share: 1,
+                    }]
+                }
+                "--authority-public-key-file" => file.authority_public_key_file = value.into(),
+                "--authority-private-key-file" => file.authority_private_key_file = value.into(),
+                "--cert-validity-sec" => {
+                    file.cert_validity_sec = value
+                        .parse()
+                        .map_err(|_| Error::InvalidArgument(format!("{} {}", arg, value)))?
+                }
+                "--channel-initial-target" => file.channel.initial_target = value,
+                "--block-archive-dir" => file.block_archive_dir = value.into(),
+                "--share-log-dir" => file.share_log.dir = value.into(),
+                "--metrics-address" => file.metrics_address = Some(value),
+                "--admin-address" => file.admin_address = Some(value),
+                "--log-level" => file.log_level = value,
+                "--log-format" => file.log_format = value,
+                _ => return Err(Error::InvalidArgument(arg)),
+            }
+        }
+        Self::new(file)
+    }
+
+    fn new(file: ConfigFile) -> Result<Self, Error> {
+        let network = parse_network(&file.network)?;
+        let mut coinbase_outputs = Vec::with_capacity(file.coinbase_outputs.len());
+        for output in file.coinbase_outputs {
+            let script_pubkey =
+                parse_coinbase_script(output.address, output.script_pubkey, network)?;
+            coinbase_outputs.push((script_pubkey, output.share));
+        }
+        JobsCreators::new(0, coinbase_outputs.clone()).map_err(|_| Error::InvalidCoinbaseShares)?;
+        let authority_public_key = read_key(&file.authority_public_key_file)?;
+        let authority_private_key = read_key(&file.authority_private_key_file)?;
+        if file.cert_validity_sec == 0 {
+            return Err(Error::InvalidCertValidity);
+        }
+        let cert_validity = Duration::from_secs(file.cert_validity_sec);
+        Responder::from_authority_kp(&authority_public_key, &authority_private_key, cert_validity)
+            .map_err(|_| Error::InvalidAuthorityKeys)?;
+        let initial_target = target_from_hex(&file.channel.initial_target)
+            .ok_or(Error::InvalidTarget(file.channel.initial_target))?;
+        let vardiff = file.channel.vardiff.map(parse_vardiff).transpose()?;
+        let extranonce_prefix_rotation = match file.channel.extranonce_prefix_rotation_sec {
+            Some(0) => return Err(Error::InvalidExtranoncePrefixRotation),
+            Some(sec) => Some(Duration::from_secs(sec)),
+            None => None,
+        };
+        let payout = parse_payout(file.payout)?;
+        let share_log = parse_share_log(file.share_log)?;
+        let metrics_address = file
+            .metrics_address
+            .map(|address| parse_socket_address("metrics_address", &address))
+            .transpose()?;
+        let admin_address = file
+            .admin_address
+            .map(|address| parse_socket_address("admin_address", &address))
+            .transpose()?;
+        match (admin_address, &file.admin_token) {
+            (_, Some(token)) if token.is_empty() => return Err(Error::InvalidAdminToken),
+            (Some(address), None) if !address.ip().is_loopback() => {
+                return Err(Error::UnauthenticatedAdmin(address))
+            }
+            _ => (),
+        }
+        logging::validate_filter(&file.log_level).map_err(Error::InvalidLogging)?;
+        let log_format = file.log_format.parse().map_err(Error::InvalidLogging)?;
+        Ok(Self {
+            listen_address: parse_socket_address("listen_address", &file.listen_address)?,
+            tp_address: parse_socket_address("tp_address", &file.tp_address)?,
+            jn_address: parse_socket_address("jn_address", &file.jn_address)?,
+            coinbase_outputs,
+            authority_public_key,
+            authority_private_key,
+            cert_validity,
+            block_archive_dir: file.block_archive_dir,
+            metrics_address,
+            admin_address,
+            admin_token: file.admin_token,
+            log_level: file.log_level,
+            log_format,
+            channel: ChannelConfig {
+                initial_target,
+                vardiff,
+                extranonce_prefix_rotation,
+            },
+            payout,
+            share_log,
+        })
+    }
+}
+
+fn parse_socket_address(field: &'static str, value: &str) -> Result<SocketAddr, Error> {
+    SocketAddr::from_str(value).map_err(|_| Error::InvalidSocketAddress(field, value.to_string()))
+}
+
+/// Any script can be used, addresses are checked against the network
+fn parse_coinbase_script(
+    address: Option<String>,
+    script_pubkey: Option<String>,
+    network: Network,
+) -> Result<Script, Error> {
+    match (address, script_pubkey) {
+        (Some(address), None) => {
+            let parsed = Address::from_str(&address)
+                .map_err(|_| Error::InvalidCoinbaseAddress(address.clone()))?;
+            if !is_address_network(parsed.network, network) {
+                return Err(Error::CoinbaseAddressNetwork(address, network));
+            }
+            Ok(parsed.script_pubkey())
+        }
+        (None, Some(script)) => Ok(Vec::<u8>::from_hex(&script)
+            .map_err(|_| Error::InvalidCoinbaseScriptPubkey(script))?
+            .into()),
+        _ => Err(Error::CoinbaseOutput),
+    }
+}
+
+fn parse_vardiff(vardiff: VardiffFile) -> Result<VardiffConfig, Error> {
+    if vardiff.shares_per_minute <= 0.0
+        || vardiff.window_sec == 0
+        || vardiff.retarget_interval_sec == 0
+    {
+        return Err(Error::InvalidVardiff);
+    }
+    Ok(VardiffConfig::new(
+        vardiff.shares_per_minute,
+        Duration::from_secs(vardiff.window_sec),
+        Duration::from_secs(vardiff.retarget_interval_sec),
+    ))
+}
+
+fn parse_payout(payout: PayoutFile) -> Result<PayoutConfig, Error> {
+    let scheme = match (payout.scheme.as_str(), payout.pplns_window) {
+        ("pplns", Some(window)) if window > 0.0 => PayoutScheme::Pplns { window },
+        ("pplns", _) => return Err(Error::InvalidPplnsWindow),
+        ("pps", _) => PayoutScheme::Pps,
+        _ => return Err(Error::InvalidPayoutScheme(payout.scheme)),
+    };
+    if !(0.0..=100.0).contains(&payout.fee_percent) {
+        return Err(Error::InvalidPoolFee);
+    }
+    Ok(PayoutConfig {
+        scheme,
+        fee: payout.fee_percent / 100.0,
+    })
+}
+
+fn parse_share_log(share_log: ShareLogFile) -> Result<ShareLogConfig, Error> {
+    if share_log.fsync_interval_ms == 0 || share_log.rotate_size_mb == 0 {
+        return Err(Error::InvalidShareLog);
+    }
+    Ok(ShareLogConfig {
+        dir: share_log.dir,
+        fsync_interval: Duration::from_millis(share_log.fsync_interval_ms),
+        rotate_size: share_log.rotate_size_mb.saturating_mul(1024 * 1024),
+    })
+}
+
+fn parse_network(value: &str) -> Result<Network, Error> {
+    match value {
+        "bitcoin" | "mainnet" => Ok(Network::Bitcoin),
+        "testnet" => Ok(Network::Testnet),
+        "signet" => Ok(Network::Signet),
+        "regtest" => Ok(Network::Regtest),
+        _ => Err(Error::InvalidNetwork(value.to_string())),
+    }
+}
+
+/// Testnet and signet addresses are encoded in the same way
+fn is_address_network(address_network: Network, network: Network) -> bool {
+    address_network == network
+        || (address_network == Network::Testnet && network == Network::Signet)
+}
+
+fn read_key(path: &Path) -> Result<[u8; 32], Error> {
+    let key = std::fs::read_to_string(path).map_err(|e| Error::ReadFile(path.into(), e))?;
+    Vec::<u8>::from_hex(key.trim())
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| Error::InvalidKeyFile(path.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = include_str!("../../pool-config.toml");
+    const ADDRESS_OUTPUT: &str =
+        r#"{ address = "tb1q2vfxp232rx0z9rzn0hay9jptagk8c86d0gwv99", share = 1 },"#;
+    const SCRIPT: &str = "0014531260aa2a199e228c537dfa42c82bea2c7c1f4d";
+
+    /// Replace `from`, that must be in the config, with `to`
+    fn edit(config: &str, from: &str, to: &str) -> String {
+        assert!(config.contains(from), "`{}` not in the config", from);
+        config.replace(from, to)
+    }
+
+    /// Directory with a copy of the authority keys, every test has its own
+    fn config_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pool-config-{}-{}", std::process::id(), test));
+        let keys = Path::new(env!("CARGO_MANIFEST_DIR")).join("keys");
+        std::fs::create_dir_all(dir.join("keys")).unwrap();
+        for key in ["authority_public_key", "authority_private_key"].iter() {
+            std::fs::copy(keys.join(key), dir.join("keys").join(key)).unwrap();
+        }
+        dir
+    }
+
+    /// Write `config` in the directory of the test and load it with the command line `args`
+    fn load(test: &str, config: &str, args: &[&str]) -> Result<Config, Error> {
+        let path = config_dir(test).join("pool-config.toml");
+        std::fs::write(&path, config).unwrap();
+        let mut all = vec!["--config".to_string(), path.display().to_string()];
+        all.extend(args.iter().map(|arg| arg.to_string()));
+        Config::from_args(all.into_iter())
+    }
+
+    #[test]
+    fn loads_the_sample_config() {
+        let dir = config_dir("sample");
+        let config = load("sample", SAMPLE, &[]).unwrap();
+
+        assert_eq!(config.listen_address, "127.0.0.1:34254".parse().unwrap());
+        assert_eq!(config.coinbase_outputs.len(), 1);
+        assert_eq!(config.coinbase_outputs[0].1, 1);
+        assert_eq!(config.cert_validity, Duration::from_secs(3600));
+        assert_eq!(config.block_archive_dir, dir.join("blocks"));
+        assert_eq!(config.share_log.dir, dir.join("shares"));
+        assert_eq!(config.share_log.rotate_size, 64 * 1024 * 1024);
+        assert_eq!(config.share_log.fsync_interval, Duration::from_millis(100));
+        assert!(matches!(
+            config.payout.scheme,
+            PayoutScheme::Pplns { window } if window == 2.0
+        ));
+        assert!((config.payout.fee - 0.01).abs() < f64::EPSILON);
+        assert_eq!(config.channel.initial_target.to_vec()[30], 1);
+        assert!(config.channel.vardiff.is_none());
+        assert!(config.metrics_address.is_none());
+        assert!(config.admin_address.is_none());
+    }
+
+    #[test]
+    fn command_line_overrides_the_config_file() {
+        let config = load(
+            "overrides",
+            SAMPLE,
+            &[
+                "--listen-address",
+                "0.0.0.0:1",
+                "--coinbase-script-pubkey",
+                SCRIPT,
+                "--cert-validity-sec",
+                "60",
+                "--admin-address",
+                "127.0.0.1:2",
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(config.listen_address, "0.0.0.0:1".parse().unwrap());
+        assert_eq!(config.coinbase_outputs.len(), 1);
+        assert_eq!(
+            config.coinbase_outputs[0].0,
+            Script::from(Vec::<u8>::from_hex(SCRIPT).unwrap())
+        );
+        assert_eq!(config.cert_validity, Duration::from_secs(60));
+        assert_eq!(config.admin_address, Some("127.0.0.1:2".parse().unwrap()));
+    }
+
+    #[test]
+    fn admin_api_needs_a_token_when_it_is_not_on_a_loopback_address() {
+        let public = ["--admin-address", "0.0.0.0:9186"];
+        assert!(matches!(
+            load("admin-no-token", SAMPLE, &public),
+            Err(Error::UnauthenticatedAdmin(_))
+        ));
+
+        let with_token = edit(
+            SAMPLE,
+            r#"#admin_token = "change-me""#,
+            r#"admin_token = "secret""#,
+        );
+        let config = load("admin-token", &with_token, &public).unwrap();
+        assert_eq!(config.admin_token.as_deref(), Some("secret"));
+
+        let empty_token = edit(
+            SAMPLE,
+            r#"#admin_token = "change-me""#,
+            r#"admin_token = """#,
+        );
+        assert!(matches!(
+            load("admin-empty-token", &empty_token, &[]),
+            Err(Error::InvalidAdminToken)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        assert!(matches!(
+            load("unknown-argument", SAMPLE, &["--unknown", "1"]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            load("positional-argument", SAMPLE, &["pool"]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            load("missing-value", SAMPLE, &["--network"]),
+            Err(Error::MissingArgumentValue(_))
+        ));
+        assert!(matches!(
+            load("invalid-number", SAMPLE, &["--cert-validity-sec", "soon"]),
+            Err(Error::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            Config::from_args(vec!["--help".to_string()].into_iter()),
+            Err(Error::HelpRequested)
+        ));
+    }
+
+    #[test]
+    fn rejects_a_missing_or_invalid_config_file() {
+        let missing = config_dir("missing-file").join("missing.toml");
+        assert!(matches!(
+            Config::from_args(vec!["-c".to_string(), missing.display().to_string()].into_iter()),
+            Err(Error::ReadFile(..))
+        ));
+        assert!(matches!(
+            load("invalid-toml", "listen_address = ", &[]),
+            Err(Error::InvalidToml(..))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_addresses_and_network() {
+        assert!(matches!(
+            load("socket-address", SAMPLE, &["--tp-address", "localhost"]),
+            Err(Error::InvalidSocketAddress("tp_address", _))
+        ));
+        assert!(matches!(
+            load("metrics-address", SAMPLE, &["--metrics-address", "9184"]),
+            Err(Error::InvalidSocketAddress("metrics_address", _))
+        ));
+        assert!(matches!(
+            load("network", SAMPLE, &["--network", "litecoin"]),
+            Err(Error::InvalidNetwork(_))
+        ));
+    }
+
+    #[test]
+    fn coinbase_outputs_need_exactly_one_of_address_and_script_pubkey() {
+        let both = edit(
+            SAMPLE,
+            ADDRESS_OUTPUT,
+            &format!(
+                r#"{{ address = "tb1q2vfxp232rx0z9rzn0hay9jptagk8c86d0gwv99", script_pubkey = "{}", share = 1 }},"#,
+                SCRIPT
+            ),
+        );
+        assert!(matches!(
+            load("both-scripts", &both, &[]),
+            Err(Error::CoinbaseOutput)
+        ));
+
+        let none = edit(SAMPLE, ADDRESS_OUTPUT, "{ share = 1 },");
+        assert!(matches!(
+            load("no-script", &none, &[]),
+            Err(Error::CoinbaseOutput)
+        ));
+
+        let script = edit(
+            SAMPLE,
+            ADDRESS_OUTPUT,
+            &format!(r#"{{ script_pubkey = "{}", share = 1 }},"#, SCRIPT),
+        );
+        assert!(load("script", &script, &[]).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_coinbase_outputs() {
+        assert!(matches!(
+            load("address", SAMPLE, &["--coinbase-address", "not-an-address"]),
+            Err(Error::InvalidCoinbaseAddress(_))
+        ));
+        assert!(matches!(
+            load("address-network", SAMPLE, &["--network", "bitcoin"]),
+            Err(Error::CoinbaseAddressNetwork(_, Network::Bitcoin))
+        ));
+        assert!(matches!(
+            load("script-hex", SAMPLE, &["--coinbase-script-pubkey", "zz"]),
+            Err(Error::InvalidCoinbaseScriptPubkey(_))
+        ));
+        let zero_share = edit(
+            SAMPLE,
+            ADDRESS_OUTPUT,
+            r#"{ address = "tb1q2vfxp232rx0z9rzn0hay9jptagk8c86d0gwv99", share = 0 },"#,
+        );
+        assert!(matches!(
+            load("zero-share", &zero_share, &[]),
+            Err(Error::InvalidCoinbaseShares)
+        ));
+        let no_outputs = edit(SAMPLE, ADDRESS_OUTPUT, r#"# no outputs"#);
+        assert!(matches!(
+            load("no-outputs", &no_outputs, &[]),
+            Err(Error::InvalidCoinbaseShares)
+        ));
+    }
+
+    #[test]
+    fn signet_accepts_testnet_addresses() {
+        assert!(load("signet", SAMPLE, &["--network", "signet"]).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_key_files() {
+        let dir = config_dir("short-key");
+        std::fs::write(dir.join("short_key"), "d70b2f4e").unwrap();
+        let short_key = dir.join("short_key").display().to_string();
+        assert!(matches!(
+            load(
+                "short-key",
+                SAMPLE,
+                &["--authority-public-key-file", &short_key]
+            ),
+            Err(Error::InvalidKeyFile(_))
+        ));
+
+        let dir = config_dir("not-hex-key");
+        std::fs::write(dir.join("not_hex_key"), "z".repeat(64)).unwrap();
+        let not_hex_key = dir.join("not_hex_key").display().to_string();
+        assert!(matches!(
+            load(
+                "not-hex-key",
+                SAMPLE,
+                &["--authority-private-key-file", &not_hex_key]
+            ),
+            Err(Error::InvalidKeyFile(_))
+        ));
+
+        assert!(matches!(
+            load(
+                "missing-key",
+                SAMPLE,
+                &["--authority-private-key-file", "/nonexistent/key"]
+            ),
+            Err(Error::ReadFile(..))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_channel_values() {
+        assert!(matches!(
+            load("cert-validity", SAMPLE, &["--cert-validity-sec", "0"]),
+            Err(Error::InvalidCertValidity)
+        ));
+        assert!(matches!(
+            load("target-len", SAMPLE, &["--channel-initial-target", "0001"]),
+            Err(Error::InvalidTarget(_))
+        ));
+        let vardiff = edit(
+            SAMPLE,
+            "#[channel.vardiff]\n#shares_per_minute = 6.0",
+            "[channel.vardiff]\nshares_per_minute = 0.0\nwindow_sec = 300\nretarget_interval_sec = 60\n#",
+        );
+        assert!(matches!(
+            load("vardiff", &vardiff, &[]),
+            Err(Error::InvalidVardiff)
+        ));
+        let rotation = edit(
+            SAMPLE,
+            "#extranonce_prefix_rotation_sec = 3600",
+            "extranonce_prefix_rotation_sec = 0",
+        );
+        assert!(matches!(
+            load("rotation", &rotation, &[]),
+            Err(Error::InvalidExtranoncePrefixRotation)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_payouts() {
+        for (test, fee) in [("fee-above", "100.5"), ("fee-below", "-1.0")].iter() {
+            let config = edit(
+                SAMPLE,
+                "fee_percent = 1.0",
+                &format!("fee_percent = {}", fee),
+            );
+            assert!(matches!(
+                load(test, &config, &[]),
+                Err(Error::InvalidPoolFee)
+            ));
+        }
+        let no_fee = edit(SAMPLE, "fee_percent = 1.0", "");
+        assert_eq!(load("no-fee", &no_fee, &[]).ok().unwrap().payout.fee, 0.0);
+
+        let no_window = edit(SAMPLE, "pplns_window = 2.0", "");
+        assert!(matches!(
+            load("no-window", &no_window, &[]),
+            Err(Error::InvalidPplnsWindow)
+        ));
+        let zero_window = edit(SAMPLE, "pplns_window = 2.0", "pplns_window = 0.0");
+        assert!(matches!(
+            load("zero-window", &zero_window, &[]),
+            Err(Error::InvalidPplnsWindow)
+        ));
+        let pps = edit(SAMPLE, r#"scheme = "pplns""#, r#"scheme = "pps""#);
+        assert!(matches!(
+            load("pps", &pps, &[]).ok().unwrap().payout.scheme,
+            PayoutScheme::Pps
+        ));
+        let scheme = edit(SAMPLE, r#"scheme = "pplns""#, r#"scheme = "fpps""#);
+        assert!(matches!(
+            load("scheme", &scheme, &[]),
+            Err(Error::InvalidPayoutScheme(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_share_log() {
+        let rotate = edit(SAMPLE, "rotate_size_mb = 64", "rotate_size_mb = 0");
+        assert!(matches!(
+            load("rotate-size", &rotate, &[]),
+            Err(Error::InvalidShareLog)
+        ));
+        let fsync = edit(SAMPLE, "fsync_interval_ms = 100", "fsync_interval_ms = 0");
+        assert!(matches!(
+            load("fsync-interval", &fsync, &[]),
+            Err(Error::InvalidShareLog)
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_logging() {
+        assert!(matches!(
+            load("log-format", SAMPLE, &["--log-format", "xml"]),
+            Err(Error::InvalidLogging(_))
+        ));
+        assert!(matches!(
+            load("log-level", SAMPLE, &["--log-level", "pool=loud"]),
+            Err(Error::InvalidLogging(_))
+        ));
+    }
+}
@@ -0,0 +1,115 @@
+use super::JobNegotiatorDownstream;
+use roles_logic_sv2::{
+    errors::Error,
+    handlers::job_negotiation::{ParseClientJobNegotiationMessages, SendTo},
+    job_negotiation_sv2::*,
+    parsers::JobNegotiation,
+    utils::{tx_hash, tx_short_hash},
+};
+use std::convert::TryInto;
+
+impl ParseClientJobNegotiationMessages for JobNegotiatorDownstream {
+    fn handle_allocate_mining_job_token(
+        &mut self,
+        m: AllocateMiningJobToken,
+    ) -> Result<SendTo, Error> {
+        let mining_job_token = self
+            .committed_jobs
+            .safe_lock(|c| c.allocate_token())
+            .unwrap();
+        let coinbase_output_max_additional_size = self
+            .job_creators
+            .safe_lock(|jc| jc.coinbase_outputs_size())
+            .unwrap();
+        Ok(SendTo::Respond(
+            JobNegotiation::AllocateMiningJobTokenSuccess(AllocateMiningJobTokenSuccess {
+                request_id: m.request_id,
+                mining_job_token,
+                coinbase_output_max_additional_size,
+                // Custom jobs are accepted only after CommitMiningJob.Success
+                async_mining_allowed: false,
+            }),
+        ))
+    }
+
+    fn handle_commit_mining_job(&mut self, m: CommitMiningJob) -> Result<SendTo, Error> {
+        let is_allocated = self
+            .committed_jobs
+            .safe_lock(|c| c.is_allocated(m.mining_job_token))
+            .unwrap();
+        if !is_allocated {
+            return Ok(Self::commit_error(m.request_id, "invalid-mining-job-token"));
+        }
+        if let Err(error_code) = self.check_coinbase_outputs(&m) {
+            return Ok(Self::commit_error(m.request_id, error_code));
+        }
+        let pending = self.pending_commit(&m);
+        let has_collisions = pending.has_collisions;
+        self.pending_commits.insert(m.request_id, pending);
+        if has_collisions {
+            Ok(SendTo::Respond(JobNegotiation::IdentifyTransactions(
+                IdentifyTransactions {
+                    request_id: m.request_id,
+                },
+            )))
+        } else {
+            self.try_commit(m.request_id)
+        }
+    }
+
+    fn handle_identify_transactions_success(
+        &mut self,
+        m: IdentifyTransactionsSuccess,
+    ) -> Result<SendTo, Error> {
+        let tx_hashes = m.tx_hash_list.to_vec();
+        let pending = self
+            .pending_commits
+            .get(&m.request_id)
+            .ok_or(Error::UnknownRequestId(m.request_id))?;
+        if tx_hashes.len() != pending.transactions.len() {
+            self.pending_commits.remove(&m.request_id);
+            return Ok(Self::commit_error(m.request_id, "invalid-tx-hash-list"));
+        }
+        let mut transactions = Vec::with_capacity(tx_hashes.len());
+        for (i, hash) in tx_hashes.iter().enumerate() {
+            if tx_short_hash(hash, pending.tx_short_hash_nonce) != pending.tx_short_hash_list[i] {
+                self.pending_commits.remove(&m.request_id);
+                return Ok(Self::commit_error(m.request_id, "invalid-tx-hash-list"));
+            }
+            let hash: [u8; 32] = hash[..].try_into().unwrap();
+            transactions.push(self.known_transaction(&hash));
+        }
+        // Safe unwrap checked above
+        self.pending_commits
+            .get_mut(&m.request_id)
+            .unwrap()
+            .transactions = transactions;
+        self.try_commit(m.request_id)
+    }
+
+    fn handle_provide_missing_transactions_success(
+        &mut self,
+        m: ProvideMissingTransactionsSuccess,
+    ) -> Result<SendTo, Error> {
+        let pending = self
+            .pending_commits
+            .get_mut(&m.request_id)
+            .ok_or(Error::UnknownRequestId(m.request_id))?;
+        let missing = pending.missing_positions();
+        let transactions = m.transaction_list.to_vec();
+        if transactions.len() != missing.len() {
+            self.pending_commits.remove(&m.request_id);
+            return Ok(Self::commit_error(m.request_id, "invalid-transaction-list"));
+        }
+        for (position, transaction) in missing.into_iter().zip(transactions) {
+            let position = position as usize;
+            let short_hash = tx_short_hash(&tx_hash(&transaction), pending.tx_short_hash_nonce);
+            if short_hash != pending.tx_short_hash_list[position] {
+                self.pending_commits.remove(&m.request_id);
+                return Ok(Self::commit_error(m.request_id, "invalid-transaction-list"));
+            }
+            pending.transactions[position] = Some(transaction);
+        }
+        self.try_commit(m.request_id)
+    }
+}
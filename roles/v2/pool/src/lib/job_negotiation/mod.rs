@@ -0,0 +1,587 @@
+use crate::{
+    lib::{config::Config, template_receiver::TemplatesTransactions},
+    EitherFrame, StdFrame,
+};
+use async_channel::{Receiver, Sender};
+use bitcoin::{consensus::deserialize, TxOut};
+use codec_sv2::{Frame, HandshakeRole, Responder};
+use network_helpers::noise_connection_tokio::Connection;
+use roles_logic_sv2::{
+    errors::Error,
+    handlers::job_negotiation::{ParseClientJobNegotiationMessages, SendTo},
+    job_creator::JobsCreators,
+    job_negotiation_sv2::{CommitMiningJob, CommitMiningJobError, CommitMiningJobSuccess},
+    mining_sv2::SetCustomMiningJob,
+    parsers::{JobNegotiation, PoolMessages},
+    utils::{tx_hash, tx_hash_list_hash, tx_short_hash, Id, Mutex},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    sync::Arc,
+};
+use tokio::{net::TcpListener, task};
+use tracing::warn;
+
+pub mod message_handler;
+pub mod setup_connection;
+use setup_connection::SetupConnectionHandler;
+
+/// A job committed by a Job Negotiator, downstreams can mine it with SetCustomMiningJob
+#[derive(Debug, Clone)]
+pub struct CommittedJob {
+    pub coinbase_tx_outputs: Vec<Vec<u8>>,
+    pub coinbase_tx_value_remaining: u64,
+    pub transactions: Vec<Vec<u8>>,
+}
+
+/// Mining job tokens allocated to the Job Negotiators. Shared between the Job Negotiation
+/// connections, that commit the jobs, and the mining connections, that accept SetCustomMiningJob
+/// only for committed tokens.
+#[derive(Debug)]
+pub struct CommittedJobs {
+    token_ids: Id,
+    // Tokens allocated and not yet committed
+    allocated: HashSet<u32>,
+    committed: HashMap<u32, CommittedJob>,
+    // Transactions known by the pool: tx_hash -> transaction data
+    transactions: HashMap<[u8; 32], Vec<u8>>,
+}
+
+impl CommittedJobs {
+    pub fn new() -> Self {
+        Self {
+            token_ids: Id::new(),
+            allocated: HashSet::new(),
+            committed: HashMap::new(),
+            transactions: HashMap::new(),
+        }
+    }
+
+    pub fn allocate_token(&mut self) -> u32 {
+        let token = self.token_ids.next();
+        self.allocated.insert(token);
+        token
+    }
+
+    pub fn is_allocated(&self, token: u32) -> bool {
+        self.allocated.contains(&token)
+    }
+
+    pub fn commit(&mut self, token: u32, job: CommittedJob) {
+        self.allocated.remove(&token);
+        for transaction in &job.transactions {
+            self.transactions
+                .insert(tx_hash(transaction), transaction.clone());
+        }
+        self.committed.insert(token, job);
+    }
+
+    /// Transactions of the committed job in block order, the coinbase is not included
+    pub fn transactions(&self, token: u32) -> Option<&[Vec<u8>]> {
+        self.committed.get(&token).map(|job| &job.transactions[..])
+    }
+
+    /// Return the error code of SetCustomMiningJob.Error if the custom job is not the committed
+    /// one
+    pub fn check_custom_job(&self, m: &SetCustomMiningJob) -> Result<(), String> {
+        let token: [u8; 4] = m
+            .mining_job_token
+            .inner_as_ref()
+            .try_into()
+            .map_err(|_| "invalid-mining-job-token".to_string())?;
+        let committed = self
+            .committed
+            .get(&u32::from_le_bytes(token))
+            .ok_or_else(|| "invalid-mining-job-token".to_string())?;
+        if committed.coinbase_tx_value_remaining != m.coinbase_tx_value_remaining {
+            return Err("invalid-job-param-value-coinbase_tx_value_remaining".to_string());
+        }
+        if committed.coinbase_tx_outputs != m.coinbase_tx_outputs.to_vec() {
+            return Err("invalid-job-param-value-coinbase_tx_outputs".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl Default for CommittedJobs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A CommitMiningJob waiting for the transactions that the pool do not know
+#[derive(Debug)]
+struct PendingCommit {
+    mining_job_token: u32,
+    coinbase_tx_outputs: Vec<Vec<u8>>,
+    coinbase_tx_value_remaining: u64,
+    tx_short_hash_nonce: u64,
+    tx_short_hash_list: Vec<u64>,
+    tx_hash_list_hash: Vec<u8>,
+    // None if the transaction in that position is still unknown
+    transactions: Vec<Option<Vec<u8>>>,
+    // True if a short hash matched more than one known transaction
+    has_collisions: bool,
+}
+
+impl PendingCommit {
+    fn missing_positions(&self) -> Vec<u16> {
+        self.transactions
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(i, _)| i as u16)
+            .collect()
+    }
+
+    fn into_committed_job(self) -> Option<CommittedJob> {
+        let transactions: Vec<Vec<u8>> = self.transactions.into_iter().collect::<Option<_>>()?;
+        Some(CommittedJob {
+            coinbase_tx_outputs: self.coinbase_tx_outputs,
+            coinbase_tx_value_remaining: self.coinbase_tx_value_remaining,
+            transactions,
+        })
+    }
+
+    fn has_valid_tx_hash_list_hash(&self) -> bool {
+        let tx_hashes: Option<Vec<[u8; 32]>> = self
+            .transactions
+            .iter()
+            .map(|tx| tx.as_ref().map(|tx| tx_hash(tx)))
+            .collect();
+        match tx_hashes {
+            Some(tx_hashes) => tx_hash_list_hash(&tx_hashes).to_vec() == self.tx_hash_list_hash,
+            None => false,
+        }
+    }
+}
+
+/// Connection with a Job Negotiator
+pub struct JobNegotiatorDownstream {
+    receiver: Receiver<EitherFrame>,
+    sender: Sender<EitherFrame>,
+    committed_jobs: Arc<Mutex<CommittedJobs>>,
+    job_creators: Arc<Mutex<JobsCreators>>,
+    templates_transactions: Arc<Mutex<TemplatesTransactions>>,
+    // request_id -> PendingCommit
+    pending_commits: HashMap<u32, PendingCommit>,
+}
+
+impl JobNegotiatorDownstream {
+    pub async fn accept_connections(
+        config: Config,
+        committed_jobs: Arc<Mutex<CommittedJobs>>,
+        job_creators: Arc<Mutex<JobsCreators>>,
+        templates_transactions: Arc<Mutex<TemplatesTransactions>>,
+    ) {
+        let listner = TcpListener::bind(config.jn_address).await.unwrap();
+        while let Ok((stream, _)) = listner.accept().await {
+            // Safe unwrap the keys are validated when the config is loaded
+            let responder = Responder::from_authority_kp(
+                &config.authority_public_key[..],
+                &config.authority_private_key[..],
+                config.cert_validity,
+            )
+            .unwrap();
+            let (mut receiver, mut sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
+                Connection::new(stream, HandshakeRole::Responder(responder)).await;
+            if SetupConnectionHandler::setup(&mut receiver, &mut sender)
+                .await
+                .is_err()
+            {
+                continue;
+            }
+            let self_ = Arc::new(Mutex::new(Self {
+                receiver,
+                sender,
+                committed_jobs: committed_jobs.clone(),
+                job_creators: job_creators.clone(),
+                templates_transactions: templates_transactions.clone(),
+                pending_commits: HashMap::new(),
+            }));
+            task::spawn(async { Self::start(self_).await });
+        }
+    }
+
+    async fn start(self_: Arc<Mutex<Self>>) {
+        let receiver = self_.safe_lock(|s| s.receiver.clone()).unwrap();
+        while let Ok(incoming) = receiver.recv().await {
+            let mut incoming: StdFrame = incoming.try_into().unwrap();
+            let message_type = incoming.get_header().unwrap().msg_type();
+            let payload = incoming.payload();
+            match ParseClientJobNegotiationMessages::handle_message_job_negotiation(
+                self_.clone(),
+                message_type,
+                payload,
+            ) {
+                Ok(SendTo::Respond(message)) => {
+                    let sv2_frame: StdFrame =
+                        PoolMessages::JobNegotiation(message).try_into().unwrap();
+                    let sender = self_.safe_lock(|s| s.sender.clone()).unwrap();
+                    if sender.send(sv2_frame.into()).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(SendTo::None(_)) => (),
+                Ok(_) => panic!(),
+                Err(Error::UnexpectedMessage) => {
+                    warn!(msg_type = message_type, "unexpected message")
+                }
+                Err(e) => warn!(msg_type = message_type, "invalid message: {}", e),
+            }
+        }
+    }
+
+    /// Check that the coinbase pay the pool outputs, with the block reward split like in the
+    /// pool's jobs, and that the outputs spend exactly coinbase_tx_value_remaining. Outputs that
+    /// do not pay the pool (eg the witness commitment) must have 0 value.
+    fn check_coinbase_outputs(&self, m: &CommitMiningJob) -> Result<(), &'static str> {
+        let required_outputs = self
+            .job_creators
+            .safe_lock(|jc| jc.new_outputs(m.coinbase_tx_value_remaining))
+            .unwrap();
+        let mut outputs = Vec::new();
+        for output in m.coinbase_tx_outputs.inner_as_ref() {
+            let output: TxOut = deserialize(output).map_err(|_| "invalid-coinbase-outputs")?;
+            outputs.push(output);
+        }
+        for required in &required_outputs {
+            if !outputs.contains(required) {
+                return Err("missing-required-outputs");
+            }
+        }
+        let total_value: u64 = outputs.iter().map(|o| o.value).sum();
+        if total_value != m.coinbase_tx_value_remaining {
+            return Err("invalid-coinbase-tx-value-remaining");
+        }
+        Ok(())
+    }
+
+    /// Transactions of the committed jobs and of the templates received from the Template
+    /// Provider: tx_hash -> transaction data
+    fn known_transactions(&self) -> HashMap<[u8; 32], Vec<u8>> {
+        let mut known = self
+            .committed_jobs
+            .safe_lock(|c| c.transactions.clone())
+            .unwrap();
+        self.templates_transactions
+            .safe_lock(|t| {
+                for (hash, transaction) in t.iter() {
+                    known.entry(*hash).or_insert_with(|| transaction.clone());
+                }
+            })
+            .unwrap();
+        known
+    }
+
+    fn known_transaction(&self, tx_hash: &[u8; 32]) -> Option<Vec<u8>> {
+        self.committed_jobs
+            .safe_lock(|c| c.transactions.get(tx_hash).cloned())
+            .unwrap()
+            .or_else(|| {
+                self.templates_transactions
+                    .safe_lock(|t| t.get_by_hash(tx_hash).cloned())
+                    .unwrap()
+            })
+    }
+
+    /// Look for the transactions in the ones known by the pool, if a short hash match more than
+    /// one transaction the transaction is considered unknown and the full hashes are asked with
+    /// IdentifyTransactions
+    fn pending_commit(&self, m: &CommitMiningJob) -> PendingCommit {
+        let tx_short_hash_list = m.tx_short_hash_list.to_vec();
+        let mut known: HashMap<u64, Option<Vec<u8>>> = HashMap::new();
+        for (hash, transaction) in self.known_transactions() {
+            let short_hash = tx_short_hash(&hash, m.tx_short_hash_nonce);
+            known
+                .entry(short_hash)
+                .and_modify(|tx| *tx = None)
+                .or_insert(Some(transaction));
+        }
+        let has_collisions = tx_short_hash_list
+            .iter()
+            .any(|short_hash| matches!(known.get(short_hash), Some(None)));
+        let transactions = tx_short_hash_list
+            .iter()
+            .map(|short_hash| known.get(short_hash).cloned().flatten())
+            .collect();
+        PendingCommit {
+            mining_job_token: m.mining_job_token,
+            coinbase_tx_outputs: m.coinbase_tx_outputs.to_vec(),
+            coinbase_tx_value_remaining: m.coinbase_tx_value_remaining,
+            tx_short_hash_nonce: m.tx_short_hash_nonce,
+            tx_short_hash_list,
+            tx_hash_list_hash: m.tx_hash_list_hash.to_vec(),
+            transactions,
+            has_collisions,
+        }
+    }
+
+    /// When all the transactions are known the job is committed
+    fn try_commit(&mut self, request_id: u32) -> Result<SendTo, Error> {
+        let pending = match self.pending_commits.get(&request_id) {
+            Some(pending) => pending,
+            None => return Err(Error::UnknownRequestId(request_id)),
+        };
+        let missing = pending.missing_positions();
+        if !missing.is_empty() {
+            return Ok(SendTo::Respond(JobNegotiation::ProvideMissingTransactions(
+                roles_logic_sv2::job_negotiation_sv2::ProvideMissingTransactions {
+                    request_id,
+                    unknown_tx_position_list: missing.into(),
+                },
+            )));
+        }
+        // Safe unwrap the pending commit is in the hashmap
+        let pending = self.pending_commits.remove(&request_id).unwrap();
+        if !pending.has_valid_tx_hash_list_hash() {
+            return Ok(Self::commit_error(request_id, "invalid-tx-hash-list-hash"));
+        }
+        let mining_job_token = pending.mining_job_token;
+        // Safe unwrap all the transactions are known
+        let job = pending.into_committed_job().unwrap();
+        self.committed_jobs
+            .safe_lock(|c| c.commit(mining_job_token, job))
+            .unwrap();
+        Ok(SendTo::Respond(JobNegotiation::CommitMiningJobSuccess(
+            CommitMiningJobSuccess {
+                request_id,
+                new_mining_job_token: mining_job_token,
+            },
+        )))
+    }
+
+    fn commit_error(request_id: u32, error_code: &str) -> SendTo {
+        SendTo::Respond(JobNegotiation::CommitMiningJobError(CommitMiningJobError {
+            request_id,
+            error_code: error_code.to_string().try_into().unwrap(),
+            error_details: Vec::new().try_into().unwrap(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use binary_sv2::{Seq0255, Seq064K, B064K};
+    use bitcoin::{consensus::serialize, Script};
+    use roles_logic_sv2::job_negotiation_sv2::AllocateMiningJobToken;
+
+    const REWARD: u64 = 5_000_000_000;
+
+    fn pool_script() -> Script {
+        Script::from(vec![
+            0x00, 0x14, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20,
+        ])
+    }
+
+    fn job_negotiator() -> JobNegotiatorDownstream {
+        let (sender, receiver) = async_channel::bounded(1);
+        JobNegotiatorDownstream {
+            receiver,
+            sender,
+            committed_jobs: Arc::new(Mutex::new(CommittedJobs::new())),
+            job_creators: Arc::new(Mutex::new(
+                JobsCreators::new(REWARD, vec![(pool_script(), 1)]).unwrap(),
+            )),
+            templates_transactions: Arc::new(Mutex::new(TemplatesTransactions::new())),
+            pending_commits: HashMap::new(),
+        }
+    }
+
+    fn allocate_token(job_negotiator: &mut JobNegotiatorDownstream) -> u32 {
+        let allocate = AllocateMiningJobToken {
+            user_identifier: "job-negotiator".to_string().try_into().unwrap(),
+            request_id: 1,
+        };
+        match job_negotiator.handle_allocate_mining_job_token(allocate) {
+            Ok(SendTo::Respond(JobNegotiation::AllocateMiningJobTokenSuccess(m))) => {
+                m.mining_job_token
+            }
+            _ => panic!("token not allocated"),
+        }
+    }
+
+    fn serialized(outputs: &[TxOut]) -> Seq064K<'static, B064K<'static>> {
+        let outputs: Vec<B064K<'static>> = outputs
+            .iter()
+            .map(|output| serialize(output).try_into().unwrap())
+            .collect();
+        Seq064K::new(outputs).unwrap()
+    }
+
+    /// A job without transactions that pay `outputs`
+    fn commit_mining_job(mining_job_token: u32, outputs: &[TxOut]) -> CommitMiningJob<'static> {
+        let tx_hashes: Vec<[u8; 32]> = Vec::new();
+        CommitMiningJob {
+            request_id: 2,
+            mining_job_token,
+            version: 0x2000_0000,
+            coinbase_tx_version: 2,
+            coinbase_prefix: vec![3, 1, 0, 0].try_into().unwrap(),
+            coinbase_tx_input_n_sequence: u32::MAX,
+            coinbase_tx_value_remaining: REWARD,
+            coinbase_tx_outputs: serialized(outputs),
+            coinbase_tx_locktime: 0,
+            min_extranonce_size: 16,
+            tx_short_hash_nonce: 0,
+            tx_short_hash_list: Seq064K::new(Vec::new()).unwrap(),
+            tx_hash_list_hash: tx_hash_list_hash(&tx_hashes),
+            excess_data: Vec::new().try_into().unwrap(),
+        }
+    }
+
+    fn pool_output(value: u64) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: pool_script(),
+        }
+    }
+
+    fn other_output(value: u64) -> TxOut {
+        TxOut {
+            value,
+            script_pubkey: Script::from(vec![0x6a]),
+        }
+    }
+
+    /// Error code of the CommitMiningJob.Error, None if the job is committed
+    fn commit(job_negotiator: &mut JobNegotiatorDownstream, m: CommitMiningJob) -> Option<String> {
+        match job_negotiator.handle_commit_mining_job(m) {
+            Ok(SendTo::Respond(JobNegotiation::CommitMiningJobSuccess(_))) => None,
+            Ok(SendTo::Respond(JobNegotiation::CommitMiningJobError(m))) => {
+                Some(String::from_utf8(m.error_code.inner_as_ref().to_vec()).unwrap())
+            }
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    fn set_custom_mining_job(
+        mining_job_token: u32,
+        outputs: &[TxOut],
+    ) -> SetCustomMiningJob<'static> {
+        SetCustomMiningJob {
+            channel_id: 1,
+            request_id: 3,
+            mining_job_token: mining_job_token.to_le_bytes().to_vec().try_into().unwrap(),
+            version: 0x2000_0000,
+            prev_hash: [0; 32].into(),
+            min_ntime: 0,
+            nbits: 0,
+            coinbase_tx_version: 2,
+            coinbase_prefix: 0,
+            coinbase_tx_input_n_sequence: u32::MAX,
+            coinbase_tx_value_remaining: REWARD,
+            coinbase_tx_outputs: serialized(outputs),
+            coinbase_tx_locktime: 0,
+            merkle_path: Seq0255::new(Vec::new()).unwrap(),
+            extranonce_size: 16,
+            future_job: false,
+        }
+    }
+
+    #[test]
+    fn job_paying_the_pool_is_committed() {
+        let mut job_negotiator = job_negotiator();
+        let token = allocate_token(&mut job_negotiator);
+        let m = commit_mining_job(token, &[pool_output(REWARD), other_output(0)]);
+        assert_eq!(commit(&mut job_negotiator, m), None);
+    }
+
+    #[test]
+    fn job_without_the_pool_output_is_rejected() {
+        let mut job_negotiator = job_negotiator();
+        let token = allocate_token(&mut job_negotiator);
+        let m = commit_mining_job(token, &[other_output(REWARD)]);
+        assert_eq!(
+            commit(&mut job_negotiator, m),
+            Some("missing-required-outputs".to_string())
+        );
+    }
+
+    #[test]
+    fn job_underpaying_the_pool_is_rejected() {
+        let mut job_negotiator = job_negotiator();
+        let token = allocate_token(&mut job_negotiator);
+        let m = commit_mining_job(token, &[pool_output(REWARD - 1), other_output(1)]);
+        assert_eq!(
+            commit(&mut job_negotiator, m),
+            Some("missing-required-outputs".to_string())
+        );
+    }
+
+    #[test]
+    fn job_spending_more_than_the_value_remaining_is_rejected() {
+        let mut job_negotiator = job_negotiator();
+        let token = allocate_token(&mut job_negotiator);
+        let m = commit_mining_job(token, &[pool_output(REWARD), other_output(1)]);
+        assert_eq!(
+            commit(&mut job_negotiator, m),
+            Some("invalid-coinbase-tx-value-remaining".to_string())
+        );
+    }
+
+    #[test]
+    fn job_with_an_unknown_token_is_rejected() {
+        let mut job_negotiator = job_negotiator();
+        let token = allocate_token(&mut job_negotiator);
+        let m = commit_mining_job(token + 1, &[pool_output(REWARD)]);
+        assert_eq!(
+            commit(&mut job_negotiator, m),
+            Some("invalid-mining-job-token".to_string())
+        );
+    }
+
+    #[test]
+    fn token_can_not_be_committed_twice() {
+        let mut job_negotiator = job_negotiator();
+        let token = allocate_token(&mut job_negotiator);
+        let m = commit_mining_job(token, &[pool_output(REWARD)]);
+        assert_eq!(commit(&mut job_negotiator, m.clone()), None);
+        assert_eq!(
+            commit(&mut job_negotiator, m),
+            Some("invalid-mining-job-token".to_string())
+        );
+    }
+
+    #[test]
+    fn custom_job_must_match_the_committed_job() {
+        let mut job_negotiator = job_negotiator();
+        let token = allocate_token(&mut job_negotiator);
+        let outputs = [pool_output(REWARD)];
+        assert_eq!(
+            commit(&mut job_negotiator, commit_mining_job(token, &outputs)),
+            None
+        );
+        let committed_jobs = job_negotiator.committed_jobs.clone();
+        let check = |m: SetCustomMiningJob| {
+            committed_jobs
+                .safe_lock(|c| c.check_custom_job(&m))
+                .unwrap()
+        };
+
+        assert_eq!(check(set_custom_mining_job(token, &outputs)), Ok(()));
+        assert_eq!(
+            check(set_custom_mining_job(token + 1, &outputs)),
+            Err("invalid-mining-job-token".to_string())
+        );
+        let other_outputs = [pool_output(REWARD - 1), other_output(1)];
+        assert_eq!(
+            check(set_custom_mining_job(token, &other_outputs)),
+            Err("invalid-job-param-value-coinbase_tx_outputs".to_string())
+        );
+    }
+
+    #[test]
+    fn custom_job_with_an_uncommitted_token_is_rejected() {
+        let mut job_negotiator = job_negotiator();
+        let token = allocate_token(&mut job_negotiator);
+        let outputs = [pool_output(REWARD)];
+        let check = job_negotiator
+            .committed_jobs
+            .safe_lock(|c| c.check_custom_job(&set_custom_mining_job(token, &outputs)))
+            .unwrap();
+        assert_eq!(check, Err("invalid-mining-job-token".to_string()));
+    }
+}
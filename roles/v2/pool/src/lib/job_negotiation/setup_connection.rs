@@ -0,0 +1,66 @@
+use crate::{EitherFrame, StdFrame};
+use async_channel::{Receiver, Sender};
+use codec_sv2::Frame;
+use roles_logic_sv2::{
+    common_messages_sv2::{Protocol, SetupConnection, SetupConnectionSuccess},
+    common_properties::CommonDownstreamData,
+    errors::Error,
+    handlers::common::{ParseDownstreamCommonMessages, SendTo},
+    parsers::{CommonMessages, PoolMessages},
+    routing_logic::{CommonRoutingLogic, NoRouting},
+    utils::Mutex,
+};
+use std::{convert::TryInto, sync::Arc};
+
+/// Accept only connections that want to use the Job Negotiation Protocol
+pub struct SetupConnectionHandler {}
+
+impl SetupConnectionHandler {
+    pub async fn setup(
+        receiver: &mut Receiver<EitherFrame>,
+        sender: &mut Sender<EitherFrame>,
+    ) -> Result<(), ()> {
+        let mut incoming: StdFrame = receiver
+            .recv()
+            .await
+            .map_err(|_| ())?
+            .try_into()
+            .map_err(|_| ())?;
+        let message_type = incoming.get_header().ok_or(())?.msg_type();
+        let payload = incoming.payload();
+        let response = ParseDownstreamCommonMessages::handle_message_common(
+            Arc::new(Mutex::new(SetupConnectionHandler {})),
+            message_type,
+            payload,
+            CommonRoutingLogic::None,
+        )
+        .map_err(|_| ())?;
+
+        let message = response.into_message().ok_or(())?;
+        let sv2_frame: StdFrame = PoolMessages::Common(message.clone()).try_into().unwrap();
+        sender.send(sv2_frame.into()).await.map_err(|_| ())?;
+        match message {
+            CommonMessages::SetupConnectionSuccess(_) => Ok(()),
+            _ => Err(()),
+        }
+    }
+}
+
+impl ParseDownstreamCommonMessages<NoRouting> for SetupConnectionHandler {
+    fn handle_setup_connection(
+        &mut self,
+        incoming: SetupConnection,
+        _: Option<Result<(CommonDownstreamData, SetupConnectionSuccess), Error>>,
+    ) -> Result<SendTo, Error> {
+        match incoming.protocol {
+            Protocol::JobNegotiationProtocol => Ok(SendTo::RelayNewMessage(
+                Arc::new(Mutex::new(())),
+                CommonMessages::SetupConnectionSuccess(SetupConnectionSuccess {
+                    flags: 0,
+                    used_version: 2,
+                }),
+            )),
+            _ => Err(Error::UnexpectedMessage),
+        }
+    }
+}
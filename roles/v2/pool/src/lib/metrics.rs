@@ -0,0 +1,418 @@
+//! Operational metrics of the pool, exported in the Prometheus text format on `/metrics` when
+//! `metrics_address` is set in the config.
+//!
+//! The hash rate of a channel is estimated from the difficulty of the shares accepted in the last
+//! `HASH_RATE_WINDOW`.
+use crate::lib::share_ledger::ChannelKey;
+use network_helpers::http_tokio::{self, Response};
+use roles_logic_sv2::utils::Mutex;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tracing::error;
+
+const HASH_RATE_WINDOW: Duration = Duration::from_secs(600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChannelType {
+    Standard,
+    Extended,
+    Group,
+}
+
+impl ChannelType {
+    pub fn label(&self) -> &'static str {
+        match self {
+            ChannelType::Standard => "standard",
+            ChannelType::Extended => "extended",
+            ChannelType::Group => "group",
+        }
+    }
+}
+
+#[derive(Debug)]
+struct ChannelHashRate {
+    user_identity: String,
+    opened: Instant,
+    /// (accepted at, difficulty)
+    shares: VecDeque<(Instant, f64)>,
+}
+
+impl ChannelHashRate {
+    /// Hashes per second, a share of difficulty 1 takes 2^32 hashes on average
+    fn estimate(&mut self, now: Instant) -> f64 {
+        while let Some((accepted, _)) = self.shares.front() {
+            if now.duration_since(*accepted) <= HASH_RATE_WINDOW {
+                break;
+            }
+            self.shares.pop_front();
+        }
+        let elapsed = now.duration_since(self.opened).min(HASH_RATE_WINDOW);
+        if elapsed.as_secs_f64() == 0.0 {
+            return 0.0;
+        }
+        let work: f64 = self.shares.iter().map(|(_, difficulty)| difficulty).sum();
+        work * 2_f64.powi(32) / elapsed.as_secs_f64()
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    downstreams: u64,
+    channels: HashMap<ChannelType, u64>,
+    hash_rates: HashMap<ChannelKey, ChannelHashRate>,
+    shares_accepted: u64,
+    /// reason -> shares
+    shares_rejected: HashMap<String, u64>,
+    templates_received: u64,
+    /// Time between a prev hash received from the Template Provider and sent to every downstream
+    last_prev_hash_latency: Option<Duration>,
+    blocks_found: u64,
+}
+
+impl Metrics {
+    pub fn on_downstream_connected(&mut self) {
+        self.downstreams += 1;
+        *self.channels.entry(ChannelType::Group).or_default() += 1;
+    }
+
+    pub fn on_downstream_disconnected(&mut self) {
+        self.downstreams = self.downstreams.saturating_sub(1);
+        self.on_channel_closed(ChannelType::Group, None);
+    }
+
+    pub fn on_channel_opened(
+        &mut self,
+        channel_type: ChannelType,
+        channel: ChannelKey,
+        user_identity: String,
+    ) {
+        *self.channels.entry(channel_type).or_default() += 1;
+        self.hash_rates.insert(
+            channel,
+            ChannelHashRate {
+                user_identity,
+                opened: Instant::now(),
+                shares: VecDeque::new(),
+            },
+        );
+    }
+
+    pub fn on_channel_closed(&mut self, channel_type: ChannelType, channel: Option<ChannelKey>) {
+        if let Some(open) = self.channels.get_mut(&channel_type) {
+            *open = open.saturating_sub(1);
+        }
+        if let Some(channel) = channel {
+            self.hash_rates.remove(&channel);
+        }
+    }
+
+    pub fn on_share_accepted(&mut self, channel: ChannelKey, difficulty: f64) {
+        self.shares_accepted += 1;
+        if let Some(hash_rate) = self.hash_rates.get_mut(&channel) {
+            hash_rate.shares.push_back((Instant::now(), difficulty));
+        }
+    }
+
+    pub fn on_share_rejected(&mut self, reason: &str) {
+        *self.shares_rejected.entry(reason.to_string()).or_default() += 1;
+    }
+
+    /// (user identity, estimated hash rate in hashes per second) of an open channel
+    pub fn channel_hash_rate(&mut self, channel: ChannelKey) -> Option<(String, f64)> {
+        let hash_rate = self.hash_rates.get_mut(&channel)?;
+        Some((
+            hash_rate.user_identity.clone(),
+            hash_rate.estimate(Instant::now()),
+        ))
+    }
+
+    pub fn on_template_received(&mut self) {
+        self.templates_received += 1;
+    }
+
+    pub fn on_prev_hash_propagated(&mut self, latency: Duration) {
+        self.last_prev_hash_latency = Some(latency);
+    }
+
+    pub fn on_block_found(&mut self) {
+        self.blocks_found += 1;
+    }
+
+    /// Prometheus text format
+    pub fn encode(&mut self) -> String {
+        let now = Instant::now();
+        let mut out = String::new();
+        // Safe unwraps writing in a String never fail
+        header(
+            &mut out,
+            "sv2_pool_downstreams",
+            "gauge",
+            "Connected downstreams",
+        );
+        writeln!(out, "sv2_pool_downstreams {}", self.downstreams).unwrap();
+        header(
+            &mut out,
+            "sv2_pool_channels",
+            "gauge",
+            "Open channels by type",
+        );
+        for channel_type in [
+            ChannelType::Standard,
+            ChannelType::Extended,
+            ChannelType::Group,
+        ] {
+            let open = self.channels.get(&channel_type).copied().unwrap_or(0);
+            writeln!(
+                out,
+                "sv2_pool_channels{{type=\"{}\"}} {}",
+                channel_type.label(),
+                open
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "sv2_pool_shares_accepted_total",
+            "counter",
+            "Accepted shares",
+        );
+        writeln!(
+            out,
+            "sv2_pool_shares_accepted_total {}",
+            self.shares_accepted
+        )
+        .unwrap();
+        header(
+            &mut out,
+            "sv2_pool_shares_rejected_total",
+            "counter",
+            "Rejected shares by reason",
+        );
+        for (reason, shares) in &self.shares_rejected {
+            writeln!(
+                out,
+                "sv2_pool_shares_rejected_total{{reason=\"{}\"}} {}",
+                escape(reason),
+                shares
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "sv2_pool_channel_hashrate",
+            "gauge",
+            "Hash rate of the channel estimated from its shares in hashes per second",
+        );
+        let mut user_hash_rates: HashMap<String, f64> = HashMap::new();
+        for ((group_id, channel_id), hash_rate) in self.hash_rates.iter_mut() {
+            let estimate = hash_rate.estimate(now);
+            *user_hash_rates
+                .entry(hash_rate.user_identity.clone())
+                .or_default() += estimate;
+            writeln!(
+                out,
+                "sv2_pool_channel_hashrate{{group=\"{}\",channel=\"{}\",user=\"{}\"}} {}",
+                group_id,
+                channel_id,
+                escape(&hash_rate.user_identity),
+                estimate
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "sv2_pool_user_hashrate",
+            "gauge",
+            "Hash rate of the open channels of the user in hashes per second",
+        );
+        for (user_identity, estimate) in user_hash_rates {
+            writeln!(
+                out,
+                "sv2_pool_user_hashrate{{user=\"{}\"}} {}",
+                escape(&user_identity),
+                estimate
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "sv2_pool_templates_received_total",
+            "counter",
+            "Templates received from the Template Provider",
+        );
+        writeln!(
+            out,
+            "sv2_pool_templates_received_total {}",
+            self.templates_received
+        )
+        .unwrap();
+        if let Some(latency) = self.last_prev_hash_latency {
+            header(
+                &mut out,
+                "sv2_pool_prev_hash_latency_seconds",
+                "gauge",
+                "Time to send the last prev hash to every downstream",
+            );
+            writeln!(
+                out,
+                "sv2_pool_prev_hash_latency_seconds {}",
+                latency.as_secs_f64()
+            )
+            .unwrap();
+        }
+        header(
+            &mut out,
+            "sv2_pool_blocks_found_total",
+            "counter",
+            "Blocks found",
+        );
+        writeln!(out, "sv2_pool_blocks_found_total {}", self.blocks_found).unwrap();
+        out
+    }
+
+    /// Serve the metrics on `http://<address>/metrics`
+    pub async fn serve(self_: Arc<Mutex<Self>>, address: SocketAddr) {
+        let served = http_tokio::serve(address, move |request| match request.path.as_str() {
+            "/metrics" => Response::new(
+                200,
+                "text/plain; version=0.0.4",
+                self_.safe_lock(|m| m.encode()).unwrap(),
+            ),
+            _ => Response::not_found(),
+        })
+        .await;
+        if let Err(e) = served {
+            error!(%address, "metrics not served: {}", e);
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, metric_type: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+/// Label values are quoted
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Value of the sample `name`, written with its labels as in the output
+    fn sample<'a>(out: &'a str, name: &str) -> Option<&'a str> {
+        out.lines()
+            .filter(|line| !line.starts_with('#'))
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+    }
+
+    #[test]
+    fn every_sample_follows_the_help_and_type_of_its_metric() {
+        let mut metrics = Metrics::default();
+        metrics.on_downstream_connected();
+        metrics.on_channel_opened(ChannelType::Standard, (1, 2), "alice".to_string());
+        metrics.on_share_rejected("stale-share");
+        metrics.on_prev_hash_propagated(Duration::from_millis(1500));
+        let out = metrics.encode();
+
+        let mut described: Option<&str> = None;
+        let mut typed: Option<&str> = None;
+        for line in out.lines() {
+            if let Some(help) = line.strip_prefix("# HELP ") {
+                described = help.split(' ').next();
+            } else if let Some(metric_type) = line.strip_prefix("# TYPE ") {
+                let mut parts = metric_type.split(' ');
+                typed = parts.next();
+                assert_eq!(typed, described, "TYPE without HELP: {}", line);
+                assert!(matches!(parts.next(), Some("counter") | Some("gauge")));
+            } else {
+                let name = line.split(&['{', ' '][..]).next().unwrap();
+                assert_eq!(Some(name), typed, "sample without TYPE: {}", line);
+                let value = line.rsplit(' ').next().unwrap();
+                assert!(value.parse::<f64>().is_ok(), "invalid value: {}", line);
+                if name.ends_with("_total") {
+                    assert!(out.contains(&format!("# TYPE {} counter\n", name)));
+                }
+            }
+        }
+        assert!(out.ends_with('\n'));
+    }
+
+    #[test]
+    fn samples_follow_the_events() {
+        let mut metrics = Metrics::default();
+        metrics.on_downstream_connected();
+        metrics.on_downstream_connected();
+        metrics.on_channel_opened(ChannelType::Extended, (1, 2), "alice".to_string());
+        metrics.on_share_accepted((1, 2), 1.0);
+        metrics.on_share_rejected("stale-share");
+        metrics.on_share_rejected("stale-share");
+        metrics.on_template_received();
+        metrics.on_block_found();
+        metrics.on_downstream_disconnected();
+        let out = metrics.encode();
+
+        assert_eq!(sample(&out, "sv2_pool_downstreams"), Some("1"));
+        assert_eq!(
+            sample(&out, r#"sv2_pool_channels{type="group"}"#),
+            Some("1")
+        );
+        assert_eq!(
+            sample(&out, r#"sv2_pool_channels{type="extended"}"#),
+            Some("1")
+        );
+        assert_eq!(
+            sample(&out, r#"sv2_pool_channels{type="standard"}"#),
+            Some("0")
+        );
+        assert_eq!(sample(&out, "sv2_pool_shares_accepted_total"), Some("1"));
+        assert_eq!(
+            sample(
+                &out,
+                r#"sv2_pool_shares_rejected_total{reason="stale-share"}"#
+            ),
+            Some("2")
+        );
+        assert_eq!(sample(&out, "sv2_pool_templates_received_total"), Some("1"));
+        assert_eq!(sample(&out, "sv2_pool_blocks_found_total"), Some("1"));
+        let channel_hash_rate = sample(
+            &out,
+            r#"sv2_pool_channel_hashrate{group="1",channel="2",user="alice"}"#,
+        );
+        assert!(channel_hash_rate.is_some());
+        assert_eq!(
+            sample(&out, r#"sv2_pool_user_hashrate{user="alice"}"#),
+            channel_hash_rate
+        );
+        // Exported only once a prev hash has been propagated
+        assert!(!out.contains("sv2_pool_prev_hash_latency_seconds"));
+        metrics.on_prev_hash_propagated(Duration::from_millis(1500));
+        assert_eq!(
+            sample(&metrics.encode(), "sv2_pool_prev_hash_latency_seconds"),
+            Some("1.5")
+        );
+    }
+
+    #[test]
+    fn label_values_are_escaped() {
+        let mut metrics = Metrics::default();
+        metrics.on_channel_opened(ChannelType::Standard, (1, 2), "a\"b\\c\nd".to_string());
+        metrics.on_share_rejected("bad \"reason\"");
+        let out = metrics.encode();
+
+        assert!(out.contains(r#"user="a\"b\\c\nd""#));
+        assert!(out.contains(r#"reason="bad \"reason\"""#));
+        // The new line does not split the sample
+        assert!(out.lines().all(|line| !line.starts_with('d')));
+    }
+}
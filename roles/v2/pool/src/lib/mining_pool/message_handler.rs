@@ -1,9 +1,16 @@
-use crate::lib::mining_pool::{Downstream, VelideateTargetResult};
+use crate::lib::{
+    metrics::ChannelType,
+    mining_pool::{u256_to_block_hash, Downstream, VelideateTargetResult, CUSTOM_JOB_TEMPLATE_ID},
+};
 use binary_sv2::U256;
-use bitcoin::util::uint::Uint256;
+use bitcoin::{
+    hashes::hex::{FromHex, ToHex},
+    util::uint::Uint256,
+};
 use roles_logic_sv2::{
     errors::Error,
     handlers::mining::{ParseDownstreamMiningMessages, SendTo, SupportedChannelTypes},
+    job_creator::extended_job_from_custom_job,
     mining_sv2::*,
     parsers::Mining,
     routing_logic::NoRouting,
@@ -12,28 +19,37 @@ use roles_logic_sv2::{
 };
 use std::{convert::TryInto, sync::Arc};
 
-// [h/s] Expected hash rate of the device (or cumulative hashrate on the
-// channel if multiple devices are connected downstream) in h/s.
-// Depending on server’s target setting policy, this value can be used for
-// setting a reasonable target for the channel. Proxy MUST send 0.0f when
-// there are no mining devices connected yet.
-pub fn hash_rate_to_target(_hs: f32) -> U256<'static> {
-    vec![
-        0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
-        0, 0,
-    ]
-    .try_into()
-    .unwrap()
-}
-
+/// Targets are little endian on the wire
 #[allow(clippy::many_single_char_names)]
 pub fn u256_to_uint_256(v: U256<'static>) -> Uint256 {
     let bs = v.to_vec();
-    let a = u64::from_be_bytes(bs[0..8].try_into().unwrap());
-    let b = u64::from_be_bytes(bs[8..16].try_into().unwrap());
-    let c = u64::from_be_bytes(bs[16..24].try_into().unwrap());
-    let d = u64::from_be_bytes(bs[24..32].try_into().unwrap());
-    Uint256([d, c, b, a])
+    let a = u64::from_le_bytes(bs[0..8].try_into().unwrap());
+    let b = u64::from_le_bytes(bs[8..16].try_into().unwrap());
+    let c = u64::from_le_bytes(bs[16..24].try_into().unwrap());
+    let d = u64::from_le_bytes(bs[24..32].try_into().unwrap());
+    Uint256([a, b, c, d])
+}
+
+pub fn uint_256_to_u256(v: Uint256) -> U256<'static> {
+    let mut bs = Vec::with_capacity(32);
+    for limb in v.0.iter() {
+        bs.extend_from_slice(&limb.to_le_bytes());
+    }
+    // below unwrap never panic bs is 32 bytes
+    bs.try_into().unwrap()
+}
+
+/// Targets in the config and in the admin API are big endian hex, as the block hashes are shown
+pub fn target_from_hex(hex: &str) -> Option<U256<'static>> {
+    let mut target = Vec::<u8>::from_hex(hex).ok()?;
+    target.reverse();
+    target.try_into().ok()
+}
+
+pub fn target_to_hex(target: &U256) -> String {
+    let mut target = target.to_vec();
+    target.reverse();
+    target.to_hex()
 }
 
 impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting> for Downstream {
@@ -42,7 +58,7 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
     }
 
     fn is_work_selection_enabled(&self) -> bool {
-        false
+        self.downstream_data.work_selection
     }
 
     fn handle_open_standard_mining_channel(
@@ -51,7 +67,6 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         _m: Option<Arc<Mutex<()>>>,
     ) -> Result<SendTo<()>, Error> {
         let request_id = incoming.get_request_id_as_u32();
-        let target = hash_rate_to_target(incoming.nominal_hash_rate);
         let extranonce_prefix = self
             .extranonces
             .safe_lock(|e| e.next_standard().unwrap().into_b032())
@@ -59,6 +74,12 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         let message = match (self.downstream_data.header_only, self.id) {
             (false, group_channel_id) => {
                 let channel_id = self.channel_ids.next();
+                self.on_channel_opened(
+                    ChannelType::Standard,
+                    channel_id,
+                    incoming.user_identity.inner_as_ref(),
+                );
+                let target = self.new_channel_target(channel_id, incoming.nominal_hash_rate);
                 let mut partial_job = crate::lib::mining_pool::Job::new(
                     u256_to_uint_256(target.clone()),
                     extranonce_prefix.clone().to_vec(),
@@ -98,6 +119,12 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
                 }
             }
             (true, channel_id) => {
+                self.on_channel_opened(
+                    ChannelType::Standard,
+                    channel_id,
+                    incoming.user_identity.inner_as_ref(),
+                );
+                let target = self.new_channel_target(channel_id, incoming.nominal_hash_rate);
                 let mut partial_job = crate::lib::mining_pool::Job::new(
                     u256_to_uint_256(target.clone()),
                     extranonce_prefix.clone().to_vec(),
@@ -154,7 +181,6 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
             todo!()
         };
         let request_id = incoming.get_request_id_as_u32();
-        let target = hash_rate_to_target(incoming.nominal_hash_rate);
         let extended = self
             .extranonces
             .safe_lock(|e| {
@@ -164,6 +190,12 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
             })
             .unwrap();
         let channel_id = self.channel_ids.next();
+        self.on_channel_opened(
+            ChannelType::Extended,
+            channel_id,
+            incoming.user_identity.inner_as_ref(),
+        );
+        let target = self.new_channel_target(channel_id, incoming.nominal_hash_rate);
         let mut partial_job = crate::lib::mining_pool::Job::new(
             u256_to_uint_256(target.clone()),
             extended.clone().to_vec(),
@@ -201,7 +233,7 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
             request_id,
             target,
             channel_id,
-            extranonce_size: 128,
+            extranonce_size: 16,
             extranonce_prefix: extended.try_into().unwrap(),
         };
         Ok(SendTo::RelayNewMessage(
@@ -210,35 +242,55 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         ))
     }
 
-    fn handle_update_channel(&mut self, _: UpdateChannel) -> Result<SendTo<()>, Error> {
-        todo!()
+    fn handle_update_channel(&mut self, m: UpdateChannel) -> Result<SendTo<()>, Error> {
+        let channel_id = m.channel_id;
+        let maximum_target = m.maximum_target.into_static();
+        match self.update_channel(channel_id, m.nominal_hash_rate, maximum_target) {
+            Ok(Some(set_target)) => Ok(SendTo::Respond(Mining::SetTarget(set_target))),
+            Ok(None) => Ok(SendTo::None(None)),
+            Err(error_code) => {
+                let error = UpdateChannelError {
+                    channel_id,
+                    // Safe unwrap error codes are shorter than 32 bytes
+                    error_code: error_code.to_string().try_into().unwrap(),
+                };
+                Ok(SendTo::Respond(Mining::UpdateChannelError(error)))
+            }
+        }
+    }
+
+    /// CloseChannel has no response
+    fn handle_close_channel(&mut self, m: CloseChannel) -> Result<SendTo<()>, Error> {
+        self.close_channel(m.channel_id);
+        Ok(SendTo::None(None))
     }
 
     fn handle_submit_shares_standard(
         &mut self,
         m: SubmitSharesStandard,
     ) -> Result<SendTo<()>, Error> {
-        match self.check_target(m.channel_id, m.nonce, m.version, m.ntime, None) {
-            Ok(VelideateTargetResult::LessThanBitcoinTarget(_, new_shares_sum, solution)) => {
-                // That unwrap means lose a block!!! TODO
-                self.solution_sender.try_send(solution).unwrap();
-                Ok(SendTo::Respond(Mining::SubmitSharesSuccess(
-                    SubmitSharesSuccess {
-                        channel_id: m.channel_id,
-                        last_sequence_number: m.sequence_number,
-                        new_submits_accepted_count: 1,
-                        new_shares_sum,
-                    },
-                )))
+        match self.check_target(m.channel_id, m.job_id, m.nonce, m.version, m.ntime, None) {
+            Ok(VelideateTargetResult::LessThanBitcoinTarget(
+                _,
+                new_shares_sum,
+                solution,
+                header,
+            )) => {
+                self.on_block_found(m.channel_id, solution, header);
+                Ok(self.on_share_accepted(SubmitSharesSuccess {
+                    channel_id: m.channel_id,
+                    last_sequence_number: m.sequence_number,
+                    new_submits_accepted_count: 1,
+                    new_shares_sum,
+                }))
             }
-            Ok(VelideateTargetResult::LessThanDownstreamTarget(_, new_shares_sum)) => Ok(
-                SendTo::Respond(Mining::SubmitSharesSuccess(SubmitSharesSuccess {
+            Ok(VelideateTargetResult::LessThanDownstreamTarget(_, new_shares_sum)) => Ok(self
+                .on_share_accepted(SubmitSharesSuccess {
                     channel_id: m.channel_id,
                     last_sequence_number: m.sequence_number,
                     new_submits_accepted_count: 1,
                     new_shares_sum,
                 })),
-            ),
             Ok(VelideateTargetResult::Invalid(_)) => Ok(SendTo::Respond(
                 Mining::SubmitSharesError(SubmitSharesError {
                     channel_id: m.channel_id,
@@ -256,31 +308,33 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
     ) -> Result<SendTo<()>, Error> {
         match self.check_target(
             m.channel_id,
+            m.job_id,
             m.nonce,
             m.version,
             m.ntime,
             Some(m.extranonce.inner_as_ref()),
         ) {
-            Ok(VelideateTargetResult::LessThanBitcoinTarget(_, new_shares_sum, solution)) => {
-                // That unwrap means lose a block!!! TODO
-                self.solution_sender.try_send(solution).unwrap();
-                Ok(SendTo::Respond(Mining::SubmitSharesSuccess(
-                    SubmitSharesSuccess {
-                        channel_id: m.channel_id,
-                        last_sequence_number: m.sequence_number,
-                        new_submits_accepted_count: 1,
-                        new_shares_sum,
-                    },
-                )))
+            Ok(VelideateTargetResult::LessThanBitcoinTarget(
+                _,
+                new_shares_sum,
+                solution,
+                header,
+            )) => {
+                self.on_block_found(m.channel_id, solution, header);
+                Ok(self.on_share_accepted(SubmitSharesSuccess {
+                    channel_id: m.channel_id,
+                    last_sequence_number: m.sequence_number,
+                    new_submits_accepted_count: 1,
+                    new_shares_sum,
+                }))
             }
-            Ok(VelideateTargetResult::LessThanDownstreamTarget(_, new_shares_sum)) => Ok(
-                SendTo::Respond(Mining::SubmitSharesSuccess(SubmitSharesSuccess {
+            Ok(VelideateTargetResult::LessThanDownstreamTarget(_, new_shares_sum)) => Ok(self
+                .on_share_accepted(SubmitSharesSuccess {
                     channel_id: m.channel_id,
                     last_sequence_number: m.sequence_number,
                     new_submits_accepted_count: 1,
                     new_shares_sum,
                 })),
-            ),
             Ok(VelideateTargetResult::Invalid(_)) => Ok(SendTo::Respond(
                 Mining::SubmitSharesError(SubmitSharesError {
                     channel_id: m.channel_id,
@@ -292,7 +346,82 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         }
     }
 
-    fn handle_set_custom_mining_job(&mut self, _: SetCustomMiningJob) -> Result<SendTo<()>, Error> {
-        todo!()
+    fn handle_set_custom_mining_job(&mut self, m: SetCustomMiningJob) -> Result<SendTo<()>, Error> {
+        if !self.prefixes.contains_key(&m.channel_id) {
+            return Ok(Self::custom_job_error(&m, "invalid-channel-id"));
+        }
+        let checked = self
+            .committed_jobs
+            .safe_lock(|c| c.check_custom_job(&m))
+            .unwrap();
+        if let Err(error_code) = checked {
+            return Ok(Self::custom_job_error(&m, &error_code));
+        }
+        let job_id = self.job_ids.next();
+        let extended_job =
+            match extended_job_from_custom_job(&m, job_id, self.downstream_data.version_rolling) {
+                Ok(extended_job) => extended_job,
+                Err(Error::InvalidCoinbasePrefix) => {
+                    return Ok(Self::custom_job_error(
+                        &m,
+                        "invalid-job-param-value-coinbase_prefix",
+                    ))
+                }
+                Err(_) => {
+                    return Ok(Self::custom_job_error(
+                        &m,
+                        "invalid-job-param-value-coinbase_tx_outputs",
+                    ))
+                }
+            };
+        self.previous_prefix_jobs.remove(&m.channel_id);
+        // Safe unwrap the channel is in prefixes so it has a job
+        self.jobs.get_mut(&m.channel_id).unwrap().update_job(
+            &extended_job,
+            m.nbits,
+            u256_to_block_hash(m.prev_hash.clone().into_static()),
+            CUSTOM_JOB_TEMPLATE_ID,
+        );
+        // Safe unwrap the token is checked by check_custom_job
+        let mining_job_token: [u8; 4] = m.mining_job_token.inner_as_ref().try_into().unwrap();
+        self.custom_job_channels
+            .insert(m.channel_id, u32::from_le_bytes(mining_job_token));
+        Ok(SendTo::Respond(Mining::SetCustomMiningJobSuccess(
+            SetCustomMiningJobSuccess {
+                channel_id: m.channel_id,
+                request_id: m.request_id,
+                job_id,
+                coinbase_tx_prefix: extended_job.coinbase_tx_prefix,
+                coinbase_tx_suffix: extended_job.coinbase_tx_suffix,
+            },
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wire_targets_are_little_endian() {
+        let mut bytes = [0_u8; 32];
+        bytes[0] = 1;
+        bytes[31] = 2;
+        let target: U256<'static> = bytes.into();
+        let expect = Uint256([1, 0, 0, 2 << 56]);
+        assert_eq!(u256_to_uint_256(target.clone()), expect);
+        assert_eq!(uint_256_to_u256(expect), target);
+    }
+
+    #[test]
+    fn hex_targets_are_big_endian() {
+        let hex = "0001000000000000000000000000000000000000000000000000000000000000";
+        let target = target_from_hex(hex).unwrap();
+        assert_eq!(
+            u256_to_uint_256(target.clone()),
+            Uint256([0, 0, 0, 1 << 48])
+        );
+        assert_eq!(target_to_hex(&target), hex);
+        assert!(target_from_hex("0001").is_none());
     }
 }
@@ -2,29 +2,64 @@ use codec_sv2::{HandshakeRole, Responder};
 use network_helpers::noise_connection_tokio::Connection;
 use tokio::{net::TcpListener, task};
 
-use crate::{EitherFrame, StdFrame};
+use crate::{
+    lib::{
+        admin,
+        block_archive::{self, BlockArchive},
+        config::Config,
+        job_negotiation::{CommittedJobs, JobNegotiatorDownstream},
+        metrics::{ChannelType, Metrics},
+        share_ledger::{target_to_difficulty, ShareLedger},
+        template_receiver::TemplatesTransactions,
+    },
+    EitherFrame, StdFrame,
+};
 use async_channel::{Receiver, Sender};
-use binary_sv2::{B064K, U256};
+use binary_sv2::{Seq0255, B064K, U256};
 use bitcoin::{
     blockdata::block::BlockHeader,
+    consensus::deserialize,
     hash_types::BlockHash,
     hashes::{sha256d::Hash, Hash as Hash_},
     util::uint::Uint256,
-    TxMerkleNode,
+    Transaction, TxMerkleNode,
 };
 use codec_sv2::Frame;
 use roles_logic_sv2::{
     common_properties::{CommonDownstreamData, IsDownstream, IsMiningDownstream},
     errors::Error,
     handlers::mining::{ParseDownstreamMiningMessages, SendTo},
-    job_creator::JobsCreators,
-    mining_sv2::{ExtendedExtranonce, NewExtendedMiningJob, SetNewPrevHash as NewPrevHash},
+    job_creator::{coinbase_with_witness, JobsCreators},
+    mining_sv2::{
+        ExtendedExtranonce, NewExtendedMiningJob, NewMiningJob, SetCustomMiningJob,
+        SetCustomMiningJobError, SetExtranoncePrefix, SetNewPrevHash as NewPrevHash, SetTarget,
+        SubmitSharesSuccess,
+    },
     parsers::{Mining, PoolMessages},
     routing_logic::MiningRoutingLogic,
     template_distribution_sv2::{NewTemplate, SetNewPrevHash, SubmitSolution},
     utils::{merkle_root_from_path, Id, Mutex},
+    vardiff::{Vardiff, VardiffConfig},
+};
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use std::{collections::HashMap, convert::TryInto, sync::Arc};
+use tracing::{debug, error, info, info_span, warn, Instrument, Span};
+
+/// Template id of the jobs created with SetCustomMiningJob, the Template Provider do not know them
+pub const CUSTOM_JOB_TEMPLATE_ID: u64 = u64::MAX;
+
+/// Sum of the outputs of the coinbase of the solution
+fn coinbase_reward(solution: &SubmitSolution<'static>) -> u64 {
+    deserialize::<Transaction>(solution.coinbase_tx.inner_as_ref())
+        .map(|coinbase| coinbase.output.iter().map(|output| output.value).sum())
+        .unwrap_or(0)
+}
 
 pub fn u256_to_block_hash(v: U256<'static>) -> BlockHash {
     let hash: [u8; 32] = v.to_vec().try_into().unwrap();
@@ -36,6 +71,7 @@ pub mod setup_connection;
 use setup_connection::SetupConnectionHandler;
 
 pub mod message_handler;
+use message_handler::{u256_to_uint_256, uint_256_to_u256};
 
 #[derive(Debug, Clone)]
 struct PartialJob {
@@ -66,6 +102,8 @@ impl PartialJob {
             target: self.target,
             nbits,
             prev_hash,
+            version: new_ext_job.version,
+            version_rolling_allowed: new_ext_job.version_rolling_allowed,
             new_shares_sum: 0,
             coinbase_tx_prefix: new_ext_job.coinbase_tx_prefix.to_vec(),
             coinbase_tx_suffix: new_ext_job.coinbase_tx_suffix.to_vec(),
@@ -82,29 +120,35 @@ struct CompleteJob {
     target: Uint256,
     nbits: u32,
     prev_hash: BlockHash,
+    version: u32,
+    version_rolling_allowed: bool,
     new_shares_sum: u64,
     coinbase_tx_suffix: Vec<u8>,
     coinbase_tx_prefix: Vec<u8>,
     extranonce: Vec<u8>,
-    #[allow(dead_code)]
     merkle_path: Vec<Vec<u8>>,
     merkle_root: TxMerkleNode,
 }
 
 #[derive(Debug)]
 pub enum VelideateTargetResult {
-    LessThanBitcoinTarget(BlockHash, u64, SubmitSolution<'static>),
+    LessThanBitcoinTarget(BlockHash, u64, SubmitSolution<'static>, BlockHeader),
     LessThanDownstreamTarget(BlockHash, u64),
     Invalid(BlockHash),
 }
 
 impl CompleteJob {
-    pub fn get_coinbase(&self) -> B064K<'static> {
+    /// The coinbase as it must be included in the block (segwit serialized when it commits to the
+    /// witnesses)
+    pub fn get_coinbase(&self, extranonce: &[u8]) -> B064K<'static> {
         let mut coinbase = Vec::new();
         coinbase.extend(self.coinbase_tx_prefix.clone());
-        coinbase.extend(self.extranonce.clone());
+        coinbase.extend(extranonce);
         coinbase.extend(self.coinbase_tx_suffix.clone());
-        coinbase.try_into().unwrap()
+        coinbase_with_witness(&coinbase)
+            .unwrap()
+            .try_into()
+            .unwrap()
     }
     pub fn validate_target(
         &mut self,
@@ -113,8 +157,8 @@ impl CompleteJob {
         ntime: u32,
         extranonce_suffix: Option<&[u8]>,
     ) -> VelideateTargetResult {
-        let merkle_root = match extranonce_suffix {
-            None => self.merkle_root,
+        let (merkle_root, extranonce) = match extranonce_suffix {
+            None => (self.merkle_root, self.extranonce.clone()),
             Some(suffix) => {
                 let mid_point = self.extranonce.len() - suffix.len();
                 let extranonce = [&self.extranonce[0..mid_point], suffix].concat();
@@ -129,7 +173,7 @@ impl CompleteJob {
                 .try_into()
                 .unwrap();
                 let merkle_root = Hash::from_inner(merkle_root);
-                TxMerkleNode::from_hash(merkle_root)
+                (TxMerkleNode::from_hash(merkle_root), extranonce)
             }
         };
         // TODO  how should version be transoformed from u32 into i32???
@@ -156,9 +200,14 @@ impl CompleteJob {
                 version: version as u32,
                 header_timestamp: ntime,
                 header_nonce: nonce,
-                coinbase_tx: self.get_coinbase(),
+                coinbase_tx: self.get_coinbase(&extranonce),
             };
-            VelideateTargetResult::LessThanBitcoinTarget(hash_, self.new_shares_sum, solution)
+            VelideateTargetResult::LessThanBitcoinTarget(
+                hash_,
+                self.new_shares_sum,
+                solution,
+                header,
+            )
         } else if hash <= self.target {
             self.new_shares_sum += 1;
             VelideateTargetResult::LessThanDownstreamTarget(hash_, self.new_shares_sum)
@@ -167,6 +216,61 @@ impl CompleteJob {
         }
     }
 
+    /// The merkle root is computed again with the new extranonce
+    pub fn set_extranonce(&mut self, extranonce: Vec<u8>) {
+        let merkle_root: [u8; 32] = merkle_root_from_path(
+            &(self.coinbase_tx_prefix[..]),
+            &(self.coinbase_tx_suffix[..]),
+            &(extranonce[..]),
+            &(self.merkle_path[..]),
+        )
+        .unwrap()
+        .try_into()
+        .unwrap();
+        let merkle_root = Hash::from_inner(merkle_root);
+        self.merkle_root = TxMerkleNode::from_hash(merkle_root);
+        self.extranonce = extranonce;
+    }
+
+    /// The same job with a new id: an extended job for extended channels, a job with the merkle
+    /// root computed with the extranonce of the channel for standard channels
+    pub fn fresh_job(&self, channel_id: u32, job_id: u32, extended: bool) -> Mining<'static> {
+        if extended {
+            let merkle_path: Vec<U256<'static>> = self
+                .merkle_path
+                .iter()
+                // Safe unwrap the hashes of the path are received as U256
+                .map(|hash| hash.clone().try_into().unwrap())
+                .collect();
+            Mining::NewExtendedMiningJob(NewExtendedMiningJob {
+                channel_id,
+                job_id,
+                future_job: false,
+                version: self.version,
+                version_rolling_allowed: self.version_rolling_allowed,
+                // Below unwraps never panic the fields are received in the same types
+                merkle_path: Seq0255::new(merkle_path).unwrap(),
+                coinbase_tx_prefix: self.coinbase_tx_prefix.clone().try_into().unwrap(),
+                coinbase_tx_suffix: self.coinbase_tx_suffix.clone().try_into().unwrap(),
+            })
+        } else {
+            Mining::NewMiningJob(NewMiningJob {
+                channel_id,
+                job_id,
+                future_job: false,
+                version: self.version,
+                // Safe unwrap the merkle root is 32 bytes
+                merkle_root: self
+                    .merkle_root
+                    .as_hash()
+                    .into_inner()
+                    .to_vec()
+                    .try_into()
+                    .unwrap(),
+            })
+        }
+    }
+
     pub fn update_job(
         &self,
         new_ext_job: &NewExtendedMiningJob<'static>,
@@ -189,6 +293,8 @@ impl CompleteJob {
             target: self.target,
             nbits,
             prev_hash,
+            version: new_ext_job.version,
+            version_rolling_allowed: new_ext_job.version_rolling_allowed,
             new_shares_sum: 0,
             coinbase_tx_prefix: new_ext_job.coinbase_tx_prefix.to_vec(),
             coinbase_tx_suffix: new_ext_job.coinbase_tx_suffix.to_vec(),
@@ -232,6 +338,27 @@ impl Job {
         }
     }
 
+    pub fn target(&self) -> Uint256 {
+        match self {
+            Self::Partial(p) => p.target,
+            Self::Complete(c) => c.target,
+        }
+    }
+
+    pub fn set_target(&mut self, target: Uint256) {
+        match self {
+            Self::Partial(p) => p.target = target,
+            Self::Complete(c) => c.target = target,
+        }
+    }
+
+    pub fn set_extranonce(&mut self, extranonce: Vec<u8>) {
+        match self {
+            Self::Partial(p) => p.extranonce = extranonce,
+            Self::Complete(c) => c.set_extranonce(extranonce),
+        }
+    }
+
     pub fn make_partial(&mut self) {
         match self {
             Self::Partial(_) => (),
@@ -264,6 +391,9 @@ pub struct Downstream {
     extranonces: Arc<Mutex<ExtendedExtranonce>>,
     // channel_id -> Job
     jobs: HashMap<u32, Job>,
+    // channel_id -> Job with the extranonce prefix that the channel had before the last rotation,
+    // the downstream can still submit shares for it until the next job
+    previous_prefix_jobs: HashMap<u32, Job>,
     // extended_job_id -> (FutureJob,template_id)
     future_jobs: HashMap<u32, (NewExtendedMiningJob<'static>, u64)>,
     // channel_id -> Prefixes VALID ONLY FOR EXTENDED CHANNELS
@@ -273,6 +403,22 @@ pub struct Downstream {
     // (job,template_id)
     last_valid_extended_job: Option<(NewExtendedMiningJob<'static>, u64)>,
     solution_sender: Sender<SubmitSolution<'static>>,
+    initial_target: U256<'static>,
+    committed_jobs: Arc<Mutex<CommittedJobs>>,
+    // ids of the jobs created for this downstream only: custom jobs and the jobs sent after an
+    // extranonce prefix rotation
+    job_ids: Id,
+    // channel_id -> mining_job_token of the custom job that the channel is mining, pool's jobs do
+    // not replace it until the next prev hash
+    custom_job_channels: HashMap<u32, u32>,
+    templates_transactions: Arc<Mutex<TemplatesTransactions>>,
+    block_archive: BlockArchive,
+    vardiff: Option<Vardiff>,
+    share_ledger: Arc<Mutex<ShareLedger>>,
+    metrics: Arc<Mutex<Metrics>>,
+    remote: SocketAddr,
+    /// Id and remote address of the downstream, every event of the downstream is in this span
+    span: Span,
 }
 
 /// Accept downstream connection
@@ -288,45 +434,482 @@ pub struct Pool {
     extranonces: Arc<Mutex<ExtendedExtranonce>>,
     solution_sender: Sender<SubmitSolution<'static>>,
     new_template_processed: bool,
+    committed_jobs: Arc<Mutex<CommittedJobs>>,
+    templates_transactions: Arc<Mutex<TemplatesTransactions>>,
+    block_archive: BlockArchive,
+    share_ledger: Arc<Mutex<ShareLedger>>,
+    metrics: Arc<Mutex<Metrics>>,
+    /// Last template received from the Template Provider
+    last_template: Option<NewTemplate<'static>>,
+    config: Config,
 }
 
 impl Downstream {
     pub fn check_target(
         &mut self,
         channel_id: u32,
+        job_id: u32,
         nonce: u32,
         version: u32,
         ntime: u32,
         extranonce_suffix: Option<&[u8]>,
     ) -> Result<VelideateTargetResult, ()> {
+        let _channel = info_span!("channel", channel_id).entered();
         let id = channel_id;
         match self.jobs.get_mut(&id) {
             Some(Job::Complete(job)) => {
-                let res = job.validate_target(nonce, version, ntime, extranonce_suffix);
+                let mut res = job.validate_target(nonce, version, ntime, extranonce_suffix);
+                let (mut target, mut nbits) = (job.target, job.nbits);
+                if let (VelideateTargetResult::Invalid(_), Some(Job::Complete(previous))) =
+                    (&res, self.previous_prefix_jobs.get_mut(&id))
+                {
+                    // The share can be for the job mined before the extranonce prefix rotation
+                    let previous_res =
+                        previous.validate_target(nonce, version, ntime, extranonce_suffix);
+                    if !matches!(previous_res, VelideateTargetResult::Invalid(_)) {
+                        res = previous_res;
+                        target = previous.target;
+                        nbits = previous.nbits;
+                    }
+                }
                 match res {
-                    VelideateTargetResult::LessThanBitcoinTarget(_, _, _) => {
+                    VelideateTargetResult::LessThanBitcoinTarget(_, _, ref solution, header) => {
                         self.jobs.get_mut(&id).as_mut().unwrap().make_partial();
+                        self.previous_prefix_jobs.remove(&id);
+                        let block = (header.block_hash(), coinbase_reward(solution));
+                        self.add_share(id, job_id, target, nbits, Some(block));
+                    }
+                    VelideateTargetResult::LessThanDownstreamTarget(_, _) => {
+                        self.add_share(id, job_id, target, nbits, None)
+                    }
+                    VelideateTargetResult::Invalid(_) => {
+                        self.on_share_rejected(job_id, "difficulty-too-low")
                     }
-                    VelideateTargetResult::LessThanDownstreamTarget(_, _) => (),
-                    VelideateTargetResult::Invalid(_) => (),
                 };
                 Ok(res)
             }
-            Some(Job::Partial(_)) => Err(()),
-            None => Err(()),
+            Some(Job::Partial(_)) => {
+                self.on_share_rejected(job_id, "invalid-job-id");
+                Err(())
+            }
+            None => {
+                self.on_share_rejected(job_id, "invalid-channel-id");
+                Err(())
+            }
+        }
+    }
+
+    /// Credit the share to the user of the channel, when it found a block report the balances
+    fn add_share(
+        &self,
+        channel_id: u32,
+        job_id: u32,
+        target: Uint256,
+        nbits: u32,
+        block: Option<(BlockHash, u64)>,
+    ) {
+        let difficulty = target_to_difficulty(target);
+        debug!(job_id, difficulty, "share accepted");
+        self.metrics
+            .safe_lock(|m| m.on_share_accepted((self.id, channel_id), difficulty))
+            .unwrap();
+        let report = self
+            .share_ledger
+            .safe_lock(|l| l.add_share((self.id, channel_id), job_id, target, nbits, block))
+            .unwrap();
+        if let Some(report) = report {
+            info!("{}", report);
+        }
+    }
+
+    fn on_share_rejected(&self, job_id: u32, reason: &str) {
+        warn!(job_id, reason, "share rejected");
+        self.metrics
+            .safe_lock(|m| m.on_share_rejected(reason))
+            .unwrap();
+    }
+
+    /// Attribute the shares of a new channel to the user that opened it
+    pub fn on_channel_opened(
+        &self,
+        channel_type: ChannelType,
+        channel_id: u32,
+        user_identity: &[u8],
+    ) {
+        let user_identity = String::from_utf8_lossy(user_identity).into_owned();
+        info!(
+            channel_id,
+            channel_type = ?channel_type,
+            user_identity = %user_identity,
+            "channel opened"
+        );
+        let channel = (self.id, channel_id);
+        self.metrics
+            .safe_lock(|m| m.on_channel_opened(channel_type, channel, user_identity.clone()))
+            .unwrap();
+        self.share_ledger
+            .safe_lock(|l| l.open_channel(channel, user_identity))
+            .unwrap();
+    }
+
+    /// Write the block in the archive and send the solution to the Template Provider. The block is
+    /// archived first so that it can be submitted by hand if the solution can not be sent.
+    pub fn on_block_found(
+        &self,
+        channel_id: u32,
+        solution: SubmitSolution<'static>,
+        header: BlockHeader,
+    ) {
+        info!(block_hash = %header.block_hash(), "block found");
+        self.metrics.safe_lock(|m| m.on_block_found()).unwrap();
+        let template_id = solution.template_id;
+        match self.archive_block(
+            channel_id,
+            template_id,
+            header,
+            solution.coinbase_tx.inner_as_ref(),
+        ) {
+            Some(Ok(path)) => info!(path = %path.display(), "block archived"),
+            Some(Err(e)) => error!("block not archived: {}", e),
+            None => error!(
+                template_id,
+                "block not archived: transactions of the job not available"
+            ),
+        }
+        // Blocks of custom jobs are propagated by the Job Negotiator
+        if template_id == CUSTOM_JOB_TEMPLATE_ID {
+            return;
+        }
+        if let Err(e) = self.solution_sender.try_send(solution) {
+            error!(template_id, "block solution not sent: {}", e);
         }
     }
 
+    /// The transactions of a custom job are the ones committed by the Job Negotiator, the ones of
+    /// a pool's job are the ones of the template. None if they are not available.
+    fn archive_block(
+        &self,
+        channel_id: u32,
+        template_id: u64,
+        header: BlockHeader,
+        coinbase: &[u8],
+    ) -> Option<Result<PathBuf, block_archive::Error>> {
+        if template_id == CUSTOM_JOB_TEMPLATE_ID {
+            let mining_job_token = self.custom_job_channels.get(&channel_id)?;
+            self.committed_jobs
+                .safe_lock(|c| {
+                    c.transactions(*mining_job_token).map(|transactions| {
+                        self.block_archive.archive(header, coinbase, transactions)
+                    })
+                })
+                .unwrap()
+        } else {
+            self.templates_transactions
+                .safe_lock(|t| {
+                    t.get(template_id).map(|transactions| {
+                        self.block_archive.archive(header, coinbase, transactions)
+                    })
+                })
+                .unwrap()
+        }
+    }
+
+    /// Target of a new channel: computed from the declared hash rate when vardiff is enabled,
+    /// the initial target of the config otherwise
+    pub fn new_channel_target(&mut self, channel_id: u32, nominal_hash_rate: f32) -> U256<'static> {
+        match &mut self.vardiff {
+            Some(vardiff) => vardiff.add_channel(channel_id, nominal_hash_rate, Instant::now()),
+            None => self.initial_target.clone(),
+        }
+    }
+
+    /// Respond to an accepted share, if vardiff retarget the channel the new target is used to
+    /// validate the next shares and sent after the SubmitSharesSuccess
+    pub fn on_share_accepted(&mut self, success: SubmitSharesSuccess) -> SendTo<()> {
+        let respond = SendTo::Respond(Mining::SubmitSharesSuccess(success.clone()));
+        let set_target = match &mut self.vardiff {
+            Some(vardiff) => vardiff.on_share_accepted(success.channel_id, Instant::now()),
+            None => None,
+        };
+        match set_target {
+            Some(set_target) => {
+                self.set_target(&set_target);
+                SendTo::Multiple(vec![
+                    respond,
+                    SendTo::Respond(Mining::SetTarget(set_target)),
+                ])
+            }
+            None => respond,
+        }
+    }
+
+    /// New target of a channel after an UpdateChannel: computed from the new nominal hash rate
+    /// when vardiff is enabled, unchanged otherwise, in both cases never greater than
+    /// `maximum_target`. The hash rate of a group channel is only informative as the channels
+    /// of the group are retargeted one by one, so no SetTarget is returned for it.
+    pub fn update_channel(
+        &mut self,
+        channel_id: u32,
+        nominal_hash_rate: f32,
+        maximum_target: U256<'static>,
+    ) -> Result<Option<SetTarget<'static>>, &'static str> {
+        if !self.downstream_data.header_only && channel_id == self.id {
+            return Ok(None);
+        }
+        let current_target = match self.jobs.get(&channel_id) {
+            Some(job) => job.target(),
+            None => return Err("invalid-channel-id"),
+        };
+        // No share can meet it
+        if maximum_target.to_vec().iter().all(|b| *b == 0) {
+            return Err("max-target-out-of-range");
+        }
+        let vardiff_set_target = self.vardiff.as_mut().and_then(|vardiff| {
+            vardiff.update_channel(
+                channel_id,
+                nominal_hash_rate,
+                maximum_target.clone(),
+                Instant::now(),
+            )
+        });
+        let set_target = match vardiff_set_target {
+            Some(set_target) => set_target,
+            None if u256_to_uint_256(maximum_target.clone()) < current_target => SetTarget {
+                channel_id,
+                maximum_target,
+            },
+            None => SetTarget {
+                channel_id,
+                maximum_target: uint_256_to_u256(current_target),
+            },
+        };
+        self.set_target(&set_target);
+        Ok(Some(set_target))
+    }
+
+    /// Free the state of the channel, closing the group channel close every channel of the group
+    pub fn close_channel(&mut self, channel_id: u32) {
+        if !self.downstream_data.header_only && channel_id == self.id {
+            self.close_all_channels();
+            return;
+        }
+        if self.jobs.remove(&channel_id).is_some() {
+            info!(channel_id, "channel closed");
+            let channel_type = match self.prefixes.contains_key(&channel_id) {
+                true => ChannelType::Extended,
+                false => ChannelType::Standard,
+            };
+            self.metrics
+                .safe_lock(|m| m.on_channel_closed(channel_type, Some((self.id, channel_id))))
+                .unwrap();
+        }
+        self.prefixes.remove(&channel_id);
+        self.previous_prefix_jobs.remove(&channel_id);
+        self.custom_job_channels.remove(&channel_id);
+        if let Some(vardiff) = &mut self.vardiff {
+            vardiff.remove_channel(channel_id);
+        }
+        self.share_ledger
+            .safe_lock(|l| l.close_channel((self.id, channel_id)))
+            .unwrap();
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        self.remote
+    }
+
+    pub fn is_header_only(&self) -> bool {
+        self.downstream_data.header_only
+    }
+
+    /// (channel id, channel type, target) of the open channels, the group channel is not listed
+    pub fn channels(&self) -> Vec<(u32, ChannelType, U256<'static>)> {
+        let mut channels: Vec<(u32, ChannelType, U256<'static>)> = self
+            .jobs
+            .iter()
+            .map(|(channel_id, job)| {
+                let channel_type = match self.prefixes.contains_key(channel_id) {
+                    true => ChannelType::Extended,
+                    false => ChannelType::Standard,
+                };
+                (*channel_id, channel_type, uint_256_to_u256(job.target()))
+            })
+            .collect();
+        channels.sort_by_key(|(channel_id, _, _)| *channel_id);
+        channels
+    }
+
+    /// True for the open channels and for the group channel
+    pub fn has_channel(&self, channel_id: u32) -> bool {
+        self.jobs.contains_key(&channel_id)
+            || (!self.downstream_data.header_only && channel_id == self.id)
+    }
+
+    /// Target set by an operator, the shares are validated against it from now on. With vardiff
+    /// the channel is retargeted starting from it. Return None if the channel do not exist.
+    pub fn force_target(
+        &mut self,
+        channel_id: u32,
+        target: U256<'static>,
+    ) -> Option<SetTarget<'static>> {
+        if !self.jobs.contains_key(&channel_id) {
+            return None;
+        }
+        if let Some(vardiff) = &mut self.vardiff {
+            vardiff.add_channel_with_target(channel_id, target.clone(), Instant::now());
+        }
+        let set_target = SetTarget {
+            channel_id,
+            maximum_target: target,
+        };
+        self.set_target(&set_target);
+        Some(set_target)
+    }
+
+    fn close_all_channels(&mut self) {
+        let channel_ids: Vec<u32> = self.jobs.keys().copied().collect();
+        for channel_id in channel_ids {
+            self.close_channel(channel_id);
+        }
+    }
+
+    fn set_target(&mut self, set_target: &SetTarget<'static>) {
+        let target = u256_to_uint_256(set_target.maximum_target.clone());
+        if let Some(job) = self.jobs.get_mut(&set_target.channel_id) {
+            job.set_target(target);
+        }
+    }
+
+    /// Retarget the channels that do not submit enough shares
+    async fn retarget_periodically(self_mutex: Arc<Mutex<Self>>, interval: Duration) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let set_targets = self_mutex
+                .safe_lock(|d| {
+                    let set_targets = match &mut d.vardiff {
+                        Some(vardiff) => vardiff.on_tick(Instant::now()),
+                        None => Vec::new(),
+                    };
+                    for set_target in &set_targets {
+                        d.set_target(set_target);
+                    }
+                    set_targets
+                })
+                .unwrap();
+            for set_target in set_targets {
+                // The downstream is gone
+                if Self::send(self_mutex.clone(), Mining::SetTarget(set_target))
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Give a new extranonce prefix to the channel. Return the SetExtranoncePrefix followed by
+    /// the current job with a new id computed with the new prefix, so that the downstream start to
+    /// use it. The job is not a future job so the prev hash that the downstream already has is
+    /// used. Shares for the job mined with the old prefix are still accepted until the next job.
+    /// Return nothing if the channel do not exist or the prefixes are exhausted.
+    pub fn rotate_extranonce_prefix(&mut self, channel_id: u32) -> Vec<Mining<'static>> {
+        let job = match self.jobs.get_mut(&channel_id) {
+            Some(job) => job,
+            None => return Vec::new(),
+        };
+        let (extranonce, prefix) = match self.prefixes.get_mut(&channel_id) {
+            // Extended channel, the downstream roll the bytes that follow the prefix
+            Some(prefix) => {
+                let extranonce = match self
+                    .extranonces
+                    .safe_lock(|e| e.next_extended(32 - prefix.len()))
+                    .unwrap()
+                {
+                    Some(extranonce) => extranonce.into_b032().to_vec(),
+                    None => return Vec::new(),
+                };
+                *prefix = extranonce[..prefix.len()].to_vec();
+                (extranonce, prefix.clone())
+            }
+            None => {
+                let extranonce = match self.extranonces.safe_lock(|e| e.next_standard()).unwrap() {
+                    Some(extranonce) => extranonce.into_b032().to_vec(),
+                    None => return Vec::new(),
+                };
+                (extranonce.clone(), extranonce)
+            }
+        };
+        self.previous_prefix_jobs.insert(channel_id, job.clone());
+        job.set_extranonce(extranonce);
+        let mut messages = vec![Mining::SetExtranoncePrefix(SetExtranoncePrefix {
+            channel_id,
+            // Safe unwrap the prefix is at most 32 bytes long
+            extranonce_prefix: prefix.try_into().unwrap(),
+        })];
+        if let Job::Complete(job) = job {
+            let extended = self.prefixes.contains_key(&channel_id);
+            messages.push(job.fresh_job(channel_id, self.job_ids.next(), extended));
+        }
+        messages
+    }
+
+    /// Rotate the extranonce prefix of every channel
+    async fn rotate_extranonce_prefixes_periodically(
+        self_mutex: Arc<Mutex<Self>>,
+        interval: Duration,
+    ) {
+        loop {
+            tokio::time::sleep(interval).await;
+            let messages = self_mutex
+                .safe_lock(|d| {
+                    let channel_ids: Vec<u32> = d.jobs.keys().copied().collect();
+                    channel_ids
+                        .into_iter()
+                        .flat_map(|channel_id| d.rotate_extranonce_prefix(channel_id))
+                        .collect::<Vec<Mining<'static>>>()
+                })
+                .unwrap();
+            for message in messages {
+                // The downstream is gone
+                if Self::send(self_mutex.clone(), message).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn custom_job_error(m: &SetCustomMiningJob, error_code: &str) -> SendTo<()> {
+        SendTo::Respond(Mining::SetCustomMiningJobError(SetCustomMiningJobError {
+            channel_id: m.channel_id,
+            request_id: m.request_id,
+            error_code: error_code.to_string().try_into().unwrap(),
+        }))
+    }
+
     #[allow(clippy::too_many_arguments)]
     pub async fn new(
         mut receiver: Receiver<EitherFrame>,
         mut sender: Sender<EitherFrame>,
+        remote: SocketAddr,
         group_ids: Arc<Mutex<Id>>,
         _hom_ids: Arc<Mutex<Id>>,
         job_creators: Arc<Mutex<JobsCreators>>,
         extranonces: Arc<Mutex<ExtendedExtranonce>>,
         last_new_prev_hash: Option<SetNewPrevHash<'static>>,
         solution_sender: Sender<SubmitSolution<'static>>,
+        initial_target: U256<'static>,
+        committed_jobs: Arc<Mutex<CommittedJobs>>,
+        templates_transactions: Arc<Mutex<TemplatesTransactions>>,
+        block_archive: BlockArchive,
+        vardiff: Option<VardiffConfig>,
+        extranonce_prefix_rotation: Option<Duration>,
+        share_ledger: Arc<Mutex<ShareLedger>>,
+        metrics: Arc<Mutex<Metrics>>,
     ) -> Arc<Mutex<Self>> {
         let setup_connection = Arc::new(Mutex::new(SetupConnectionHandler::new()));
         let downstream_data =
@@ -340,6 +923,8 @@ impl Downstream {
                 panic!("Downstream standard channel not supported");
             }
         };
+        let span = info_span!("downstream", id, %remote);
+        span.in_scope(|| info!("connected"));
         let extended_jobs = job_creators
             .safe_lock(|j| {
                 j.new_group_channel(id, downstream_data.version_rolling)
@@ -379,12 +964,24 @@ impl Downstream {
             channel_ids: Id::new(),
             extranonces,
             jobs: HashMap::new(),
+            previous_prefix_jobs: HashMap::new(),
             future_jobs,
             last_prev_hash: None,
             last_nbits: None,
             last_valid_extended_job,
             solution_sender,
             prefixes: HashMap::new(),
+            initial_target,
+            committed_jobs,
+            job_ids: Id::new(),
+            custom_job_channels: HashMap::new(),
+            templates_transactions,
+            block_archive,
+            vardiff: vardiff.clone().map(Vardiff::new),
+            share_ledger,
+            metrics,
+            remote,
+            span: span.clone(),
         }));
 
         for job in extended_jobs {
@@ -416,27 +1013,63 @@ impl Downstream {
                 .unwrap();
         };
 
+        if let Some(vardiff) = vardiff {
+            task::spawn(
+                Self::retarget_periodically(self_.clone(), vardiff.retarget_interval)
+                    .instrument(span.clone()),
+            );
+        }
+
+        if let Some(interval) = extranonce_prefix_rotation {
+            task::spawn(
+                Self::rotate_extranonce_prefixes_periodically(self_.clone(), interval)
+                    .instrument(span.clone()),
+            );
+        }
+
         let cloned = self_.clone();
 
-        task::spawn(async move {
-            loop {
-                let receiver = cloned.safe_lock(|d| d.receiver.clone()).unwrap();
-                let incoming: StdFrame = receiver.recv().await.unwrap().try_into().unwrap();
-                Downstream::next(cloned.clone(), incoming).await
+        task::spawn(
+            async move {
+                loop {
+                    let receiver = cloned.safe_lock(|d| d.receiver.clone()).unwrap();
+                    match receiver.recv().await {
+                        Ok(message) => {
+                            let incoming: StdFrame = message.try_into().unwrap();
+                            Downstream::next(cloned.clone(), incoming).await
+                        }
+                        // The pool forget the downstream the next time that it fail to send it a job
+                        Err(_) => {
+                            info!("disconnected");
+                            cloned.safe_lock(|d| d.close_all_channels()).unwrap();
+                            cloned
+                                .safe_lock(|d| {
+                                    d.metrics
+                                        .safe_lock(|m| m.on_downstream_disconnected())
+                                        .unwrap()
+                                })
+                                .unwrap();
+                            break;
+                        }
+                    }
+                }
             }
-        });
+            .instrument(span),
+        );
         self_
     }
 
     pub async fn next(self_mutex: Arc<Mutex<Self>>, mut incoming: StdFrame) {
         let message_type = incoming.get_header().unwrap().msg_type();
         let payload = incoming.payload();
-        let next_message_to_send = ParseDownstreamMiningMessages::handle_message_mining(
-            self_mutex.clone(),
-            message_type,
-            payload,
-            MiningRoutingLogic::None,
-        );
+        let next_message_to_send = info_span!("message", msg_type = message_type).in_scope(|| {
+            ParseDownstreamMiningMessages::handle_message_mining(
+                self_mutex.clone(),
+                message_type,
+                payload,
+                MiningRoutingLogic::None,
+            )
+        });
         match next_message_to_send {
             Ok(SendTo::RelayNewMessage(_, message)) => {
                 Self::send(self_mutex, message).await.unwrap();
@@ -444,13 +1077,30 @@ impl Downstream {
             Ok(SendTo::Respond(message)) => {
                 Self::send(self_mutex, message).await.unwrap();
             }
+            Ok(SendTo::Multiple(sends_to)) => {
+                for send_to in sends_to {
+                    match send_to {
+                        SendTo::Respond(message) => {
+                            Self::send(self_mutex.clone(), message).await.unwrap();
+                        }
+                        _ => panic!(),
+                    }
+                }
+            }
             Ok(SendTo::None(_)) => (),
             Ok(_) => panic!(),
-            Err(Error::UnexpectedMessage) => todo!(),
-            Err(_) => todo!(),
+            Err(Error::UnexpectedMessage) => warn!(msg_type = message_type, "unexpected message"),
+            Err(e) => warn!(msg_type = message_type, "invalid message: {}", e),
         }
     }
 
+    /// Queue a message for the downstream without waiting, for the callers that can not await.
+    /// Fail if the downstream is gone or if its queue is full.
+    pub fn try_send(&self, message: Mining<'static>) -> Result<(), ()> {
+        let sv2_frame: StdFrame = PoolMessages::Mining(message).try_into().unwrap();
+        self.sender.try_send(sv2_frame.into()).map_err(|_| ())
+    }
+
     pub async fn send(
         self_mutex: Arc<Mutex<Self>>,
         message: roles_logic_sv2::parsers::Mining<'static>,
@@ -478,6 +1128,8 @@ impl Downstream {
         self.last_nbits = Some(message.nbits);
         self.last_prev_hash = Some(u256_to_block_hash(prev_hash));
         self.future_jobs = HashMap::new();
+        self.previous_prefix_jobs = HashMap::new();
+        self.custom_job_channels = HashMap::new();
 
         let sv2_frame: StdFrame = PoolMessages::Mining(Mining::SetNewPrevHash(message))
             .try_into()
@@ -508,7 +1160,11 @@ impl Downstream {
         if !message.future_job {
             self_
                 .safe_lock(|s| {
-                    for job in s.jobs.values_mut() {
+                    for (channel_id, job) in s.jobs.iter_mut() {
+                        if s.custom_job_channels.contains_key(channel_id) {
+                            continue;
+                        }
+                        s.previous_prefix_jobs.remove(channel_id);
                         job.update_job(
                             &message,
                             s.last_nbits.unwrap(),
@@ -546,14 +1202,62 @@ impl IsDownstream for Downstream {
 impl IsMiningDownstream for Downstream {}
 
 impl Pool {
+    /// Connected downstreams ordered by id
+    pub fn downstreams(&self) -> Vec<Arc<Mutex<Downstream>>> {
+        let mut downstreams: Vec<(u32, Arc<Mutex<Downstream>>)> = self
+            .group_downstreams
+            .iter()
+            .chain(self.hom_downstreams.iter())
+            .map(|(id, downstream)| (*id, downstream.clone()))
+            .collect();
+        downstreams.sort_by_key(|(id, _)| *id);
+        downstreams
+            .into_iter()
+            .map(|(_, downstream)| downstream)
+            .collect()
+    }
+
+    pub fn downstream(&self, id: u32) -> Option<Arc<Mutex<Downstream>>> {
+        self.group_downstreams
+            .get(&id)
+            .or_else(|| self.hom_downstreams.get(&id))
+            .cloned()
+    }
+
+    pub fn last_template(&self) -> Option<NewTemplate<'static>> {
+        self.last_template.clone()
+    }
+
+    pub fn last_new_prev_hash(&self) -> Option<SetNewPrevHash<'static>> {
+        self.last_new_prev_hash.clone()
+    }
+
+    /// Forget a downstream that is not connected anymore
+    fn remove_downstream(&mut self, downstream: &Arc<Mutex<Downstream>>) {
+        let (is_header_only, id, span) = downstream
+            .safe_lock(|d| (d.downstream_data.header_only, d.id, d.span.clone()))
+            .unwrap();
+        span.in_scope(|| info!("no more jobs sent, removed"));
+        if is_header_only {
+            self.hom_downstreams.remove(&id);
+        } else {
+            self.group_downstreams.remove(&id);
+            self.job_creators
+                .safe_lock(|jc| jc.remove_group_channel(id))
+                .unwrap();
+        }
+    }
+
     async fn accept_incoming_connection(self_: Arc<Mutex<Pool>>) {
-        let listner = TcpListener::bind(crate::ADDR).await.unwrap();
-        while let Ok((stream, _)) = listner.accept().await {
+        let config = self_.safe_lock(|p| p.config.clone()).unwrap();
+        let listner = TcpListener::bind(config.listen_address).await.unwrap();
+        while let Ok((stream, remote)) = listner.accept().await {
             let solution_sender = self_.safe_lock(|p| p.solution_sender.clone()).unwrap();
+            // Safe unwrap the keys are validated when the config is loaded
             let responder = Responder::from_authority_kp(
-                &crate::AUTHORITY_PUBLIC_K[..],
-                &crate::AUTHORITY_PRIVATE_K[..],
-                crate::CERT_VALIDITY,
+                &config.authority_public_key[..],
+                &config.authority_private_key[..],
+                config.cert_validity,
             )
             .unwrap();
             let last_new_prev_hash = self_.safe_lock(|x| x.last_new_prev_hash.clone()).unwrap();
@@ -563,15 +1267,31 @@ impl Pool {
             let hom_ids = self_.safe_lock(|s| s.hom_ids.clone()).unwrap();
             let job_creators = self_.safe_lock(|s| s.job_creators.clone()).unwrap();
             let extranonces = self_.safe_lock(|s| s.extranonces.clone()).unwrap();
+            let committed_jobs = self_.safe_lock(|s| s.committed_jobs.clone()).unwrap();
+            let (templates_transactions, block_archive) = self_
+                .safe_lock(|s| (s.templates_transactions.clone(), s.block_archive.clone()))
+                .unwrap();
+            let (share_ledger, metrics) = self_
+                .safe_lock(|s| (s.share_ledger.clone(), s.metrics.clone()))
+                .unwrap();
             let downstream = Downstream::new(
                 receiver,
                 sender,
+                remote,
                 group_ids,
                 hom_ids,
                 job_creators,
                 extranonces,
                 last_new_prev_hash,
                 solution_sender,
+                config.channel.initial_target.clone(),
+                committed_jobs,
+                templates_transactions,
+                block_archive,
+                config.channel.vardiff.clone(),
+                config.channel.extranonce_prefix_rotation,
+                share_ledger,
+                metrics.clone(),
             )
             .await;
 
@@ -588,11 +1308,14 @@ impl Pool {
                     }
                 })
                 .unwrap();
+            metrics.safe_lock(|m| m.on_downstream_connected()).unwrap();
         }
     }
 
     async fn on_new_prev_hash(self_: Arc<Mutex<Self>>, rx: Receiver<SetNewPrevHash<'static>>) {
         while let Ok(new_prev_hash) = rx.recv().await {
+            let received = Instant::now();
+            debug!(template_id = new_prev_hash.template_id, "new prev hash");
             while !self_.safe_lock(|s| s.new_template_processed).unwrap() {
                 tokio::time::sleep(std::time::Duration::from_millis(1)).await;
             }
@@ -633,15 +1356,40 @@ impl Pool {
                     min_ntime: 0,
                     nbits: new_prev_hash.n_bits,
                 };
-                Downstream::on_new_prev_hash(downstream.clone(), message)
+                if Downstream::on_new_prev_hash(downstream.clone(), message)
                     .await
-                    .unwrap();
+                    .is_err()
+                {
+                    self_
+                        .safe_lock(|s| s.remove_downstream(&downstream))
+                        .unwrap();
+                }
             }
+            self_
+                .safe_lock(|s| {
+                    s.metrics
+                        .safe_lock(|m| m.on_prev_hash_propagated(received.elapsed()))
+                        .unwrap()
+                })
+                .unwrap();
         }
     }
 
-    async fn on_new_template(self_: Arc<Mutex<Self>>, rx: Receiver<NewTemplate<'_>>) {
+    async fn on_new_template(self_: Arc<Mutex<Self>>, rx: Receiver<NewTemplate<'static>>) {
         while let Ok(mut new_template) = rx.recv().await {
+            debug!(
+                template_id = new_template.template_id,
+                future_template = new_template.future_template,
+                "new template"
+            );
+            self_
+                .safe_lock(|s| {
+                    s.metrics.safe_lock(|m| m.on_template_received()).unwrap();
+                    s.share_ledger
+                        .safe_lock(|l| l.on_new_template(new_template.coinbase_tx_value_remaining))
+                        .unwrap();
+                })
+                .unwrap();
             let job_creators = self_.safe_lock(|s| s.job_creators.clone()).unwrap();
             let mut new_jobs = job_creators
                 .safe_lock(|j| j.on_new_template(&mut new_template).unwrap())
@@ -653,22 +1401,36 @@ impl Pool {
             for downstream in group_downstreams {
                 let channel_id = downstream.safe_lock(|x| x.id).unwrap();
                 let extended_job = new_jobs.remove(&channel_id).unwrap();
-                Downstream::on_new_extended_job(
-                    downstream,
+                if Downstream::on_new_extended_job(
+                    downstream.clone(),
                     extended_job,
                     new_template.merkle_path.to_vec(),
                     new_template.template_id,
                 )
                 .await
-                .unwrap();
+                .is_err()
+                {
+                    self_
+                        .safe_lock(|s| s.remove_downstream(&downstream))
+                        .unwrap();
+                }
             }
             self_
-                .safe_lock(|s| s.new_template_processed = true)
+                .safe_lock(|s| {
+                    s.last_template = Some(new_template);
+                    s.new_template_processed = true;
+                })
                 .unwrap();
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub async fn start(
+        config: Config,
+        job_creators: Arc<Mutex<JobsCreators>>,
+        templates_transactions: Arc<Mutex<TemplatesTransactions>>,
+        block_archive: BlockArchive,
+        share_ledger: Arc<Mutex<ShareLedger>>,
         new_template_rx: Receiver<NewTemplate<'static>>,
         new_prev_hash_rx: Receiver<SetNewPrevHash<'static>>,
         solution_sender: Sender<SubmitSolution<'static>>,
@@ -677,22 +1439,43 @@ impl Pool {
         let range_0 = std::ops::Range { start: 0, end: 0 };
         let range_1 = std::ops::Range { start: 0, end: 16 };
         let range_2 = std::ops::Range { start: 16, end: 32 };
+        let metrics = Arc::new(Mutex::new(Metrics::default()));
         let pool = Arc::new(Mutex::new(Pool {
             group_downstreams: HashMap::new(),
             hom_downstreams: HashMap::new(),
             hom_ids: Arc::new(Mutex::new(Id::new())),
             group_ids: Arc::new(Mutex::new(Id::new())),
-            job_creators: Arc::new(Mutex::new(
-                JobsCreators::new(crate::BLOCK_REWARD, crate::new_pub_key()).unwrap(),
-            )),
+            job_creators,
+
             last_new_prev_hash: None,
             extranonces: Arc::new(Mutex::new(ExtendedExtranonce::new(
                 range_0, range_1, range_2,
             ))),
             solution_sender,
             new_template_processed: false,
+            committed_jobs: Arc::new(Mutex::new(CommittedJobs::new())),
+            templates_transactions: templates_transactions.clone(),
+            block_archive,
+            share_ledger: share_ledger.clone(),
+            metrics: metrics.clone(),
+            last_template: None,
+            config: config.clone(),
         }));
 
+        if let Some(admin_address) = config.admin_address {
+            let token = config.admin_token.clone();
+            task::spawn(admin::serve(
+                pool.clone(),
+                metrics.clone(),
+                admin_address,
+                token,
+            ));
+        }
+
+        if let Some(metrics_address) = config.metrics_address {
+            task::spawn(Metrics::serve(metrics, metrics_address));
+        }
+
         let cloned = pool.clone();
         let cloned2 = pool.clone();
         let cloned3 = pool.clone();
@@ -705,9 +1488,238 @@ impl Pool {
             Self::on_new_prev_hash(cloned2, new_prev_hash_rx).await;
         });
 
+        let fsync_interval = config.share_log.fsync_interval;
+        task::spawn(async move {
+            loop {
+                tokio::time::sleep(fsync_interval).await;
+                share_ledger.safe_lock(|l| l.sync_log()).unwrap();
+            }
+        });
+
+        let (committed_jobs, job_creators) = pool
+            .safe_lock(|p| (p.committed_jobs.clone(), p.job_creators.clone()))
+            .unwrap();
+        task::spawn(async {
+            JobNegotiatorDownstream::accept_connections(
+                config,
+                committed_jobs,
+                job_creators,
+                templates_transactions,
+            )
+            .await;
+        });
+
         let _ = task::spawn(async move {
             Self::on_new_template(cloned3, new_template_rx).await;
         })
         .await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lib::config::{PayoutConfig, PayoutScheme, ShareLogConfig};
+    use bitcoin::{consensus::serialize, OutPoint, Script, TxIn, TxOut};
+
+    const STANDARD: u32 = 1;
+    const EXTENDED: u32 = 2;
+    const VERSION: u32 = 0x2000_0000;
+    const NTIME: u32 = 1_650_000_000;
+
+    /// Coinbase prefix and suffix around a 32 bytes extranonce
+    fn coinbase() -> (Vec<u8>, Vec<u8>) {
+        let coinbase = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::null(),
+                script_sig: Script::from(vec![0; 32]),
+                sequence: u32::MAX,
+                witness: Default::default(),
+            }],
+            output: vec![TxOut {
+                value: 625_000_000,
+                script_pubkey: Script::from(vec![0x51]),
+            }],
+        };
+        let coinbase = serialize(&coinbase);
+        // version, inputs count, prevout and script length
+        let prefix_len = 4 + 1 + 36 + 1;
+        (
+            coinbase[..prefix_len].to_vec(),
+            coinbase[prefix_len + 32..].to_vec(),
+        )
+    }
+
+    fn extended_job() -> NewExtendedMiningJob<'static> {
+        let (prefix, suffix) = coinbase();
+        NewExtendedMiningJob {
+            channel_id: 0,
+            job_id: 1,
+            future_job: false,
+            version: VERSION,
+            version_rolling_allowed: true,
+            merkle_path: Seq0255::new(Vec::new()).unwrap(),
+            coinbase_tx_prefix: prefix.try_into().unwrap(),
+            coinbase_tx_suffix: suffix.try_into().unwrap(),
+        }
+    }
+
+    /// Downstream with a standard and an extended channel mining `extended_job`, a share meet the
+    /// target with probability 1/16
+    fn downstream(test: &str) -> Downstream {
+        let dir =
+            std::env::temp_dir().join(format!("pool-downstream-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (_, receiver) = async_channel::unbounded();
+        let (sender, _) = async_channel::unbounded();
+        let (solution_sender, _) = async_channel::unbounded();
+        let share_log = ShareLogConfig {
+            dir: dir.join("shares"),
+            fsync_interval: Duration::from_millis(100),
+            rotate_size: 1024 * 1024,
+        };
+        let payout = PayoutConfig {
+            scheme: PayoutScheme::Pplns { window: 2.0 },
+            fee: 0.0,
+        };
+        let mut downstream = Downstream {
+            id: 0,
+            receiver,
+            sender,
+            downstream_data: CommonDownstreamData {
+                header_only: false,
+                work_selection: false,
+                version_rolling: true,
+            },
+            channel_ids: Id::new(),
+            extranonces: Arc::new(Mutex::new(ExtendedExtranonce::new(0..0, 0..16, 16..32))),
+            jobs: HashMap::new(),
+            previous_prefix_jobs: HashMap::new(),
+            future_jobs: HashMap::new(),
+            prefixes: HashMap::new(),
+            last_prev_hash: None,
+            last_nbits: None,
+            last_valid_extended_job: None,
+            solution_sender,
+            initial_target: [0xff; 32].into(),
+            committed_jobs: Arc::new(Mutex::new(CommittedJobs::new())),
+            job_ids: Id::new(),
+            custom_job_channels: HashMap::new(),
+            templates_transactions: Arc::new(Mutex::new(TemplatesTransactions::new())),
+            block_archive: BlockArchive::new(dir.join("blocks")).unwrap(),
+            vardiff: None,
+            share_ledger: Arc::new(Mutex::new(ShareLedger::open(payout, &share_log).unwrap())),
+            metrics: Arc::new(Mutex::new(Metrics::default())),
+            remote: "127.0.0.1:34254".parse().unwrap(),
+            span: Span::none(),
+        };
+        let target = Uint256([0, 0, 0, 1 << 60]);
+        for channel_id in [STANDARD, EXTENDED].iter() {
+            let extranonce = downstream
+                .extranonces
+                .safe_lock(|e| e.next_standard())
+                .unwrap()
+                .unwrap()
+                .into_b032()
+                .to_vec();
+            let mut job = Job::new(target, extranonce.clone());
+            job.update_job(&extended_job(), 0x1d00_ffff, Default::default(), 1);
+            downstream.jobs.insert(*channel_id, job);
+            downstream.on_channel_opened(ChannelType::Standard, *channel_id, b"alice");
+            if *channel_id == EXTENDED {
+                downstream
+                    .prefixes
+                    .insert(*channel_id, extranonce[..16].to_vec());
+            }
+        }
+        downstream
+    }
+
+    fn complete_job(job: Option<&Job>) -> CompleteJob {
+        match job {
+            Some(Job::Complete(job)) => job.clone(),
+            _ => panic!("no complete job"),
+        }
+    }
+
+    fn is_valid(job: &mut CompleteJob, nonce: u32) -> bool {
+        !matches!(
+            job.validate_target(nonce, VERSION, NTIME, None),
+            VelideateTargetResult::Invalid(_)
+        )
+    }
+
+    #[test]
+    fn rotation_sends_the_new_prefix_and_a_job_computed_with_it() {
+        let mut downstream = downstream("rotation-standard");
+
+        let messages = downstream.rotate_extranonce_prefix(STANDARD);
+
+        let extranonce = complete_job(downstream.jobs.get(&STANDARD)).extranonce;
+        let (prefix, suffix) = coinbase();
+        let empty_path: Vec<Vec<u8>> = Vec::new();
+        let merkle_root = merkle_root_from_path(&prefix, &suffix, &extranonce, &empty_path);
+        match &messages[..] {
+            [Mining::SetExtranoncePrefix(set_prefix), Mining::NewMiningJob(job)] => {
+                assert_eq!(set_prefix.channel_id, STANDARD);
+                assert_eq!(set_prefix.extranonce_prefix.to_vec(), extranonce);
+                assert_eq!(job.channel_id, STANDARD);
+                assert!(!job.future_job);
+                assert_eq!(job.version, VERSION);
+                assert_eq!(Some(job.merkle_root.to_vec()), merkle_root);
+            }
+            _ => panic!("expected SetExtranoncePrefix and NewMiningJob"),
+        }
+    }
+
+    #[test]
+    fn extended_channels_get_an_extended_job_after_the_rotation() {
+        let mut downstream = downstream("rotation-extended");
+
+        let messages = downstream.rotate_extranonce_prefix(EXTENDED);
+
+        let prefix = downstream.prefixes.get(&EXTENDED).unwrap().clone();
+        match &messages[..] {
+            [Mining::SetExtranoncePrefix(set_prefix), Mining::NewExtendedMiningJob(job)] => {
+                assert_eq!(set_prefix.extranonce_prefix.to_vec(), prefix);
+                assert_eq!(job.channel_id, EXTENDED);
+                assert!(!job.future_job);
+                assert_eq!(job.coinbase_tx_prefix.to_vec(), coinbase().0);
+                assert_eq!(job.coinbase_tx_suffix.to_vec(), coinbase().1);
+            }
+            _ => panic!("expected SetExtranoncePrefix and NewExtendedMiningJob"),
+        }
+        assert!(downstream.rotate_extranonce_prefix(3).is_empty());
+    }
+
+    #[test]
+    fn shares_with_the_old_prefix_are_accepted_until_the_next_job() {
+        let mut downstream = downstream("rotation-old-prefix");
+        downstream.rotate_extranonce_prefix(STANDARD);
+        let mut old_job = complete_job(downstream.previous_prefix_jobs.get(&STANDARD));
+        let mut new_job = complete_job(downstream.jobs.get(&STANDARD));
+        assert_ne!(old_job.merkle_root, new_job.merkle_root);
+        let nonce = (0..u32::MAX)
+            .find(|nonce| is_valid(&mut old_job, *nonce) && !is_valid(&mut new_job, *nonce))
+            .unwrap();
+
+        let result = downstream.check_target(STANDARD, 1, nonce, VERSION, NTIME, None);
+        assert!(matches!(
+            result,
+            Ok(VelideateTargetResult::LessThanDownstreamTarget(_, _))
+        ));
+
+        let new_prev_hash = NewPrevHash {
+            channel_id: 0,
+            job_id: 2,
+            prev_hash: [0; 32].into(),
+            min_ntime: NTIME,
+            nbits: 0x1d00_ffff,
+        };
+        downstream.on_new_prev_hash_sync(new_prev_hash).unwrap();
+        let result = downstream.check_target(STANDARD, 1, nonce, VERSION, NTIME, None);
+        assert!(matches!(result, Ok(VelideateTargetResult::Invalid(_))));
+    }
+}
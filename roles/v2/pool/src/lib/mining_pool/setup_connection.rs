@@ -66,10 +66,16 @@ impl ParseDownstreamCommonMessages<NoRouting> for SetupConnectionHandler {
         use roles_logic_sv2::handlers::common::SendTo;
         let header_only = incoming.requires_standard_job();
         self.header_only = Some(header_only);
+        // Work selection is allowed, custom jobs are accepted only for tokens committed by a Job
+        // Negotiator
+        let flags = match has_work_selection(incoming.flags) {
+            true => 0b0010_0000_0000_0000_0000_0000_0000_0000,
+            false => 0,
+        };
         Ok(SendTo::RelayNewMessage(
             Arc::new(Mutex::new(())),
             CommonMessages::SetupConnectionSuccess(SetupConnectionSuccess {
-                flags: 0,
+                flags,
                 used_version: 2,
             }),
         ))
@@ -1,2 +1,9 @@
+pub mod admin;
+pub mod block_archive;
+pub mod config;
+pub mod job_negotiation;
+pub mod metrics;
 pub mod mining_pool;
+pub mod share_ledger;
+pub mod share_log;
 pub mod template_receiver;
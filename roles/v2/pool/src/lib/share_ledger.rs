@@ -0,0 +1,574 @@
+//! Accounting of the work done by every user of the pool and of what the pool owe them.
+//!
+//! Channels are attributed to the `user_identity` sent when they are opened, every accepted share
+//! is weighted by its difficulty (difficulty 1 is the target of the genesis block) and credited to
+//! the user of the channel. How the users are paid depend on the payout scheme:
+//! * PPLNS: when a block is found the reward is split between the users proportionally to their
+//!   work in the last shares, the window is a multiple of the network difficulty.
+//! * PPS: every share is credited when it is accepted with its expected value, the coinbase value
+//!   of the last template times the share difficulty over the network difficulty. The pool keep
+//!   the rewards of the blocks found.
+//!
+//! In both schemes the pool fee is subtracted from what is credited. Balances are in satoshis.
+//!
+//! Every share is written in the share log before being credited. When the log start a new file
+//! the state of the ledger is written as a snapshot that replace the older files, the ledger is
+//! rebuilt from the last snapshot and the shares after it when the pool start.
+use crate::lib::{
+    config::{PayoutConfig, PayoutScheme, ShareLogConfig},
+    share_log::{self, AccountSnapshot, ShareLog, ShareRecord, Snapshot},
+};
+use bitcoin::{blockdata::block::BlockHeader, hash_types::BlockHash, util::uint::Uint256};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::{self, Display},
+};
+use tracing::{error, info};
+
+/// (group channel id, channel id), channel ids are unique only inside a group
+pub type ChannelKey = (u32, u32);
+
+/// Target of difficulty 1: 0x00000000ffff0000000000000000000000000000000000000000000000000000
+fn difficulty_1_target() -> f64 {
+    65535.0 * 2_f64.powi(208)
+}
+
+fn uint_256_to_f64(v: Uint256) -> f64 {
+    v.0.iter()
+        .enumerate()
+        .map(|(i, limb)| *limb as f64 * 2_f64.powi(64 * i as i32))
+        .sum()
+}
+
+/// Difficulty of a share that meet `target`
+pub fn target_to_difficulty(target: Uint256) -> f64 {
+    // A target of 0 can not be met, it is treated as the smallest possible target
+    difficulty_1_target() / uint_256_to_f64(target).max(1.0)
+}
+
+/// Work of a channel since the last block found
+#[derive(Debug, Default)]
+struct ChannelWork {
+    accepted_shares: u64,
+    work: f64,
+}
+
+#[derive(Debug, Default)]
+struct UserAccount {
+    channels: HashMap<ChannelKey, ChannelWork>,
+    /// Credited since the last block found
+    credited: f64,
+    balance: f64,
+}
+
+#[derive(Debug)]
+struct WindowShare {
+    user_identity: String,
+    difficulty: f64,
+}
+
+#[derive(Debug)]
+pub struct UserBalance {
+    pub user_identity: String,
+    /// Work and number of shares since the previous block found
+    pub round_work: f64,
+    pub round_shares: u64,
+    /// Satoshis credited since the previous block found
+    pub credited: u64,
+    pub balance: u64,
+}
+
+/// Balances of the users when a block is found, every user with a balance is listed
+#[derive(Debug)]
+pub struct BalanceReport {
+    pub block_hash: BlockHash,
+    /// Sum of the outputs of the coinbase
+    pub reward: u64,
+    pub users: Vec<UserBalance>,
+}
+
+impl Display for BalanceReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let credited: u64 = self.users.iter().map(|user| user.credited).sum();
+        write!(
+            f,
+            "block {} reward {} sat, {} sat credited since the previous block",
+            self.block_hash, self.reward, credited
+        )?;
+        for user in &self.users {
+            write!(
+                f,
+                "\n  {}: work {:.2} in {} shares, credited {} sat, balance {} sat",
+                user.user_identity, user.round_work, user.round_shares, user.credited, user.balance
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct ShareLedger {
+    config: PayoutConfig,
+    /// Coinbase value of the last template, used to compute the value of a share with PPS
+    block_reward: u64,
+    channels: HashMap<ChannelKey, String>,
+    users: HashMap<String, UserAccount>,
+    /// Last shares, used by PPLNS
+    window: VecDeque<WindowShare>,
+    window_work: f64,
+    log: ShareLog,
+}
+
+impl ShareLedger {
+    /// Restore the last snapshot, replay the shares logged after it and compact the log
+    pub fn open(
+        config: PayoutConfig,
+        log_config: &ShareLogConfig,
+    ) -> Result<Self, share_log::Error> {
+        let (log, snapshot, records) = ShareLog::open(log_config)?;
+        let mut ledger = Self {
+            config,
+            block_reward: 0,
+            channels: HashMap::new(),
+            users: HashMap::new(),
+            window: VecDeque::new(),
+            window_work: 0.0,
+            log,
+        };
+        let restored = snapshot.is_some();
+        if let Some(snapshot) = snapshot {
+            ledger.restore(snapshot);
+        }
+        for record in &records {
+            ledger.apply(record);
+        }
+        info!(
+            snapshot = restored,
+            shares = records.len(),
+            "share log replayed"
+        );
+        let snapshot = ledger.snapshot();
+        ledger.log.compact(&snapshot)?;
+        Ok(ledger)
+    }
+
+    /// The shares accepted from now on are worth `coinbase_tx_value_remaining`, as the coinbase
+    /// outputs of the jobs created from the template
+    pub fn on_new_template(&mut self, coinbase_tx_value_remaining: u64) {
+        self.block_reward = coinbase_tx_value_remaining;
+    }
+
+    /// Attribute the shares of the channel to `user_identity`
+    pub fn open_channel(&mut self, channel: ChannelKey, user_identity: String) {
+        self.users.entry(user_identity.clone()).or_default();
+        self.channels.insert(channel, user_identity);
+    }
+
+    /// The work of the channel stay in the account of its user
+    pub fn close_channel(&mut self, channel: ChannelKey) {
+        self.channels.remove(&channel);
+    }
+
+    /// Log and credit a share accepted with `target` for a job with `nbits`, `block` is set when
+    /// the share found a block and then the balances are returned. Shares of unknown channels are
+    /// ignored.
+    pub fn add_share(
+        &mut self,
+        channel: ChannelKey,
+        job_id: u32,
+        target: Uint256,
+        nbits: u32,
+        block: Option<(BlockHash, u64)>,
+    ) -> Option<BalanceReport> {
+        let user_identity = self.channels.get(&channel)?.clone();
+        let record = ShareRecord {
+            timestamp_ms: ShareRecord::now_ms(),
+            user_identity,
+            channel,
+            job_id,
+            difficulty: target_to_difficulty(target),
+            network_difficulty: target_to_difficulty(BlockHeader::u256_from_compact_target(nbits)),
+            block_reward: self.block_reward,
+            block,
+        };
+        // The share is credited anyway, the miner did the work
+        let new_file = match self.log.append(&record, block.is_some()) {
+            Ok(new_file) => new_file,
+            Err(e) => {
+                error!("share not logged: {}", e);
+                false
+            }
+        };
+        let report = self.apply(&record);
+        if new_file {
+            let snapshot = self.snapshot();
+            if let Err(e) = self.log.compact(&snapshot) {
+                error!("share log not compacted: {}", e);
+            }
+        }
+        report
+    }
+
+    /// Write the logged shares to disk
+    pub fn sync_log(&mut self) {
+        if let Err(e) = self.log.sync() {
+            error!("share log not synced: {}", e);
+        }
+    }
+
+    /// Accounts are sorted by user identity
+    fn snapshot(&self) -> Snapshot {
+        let mut accounts: Vec<AccountSnapshot> = self
+            .users
+            .iter()
+            .map(|(user_identity, account)| {
+                let mut channels: Vec<(ChannelKey, u64, f64)> = account
+                    .channels
+                    .iter()
+                    .map(|(channel, work)| (*channel, work.accepted_shares, work.work))
+                    .collect();
+                channels.sort_by_key(|(channel, _, _)| *channel);
+                AccountSnapshot {
+                    user_identity: user_identity.clone(),
+                    balance: account.balance,
+                    credited: account.credited,
+                    channels,
+                }
+            })
+            .collect();
+        accounts.sort_by(|a, b| a.user_identity.cmp(&b.user_identity));
+        let window = self
+            .window
+            .iter()
+            .map(|share| (share.user_identity.clone(), share.difficulty))
+            .collect();
+        Snapshot { accounts, window }
+    }
+
+    fn restore(&mut self, snapshot: Snapshot) {
+        for account in snapshot.accounts {
+            let channels = account
+                .channels
+                .into_iter()
+                .map(|(channel, accepted_shares, work)| {
+                    let work = ChannelWork {
+                        accepted_shares,
+                        work,
+                    };
+                    (channel, work)
+                })
+                .collect();
+            let restored = UserAccount {
+                channels,
+                credited: account.credited,
+                balance: account.balance,
+            };
+            self.users.insert(account.user_identity, restored);
+        }
+        for (user_identity, difficulty) in snapshot.window {
+            // Every share in the window must have an account
+            self.users.entry(user_identity.clone()).or_default();
+            self.window.push_back(WindowShare {
+                user_identity,
+                difficulty,
+            });
+            self.window_work += difficulty;
+        }
+    }
+
+    fn apply(&mut self, record: &ShareRecord) -> Option<BalanceReport> {
+        let difficulty = record.difficulty;
+        let network_difficulty = record.network_difficulty;
+        let pps_credit =
+            record.block_reward as f64 * difficulty / network_difficulty * (1.0 - self.config.fee);
+        let account = self.users.entry(record.user_identity.clone()).or_default();
+        let channel_work = account.channels.entry(record.channel).or_default();
+        channel_work.accepted_shares += 1;
+        channel_work.work += difficulty;
+        match self.config.scheme {
+            PayoutScheme::Pps => {
+                account.credited += pps_credit;
+                account.balance += pps_credit;
+            }
+            PayoutScheme::Pplns { window } => {
+                self.window.push_back(WindowShare {
+                    user_identity: record.user_identity.clone(),
+                    difficulty,
+                });
+                self.window_work += difficulty;
+                self.trim_window(window * network_difficulty);
+            }
+        }
+        record
+            .block
+            .map(|(block_hash, reward)| self.on_block_found(block_hash, reward))
+    }
+
+    /// Drop the oldest shares as long as the remaining ones still fill the window
+    fn trim_window(&mut self, window_work: f64) {
+        while let Some(oldest) = self.window.front() {
+            if self.window_work - oldest.difficulty < window_work {
+                break;
+            }
+            self.window_work -= oldest.difficulty;
+            self.window.pop_front();
+        }
+    }
+
+    /// With PPLNS split `reward` between the users of the shares in the window. Return the
+    /// balances and start a new round.
+    fn on_block_found(&mut self, block_hash: BlockHash, reward: u64) -> BalanceReport {
+        if matches!(self.config.scheme, PayoutScheme::Pplns { .. }) && self.window_work > 0.0 {
+            let to_split = reward as f64 * (1.0 - self.config.fee);
+            for share in &self.window {
+                let credit = to_split * share.difficulty / self.window_work;
+                // Safe unwrap accounts are never removed
+                let account = self.users.get_mut(&share.user_identity).unwrap();
+                account.credited += credit;
+                account.balance += credit;
+            }
+        }
+        let mut users: Vec<UserBalance> = self
+            .users
+            .iter()
+            .filter(|(_, account)| account.balance > 0.0 || !account.channels.is_empty())
+            .map(|(user_identity, account)| UserBalance {
+                user_identity: user_identity.clone(),
+                round_work: account.channels.values().map(|c| c.work).sum(),
+                round_shares: account.channels.values().map(|c| c.accepted_shares).sum(),
+                credited: account.credited as u64,
+                balance: account.balance as u64,
+            })
+            .collect();
+        users.sort_by(|a, b| a.user_identity.cmp(&b.user_identity));
+        for account in self.users.values_mut() {
+            account.channels.clear();
+            account.credited = 0.0;
+        }
+        BalanceReport {
+            block_hash,
+            reward,
+            users,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const REWARD: u64 = 625_000_000;
+    const ALICE: ChannelKey = (1, 1);
+    const BOB: ChannelKey = (1, 2);
+
+    /// Empty share log directory, every test has its own
+    fn log_config(test: &str) -> ShareLogConfig {
+        let dir = std::env::temp_dir().join(format!("pool-ledger-{}-{}", std::process::id(), test));
+        let _ = std::fs::remove_dir_all(&dir);
+        ShareLogConfig {
+            dir,
+            fsync_interval: Duration::from_millis(100),
+            rotate_size: 1024 * 1024,
+        }
+    }
+
+    /// Ledger with an empty share log and a channel for alice and bob
+    fn ledger(test: &str, scheme: PayoutScheme, fee: f64) -> ShareLedger {
+        let log_config = log_config(test);
+        let mut ledger = ShareLedger::open(PayoutConfig { scheme, fee }, &log_config).unwrap();
+        ledger.on_new_template(REWARD);
+        ledger.open_channel(ALICE, "alice".to_string());
+        ledger.open_channel(BOB, "bob".to_string());
+        ledger
+    }
+
+    /// Credit a share without going through the targets
+    fn add(
+        ledger: &mut ShareLedger,
+        channel: ChannelKey,
+        difficulty: f64,
+        network_difficulty: f64,
+        reward: Option<u64>,
+    ) -> Option<BalanceReport> {
+        let record = ShareRecord {
+            timestamp_ms: 0,
+            user_identity: ledger.channels[&channel].clone(),
+            channel,
+            job_id: 0,
+            difficulty,
+            network_difficulty,
+            block_reward: ledger.block_reward,
+            block: reward.map(|reward| (BlockHash::default(), reward)),
+        };
+        ledger.apply(&record)
+    }
+
+    fn balance(ledger: &ShareLedger, user_identity: &str) -> f64 {
+        ledger.users[user_identity].balance
+    }
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() <= expected.abs() * 1e-12 + 1e-9,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn difficulty_1_target_has_difficulty_1() {
+        let target = BlockHeader::u256_from_compact_target(0x1d00ffff);
+        assert_close(target_to_difficulty(target), 1.0);
+    }
+
+    #[test]
+    fn pps_credits_the_template_reward_less_the_fee() {
+        let mut ledger = ledger("pps-reward", PayoutScheme::Pps, 0.01);
+        let target = BlockHeader::u256_from_compact_target(0x1d00ffff);
+
+        assert!(ledger
+            .add_share(ALICE, 0, target, 0x1d00ffff, None)
+            .is_none());
+        assert_close(balance(&ledger, "alice"), REWARD as f64 * 0.99);
+
+        ledger.on_new_template(REWARD / 2);
+        ledger.add_share(ALICE, 0, target, 0x1d00ffff, None);
+        assert_close(balance(&ledger, "alice"), REWARD as f64 * 1.5 * 0.99);
+    }
+
+    #[test]
+    fn pps_credit_is_proportional_to_the_share_difficulty() {
+        let mut ledger = ledger("pps-difficulty", PayoutScheme::Pps, 0.0);
+
+        add(&mut ledger, ALICE, 2.0, 8.0, None);
+        add(&mut ledger, BOB, 6.0, 8.0, None);
+
+        assert_close(balance(&ledger, "alice"), REWARD as f64 / 4.0);
+        assert_close(balance(&ledger, "bob"), REWARD as f64 * 3.0 / 4.0);
+    }
+
+    #[test]
+    fn pps_keeps_the_reward_of_the_block_found() {
+        let mut ledger = ledger("pps-block", PayoutScheme::Pps, 0.0);
+
+        let report = add(&mut ledger, ALICE, 1.0, 100.0, Some(REWARD)).unwrap();
+
+        assert_eq!(report.reward, REWARD);
+        assert_eq!(report.users[0].credited, REWARD / 100);
+        assert_close(balance(&ledger, "alice"), REWARD as f64 / 100.0);
+    }
+
+    #[test]
+    fn pplns_splits_the_reward_by_the_work_in_the_window() {
+        let scheme = PayoutScheme::Pplns { window: 1.0 };
+        let mut ledger = ledger("pplns-split", scheme, 0.0);
+
+        assert!(add(&mut ledger, ALICE, 30.0, 100.0, None).is_none());
+        assert_close(balance(&ledger, "alice"), 0.0);
+        let report = add(&mut ledger, BOB, 20.0, 100.0, Some(1000)).unwrap();
+
+        assert_eq!(report.users.len(), 2);
+        assert_eq!(report.users[0].user_identity, "alice");
+        assert_eq!(report.users[0].credited, 600);
+        assert_eq!(report.users[0].round_shares, 1);
+        assert_eq!(report.users[1].user_identity, "bob");
+        assert_eq!(report.users[1].credited, 400);
+        assert_close(balance(&ledger, "alice"), 600.0);
+        assert_close(balance(&ledger, "bob"), 400.0);
+    }
+
+    #[test]
+    fn pplns_deducts_the_fee_from_the_reward() {
+        let scheme = PayoutScheme::Pplns { window: 1.0 };
+        let mut ledger = ledger("pplns-fee", scheme, 0.1);
+
+        add(&mut ledger, ALICE, 10.0, 100.0, None);
+        add(&mut ledger, BOB, 10.0, 100.0, Some(1000));
+
+        assert_close(balance(&ledger, "alice"), 450.0);
+        assert_close(balance(&ledger, "bob"), 450.0);
+    }
+
+    #[test]
+    fn pplns_window_keeps_only_the_last_shares() {
+        let scheme = PayoutScheme::Pplns { window: 1.0 };
+        let mut ledger = ledger("pplns-window", scheme, 0.0);
+
+        add(&mut ledger, ALICE, 6.0, 10.0, None);
+        add(&mut ledger, BOB, 6.0, 10.0, None);
+        assert_eq!(ledger.window.len(), 2);
+        // The window still fill 10 without the share of alice
+        add(&mut ledger, BOB, 6.0, 10.0, Some(1000));
+
+        assert_eq!(ledger.window.len(), 2);
+        assert_close(ledger.window_work, 12.0);
+        assert_close(balance(&ledger, "alice"), 0.0);
+        assert_close(balance(&ledger, "bob"), 1000.0);
+    }
+
+    #[test]
+    fn pplns_starts_a_new_round_after_a_block() {
+        let scheme = PayoutScheme::Pplns { window: 1.0 };
+        let mut ledger = ledger("pplns-round", scheme, 0.0);
+
+        add(&mut ledger, ALICE, 10.0, 100.0, Some(1000));
+        let report = add(&mut ledger, BOB, 10.0, 100.0, Some(1000)).unwrap();
+
+        // The window is kept, only the credits of the round are reset
+        assert_eq!(report.users[0].credited, 500);
+        assert_eq!(report.users[0].round_shares, 0);
+        assert_eq!(report.users[1].credited, 500);
+        assert_close(balance(&ledger, "alice"), 1500.0);
+        assert_close(balance(&ledger, "bob"), 500.0);
+    }
+
+    #[test]
+    fn shares_of_unknown_channels_are_ignored() {
+        let mut ledger = ledger("unknown-channel", PayoutScheme::Pps, 0.0);
+        let target = BlockHeader::u256_from_compact_target(0x1d00ffff);
+        ledger.close_channel(ALICE);
+
+        assert!(ledger
+            .add_share(ALICE, 0, target, 0x1d00ffff, None)
+            .is_none());
+        assert_close(balance(&ledger, "alice"), 0.0);
+    }
+
+    #[test]
+    fn balances_survive_a_restart() {
+        let log_config = log_config("restart");
+        let payout = PayoutConfig {
+            scheme: PayoutScheme::Pplns { window: 3.0 },
+            fee: 0.0,
+        };
+        let mut ledger = ShareLedger::open(payout.clone(), &log_config).unwrap();
+        ledger.open_channel(ALICE, "alice".to_string());
+        ledger.open_channel(BOB, "bob".to_string());
+        let target = BlockHeader::u256_from_compact_target(0x1d00ffff);
+        ledger.add_share(ALICE, 0, target, 0x1d00ffff, None);
+        ledger.add_share(
+            BOB,
+            0,
+            target,
+            0x1d00ffff,
+            Some((BlockHash::default(), 1000)),
+        );
+        ledger.add_share(ALICE, 0, target, 0x1d00ffff, None);
+        let expected = ledger.snapshot();
+        assert_close(balance(&ledger, "alice"), 500.0);
+        drop(ledger);
+
+        // The shares are replayed and then replaced by a snapshot
+        let ledger = ShareLedger::open(payout.clone(), &log_config).unwrap();
+        assert_eq!(ledger.snapshot(), expected);
+        drop(ledger);
+        let ledger = ShareLedger::open(payout, &log_config).unwrap();
+        assert_eq!(ledger.snapshot(), expected);
+        assert_close(ledger.window_work, 3.0);
+        assert_close(balance(&ledger, "bob"), 500.0);
+        let files = std::fs::read_dir(&log_config.dir).unwrap().count();
+        assert_eq!(files, 2);
+    }
+}
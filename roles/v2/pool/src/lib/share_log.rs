@@ -0,0 +1,540 @@
+//! Every accepted share is appended to a log on disk so that the share ledger can be rebuilt when
+//! the pool restart.
+//!
+//! The log is a sequence of files `shares-<sequence>.log` in a local directory, one share per
+//! line:
+//! `<timestamp ms> <group channel id> <channel id> <job id> <difficulty> <network difficulty>
+//! <block reward> <block hash>:<reward> or - <hex encoded user identity>`
+//! Writes are buffered and synced to disk at a fixed interval, the shares that find a block are
+//! synced immediately. When a file is bigger than the configured size a new one is started.
+//!
+//! Every time a file is started the share ledger write a snapshot of its state
+//! `snapshot-<sequence>.log`, the state before the shares of the file with the same sequence, and
+//! the older files and snapshots are removed. A snapshot has one account, channel work or PPLNS
+//! window share per line:
+//! `account <hex encoded user identity> <balance> <credited since the last block>`
+//! `channel <hex encoded user identity> <group channel id> <channel id> <shares> <work>`
+//! `window <hex encoded user identity> <difficulty>`
+//! On startup the last snapshot is read, the files after it are replayed in order and a new file is
+//! started, so that a line truncated by a crash is never continued.
+use crate::lib::{config::ShareLogConfig, share_ledger::ChannelKey};
+use bitcoin::{
+    hash_types::BlockHash,
+    hashes::hex::{FromHex, ToHex},
+};
+use std::{
+    fmt::{self, Display},
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+const SHARES_PREFIX: &str = "shares-";
+const SNAPSHOT_PREFIX: &str = "snapshot-";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(PathBuf, std::io::Error),
+    InvalidSnapshot(PathBuf, usize),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(path, e) => write!(f, "Share log {}: {}", path.display(), e),
+            Error::InvalidSnapshot(path, line) => {
+                write!(
+                    f,
+                    "Share log snapshot {}: invalid line {}",
+                    path.display(),
+                    line
+                )
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ShareRecord {
+    pub timestamp_ms: u64,
+    pub user_identity: String,
+    pub channel: ChannelKey,
+    pub job_id: u32,
+    pub difficulty: f64,
+    pub network_difficulty: f64,
+    /// Coinbase value of the last template when the share was accepted
+    pub block_reward: u64,
+    /// Set when the share met the bitcoin target: (block hash, sum of the coinbase outputs)
+    pub block: Option<(BlockHash, u64)>,
+}
+
+impl ShareRecord {
+    pub fn now_ms() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or(0)
+    }
+
+    fn encode(&self) -> String {
+        let block = match self.block {
+            Some((hash, reward)) => format!("{}:{}", hash, reward),
+            None => "-".to_string(),
+        };
+        format!(
+            "{} {} {} {} {} {} {} {} {}\n",
+            self.timestamp_ms,
+            self.channel.0,
+            self.channel.1,
+            self.job_id,
+            self.difficulty,
+            self.network_difficulty,
+            self.block_reward,
+            block,
+            self.user_identity.as_bytes().to_hex()
+        )
+    }
+
+    fn decode(line: &str) -> Option<Self> {
+        let mut fields = line.splitn(9, ' ');
+        let timestamp_ms = fields.next()?.parse().ok()?;
+        let channel = (fields.next()?.parse().ok()?, fields.next()?.parse().ok()?);
+        let job_id = fields.next()?.parse().ok()?;
+        let difficulty = fields.next()?.parse().ok()?;
+        let network_difficulty = fields.next()?.parse().ok()?;
+        let block_reward = fields.next()?.parse().ok()?;
+        let block = match fields.next()? {
+            "-" => None,
+            block => {
+                let (hash, reward) = block.split_once(':')?;
+                Some((BlockHash::from_str(hash).ok()?, reward.parse().ok()?))
+            }
+        };
+        let user_identity = String::from_utf8(Vec::<u8>::from_hex(fields.next()?).ok()?).ok()?;
+        Some(Self {
+            timestamp_ms,
+            user_identity,
+            channel,
+            job_id,
+            difficulty,
+            network_difficulty,
+            block_reward,
+            block,
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountSnapshot {
+    pub user_identity: String,
+    pub balance: f64,
+    pub credited: f64,
+    /// Work since the last block found: (channel, accepted shares, work)
+    pub channels: Vec<(ChannelKey, u64, f64)>,
+}
+
+/// State of the share ledger before the shares of a file
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Snapshot {
+    pub accounts: Vec<AccountSnapshot>,
+    /// Shares in the PPLNS window, oldest first: (user identity, difficulty)
+    pub window: Vec<(String, f64)>,
+}
+
+impl Snapshot {
+    fn encode(&self) -> String {
+        let mut encoded = String::new();
+        for account in &self.accounts {
+            let user_identity = account.user_identity.as_bytes().to_hex();
+            encoded.push_str(&format!(
+                "account {} {} {}\n",
+                user_identity, account.balance, account.credited
+            ));
+            for ((group_id, channel_id), shares, work) in &account.channels {
+                encoded.push_str(&format!(
+                    "channel {} {} {} {} {}\n",
+                    user_identity, group_id, channel_id, shares, work
+                ));
+            }
+        }
+        for (user_identity, difficulty) in &self.window {
+            let user_identity = user_identity.as_bytes().to_hex();
+            encoded.push_str(&format!("window {} {}\n", user_identity, difficulty));
+        }
+        encoded
+    }
+
+    /// A channel line must follow the account of its user
+    fn decode_line(&mut self, line: &str) -> Option<()> {
+        let mut fields = line.split(' ');
+        let kind = fields.next()?;
+        let user_identity = String::from_utf8(Vec::<u8>::from_hex(fields.next()?).ok()?).ok()?;
+        match kind {
+            "account" => self.accounts.push(AccountSnapshot {
+                user_identity,
+                balance: fields.next()?.parse().ok()?,
+                credited: fields.next()?.parse().ok()?,
+                channels: Vec::new(),
+            }),
+            "channel" => {
+                let channel = (fields.next()?.parse().ok()?, fields.next()?.parse().ok()?);
+                let shares = fields.next()?.parse().ok()?;
+                let work = fields.next()?.parse().ok()?;
+                let account = self.accounts.last_mut()?;
+                if account.user_identity != user_identity {
+                    return None;
+                }
+                account.channels.push((channel, shares, work));
+            }
+            "window" => self
+                .window
+                .push((user_identity, fields.next()?.parse().ok()?)),
+            _ => return None,
+        }
+        match fields.next() {
+            None => Some(()),
+            Some(_) => None,
+        }
+    }
+
+    /// Snapshots are renamed in place when complete, an invalid line is an error
+    fn read(path: &Path) -> Result<Self, Error> {
+        let file = File::open(path).map_err(|e| Error::Io(path.into(), e))?;
+        let mut snapshot = Self::default();
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| Error::Io(path.into(), e))?;
+            snapshot
+                .decode_line(&line)
+                .ok_or_else(|| Error::InvalidSnapshot(path.into(), number + 1))?;
+        }
+        Ok(snapshot)
+    }
+}
+
+#[derive(Debug)]
+pub struct ShareLog {
+    dir: PathBuf,
+    rotate_size: u64,
+    sequence: u64,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    size: u64,
+    unsynced: bool,
+}
+
+impl ShareLog {
+    /// Read the last snapshot and the shares of the files after it, and start a new file
+    pub fn open(
+        config: &ShareLogConfig,
+    ) -> Result<(Self, Option<Snapshot>, Vec<ShareRecord>), Error> {
+        let dir = config.dir.clone();
+        fs::create_dir_all(&dir).map_err(|e| Error::Io(dir.clone(), e))?;
+        let snapshot_sequence = Self::sequences(&dir, SNAPSHOT_PREFIX)?.pop();
+        let snapshot = match snapshot_sequence {
+            Some(sequence) => Some(Snapshot::read(&Self::snapshot_path(&dir, sequence))?),
+            None => None,
+        };
+        let first_sequence = snapshot_sequence.unwrap_or(0);
+        let mut sequences = Self::sequences(&dir, SHARES_PREFIX)?;
+        // Older files can be left by a crash while the snapshot was replacing them
+        sequences.retain(|sequence| *sequence >= first_sequence);
+        let mut records = Vec::new();
+        for sequence in &sequences {
+            Self::replay(&Self::file_path(&dir, *sequence), &mut records)?;
+        }
+        let sequence = sequences.last().map_or(first_sequence, |last| last + 1);
+        let (path, writer) = Self::create_file(&dir, sequence)?;
+        let log = Self {
+            dir,
+            rotate_size: config.rotate_size,
+            sequence,
+            path,
+            writer,
+            size: 0,
+            unsynced: false,
+        };
+        Ok((log, snapshot, records))
+    }
+
+    /// Sorted sequences of the files that start with `prefix`
+    fn sequences(dir: &Path, prefix: &str) -> Result<Vec<u64>, Error> {
+        let mut sequences = Vec::new();
+        for entry in fs::read_dir(dir).map_err(|e| Error::Io(dir.into(), e))? {
+            let entry = entry.map_err(|e| Error::Io(dir.into(), e))?;
+            if let Some(sequence) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| Self::sequence_of(name, prefix))
+            {
+                sequences.push(sequence);
+            }
+        }
+        sequences.sort_unstable();
+        Ok(sequences)
+    }
+
+    fn sequence_of(file_name: &str, prefix: &str) -> Option<u64> {
+        file_name
+            .strip_prefix(prefix)?
+            .strip_suffix(".log")?
+            .parse()
+            .ok()
+    }
+
+    fn file_path(dir: &Path, sequence: u64) -> PathBuf {
+        dir.join(format!("{}{:08}.log", SHARES_PREFIX, sequence))
+    }
+
+    fn snapshot_path(dir: &Path, sequence: u64) -> PathBuf {
+        dir.join(format!("{}{:08}.log", SNAPSHOT_PREFIX, sequence))
+    }
+
+    /// Lines that can not be decoded are skipped, the last line of a file can be truncated if
+    /// the pool crashed while writing it
+    fn replay(path: &Path, records: &mut Vec<ShareRecord>) -> Result<(), Error> {
+        let file = File::open(path).map_err(|e| Error::Io(path.into(), e))?;
+        for (number, line) in BufReader::new(file).lines().enumerate() {
+            let line = line.map_err(|e| Error::Io(path.into(), e))?;
+            match ShareRecord::decode(&line) {
+                Some(record) => records.push(record),
+                None => warn!(
+                    path = %path.display(),
+                    line = number + 1,
+                    "invalid share skipped"
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// The directory is synced too so that the new file is not lost
+    fn create_file(dir: &Path, sequence: u64) -> Result<(PathBuf, BufWriter<File>), Error> {
+        let path = Self::file_path(dir, sequence);
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .map_err(|e| Error::Io(path.clone(), e))?;
+        Self::sync_dir(dir)?;
+        Ok((path, BufWriter::new(file)))
+    }
+
+    fn sync_dir(dir: &Path) -> Result<(), Error> {
+        File::open(dir)
+            .and_then(|dir| dir.sync_all())
+            .map_err(|e| Error::Io(dir.into(), e))
+    }
+
+    /// Buffer the share, it is written to disk by the next `sync` unless `sync_now` is set. Return
+    /// true when a new file is started, the ledger must then be compacted.
+    pub fn append(&mut self, record: &ShareRecord, sync_now: bool) -> Result<bool, Error> {
+        let line = record.encode();
+        self.writer
+            .write_all(line.as_bytes())
+            .map_err(|e| Error::Io(self.path.clone(), e))?;
+        self.size += line.len() as u64;
+        self.unsynced = true;
+        if sync_now || self.size >= self.rotate_size {
+            self.sync()?;
+        }
+        if self.size >= self.rotate_size {
+            self.rotate()?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Write the buffered shares and wait for them to be on disk
+    pub fn sync(&mut self) -> Result<(), Error> {
+        if !self.unsynced {
+            return Ok(());
+        }
+        self.writer
+            .flush()
+            .and_then(|_| self.writer.get_ref().sync_data())
+            .map_err(|e| Error::Io(self.path.clone(), e))?;
+        self.unsynced = false;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<(), Error> {
+        let (path, writer) = Self::create_file(&self.dir, self.sequence + 1)?;
+        self.sequence += 1;
+        self.path = path;
+        self.writer = writer;
+        self.size = 0;
+        Ok(())
+    }
+
+    /// Write `snapshot`, the state before the current file, and remove the files it replaces.
+    /// The snapshot is renamed in place once on disk so that it is never read incomplete.
+    pub fn compact(&mut self, snapshot: &Snapshot) -> Result<(), Error> {
+        let tmp_path = self.dir.join("snapshot.tmp");
+        File::create(&tmp_path)
+            .and_then(|mut file| {
+                file.write_all(snapshot.encode().as_bytes())?;
+                file.sync_all()
+            })
+            .map_err(|e| Error::Io(tmp_path.clone(), e))?;
+        let path = Self::snapshot_path(&self.dir, self.sequence);
+        fs::rename(&tmp_path, &path).map_err(|e| Error::Io(path.clone(), e))?;
+        Self::sync_dir(&self.dir)?;
+        for sequence in Self::sequences(&self.dir, SHARES_PREFIX)? {
+            if sequence < self.sequence {
+                let path = Self::file_path(&self.dir, sequence);
+                fs::remove_file(&path).map_err(|e| Error::Io(path, e))?;
+            }
+        }
+        for sequence in Self::sequences(&self.dir, SNAPSHOT_PREFIX)? {
+            if sequence < self.sequence {
+                let path = Self::snapshot_path(&self.dir, sequence);
+                fs::remove_file(&path).map_err(|e| Error::Io(path, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    /// Empty directory, every test has its own
+    fn config(test: &str, rotate_size: u64) -> ShareLogConfig {
+        let dir =
+            std::env::temp_dir().join(format!("pool-share-log-{}-{}", std::process::id(), test));
+        let _ = fs::remove_dir_all(&dir);
+        ShareLogConfig {
+            dir,
+            fsync_interval: Duration::from_millis(100),
+            rotate_size,
+        }
+    }
+
+    fn record(job_id: u32, block: Option<(BlockHash, u64)>) -> ShareRecord {
+        ShareRecord {
+            timestamp_ms: 1_650_000_000_000,
+            user_identity: "alice worker 1".to_string(),
+            channel: (1, 2),
+            job_id,
+            difficulty: 1234.5,
+            network_difficulty: 29_000_000_000_000.25,
+            block_reward: 625_000_000,
+            block,
+        }
+    }
+
+    fn genesis_hash() -> BlockHash {
+        BlockHash::from_str("000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f")
+            .unwrap()
+    }
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            accounts: vec![
+                AccountSnapshot {
+                    user_identity: "alice worker 1".to_string(),
+                    balance: 1500.5,
+                    credited: 0.25,
+                    channels: vec![((1, 2), 3, 3702.5), ((1, 3), 1, 10.0)],
+                },
+                AccountSnapshot {
+                    user_identity: "bob".to_string(),
+                    balance: 0.0,
+                    credited: 0.0,
+                    channels: Vec::new(),
+                },
+            ],
+            window: vec![
+                ("alice worker 1".to_string(), 1234.5),
+                ("bob".to_string(), 1.0),
+            ],
+        }
+    }
+
+    #[test]
+    fn record_encode_decode_round_trip() {
+        for record in [
+            record(7, None),
+            record(8, Some((genesis_hash(), 625_012_345))),
+        ]
+        .iter()
+        {
+            let line = record.encode();
+            assert!(line.ends_with('\n'));
+            assert_eq!(ShareRecord::decode(line.trim_end()).as_ref(), Some(record));
+        }
+    }
+
+    #[test]
+    fn decode_rejects_invalid_lines() {
+        let line = record(7, None).encode();
+        let line = line.trim_end();
+        assert!(ShareRecord::decode("").is_none());
+        assert!(ShareRecord::decode(&line[..line.len() - 3]).is_none());
+        assert!(ShareRecord::decode(&line.replacen("1234.5", "x", 1)).is_none());
+        assert!(ShareRecord::decode(&line.replacen(" - ", " deadbeef:1 ", 1)).is_none());
+    }
+
+    #[test]
+    fn replay_skips_a_torn_last_line() {
+        let config = config("torn", 1024 * 1024);
+        let (mut log, _, records) = ShareLog::open(&config).unwrap();
+        assert!(records.is_empty());
+        assert!(!log.append(&record(1, None), false).unwrap());
+        assert!(!log.append(&record(2, None), true).unwrap());
+        drop(log);
+        // The pool crashed while writing the third share
+        let torn = record(3, None).encode();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(ShareLog::file_path(&config.dir, 0))
+            .unwrap();
+        file.write_all(&torn.as_bytes()[..torn.len() / 2]).unwrap();
+
+        let (log, _, records) = ShareLog::open(&config).unwrap();
+
+        assert_eq!(records, vec![record(1, None), record(2, None)]);
+        // The torn line is never continued
+        assert_eq!(log.sequence, 1);
+    }
+
+    #[test]
+    fn compact_replaces_the_older_files() {
+        // Every share start a new file
+        let config = config("compact", 1);
+        let (mut log, restored, _) = ShareLog::open(&config).unwrap();
+        assert!(restored.is_none());
+        assert!(log.append(&record(1, None), false).unwrap());
+        log.compact(&snapshot()).unwrap();
+        assert!(log.append(&record(2, None), false).unwrap());
+        drop(log);
+
+        let (log, restored, records) = ShareLog::open(&config).unwrap();
+
+        assert_eq!(restored, Some(snapshot()));
+        assert_eq!(records, vec![record(2, None)]);
+        assert_eq!(log.sequence, 3);
+        assert!(!ShareLog::file_path(&config.dir, 0).exists());
+        assert!(ShareLog::snapshot_path(&config.dir, 1).exists());
+    }
+
+    #[test]
+    fn invalid_snapshot_is_an_error() {
+        let config = config("invalid-snapshot", 1024 * 1024);
+        let (mut log, _, _) = ShareLog::open(&config).unwrap();
+        log.compact(&snapshot()).unwrap();
+        let path = ShareLog::snapshot_path(&config.dir, 0);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"window 626f62\n").unwrap();
+
+        assert!(matches!(
+            ShareLog::open(&config),
+            Err(Error::InvalidSnapshot(p, 7)) if p == path
+        ));
+    }
+}
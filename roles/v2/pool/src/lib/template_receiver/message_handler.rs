@@ -7,6 +7,7 @@ use roles_logic_sv2::{
     utils::Mutex,
 };
 use std::sync::Arc;
+use tracing::{debug, warn};
 
 impl ParseServerTemplateDistributionMessages for TemplateRx {
     fn handle_new_template(&mut self, m: NewTemplate) -> Result<SendTo, Error> {
@@ -23,11 +24,20 @@ impl ParseServerTemplateDistributionMessages for TemplateRx {
             coinbase_tx_locktime: m.coinbase_tx_locktime,
             merkle_path: m.merkle_path.into_static(),
         };
+        // The transactions are needed to reconstruct the block and by the job negotiation
+        self.transactions
+            .safe_lock(|t| t.on_request(new_template.template_id))
+            .unwrap();
+        let request_tx_data = RequestTransactionData {
+            template_id: new_template.template_id,
+        };
         let new_template = TemplateDistribution::NewTemplate(new_template);
-        Ok(SendTo::RelayNewMessage(
-            Arc::new(Mutex::new(())),
-            new_template,
-        ))
+        Ok(SendTo::Multiple(vec![
+            SendTo::RelayNewMessage(Arc::new(Mutex::new(())), new_template),
+            SendTo::Respond(TemplateDistribution::RequestTransactionData(
+                request_tx_data,
+            )),
+        ]))
     }
 
     fn handle_set_new_prev_hash(&mut self, m: SetNewPrevHash) -> Result<SendTo, Error> {
@@ -38,6 +48,9 @@ impl ParseServerTemplateDistributionMessages for TemplateRx {
             n_bits: m.n_bits,
             target: m.target.into_static(),
         };
+        self.transactions
+            .safe_lock(|t| t.on_new_prev_hash(new_prev_hash.template_id))
+            .unwrap();
         let new_prev_hash = TemplateDistribution::SetNewPrevHash(new_prev_hash);
         Ok(SendTo::RelayNewMessage(
             Arc::new(Mutex::new(())),
@@ -47,15 +60,34 @@ impl ParseServerTemplateDistributionMessages for TemplateRx {
 
     fn handle_request_tx_data_success(
         &mut self,
-        _m: RequestTransactionDataSuccess,
+        m: RequestTransactionDataSuccess,
     ) -> Result<SendTo, Error> {
-        todo!()
+        let template_id = m.template_id;
+        let cached = self
+            .transactions
+            .safe_lock(|t| t.on_success(template_id, m.transaction_list.to_vec()))
+            .unwrap();
+        if !cached {
+            debug!(
+                template_id,
+                "transactions data for a stale or unknown template dropped"
+            );
+        }
+        Ok(SendTo::None(None))
     }
 
     fn handle_request_tx_data_error(
         &mut self,
-        _m: RequestTransactionDataError,
+        m: RequestTransactionDataError,
     ) -> Result<SendTo, Error> {
-        todo!()
+        warn!(
+            template_id = m.template_id,
+            error_code = %String::from_utf8_lossy(m.error_code.inner_as_ref()),
+            "transactions data not available"
+        );
+        self.transactions
+            .safe_lock(|t| t.on_error(m.template_id))
+            .unwrap();
+        Ok(SendTo::None(None))
     }
 }
@@ -3,51 +3,208 @@ use async_channel::{Receiver, Sender};
 use codec_sv2::Frame;
 use network_helpers::plain_connection_tokio::PlainConnection;
 use roles_logic_sv2::{
-    handlers::template_distribution::ParseServerTemplateDistributionMessages,
+    errors::Error,
+    handlers::{template_distribution::ParseServerTemplateDistributionMessages, SendTo_},
+    job_creator::JobsCreators,
     parsers::{PoolMessages, TemplateDistribution},
-    template_distribution_sv2::{NewTemplate, SetNewPrevHash, SubmitSolution},
-    utils::Mutex,
+    template_distribution_sv2::{
+        CoinbaseOutputDataSize, NewTemplate, SetNewPrevHash, SubmitSolution,
+    },
+    utils::{tx_hash, Mutex},
+};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryInto,
+    net::SocketAddr,
+    sync::Arc,
 };
-use std::{convert::TryInto, net::SocketAddr, sync::Arc};
 use tokio::{net::TcpStream, task};
+use tracing::{error, info, warn};
 
 mod message_handler;
 mod setup_connection;
 use setup_connection::SetupConnectionHandler;
 
+#[derive(Debug)]
+struct TemplateTransactions {
+    tx_hashes: Vec<[u8; 32]>,
+    transactions: Vec<Vec<u8>>,
+}
+
+/// Transactions of the templates received from the Template Provider, the coinbase is not
+/// included. They are asked with RequestTransactionData for every NewTemplate and are dropped when
+/// a SetNewPrevHash make the template stale. Shared with the job negotiation, that use them to
+/// know the transactions of the jobs committed by the Job Negotiators, and with the mining pool,
+/// that use them to assemble the blocks found.
+#[derive(Debug)]
+pub struct TemplatesTransactions {
+    // Templates for which RequestTransactionData has been sent and no response has been received
+    requested: HashSet<u64>,
+    templates: HashMap<u64, TemplateTransactions>,
+}
+
+impl TemplatesTransactions {
+    pub fn new() -> Self {
+        Self {
+            requested: HashSet::new(),
+            templates: HashMap::new(),
+        }
+    }
+
+    fn on_request(&mut self, template_id: u64) {
+        self.requested.insert(template_id);
+    }
+
+    /// Return false if the transactions were not requested or the template is stale
+    fn on_success(&mut self, template_id: u64, transactions: Vec<Vec<u8>>) -> bool {
+        if !self.requested.remove(&template_id) {
+            return false;
+        }
+        let tx_hashes = transactions.iter().map(|tx| tx_hash(tx)).collect();
+        self.templates.insert(
+            template_id,
+            TemplateTransactions {
+                tx_hashes,
+                transactions,
+            },
+        );
+        true
+    }
+
+    fn on_error(&mut self, template_id: u64) {
+        self.requested.remove(&template_id);
+    }
+
+    /// Templates with an id lower than the template activated by SetNewPrevHash are built on the
+    /// old prev hash
+    fn on_new_prev_hash(&mut self, template_id: u64) {
+        self.requested.retain(|id| *id >= template_id);
+        self.templates.retain(|id, _| *id >= template_id);
+    }
+
+    /// Transactions of the template in block order, None if not (or not yet) received or if the
+    /// template is stale
+    pub fn get(&self, template_id: u64) -> Option<&[Vec<u8>]> {
+        self.templates
+            .get(&template_id)
+            .map(|t| &t.transactions[..])
+    }
+
+    pub fn get_by_hash(&self, tx_hash: &[u8; 32]) -> Option<&Vec<u8>> {
+        self.iter()
+            .find(|(hash, _)| *hash == tx_hash)
+            .map(|(_, tx)| tx)
+    }
+
+    /// (tx_hash, transaction) of every transaction in the cached templates
+    pub fn iter(&self) -> impl Iterator<Item = (&[u8; 32], &Vec<u8>)> {
+        self.templates
+            .values()
+            .flat_map(|t| t.tx_hashes.iter().zip(t.transactions.iter()))
+    }
+}
+
+impl Default for TemplatesTransactions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct TemplateRx {
     receiver: Receiver<EitherFrame>,
     sender: Sender<EitherFrame>,
     new_template_sender: Sender<NewTemplate<'static>>,
     new_prev_hash_sender: Sender<SetNewPrevHash<'static>>,
+    job_creators: Arc<Mutex<JobsCreators>>,
+    transactions: Arc<Mutex<TemplatesTransactions>>,
 }
 
 impl TemplateRx {
+    /// Fail if the Template Provider can not be reached or refuse the setup connection
     pub async fn connect(
         address: SocketAddr,
         templ_sender: Sender<NewTemplate<'static>>,
         prev_h_sender: Sender<SetNewPrevHash<'static>>,
         solution_receiver: Receiver<SubmitSolution<'static>>,
-    ) {
-        let stream = TcpStream::connect(address).await.unwrap();
+        job_creators: Arc<Mutex<JobsCreators>>,
+        coinbase_outputs_changed: Receiver<()>,
+        transactions: Arc<Mutex<TemplatesTransactions>>,
+    ) -> Result<(), ()> {
+        let stream = TcpStream::connect(address).await.map_err(|e| {
+            error!(%address, "can not connect to the Template Provider: {}", e);
+        })?;
 
         let (mut receiver, mut sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
             PlainConnection::new(stream).await;
 
         SetupConnectionHandler::setup(&mut receiver, &mut sender, address)
             .await
-            .unwrap();
+            .map_err(|_| {
+                error!(%address, "setup connection with the Template Provider failed");
+            })?;
 
         let self_ = Arc::new(Mutex::new(Self {
             receiver,
             sender,
             new_template_sender: templ_sender,
             new_prev_hash_sender: prev_h_sender,
+            job_creators,
+            transactions,
         }));
+        if Self::send_coinbase_output_data_size(self_.clone())
+            .await
+            .is_err()
+        {
+            warn!("coinbase output data size not sent, Template Provider disconnected");
+        }
+
         let cloned = self_.clone();
+        let cloned2 = self_.clone();
 
         task::spawn(async { Self::start(cloned).await });
+        task::spawn(async {
+            Self::on_coinbase_outputs_changed(cloned2, coinbase_outputs_changed).await
+        });
         task::spawn(async { Self::on_new_solution(self_, solution_receiver).await });
+        Ok(())
+    }
+
+    /// Tell to the Template Provider how many bytes the pool add to the coinbase outputs. Fail if
+    /// the Template Provider is disconnected.
+    async fn send_coinbase_output_data_size(self_: Arc<Mutex<Self>>) -> Result<(), ()> {
+        let coinbase_output_max_additional_size = self_
+            .safe_lock(|s| {
+                s.job_creators
+                    .safe_lock(|jc| jc.coinbase_outputs_size())
+                    .unwrap()
+            })
+            .unwrap();
+        let sv2_frame: StdFrame = PoolMessages::TemplateDistribution(
+            TemplateDistribution::CoinbaseOutputDataSize(CoinbaseOutputDataSize {
+                coinbase_output_max_additional_size,
+            }),
+        )
+        .try_into()
+        .unwrap();
+        Self::send(self_, sv2_frame).await?;
+        info!(
+            coinbase_output_max_additional_size,
+            "coinbase output data size sent"
+        );
+        Ok(())
+    }
+
+    /// The payout configuration changed, the size of the coinbase outputs could be different
+    async fn on_coinbase_outputs_changed(self_: Arc<Mutex<Self>>, rx: Receiver<()>) {
+        while rx.recv().await.is_ok() {
+            if Self::send_coinbase_output_data_size(self_.clone())
+                .await
+                .is_err()
+            {
+                warn!("coinbase output data size not sent, Template Provider disconnected");
+                return;
+            }
+        }
     }
 
     pub async fn start(self_: Arc<Mutex<Self>>) {
@@ -60,54 +217,151 @@ impl TemplateRx {
                 )
             })
             .unwrap();
-        loop {
-            let message_from_tp = receiver.recv().await.unwrap();
+        while let Ok(message_from_tp) = receiver.recv().await {
             let mut message_from_tp: StdFrame = message_from_tp.try_into().unwrap();
             let message_type = message_from_tp.get_header().unwrap().msg_type();
             let payload = message_from_tp.payload();
-            match ParseServerTemplateDistributionMessages::handle_message_template_distribution(
-                self_.clone(),
-                message_type,
-                payload,
-            )
-            .unwrap()
-            {
-                roles_logic_sv2::handlers::SendTo_::RelayNewMessage(_, m) => match m {
-                    TemplateDistribution::CoinbaseOutputDataSize(_) => todo!(),
-                    TemplateDistribution::NewTemplate(m) => {
-                        new_template_sender.send(m).await.unwrap()
+            let send_to =
+                match ParseServerTemplateDistributionMessages::handle_message_template_distribution(
+                    self_.clone(),
+                    message_type,
+                    payload,
+                ) {
+                    Ok(SendTo_::Multiple(send_to)) => send_to,
+                    Ok(send_to) => vec![send_to],
+                    Err(Error::UnexpectedMessage) => {
+                        warn!(
+                            message_type,
+                            "unexpected message from the Template Provider dropped"
+                        );
+                        continue;
                     }
-                    TemplateDistribution::RequestTransactionData(_) => todo!(),
-                    TemplateDistribution::RequestTransactionDataError(_) => todo!(),
-                    TemplateDistribution::RequestTransactionDataSuccess(_) => todo!(),
-                    TemplateDistribution::SetNewPrevHash(m) => {
-                        new_prev_hash_sender.send(m).await.unwrap()
+                    Err(e) => {
+                        warn!(
+                            message_type,
+                            "message from the Template Provider dropped: {}", e
+                        );
+                        continue;
                     }
-                    TemplateDistribution::SubmitSolution(_) => todo!(),
-                },
-                _ => todo!(),
+                };
+            for send_to in send_to {
+                match send_to {
+                    SendTo_::RelayNewMessage(_, m) => match m {
+                        // Sent by the pool, the Template Provider never send it
+                        TemplateDistribution::CoinbaseOutputDataSize(_) => {
+                            warn!("CoinbaseOutputDataSize from the Template Provider dropped")
+                        }
+                        TemplateDistribution::NewTemplate(m) => {
+                            new_template_sender.send(m).await.unwrap()
+                        }
+                        // Sent by the pool, the Template Provider never send it
+                        TemplateDistribution::RequestTransactionData(_) => {
+                            warn!("RequestTransactionData from the Template Provider dropped")
+                        }
+                        // The responses to the RequestTransactionData sent for every NewTemplate
+                        // are cached by the message handler and never relayed
+                        TemplateDistribution::RequestTransactionDataError(_)
+                        | TemplateDistribution::RequestTransactionDataSuccess(_) => (),
+                        TemplateDistribution::SetNewPrevHash(m) => {
+                            new_prev_hash_sender.send(m).await.unwrap()
+                        }
+                        // Sent by the pool, the Template Provider never send it
+                        TemplateDistribution::SubmitSolution(_) => {
+                            warn!("SubmitSolution from the Template Provider dropped")
+                        }
+                    },
+                    SendTo_::Respond(m) => {
+                        let sv2_frame: StdFrame =
+                            PoolMessages::TemplateDistribution(m).try_into().unwrap();
+                        // The loop stop when the Template Provider is disconnected
+                        if Self::send(self_.clone(), sv2_frame).await.is_err() {
+                            warn!("response not sent, Template Provider disconnected");
+                        }
+                    }
+                    SendTo_::None(_) => (),
+                    _ => warn!("unexpected response to the Template Provider dropped"),
+                }
             }
         }
+        error!("Template Provider connection closed");
     }
 
+    /// Fail if the Template Provider is disconnected
     pub async fn send(self_: Arc<Mutex<Self>>, sv2_frame: StdFrame) -> Result<(), ()> {
         let either_frame = sv2_frame.into();
         let sender = self_.safe_lock(|self_| self_.sender.clone()).unwrap();
-        match sender.send(either_frame).await {
-            Ok(_) => Ok(()),
-            Err(_) => {
-                todo!()
-            }
-        }
+        sender.send(either_frame).await.map_err(|_| ())
     }
 
     async fn on_new_solution(self_: Arc<Mutex<Self>>, rx: Receiver<SubmitSolution<'static>>) {
         while let Ok(solution) = rx.recv().await {
+            let template_id = solution.template_id;
             let sv2_frame: StdFrame =
                 PoolMessages::TemplateDistribution(TemplateDistribution::SubmitSolution(solution))
                     .try_into()
                     .unwrap();
-            Self::send(self_.clone(), sv2_frame).await.unwrap();
+            // The block is in the block archive and can be submitted by hand
+            if Self::send(self_.clone(), sv2_frame).await.is_err() {
+                error!(
+                    template_id,
+                    "block solution not sent, Template Provider disconnected"
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transactions(template_id: u64) -> Vec<Vec<u8>> {
+        vec![vec![template_id as u8, 1], vec![template_id as u8, 2]]
+    }
+
+    #[test]
+    fn requested_transactions_are_cached() {
+        let mut cache = TemplatesTransactions::new();
+        cache.on_request(1);
+        assert!(cache.get(1).is_none());
+        assert!(cache.on_success(1, transactions(1)));
+        assert_eq!(cache.get(1), Some(&transactions(1)[..]));
+        let tx_hash = tx_hash(&transactions(1)[1]);
+        assert_eq!(cache.get_by_hash(&tx_hash), Some(&transactions(1)[1]));
+    }
+
+    #[test]
+    fn transactions_not_requested_are_not_cached() {
+        let mut cache = TemplatesTransactions::new();
+        assert!(!cache.on_success(1, transactions(1)));
+        assert!(cache.get(1).is_none());
+    }
+
+    #[test]
+    fn failed_request_is_not_cached() {
+        let mut cache = TemplatesTransactions::new();
+        cache.on_request(1);
+        cache.on_error(1);
+        assert!(!cache.on_success(1, transactions(1)));
+        assert!(cache.get(1).is_none());
+    }
+
+    #[test]
+    fn new_prev_hash_evicts_the_older_templates() {
+        let mut cache = TemplatesTransactions::new();
+        for template_id in 1..=3 {
+            cache.on_request(template_id);
+            assert!(cache.on_success(template_id, transactions(template_id)));
         }
+        // Requested before the prev hash and received after
+        cache.on_request(4);
+        cache.on_request(0);
+        cache.on_new_prev_hash(2);
+        assert!(cache.get(1).is_none());
+        assert_eq!(cache.get(2), Some(&transactions(2)[..]));
+        assert_eq!(cache.get(3), Some(&transactions(3)[..]));
+        assert!(!cache.on_success(0, transactions(0)));
+        assert!(cache.on_success(4, transactions(4)));
+        assert_eq!(cache.iter().count(), 6);
     }
 }
@@ -9,8 +9,12 @@ use roles_logic_sv2::{
     utils::Mutex,
 };
 use std::{convert::TryInto, net::SocketAddr, sync::Arc};
+use tracing::warn;
 
-pub struct SetupConnectionHandler {}
+pub struct SetupConnectionHandler {
+    /// Set by SetupConnectionSuccess
+    accepted: bool,
+}
 
 impl SetupConnectionHandler {
     fn get_setup_connection_message(address: SocketAddr) -> SetupConnection<'static> {
@@ -46,17 +50,26 @@ impl SetupConnectionHandler {
         let sv2_frame = sv2_frame.into();
         sender.send(sv2_frame).await.map_err(|_| ())?;
 
-        let mut incoming: StdFrame = receiver.recv().await.unwrap().try_into().unwrap();
-        let message_type = incoming.get_header().unwrap().msg_type();
+        let mut incoming: StdFrame = receiver
+            .recv()
+            .await
+            .map_err(|_| ())?
+            .try_into()
+            .map_err(|_| ())?;
+        let message_type = incoming.get_header().ok_or(())?.msg_type();
         let payload = incoming.payload();
+        let self_ = Arc::new(Mutex::new(SetupConnectionHandler { accepted: false }));
         ParseUpstreamCommonMessages::handle_message_common(
-            Arc::new(Mutex::new(SetupConnectionHandler {})),
+            self_.clone(),
             message_type,
             payload,
             CommonRoutingLogic::None,
         )
-        .unwrap();
-        Ok(())
+        .map_err(|_| ())?;
+        match self_.safe_lock(|s| s.accepted).map_err(|_| ())? {
+            true => Ok(()),
+            false => Err(()),
+        }
     }
 }
 
@@ -65,14 +78,20 @@ impl ParseUpstreamCommonMessages<NoRouting> for SetupConnectionHandler {
         &mut self,
         _: roles_logic_sv2::common_messages_sv2::SetupConnectionSuccess,
     ) -> Result<roles_logic_sv2::handlers::common::SendTo, roles_logic_sv2::errors::Error> {
+        self.accepted = true;
         Ok(SendTo::None(None))
     }
 
     fn handle_setup_connection_error(
         &mut self,
-        _: roles_logic_sv2::common_messages_sv2::SetupConnectionError,
+        m: roles_logic_sv2::common_messages_sv2::SetupConnectionError,
     ) -> Result<roles_logic_sv2::handlers::common::SendTo, roles_logic_sv2::errors::Error> {
-        todo!()
+        warn!(
+            flags = m.flags,
+            error_code = %String::from_utf8_lossy(m.error_code.inner_as_ref()),
+            "setup connection refused"
+        );
+        Ok(SendTo::None(None))
     }
 
     fn handle_channel_endpoint_changed(
@@ -1,52 +1,126 @@
-use async_channel::bounded;
+use async_channel::{bounded, Sender};
 use codec_sv2::{StandardEitherFrame, StandardSv2Frame};
-use roles_logic_sv2::{
-    bitcoin::{secp256k1::Secp256k1, Network, PrivateKey, PublicKey},
-    parsers::PoolMessages,
-};
+use network_helpers::logging;
+use roles_logic_sv2::{job_creator::JobsCreators, parsers::PoolMessages, utils::Mutex};
+use std::sync::Arc;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
 
 mod lib;
 
-use lib::{mining_pool::Pool, template_receiver::TemplateRx};
+use lib::{
+    block_archive::BlockArchive,
+    config::{Config, Error},
+    mining_pool::Pool,
+    share_ledger::ShareLedger,
+    template_receiver::{TemplateRx, TemplatesTransactions},
+};
 
 pub type Message = PoolMessages<'static>;
 pub type StdFrame = StandardSv2Frame<Message>;
 pub type EitherFrame = StandardEitherFrame<Message>;
 
-const ADDR: &str = "127.0.0.1:34254";
-const TP_ADDR: &str = "127.0.0.1:8442";
 const HOM_GROUP_ID: u32 = u32::MAX;
 
-const PRIVATE_KEY_BTC: [u8; 32] = [34; 32];
-const NETWORK: Network = Network::Testnet;
-
 const BLOCK_REWARD: u64 = 625_000_000_000;
 
-const AUTHORITY_PUBLIC_K: [u8; 32] = [
-    215, 11, 47, 78, 34, 232, 25, 192, 195, 168, 170, 209, 95, 181, 40, 114, 154, 226, 176, 190,
-    90, 169, 238, 89, 191, 183, 97, 63, 194, 119, 11, 31,
-];
-
-const AUTHORITY_PRIVATE_K: [u8; 32] = [
-    204, 93, 167, 220, 169, 204, 172, 35, 9, 84, 174, 208, 171, 89, 25, 53, 196, 209, 161, 148, 4,
-    5, 173, 0, 234, 59, 15, 127, 31, 160, 136, 131,
-];
-
-const CERT_VALIDITY: std::time::Duration = std::time::Duration::from_secs(3600);
-
-fn new_pub_key() -> PublicKey {
-    let priv_k = PrivateKey::from_slice(&PRIVATE_KEY_BTC, NETWORK).unwrap();
-    let secp = Secp256k1::default();
-    PublicKey::from_private_key(&secp, &priv_k)
-}
-
 #[tokio::main]
 async fn main() {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(Error::HelpRequested) => {
+            println!("{}", Error::HelpRequested);
+            return;
+        }
+        Err(e) => {
+            eprintln!("POOL: {}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = logging::init(&config.log_level, config.log_format) {
+        eprintln!("POOL: {}", e);
+        std::process::exit(1);
+    }
     let (s_new_t, r_new_t) = bounded(10);
     let (s_prev_hash, r_prev_hash) = bounded(10);
     let (s_solution, r_solution) = bounded(10);
-    println!("POOL INTITIALIZING ");
-    TemplateRx::connect(TP_ADDR.parse().unwrap(), s_new_t, s_prev_hash, r_solution).await;
-    println!("POOL INITIALIZED");
-    Pool::start(r_new_t, r_prev_hash, s_solution).await;
+    let (s_coinbase_outputs_changed, r_coinbase_outputs_changed) = bounded(1);
+    // Safe unwrap coinbase outputs are validated when the config is loaded
+    let job_creators = Arc::new(Mutex::new(
+        JobsCreators::new(BLOCK_REWARD, config.coinbase_outputs.clone()).unwrap(),
+    ));
+    let transactions = Arc::new(Mutex::new(TemplatesTransactions::new()));
+    let block_archive = match BlockArchive::new(config.block_archive_dir.clone()) {
+        Ok(block_archive) => block_archive,
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    let share_ledger = match ShareLedger::open(config.payout.clone(), &config.share_log) {
+        Ok(share_ledger) => Arc::new(Mutex::new(share_ledger)),
+        Err(e) => {
+            error!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    info!("initializing");
+    if TemplateRx::connect(
+        config.tp_address,
+        s_new_t,
+        s_prev_hash,
+        r_solution,
+        job_creators.clone(),
+        r_coinbase_outputs_changed,
+        transactions.clone(),
+    )
+    .await
+    .is_err()
+    {
+        std::process::exit(1);
+    }
+    let cloned = job_creators.clone();
+    tokio::task::spawn(async {
+        reload_coinbase_outputs(cloned, s_coinbase_outputs_changed).await;
+    });
+    info!(listen_address = %config.listen_address, "initialized");
+    Pool::start(
+        config,
+        job_creators,
+        transactions,
+        block_archive,
+        share_ledger,
+        r_new_t,
+        r_prev_hash,
+        s_solution,
+    )
+    .await;
+}
+
+/// On SIGHUP the config file is read again and the new coinbase outputs are used for the next
+/// jobs, every other field of the config is ignored
+async fn reload_coinbase_outputs(
+    job_creators: Arc<Mutex<JobsCreators>>,
+    coinbase_outputs_changed: Sender<()>,
+) {
+    let mut sighup = signal(SignalKind::hangup()).unwrap();
+    while sighup.recv().await.is_some() {
+        let config = match Config::from_args(std::env::args().skip(1)) {
+            Ok(config) => config,
+            Err(e) => {
+                warn!("config not reloaded: {}", e);
+                continue;
+            }
+        };
+        // Safe unwrap coinbase outputs are validated when the config is loaded
+        job_creators
+            .safe_lock(|jc| jc.set_recipients(config.coinbase_outputs))
+            .unwrap()
+            .unwrap();
+        info!("coinbase outputs reloaded");
+        if coinbase_outputs_changed.send(()).await.is_err() {
+            error!("coinbase outputs not sent to the Template Provider, stop reloading the config");
+            return;
+        }
+    }
 }
@@ -284,7 +284,7 @@ impl IsMiningUpstream<(), NullDownstreamMiningSelector> for Device {
         todo!()
     }
 
-    fn add_hash_rate(&mut self, _to_add: u64) {
+    fn add_hash_rate(&mut self, _to_add: i64) {
         todo!()
     }
     fn get_opened_channels(
@@ -347,11 +347,13 @@ impl ParseUpstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting> fo
         todo!()
     }
 
+    /// Standard jobs already commit to the extranonce, the jobs computed with the new prefix
+    /// follow this message
     fn handle_set_extranonce_prefix(
         &mut self,
         _: SetExtranoncePrefix,
     ) -> Result<SendTo<()>, Error> {
-        todo!()
+        Ok(SendTo::None(None))
     }
 
     fn handle_submit_shares_success(
@@ -455,7 +457,9 @@ impl Miner {
         }
     }
 
-    fn new_target(&mut self, target: Vec<u8>) {
+    /// The target is little endian on the wire
+    fn new_target(&mut self, mut target: Vec<u8>) {
+        target.reverse();
         self.target = Some(Uint256::from_be_bytes(target.try_into().unwrap()));
     }
 
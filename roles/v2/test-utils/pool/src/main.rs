@@ -318,6 +318,11 @@ impl ParseDownstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting>
         todo!()
     }
 
+    fn handle_close_channel(&mut self, m: CloseChannel) -> Result<SendTo<()>, Error> {
+        self.channels_id.retain(|channel_id| *channel_id != m.channel_id);
+        Ok(SendTo::None(None))
+    }
+
     fn handle_submit_shares_standard(
         &mut self,
         _: SubmitSharesStandard,
@@ -0,0 +1,269 @@
+use crate::lib::upstream_sv2::{Sv1Share, Upstream};
+use async_channel::{bounded, Sender};
+use roles_logic_sv2::utils::Mutex;
+use std::{convert::TryInto, net::SocketAddr, sync::Arc};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task,
+};
+use tracing::{debug, info, info_span, warn, Instrument};
+use v1::{
+    client_to_server, json_rpc, server_to_client,
+    utils::{HexBytes, HexU32Be},
+    IsServer,
+};
+
+/// BIP 320 version bits that a Sv1 downstream is allowed to roll
+const VERSION_ROLLING_MASK: u32 = 0x1FFF_E000;
+
+/// A Sv1 mining device (or Sv1 proxy) connected to the translator
+#[derive(Debug)]
+pub struct Downstream {
+    authorized_names: Vec<String>,
+    extranonce1: HexBytes,
+    extranonce2_size: usize,
+    version_rolling_mask: Option<HexU32Be>,
+    version_rolling_min_bit: Option<HexU32Be>,
+    sender_outgoing: Sender<json_rpc::Message>,
+    share_sender: Sender<Sv1Share>,
+    upstream: Arc<Mutex<Upstream>>,
+    // True when the downstream has been authorized and the upstream started to send jobs to it
+    receiving_jobs: bool,
+}
+
+impl Downstream {
+    pub async fn accept_connections(
+        address: SocketAddr,
+        upstream: Arc<Mutex<Upstream>>,
+        share_sender: Sender<Sv1Share>,
+    ) {
+        let listner = TcpListener::bind(address).await.unwrap();
+        while let Ok((stream, peer)) = listner.accept().await {
+            if Self::new(stream, peer, upstream.clone(), share_sender.clone()).is_none() {
+                warn!(remote = %peer, "no more extranonce available, downstream dropped");
+            }
+        }
+    }
+
+    /// Start to serve a Sv1 downstream. Return None if the upstream extranonce space is exhausted.
+    pub fn new(
+        stream: TcpStream,
+        peer: SocketAddr,
+        upstream: Arc<Mutex<Upstream>>,
+        share_sender: Sender<Sv1Share>,
+    ) -> Option<Arc<Mutex<Self>>> {
+        let (extranonce1, extranonce2_size) =
+            upstream.safe_lock(|u| u.new_extranonce()).unwrap()?;
+        // Every event of the downstream is in this span, the extranonce1 identify the downstream
+        // in the shares sent upstream
+        let span = info_span!(
+            "downstream",
+            remote = %peer,
+            extranonce1 = %String::from(HexBytes::from(extranonce1.clone()))
+        );
+        span.in_scope(|| info!("connected"));
+        let (reader, mut writer) = stream.into_split();
+        let (sender_outgoing, receiver_outgoing) = bounded(10);
+
+        let self_ = Arc::new(Mutex::new(Downstream {
+            authorized_names: vec![],
+            extranonce1: extranonce1.into(),
+            extranonce2_size,
+            version_rolling_mask: None,
+            version_rolling_min_bit: None,
+            sender_outgoing: sender_outgoing.clone(),
+            share_sender,
+            upstream,
+            receiving_jobs: false,
+        }));
+
+        task::spawn(
+            async move {
+                while let Ok(message) = receiver_outgoing.recv().await {
+                    let message = format!("{}\n", serde_json::to_string(&message).unwrap());
+                    if let Err(e) = writer.write_all(message.as_bytes()).await {
+                        debug!(error = %e, "write failed");
+                        break;
+                    }
+                }
+            }
+            .instrument(span.clone()),
+        );
+
+        let cloned = self_.clone();
+        task::spawn(
+            async move {
+                let mut messages = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = messages.next_line().await {
+                    let message: json_rpc::Message = match serde_json::from_str(&line) {
+                        Ok(message) => message,
+                        Err(_) => {
+                            warn!(line = %line, "invalid json rpc message");
+                            continue;
+                        }
+                    };
+                    let response = cloned.safe_lock(|d| d.handle_message(message)).unwrap();
+                    match response {
+                        Ok(Some(response)) => {
+                            if sender_outgoing.send(response.into()).await.is_err() {
+                                break;
+                            }
+                        }
+                        Ok(None) => (),
+                        Err(e) => warn!("invalid Sv1 message: {:?}", e),
+                    }
+                    Self::start_jobs_if_authorized(cloned.clone());
+                }
+                info!("disconnected");
+                // Closing the channel stop the writer and remove the downstream from the upstream
+                sender_outgoing.close();
+            }
+            .instrument(span),
+        );
+
+        Some(self_)
+    }
+
+    /// Jobs are sent only after that the downstream has been subscribed and authorized
+    fn start_jobs_if_authorized(self_: Arc<Mutex<Self>>) {
+        let to_start = self_
+            .safe_lock(|d| {
+                if !d.receiving_jobs && !d.authorized_names.is_empty() {
+                    d.receiving_jobs = true;
+                    Some((
+                        d.upstream.clone(),
+                        d.extranonce1.clone(),
+                        d.sender_outgoing.clone(),
+                    ))
+                } else {
+                    None
+                }
+            })
+            .unwrap();
+        if let Some((upstream, extranonce1, sender)) = to_start {
+            upstream
+                .safe_lock(|u| u.add_downstream(extranonce1.as_ref(), sender))
+                .unwrap();
+        }
+    }
+}
+
+impl IsServer for Downstream {
+    fn handle_configure(
+        &mut self,
+        request: &client_to_server::Configure,
+    ) -> (Option<server_to_client::VersionRollingParams>, Option<bool>) {
+        match request.version_rolling_mask() {
+            Some(mask) => {
+                let mask = HexU32Be(mask.0 & VERSION_ROLLING_MASK);
+                let min_bit = request
+                    .version_rolling_min_bit_count()
+                    .unwrap_or(HexU32Be(0));
+                self.version_rolling_mask = Some(mask.clone());
+                self.version_rolling_min_bit = Some(min_bit.clone());
+                (
+                    Some(server_to_client::VersionRollingParams::new(mask, min_bit)),
+                    Some(false),
+                )
+            }
+            None => (None, Some(false)),
+        }
+    }
+
+    fn handle_subscribe(&self, _request: &client_to_server::Subscribe) -> Vec<(String, String)> {
+        let subscription_id: String = self.extranonce1.clone().into();
+        vec![
+            ("mining.set_difficulty".to_string(), subscription_id.clone()),
+            ("mining.notify".to_string(), subscription_id),
+        ]
+    }
+
+    /// The translator has a single identity with the upstream so every worker is authorized
+    fn handle_authorize(&self, _request: &client_to_server::Authorize) -> bool {
+        true
+    }
+
+    /// The share is accepted as soon as it is queued for the upstream: the Sv1 response can not
+    /// wait for the answer of the upstream, that is only logged when the share is rejected (see
+    /// `handle_submit_shares_error`). Shares with an invalid job id or extranonce2 size, or
+    /// received while the queue is full are rejected.
+    fn handle_submit(&self, request: &client_to_server::Submit) -> bool {
+        let job_id = match request.job_id.parse::<u32>() {
+            Ok(job_id) => job_id,
+            Err(_) => {
+                warn!(job_id = %request.job_id, "share rejected: invalid job id");
+                return false;
+            }
+        };
+        if request.extra_nonce2.len() != self.extranonce2_size {
+            warn!(
+                job_id,
+                extranonce2_size = request.extra_nonce2.len(),
+                expected = self.extranonce2_size,
+                "share rejected: invalid extranonce2 size"
+            );
+            return false;
+        }
+        debug!(job_id, worker = %request.user_name, "share received");
+        let mut extranonce = self.extranonce1.as_ref().clone();
+        extranonce.extend_from_slice(request.extra_nonce2.as_ref());
+        let share = Sv1Share {
+            job_id,
+            nonce: request.nonce as u32,
+            ntime: request.time as u32,
+            version_bits: request.version_bits.clone(),
+            version_rolling_mask: self.version_rolling_mask.clone(),
+            extranonce,
+        };
+        self.share_sender.try_send(share).is_ok()
+    }
+
+    fn handle_extranonce_subscribe(&self) {}
+
+    fn is_authorized(&self, name: &str) -> bool {
+        self.authorized_names.iter().any(|n| n == name)
+    }
+
+    fn authorize(&mut self, name: &str) {
+        self.authorized_names.push(name.to_string())
+    }
+
+    /// The extranonce1 is assigned when the downstream connect, a downstream can not choose it
+    fn set_extranonce1(&mut self, _extranonce1: Option<HexBytes>) -> HexBytes {
+        self.extranonce1.clone()
+    }
+
+    fn extranonce1(&self) -> HexBytes {
+        self.extranonce1.clone()
+    }
+
+    fn set_extranonce2_size(&mut self, _extra_nonce2_size: Option<usize>) -> usize {
+        self.extranonce2_size
+    }
+
+    fn extranonce2_size(&self) -> usize {
+        self.extranonce2_size
+    }
+
+    fn version_rolling_mask(&self) -> Option<HexU32Be> {
+        self.version_rolling_mask.clone()
+    }
+
+    fn set_version_rolling_mask(&mut self, mask: Option<HexU32Be>) {
+        self.version_rolling_mask = mask;
+    }
+
+    fn set_version_rolling_min_bit(&mut self, mask: Option<HexU32Be>) {
+        self.version_rolling_min_bit = mask
+    }
+
+    fn notify(&mut self) -> Result<json_rpc::Message, ()> {
+        let notify = self
+            .upstream
+            .safe_lock(|u| u.last_notify())
+            .map_err(|_| ())?
+            .ok_or(())?;
+        notify.try_into()
+    }
+}
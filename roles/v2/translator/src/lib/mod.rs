@@ -0,0 +1,2 @@
+pub mod downstream_sv1;
+pub mod upstream_sv2;
@@ -0,0 +1,389 @@
+use super::{downstream_extranonces, Upstream};
+use roles_logic_sv2::{
+    common_messages_sv2::Protocol,
+    common_properties::{IsMiningUpstream, IsUpstream, RequestIdMapper, UpstreamChannel},
+    errors::Error,
+    handlers::mining::{ParseUpstreamMiningMessages, SendTo, SupportedChannelTypes},
+    mining_sv2::*,
+    routing_logic::NoRouting,
+    selectors::NullDownstreamMiningSelector,
+};
+use std::convert::TryInto;
+use tracing::{debug, error, info, warn};
+use v1::{
+    json_rpc, server_to_client,
+    utils::{HexBytes, HexU32Be, PrevHash},
+};
+
+impl Upstream {
+    /// Build a mining.notify from an extended job and the prev hash on which the job is valid.
+    /// coinbase1 is the coinbase prefix, the Sv1 downstream append extranonce1 (that start with
+    /// the upstream extranonce prefix) + extranonce2 and then coinbase2.
+    fn new_notify(
+        job: &NewExtendedMiningJob<'static>,
+        prev_hash: &SetNewPrevHash<'static>,
+        clean_jobs: bool,
+    ) -> server_to_client::Notify {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let merkle_branch = job
+            .merkle_path
+            .to_vec()
+            .into_iter()
+            .map(HexBytes::from)
+            .collect();
+        server_to_client::Notify {
+            job_id: job.job_id.to_string(),
+            prev_hash: PrevHash(prev_hash.prev_hash.to_vec()),
+            coin_base1: job.coinbase_tx_prefix.to_vec().into(),
+            coin_base2: job.coinbase_tx_suffix.to_vec().into(),
+            merkle_branch,
+            version: HexU32Be(job.version),
+            bits: HexU32Be(prev_hash.nbits),
+            time: HexU32Be(prev_hash.min_ntime.max(now)),
+            clean_jobs,
+        }
+    }
+
+    fn on_new_notify(&mut self, notify: server_to_client::Notify) {
+        self.last_notify = Some(notify.clone());
+        let notify: Result<json_rpc::Message, ()> = notify.try_into();
+        match notify {
+            Ok(notify) => self.broadcast(notify),
+            Err(_) => warn!("impossible to serialize mining.notify"),
+        }
+    }
+}
+
+impl ParseUpstreamMiningMessages<(), NullDownstreamMiningSelector, NoRouting> for Upstream {
+    fn get_channel_type(&self) -> SupportedChannelTypes {
+        SupportedChannelTypes::Extended
+    }
+
+    fn is_work_selection_enabled(&self) -> bool {
+        false
+    }
+
+    fn handle_open_standard_mining_channel_success(
+        &mut self,
+        _: OpenStandardMiningChannelSuccess,
+        _: Option<std::sync::Arc<roles_logic_sv2::utils::Mutex<()>>>,
+    ) -> Result<SendTo<()>, Error> {
+        Err(Error::UnexpectedMessage)
+    }
+
+    fn handle_open_extended_mining_channel_success(
+        &mut self,
+        m: OpenExtendedMiningChannelSuccess,
+    ) -> Result<SendTo<()>, Error> {
+        let prefix = m.extranonce_prefix.to_vec();
+        let extranonce_size = m.extranonce_size as usize;
+        let extranonces = match downstream_extranonces(&prefix) {
+            Some(extranonces)
+                if extranonce_size
+                    >= crate::EXTRANONCE1_TRANSLATOR_LEN + crate::MIN_EXTRANONCE2_SIZE =>
+            {
+                extranonces
+            }
+            _ => {
+                error!(
+                    channel_id = m.channel_id,
+                    extranonce_size,
+                    extranonce_prefix_len = prefix.len(),
+                    "extended channel opened with an extranonce too small for the Sv1 downstreams"
+                );
+                // The translator shut down
+                self.channel_opened = None;
+                return Ok(SendTo::None(None));
+            }
+        };
+        info!(
+            channel_id = m.channel_id,
+            extranonce_prefix = ?prefix,
+            "extended channel opened"
+        );
+        self.extranonce_prefix = prefix;
+        self.extranonce_size = extranonce_size;
+        self.extranonces = Some(extranonces);
+        self.target = m.target.to_vec();
+        self.channel_id = Some(m.channel_id);
+        self.update_channels(UpstreamChannel::Extended);
+        if let Some(channel_opened) = self.channel_opened.take() {
+            let _ = channel_opened.try_send(());
+        }
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_open_mining_channel_error(
+        &mut self,
+        m: OpenMiningChannelError,
+    ) -> Result<SendTo<()>, Error> {
+        error!(
+            error_code = %String::from_utf8_lossy(m.error_code.inner_as_ref()),
+            "upstream refused to open the extended channel"
+        );
+        // The translator shut down
+        self.channel_opened = None;
+        Ok(SendTo::None(None))
+    }
+
+    /// The translator never send UpdateChannel, the nominal hash rate of the channel is 0
+    fn handle_update_channel_error(&mut self, m: UpdateChannelError) -> Result<SendTo<()>, Error> {
+        warn!(
+            channel_id = m.channel_id,
+            error_code = %String::from_utf8_lossy(m.error_code.inner_as_ref()),
+            "update channel refused"
+        );
+        Ok(SendTo::None(None))
+    }
+
+    /// The Sv1 downstreams are disconnected and the extended channel is opened again
+    fn handle_close_channel(&mut self, m: CloseChannel) -> Result<SendTo<()>, Error> {
+        if self.channel_id != Some(m.channel_id) {
+            return Err(Error::UnexpectedMessage);
+        }
+        warn!(
+            channel_id = m.channel_id,
+            reason_code = %String::from_utf8_lossy(m.reason_code.inner_as_ref()),
+            "extended channel closed by the upstream, opening it again"
+        );
+        self.close_extended_channel();
+        let opened = self.expect_channel_opened();
+        self.shutdown_if_not_opened(opened);
+        Ok(SendTo::Respond(Self::open_channel_message()))
+    }
+
+    /// The Sv1 downstreams are disconnected, when they connect again they get an extranonce1 that
+    /// start with the new prefix
+    fn handle_set_extranonce_prefix(
+        &mut self,
+        m: SetExtranoncePrefix,
+    ) -> Result<SendTo<()>, Error> {
+        if self.channel_id != Some(m.channel_id) {
+            return Err(Error::UnexpectedMessage);
+        }
+        let prefix = m.extranonce_prefix.to_vec();
+        match downstream_extranonces(&prefix) {
+            Some(extranonces) => {
+                info!(
+                    channel_id = m.channel_id,
+                    extranonce_prefix = ?prefix,
+                    "extranonce prefix changed"
+                );
+                self.extranonce_prefix = prefix;
+                self.extranonces = Some(extranonces);
+                self.drop_downstreams();
+            }
+            None => {
+                error!(
+                    channel_id = m.channel_id,
+                    extranonce_prefix_len = prefix.len(),
+                    "extranonce prefix too long for the Sv1 downstreams"
+                );
+                self.close_extended_channel();
+                self.shutdown.close();
+            }
+        }
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_submit_shares_success(
+        &mut self,
+        m: SubmitSharesSuccess,
+    ) -> Result<SendTo<()>, Error> {
+        debug!(
+            channel_id = m.channel_id,
+            last_sequence_number = m.last_sequence_number,
+            "shares accepted"
+        );
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_submit_shares_error(&mut self, m: SubmitSharesError) -> Result<SendTo<()>, Error> {
+        warn!(
+            channel_id = m.channel_id,
+            sequence_number = m.sequence_number,
+            error_code = %String::from_utf8_lossy(m.error_code.inner_as_ref()),
+            "share rejected"
+        );
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_new_mining_job(&mut self, _: NewMiningJob) -> Result<SendTo<()>, Error> {
+        Err(Error::UnexpectedMessage)
+    }
+
+    fn handle_new_extended_mining_job(
+        &mut self,
+        m: NewExtendedMiningJob,
+    ) -> Result<SendTo<()>, Error> {
+        let job = m.as_static();
+        match (job.future_job, self.last_prev_hash.clone()) {
+            (false, Some(prev_hash)) => {
+                let notify = Self::new_notify(&job, &prev_hash, false);
+                self.jobs.push(job);
+                self.on_new_notify(notify);
+            }
+            (false, None) => self.jobs.push(job),
+            (true, _) => self.future_jobs.push(job),
+        }
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_set_new_prev_hash(&mut self, m: SetNewPrevHash) -> Result<SendTo<()>, Error> {
+        let prev_hash = m.as_static();
+        match self.future_jobs.iter().position(|j| j.job_id == m.job_id) {
+            Some(index) => self.jobs = vec![self.future_jobs.remove(index)],
+            None => self.jobs.retain(|j| j.job_id == m.job_id),
+        }
+        self.future_jobs = Vec::new();
+        if let Some(job) = self.jobs.last() {
+            let notify = Self::new_notify(job, &prev_hash, true);
+            self.on_new_notify(notify);
+        }
+        self.last_prev_hash = Some(prev_hash);
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_set_custom_mining_job_success(
+        &mut self,
+        _: SetCustomMiningJobSuccess,
+    ) -> Result<SendTo<()>, Error> {
+        Err(Error::UnexpectedMessage)
+    }
+
+    fn handle_set_custom_mining_job_error(
+        &mut self,
+        _: SetCustomMiningJobError,
+    ) -> Result<SendTo<()>, Error> {
+        Err(Error::UnexpectedMessage)
+    }
+
+    fn handle_set_target(&mut self, m: SetTarget) -> Result<SendTo<()>, Error> {
+        self.target = m.maximum_target.to_vec();
+        let set_difficulty = self.set_difficulty();
+        self.broadcast(set_difficulty);
+        Ok(SendTo::None(None))
+    }
+
+    /// The connection is closed and start connect to the new host
+    fn handle_reconnect(&mut self, m: Reconnect) -> Result<SendTo<()>, Error> {
+        let new_host = String::from_utf8_lossy(m.new_host.inner_as_ref()).to_string();
+        info!(new_host = %new_host, new_port = m.new_port, "reconnect asked by the upstream");
+        self.disconnect(Some((new_host, m.new_port)));
+        Ok(SendTo::None(None))
+    }
+}
+
+impl IsUpstream<(), NullDownstreamMiningSelector> for Upstream {
+    fn get_version(&self) -> u16 {
+        self.version
+    }
+
+    fn get_flags(&self) -> u32 {
+        self.flags
+    }
+
+    fn get_supported_protocols(&self) -> Vec<Protocol> {
+        vec![Protocol::MiningProtocol]
+    }
+
+    /// The translator has a single upstream
+    fn get_id(&self) -> u32 {
+        0
+    }
+
+    fn get_mapper(&mut self) -> Option<&mut RequestIdMapper> {
+        None
+    }
+
+    fn get_remote_selector(&mut self) -> &mut NullDownstreamMiningSelector {
+        &mut self.downstream_selector
+    }
+}
+
+impl IsMiningUpstream<(), NullDownstreamMiningSelector> for Upstream {
+    fn total_hash_rate(&self) -> u64 {
+        self.hash_rate
+    }
+
+    fn add_hash_rate(&mut self, to_add: i64) {
+        self.hash_rate = (self.hash_rate as i64 + to_add).max(0) as u64;
+    }
+
+    fn get_opened_channels(&mut self) -> &mut Vec<UpstreamChannel> {
+        &mut self.opened_channels
+    }
+
+    fn update_channels(&mut self, channel: UpstreamChannel) {
+        self.opened_channels.push(channel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use binary_sv2::{Seq0255, U256};
+
+    fn job() -> NewExtendedMiningJob<'static> {
+        let merkle_path: Vec<U256<'static>> = vec![
+            vec![1; 32].try_into().unwrap(),
+            vec![2; 32].try_into().unwrap(),
+        ];
+        NewExtendedMiningJob {
+            channel_id: 1,
+            job_id: 42,
+            future_job: false,
+            version: 0x2000_0000,
+            version_rolling_allowed: true,
+            merkle_path: Seq0255::new(merkle_path).unwrap(),
+            coinbase_tx_prefix: vec![0xaa, 0xbb].try_into().unwrap(),
+            coinbase_tx_suffix: vec![0xcc].try_into().unwrap(),
+        }
+    }
+
+    fn prev_hash(min_ntime: u32) -> SetNewPrevHash<'static> {
+        SetNewPrevHash {
+            channel_id: 1,
+            job_id: 42,
+            prev_hash: (0..32).collect::<Vec<u8>>().try_into().unwrap(),
+            min_ntime,
+            nbits: 0x1d00_ffff,
+        }
+    }
+
+    #[test]
+    fn notify_maps_the_extended_job_and_the_prev_hash() {
+        let notify = Upstream::new_notify(&job(), &prev_hash(0), true);
+
+        assert_eq!(notify.job_id, "42");
+        assert_eq!(notify.prev_hash, PrevHash((0..32).collect()));
+        assert_eq!(notify.coin_base1, HexBytes::from(vec![0xaa, 0xbb]));
+        assert_eq!(notify.coin_base2, HexBytes::from(vec![0xcc]));
+        assert_eq!(
+            notify.merkle_branch,
+            vec![HexBytes::from(vec![1; 32]), HexBytes::from(vec![2; 32])]
+        );
+        assert_eq!(notify.version.0, 0x2000_0000);
+        assert_eq!(notify.bits.0, 0x1d00_ffff);
+        assert!(notify.clean_jobs);
+    }
+
+    #[test]
+    fn notify_time_is_never_before_min_ntime() {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs() as u32;
+        let in_the_future = now + 3600;
+
+        let notify = Upstream::new_notify(&job(), &prev_hash(in_the_future), false);
+        assert_eq!(notify.time.0, in_the_future);
+        assert!(!notify.clean_jobs);
+
+        let notify = Upstream::new_notify(&job(), &prev_hash(0), false);
+        assert!(notify.time.0 >= now);
+    }
+}
@@ -0,0 +1,570 @@
+use crate::{EitherFrame, StdFrame};
+use async_channel::{bounded, Receiver, Sender, TrySendError};
+use codec_sv2::{Frame, HandshakeRole, Initiator};
+use network_helpers::noise_connection_tokio::Connection;
+use roles_logic_sv2::{
+    common_properties::UpstreamChannel,
+    errors::Error,
+    handlers::mining::{ParseUpstreamMiningMessages, SendTo},
+    mining_sv2::{
+        ExtendedExtranonce, NewExtendedMiningJob, OpenExtendedMiningChannel, SetNewPrevHash,
+        SubmitSharesExtended,
+    },
+    parsers::{Mining, PoolMessages},
+    routing_logic::MiningRoutingLogic,
+    selectors::NullDownstreamMiningSelector,
+    utils::{Id, Mutex},
+};
+use std::{
+    convert::TryInto,
+    fmt::{self, Display},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{lookup_host, TcpStream},
+    task,
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use v1::{
+    json_rpc, server_to_client,
+    utils::{HexBytes, HexU32Be},
+};
+
+mod message_handler;
+mod setup_connection;
+use setup_connection::SetupConnectionHandler;
+
+/// Time given to the upstream to answer to OpenExtendedMiningChannel
+const OPEN_CHANNEL_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    Io(std::io::Error),
+    /// The upstream refused the SetupConnection or closed the connection before answering
+    SetupConnection,
+    /// The host of a Reconnect can not be resolved
+    UnknownHost(String),
+    /// The upstream refused to open the extended channel or opened an unusable one
+    ChannelNotOpened,
+    /// The upstream did not answer to OpenExtendedMiningChannel in time
+    OpenChannelTimeout,
+}
+
+impl Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpstreamError::Io(e) => write!(f, "Upstream connection: {}", e),
+            UpstreamError::SetupConnection => write!(f, "Upstream refused the SetupConnection"),
+            UpstreamError::UnknownHost(host) => write!(f, "Upstream host {} not found", host),
+            UpstreamError::ChannelNotOpened => {
+                write!(f, "Upstream did not open a usable extended channel")
+            }
+            UpstreamError::OpenChannelTimeout => write!(
+                f,
+                "Upstream did not open the extended channel in {}s",
+                OPEN_CHANNEL_TIMEOUT.as_secs()
+            ),
+        }
+    }
+}
+
+/// A mining.submit received from a Sv1 downstream. The job_id is the one of the upstream
+/// NewExtendedMiningJob and the extranonce is extranonce1 + extranonce2.
+#[derive(Debug, Clone)]
+pub struct Sv1Share {
+    pub job_id: u32,
+    pub nonce: u32,
+    pub ntime: u32,
+    pub version_bits: Option<HexU32Be>,
+    pub version_rolling_mask: Option<HexU32Be>,
+    pub extranonce: Vec<u8>,
+}
+
+/// Sv2 connection with the upstream, the translator open one and only one extended channel.
+#[derive(Debug)]
+pub struct Upstream {
+    receiver: Receiver<EitherFrame>,
+    sender: Sender<EitherFrame>,
+    /// Changed by Reconnect
+    address: SocketAddr,
+    /// Version and flags accepted by the upstream in SetupConnectionSuccess
+    version: u16,
+    flags: u32,
+    /// Set by Reconnect: (host, port) used when the current connection is closed
+    reconnect_to: Option<(String, u16)>,
+    channel_id: Option<u32>,
+    /// Notified when the extended channel is opened, dropped if it can not be opened
+    channel_opened: Option<Sender<()>>,
+    extranonce_prefix: Vec<u8>,
+    extranonce_size: usize,
+    // Used to carve the extranonce1 of the Sv1 downstreams out of the extended channel extranonce
+    extranonces: Option<ExtendedExtranonce>,
+    target: Vec<u8>,
+    future_jobs: Vec<NewExtendedMiningJob<'static>>,
+    // Jobs that are valid for last_prev_hash
+    jobs: Vec<NewExtendedMiningJob<'static>>,
+    last_prev_hash: Option<SetNewPrevHash<'static>>,
+    last_notify: Option<server_to_client::Notify>,
+    // Sv1 downstreams that are receiving jobs
+    downstreams: Vec<Sender<json_rpc::Message>>,
+    sequence_numbers: Id,
+    hash_rate: u64,
+    opened_channels: Vec<UpstreamChannel>,
+    downstream_selector: NullDownstreamMiningSelector,
+    /// Closed when the upstream is gone and the translator can not work anymore
+    shutdown: Sender<()>,
+}
+
+impl Upstream {
+    /// Connect to the upstream, setup the connection and open the extended channel. Return when
+    /// the channel is opened, with a receiver that is closed when the upstream is lost for good.
+    pub async fn connect(
+        address: SocketAddr,
+        shares: Receiver<Sv1Share>,
+    ) -> Result<(Arc<Mutex<Self>>, Receiver<()>), UpstreamError> {
+        let (receiver, sender, version, flags) = Self::open_connection(address).await?;
+        let (shutdown, shutdown_receiver) = bounded(1);
+
+        let self_ = Arc::new(Mutex::new(Self {
+            receiver,
+            sender,
+            address,
+            version,
+            flags,
+            reconnect_to: None,
+            channel_id: None,
+            channel_opened: None,
+            extranonce_prefix: Vec::new(),
+            extranonce_size: 0,
+            extranonces: None,
+            target: Vec::new(),
+            future_jobs: Vec::new(),
+            jobs: Vec::new(),
+            last_prev_hash: None,
+            last_notify: None,
+            downstreams: Vec::new(),
+            sequence_numbers: Id::new(),
+            hash_rate: 0,
+            opened_channels: Vec::new(),
+            downstream_selector: NullDownstreamMiningSelector::new(),
+            shutdown,
+        }));
+
+        // Every event of the upstream is in this span
+        let span = info_span!("upstream", %address);
+        let cloned = self_.clone();
+        task::spawn(async { Self::start(cloned).await }.instrument(span.clone()));
+        let cloned = self_.clone();
+        task::spawn(async { Self::on_new_share(cloned, shares).await }.instrument(span));
+
+        let opened = self_.safe_lock(|s| s.expect_channel_opened()).unwrap();
+        let sv2_frame: StdFrame = PoolMessages::Mining(Self::open_channel_message())
+            .try_into()
+            .unwrap();
+        Self::send(self_.clone(), sv2_frame)
+            .await
+            .map_err(|_| UpstreamError::ChannelNotOpened)?;
+        Self::wait_channel_opened(opened).await?;
+        Ok((self_, shutdown_receiver))
+    }
+
+    /// Noise handshake and SetupConnection, return the version and the flags accepted by the
+    /// upstream
+    async fn open_connection(
+        address: SocketAddr,
+    ) -> Result<(Receiver<EitherFrame>, Sender<EitherFrame>, u16, u32), UpstreamError> {
+        let stream = TcpStream::connect(address)
+            .await
+            .map_err(UpstreamError::Io)?;
+        // Safe unwrap the authority key is a valid constant
+        let initiator = Initiator::from_raw_k(crate::AUTHORITY_PUBLIC_K).unwrap();
+        let (mut receiver, mut sender): (Receiver<EitherFrame>, Sender<EitherFrame>) =
+            Connection::new(stream, HandshakeRole::Initiator(initiator)).await;
+        let (version, flags) = SetupConnectionHandler::setup(&mut receiver, &mut sender, address)
+            .await
+            .map_err(|_| UpstreamError::SetupConnection)?;
+        Ok((receiver, sender, version, flags))
+    }
+
+    fn open_channel_message() -> Mining<'static> {
+        Mining::OpenExtendedMiningChannel(OpenExtendedMiningChannel {
+            request_id: 0_u32.into(),
+            user_identity: crate::USER_IDENTITY.to_string().try_into().unwrap(),
+            nominal_hash_rate: 0.0,
+            max_target: vec![0xff; 32].try_into().unwrap(),
+            min_extranonce_size: (crate::EXTRANONCE1_TRANSLATOR_LEN + crate::MIN_EXTRANONCE2_SIZE)
+                as u16,
+        })
+    }
+
+    /// Called before that OpenExtendedMiningChannel is sent, the returned receiver is notified
+    /// when the channel is opened
+    fn expect_channel_opened(&mut self) -> Receiver<()> {
+        let (channel_opened, opened) = bounded(1);
+        self.channel_opened = Some(channel_opened);
+        opened
+    }
+
+    async fn wait_channel_opened(opened: Receiver<()>) -> Result<(), UpstreamError> {
+        match tokio::time::timeout(OPEN_CHANNEL_TIMEOUT, opened.recv()).await {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(_)) => Err(UpstreamError::ChannelNotOpened),
+            Err(_) => Err(UpstreamError::OpenChannelTimeout),
+        }
+    }
+
+    /// Used when the channel is reopened while the messages are handled: the translator shut down
+    /// if the upstream do not open it
+    fn shutdown_if_not_opened(&self, opened: Receiver<()>) {
+        let shutdown = self.shutdown.clone();
+        task::spawn(
+            async move {
+                if let Err(e) = Self::wait_channel_opened(opened).await {
+                    error!("{}", e);
+                    shutdown.close();
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Handle the upstream messages. When the connection is closed, or the upstream sent
+    /// Reconnect, connect again and reopen the extended channel. If that is not possible the
+    /// translator shut down.
+    pub async fn start(self_: Arc<Mutex<Self>>) {
+        loop {
+            let receiver = self_.safe_lock(|s| s.receiver.clone()).unwrap();
+            while let Ok(incoming) = receiver.recv().await {
+                Self::on_frame(self_.clone(), incoming).await;
+            }
+            let reconnect_to = self_.safe_lock(|s| s.reconnect_to.take()).unwrap();
+            if reconnect_to.is_none() {
+                warn!("upstream connection closed");
+            }
+            if let Err(e) = Self::reconnect(self_.clone(), reconnect_to).await {
+                error!("{}", e);
+                self_.safe_lock(|s| s.shutdown.close()).unwrap();
+                return;
+            }
+        }
+    }
+
+    async fn on_frame(self_: Arc<Mutex<Self>>, incoming: EitherFrame) {
+        let mut incoming: StdFrame = match incoming.try_into() {
+            Ok(incoming) => incoming,
+            Err(_) => {
+                warn!("invalid frame dropped");
+                return;
+            }
+        };
+        let message_type = match incoming.get_header() {
+            Some(header) => header.msg_type(),
+            None => {
+                warn!("frame without header dropped");
+                return;
+            }
+        };
+        let payload = incoming.payload();
+        let handled = info_span!("message", msg_type = message_type).in_scope(|| {
+            ParseUpstreamMiningMessages::handle_message_mining(
+                self_.clone(),
+                message_type,
+                payload,
+                MiningRoutingLogic::None,
+            )
+        });
+        match handled {
+            Ok(SendTo::Respond(message)) => {
+                let sv2_frame: StdFrame = PoolMessages::Mining(message).try_into().unwrap();
+                // When the connection is closed start connect again
+                if Self::send(self_, sv2_frame).await.is_err() {
+                    warn!(
+                        msg_type = message_type,
+                        "response not sent, upstream disconnected"
+                    );
+                }
+            }
+            // The handlers of the translator only respond or do nothing
+            Ok(_) => (),
+            Err(Error::UnexpectedMessage) => {
+                warn!(msg_type = message_type, "unexpected message")
+            }
+            Err(e) => warn!(msg_type = message_type, "invalid message: {}", e),
+        }
+    }
+
+    /// Connect to the address of the last Reconnect (or to the current upstream) and reopen the
+    /// extended channel. The Sv1 downstreams are disconnected as their extranonce1 start with the
+    /// extranonce prefix of the old channel.
+    async fn reconnect(
+        self_: Arc<Mutex<Self>>,
+        reconnect_to: Option<(String, u16)>,
+    ) -> Result<(), UpstreamError> {
+        let current = self_.safe_lock(|s| s.address).unwrap();
+        let address = match reconnect_to {
+            Some((host, port)) => reconnect_address(current, host, port).await?,
+            None => current,
+        };
+        info!(%address, "connecting to the upstream");
+        let (receiver, sender, version, flags) = Self::open_connection(address).await?;
+        let opened = self_
+            .safe_lock(|s| {
+                s.close_extended_channel();
+                s.receiver = receiver;
+                s.sender = sender;
+                s.address = address;
+                s.version = version;
+                s.flags = flags;
+                s.expect_channel_opened()
+            })
+            .unwrap();
+        let sv2_frame: StdFrame = PoolMessages::Mining(Self::open_channel_message())
+            .try_into()
+            .unwrap();
+        Self::send(self_.clone(), sv2_frame)
+            .await
+            .map_err(|_| UpstreamError::ChannelNotOpened)?;
+        self_
+            .safe_lock(|s| s.shutdown_if_not_opened(opened))
+            .unwrap();
+        Ok(())
+    }
+
+    /// Close the current connection, start connect again to the given host
+    fn disconnect(&mut self, reconnect_to: Option<(String, u16)>) {
+        self.reconnect_to = reconnect_to;
+        self.receiver.close();
+        self.sender.close();
+    }
+
+    /// Forget the extended channel and disconnect the Sv1 downstreams
+    fn close_extended_channel(&mut self) {
+        self.channel_id = None;
+        self.extranonces = None;
+        self.future_jobs = Vec::new();
+        self.jobs = Vec::new();
+        self.last_prev_hash = None;
+        self.last_notify = None;
+        self.opened_channels = Vec::new();
+        self.drop_downstreams();
+    }
+
+    /// The extranonce1 of a Sv1 downstream can not be changed, when the extranonce prefix change
+    /// the downstreams are disconnected so that they subscribe again and get a new extranonce1
+    fn drop_downstreams(&mut self) {
+        if !self.downstreams.is_empty() {
+            info!(
+                downstreams = self.downstreams.len(),
+                "Sv1 downstreams disconnected"
+            );
+        }
+        for downstream in self.downstreams.drain(..) {
+            downstream.close();
+        }
+    }
+
+    pub async fn send(self_: Arc<Mutex<Self>>, sv2_frame: StdFrame) -> Result<(), ()> {
+        let either_frame = sv2_frame.into();
+        let sender = self_.safe_lock(|self_| self_.sender.clone()).unwrap();
+        sender.send(either_frame).await.map_err(|_| ())
+    }
+
+    async fn on_new_share(self_: Arc<Mutex<Self>>, rx: Receiver<Sv1Share>) {
+        while let Ok(share) = rx.recv().await {
+            let job_id = share.job_id;
+            let extranonce = String::from(HexBytes::from(share.extranonce.clone()));
+            match self_.safe_lock(|s| s.share_to_submit(share)).unwrap() {
+                Some(submit) => {
+                    // The extranonce starts with the extranonce1 of the downstream
+                    debug!(
+                        job_id,
+                        sequence_number = submit.sequence_number,
+                        extranonce = %extranonce,
+                        "share submitted"
+                    );
+                    let sv2_frame: StdFrame =
+                        PoolMessages::Mining(Mining::SubmitSharesExtended(submit))
+                            .try_into()
+                            .unwrap();
+                    if Self::send(self_.clone(), sv2_frame).await.is_err() {
+                        warn!(job_id, "share dropped, upstream disconnected");
+                    }
+                }
+                None => warn!(job_id, extranonce = %extranonce, "share for a stale job dropped"),
+            }
+        }
+    }
+
+    /// Translate a Sv1 share into a SubmitSharesExtended for the upstream extended channel.
+    /// Return None if the share refer to a job that is no more valid.
+    fn share_to_submit(&mut self, share: Sv1Share) -> Option<SubmitSharesExtended<'static>> {
+        // The extranonce1 of the downstream was given for an old extranonce prefix
+        if !share.extranonce.starts_with(&self.extranonce_prefix) {
+            return None;
+        }
+        let job = self.jobs.iter().find(|j| j.job_id == share.job_id)?;
+        let version = match (share.version_bits, share.version_rolling_mask) {
+            (Some(bits), Some(mask)) => (job.version & !mask.0) | (bits.0 & mask.0),
+            _ => job.version,
+        };
+        let extranonce = share
+            .extranonce
+            .get(self.extranonce_prefix.len()..)?
+            .to_vec();
+        Some(SubmitSharesExtended {
+            channel_id: self.channel_id?,
+            sequence_number: self.sequence_numbers.next(),
+            job_id: share.job_id,
+            nonce: share.nonce,
+            ntime: share.ntime,
+            version,
+            extranonce: extranonce.try_into().ok()?,
+        })
+    }
+
+    /// Return a new extranonce1 and the extranonce2 size for a Sv1 downstream. The extranonce1 is
+    /// made by the upstream extranonce prefix plus `EXTRANONCE1_TRANSLATOR_LEN` bytes that are
+    /// unique for each downstream.
+    pub fn new_extranonce(&mut self) -> Option<(Vec<u8>, usize)> {
+        let extranonce2_size = self.extranonce_size - crate::EXTRANONCE1_TRANSLATOR_LEN;
+        let extranonce1 = self
+            .extranonces
+            .as_mut()?
+            .next_prefix_extended(extranonce2_size)?;
+        Some((extranonce1, extranonce2_size))
+    }
+
+    /// Start to send jobs to a Sv1 downstream, the current difficulty and the last job are sent
+    /// immediately. A downstream with an extranonce1 given for an old extranonce prefix is
+    /// disconnected.
+    pub fn add_downstream(&mut self, extranonce1: &[u8], downstream: Sender<json_rpc::Message>) {
+        if self.channel_id.is_none() || !extranonce1.starts_with(&self.extranonce_prefix) {
+            downstream.close();
+            return;
+        }
+        let _ = downstream.try_send(self.set_difficulty());
+        if let Some(notify) = self.last_notify.clone() {
+            if let Ok(notify) = notify.try_into() {
+                let _ = downstream.try_send(notify);
+            }
+        }
+        self.downstreams.push(downstream);
+    }
+
+    pub fn last_notify(&self) -> Option<server_to_client::Notify> {
+        self.last_notify.clone()
+    }
+
+    fn set_difficulty(&self) -> json_rpc::Message {
+        server_to_client::SetDifficulty {
+            value: target_to_difficulty(&self.target),
+        }
+        .into()
+    }
+
+    /// Send a Sv1 message to every downstream, downstreams that are disconnected are removed
+    fn broadcast(&mut self, message: json_rpc::Message) {
+        self.downstreams
+            .retain(|d| !matches!(d.try_send(message.clone()), Err(TrySendError::Closed(_))));
+    }
+}
+
+/// Address of a Reconnect, an empty host and a 0 port mean the ones of the current upstream
+async fn reconnect_address(
+    current: SocketAddr,
+    host: String,
+    port: u16,
+) -> Result<SocketAddr, UpstreamError> {
+    let port = match port {
+        0 => current.port(),
+        port => port,
+    };
+    if host.is_empty() {
+        return Ok(SocketAddr::new(current.ip(), port));
+    }
+    let address = lookup_host((host.as_str(), port))
+        .await
+        .map_err(UpstreamError::Io)?
+        .next();
+    address.ok_or(UpstreamError::UnknownHost(host))
+}
+
+/// Extranonce of the Sv1 downstreams: the upstream prefix, `EXTRANONCE1_TRANSLATOR_LEN` bytes
+/// that are unique for each downstream and the extranonce2. None if the prefix is too long.
+fn downstream_extranonces(prefix: &[u8]) -> Option<ExtendedExtranonce> {
+    let range_1 = prefix.len()..prefix.len() + crate::EXTRANONCE1_TRANSLATOR_LEN;
+    let range_2 = range_1.end..32;
+    if range_2.start >= range_2.end {
+        return None;
+    }
+    ExtendedExtranonce::from_upstream_prefix(prefix, range_1, range_2)
+}
+
+/// Targets are 32 bytes little endian as they are sent on the wire
+fn target_to_difficulty(target: &[u8]) -> f64 {
+    // target of difficulty 1 is 0x00000000ffff0000000000000000000000000000000000000000000000000000
+    let difficulty_1_target = 65535.0 * 2_f64.powi(208);
+    let target = target
+        .iter()
+        .rev()
+        .fold(0.0, |acc, b| acc * 256.0 + *b as f64);
+    if target == 0.0 {
+        difficulty_1_target
+    } else {
+        difficulty_1_target / target
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Little endian target of difficulty 1
+    fn difficulty_1_target() -> Vec<u8> {
+        let mut target = vec![0; 32];
+        target[27] = 0xff;
+        target[26] = 0xff;
+        target
+    }
+
+    #[test]
+    fn difficulty_1_target_has_difficulty_1() {
+        assert!((target_to_difficulty(&difficulty_1_target()) - 1.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn halving_the_target_doubles_the_difficulty() {
+        let mut target = difficulty_1_target();
+        target[27] = 0x7f;
+        target[26] = 0xff;
+        target[25] = 0x80;
+        assert!((target_to_difficulty(&target) - 2.0).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn max_target_has_the_lowest_difficulty() {
+        let difficulty = target_to_difficulty(&[0xff; 32]);
+        assert!(difficulty > 0.0 && difficulty < 1.0 / 4_000_000_000.0);
+    }
+
+    #[test]
+    fn zero_target_is_treated_as_impossible_to_meet() {
+        assert!(target_to_difficulty(&[0; 32]) > 1e60);
+    }
+
+    #[test]
+    fn downstream_extranonces_start_with_the_upstream_prefix() {
+        let mut extranonces = downstream_extranonces(&[1, 2, 3]).unwrap();
+        let extranonce1 = extranonces.next_prefix_extended(4).unwrap();
+        assert_eq!(extranonce1.len(), 3 + crate::EXTRANONCE1_TRANSLATOR_LEN);
+        assert!(extranonce1.starts_with(&[1, 2, 3]));
+        assert_ne!(extranonces.next_prefix_extended(4).unwrap(), extranonce1);
+    }
+
+    #[test]
+    fn prefix_too_long_has_no_downstream_extranonces() {
+        assert!(downstream_extranonces(&[0; 32]).is_none());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::{EitherFrame, StdFrame};
+use async_channel::{Receiver, Sender};
+use codec_sv2::Frame;
+use roles_logic_sv2::{
+    common_messages_sv2::{Protocol, SetupConnection},
+    handlers::common::{ParseUpstreamCommonMessages, SendTo},
+    parsers::PoolMessages,
+    routing_logic::{CommonRoutingLogic, NoRouting},
+    utils::Mutex,
+};
+use std::{convert::TryInto, net::SocketAddr, sync::Arc};
+use tracing::warn;
+
+pub struct SetupConnectionHandler {
+    /// Version and flags of the SetupConnectionSuccess
+    accepted: Option<(u16, u32)>,
+}
+
+impl SetupConnectionHandler {
+    fn get_setup_connection_message(address: SocketAddr) -> SetupConnection<'static> {
+        let endpoint_host = address.ip().to_string().into_bytes().try_into().unwrap();
+        let vendor = String::new().try_into().unwrap();
+        let hardware_version = String::new().try_into().unwrap();
+        let firmware = String::new().try_into().unwrap();
+        let device_id = String::new().try_into().unwrap();
+        SetupConnection {
+            protocol: Protocol::MiningProtocol,
+            min_version: 2,
+            max_version: 2,
+            flags: 0b0000_0000_0000_0000_0000_0000_0000_0000,
+            endpoint_host,
+            endpoint_port: address.port(),
+            vendor,
+            hardware_version,
+            firmware,
+            device_id,
+        }
+    }
+
+    /// Return the version and the flags accepted by the upstream
+    pub async fn setup(
+        receiver: &mut Receiver<EitherFrame>,
+        sender: &mut Sender<EitherFrame>,
+        address: SocketAddr,
+    ) -> Result<(u16, u32), ()> {
+        let setup_connection = Self::get_setup_connection_message(address);
+
+        let sv2_frame: StdFrame = PoolMessages::Common(setup_connection.into())
+            .try_into()
+            .unwrap();
+        let sv2_frame = sv2_frame.into();
+        sender.send(sv2_frame).await.map_err(|_| ())?;
+
+        let mut incoming: StdFrame = receiver
+            .recv()
+            .await
+            .map_err(|_| ())?
+            .try_into()
+            .map_err(|_| ())?;
+        let message_type = incoming.get_header().ok_or(())?.msg_type();
+        let payload = incoming.payload();
+        let self_ = Arc::new(Mutex::new(SetupConnectionHandler { accepted: None }));
+        ParseUpstreamCommonMessages::handle_message_common(
+            self_.clone(),
+            message_type,
+            payload,
+            CommonRoutingLogic::None,
+        )
+        .map_err(|_| ())?;
+        self_.safe_lock(|s| s.accepted).map_err(|_| ())?.ok_or(())
+    }
+}
+
+impl ParseUpstreamCommonMessages<NoRouting> for SetupConnectionHandler {
+    fn handle_setup_connection_success(
+        &mut self,
+        m: roles_logic_sv2::common_messages_sv2::SetupConnectionSuccess,
+    ) -> Result<roles_logic_sv2::handlers::common::SendTo, roles_logic_sv2::errors::Error> {
+        self.accepted = Some((m.used_version, m.flags));
+        Ok(SendTo::None(None))
+    }
+
+    fn handle_setup_connection_error(
+        &mut self,
+        m: roles_logic_sv2::common_messages_sv2::SetupConnectionError,
+    ) -> Result<roles_logic_sv2::handlers::common::SendTo, roles_logic_sv2::errors::Error> {
+        warn!(
+            flags = m.flags,
+            error_code = %String::from_utf8_lossy(m.error_code.inner_as_ref()),
+            "setup connection refused"
+        );
+        Ok(SendTo::None(None))
+    }
+
+    /// No channel is opened before that the connection is set up
+    fn handle_channel_endpoint_changed(
+        &mut self,
+        _: roles_logic_sv2::common_messages_sv2::ChannelEndpointChanged,
+    ) -> Result<roles_logic_sv2::handlers::common::SendTo, roles_logic_sv2::errors::Error> {
+        Err(roles_logic_sv2::errors::Error::UnexpectedMessage)
+    }
+}
@@ -0,0 +1,69 @@
+//! Sv1 to Sv2 translator proxy
+//!
+//! Downstream means a Sv1 mining device (or a Sv1 proxy)
+//! Upstream means a Sv2 pool (or a Sv2 proxy)
+//!
+//! The translator open one extended channel with the upstream and split the extranonce space of
+//! that channel between the downstreams: each Sv1 downstream get its own extranonce1 and roll the
+//! remaining bytes as extranonce2.
+//!
+//! Sv2 NewExtendedMiningJob + SetNewPrevHash are translated into mining.notify
+//! Sv2 SetTarget is translated into mining.set_difficulty
+//! Sv1 mining.submit is translated into SubmitSharesExtended
+//!
+use codec_sv2::{StandardEitherFrame, StandardSv2Frame};
+use network_helpers::logging;
+use roles_logic_sv2::parsers::PoolMessages;
+use tracing::{error, info};
+
+mod lib;
+
+use lib::{downstream_sv1::Downstream, upstream_sv2::Upstream};
+
+pub type Message = PoolMessages<'static>;
+pub type StdFrame = StandardSv2Frame<Message>;
+pub type EitherFrame = StandardEitherFrame<Message>;
+
+const LISTEN_ADDR: &str = "0.0.0.0:34255";
+const UPSTREAM_ADDR: &str = "127.0.0.1:34254";
+
+const AUTHORITY_PUBLIC_K: [u8; 32] = [
+    215, 11, 47, 78, 34, 232, 25, 192, 195, 168, 170, 209, 95, 181, 40, 114, 154, 226, 176, 190,
+    90, 169, 238, 89, 191, 183, 97, 63, 194, 119, 11, 31,
+];
+
+/// Identity used to open the extended channel with the upstream
+const USER_IDENTITY: &str = "translator";
+
+/// Bytes of the upstream extranonce that the translator reserve to tell apart its downstreams
+const EXTRANONCE1_TRANSLATOR_LEN: usize = 4;
+
+/// Extranonce2 size that the translator ask for the Sv1 downstreams
+const MIN_EXTRANONCE2_SIZE: usize = 8;
+
+#[tokio::main]
+async fn main() {
+    // The level is set with RUST_LOG and the format with SV2_LOG_FORMAT
+    if let Err(e) = logging::init_from_env() {
+        eprintln!("TRANSLATOR: {}", e);
+        std::process::exit(1);
+    }
+    info!("initializing");
+    let (s_share, r_share) = async_channel::bounded(100);
+    let (upstream, shutdown) =
+        match Upstream::connect(UPSTREAM_ADDR.parse().unwrap(), r_share).await {
+            Ok(upstream) => upstream,
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        };
+    info!(listen_address = LISTEN_ADDR, "initialized");
+    tokio::select! {
+        _ = Downstream::accept_connections(LISTEN_ADDR.parse().unwrap(), upstream, s_share) => (),
+        // Closed when the upstream is lost and can not be reached again
+        _ = shutdown.recv() => (),
+    }
+    error!("upstream lost, shutting down");
+    std::process::exit(1);
+}
@@ -0,0 +1,264 @@
+//! Minimal HTTP/1.1 server used by the roles to expose operational endpoints (metrics, admin).
+//! Every connection serve one request and is then closed, bodies are read only when a
+//! Content-Length is given. A client has `REQUEST_TIMEOUT` to send the request, so that idle
+//! connections do not pile up.
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+    task, time,
+};
+use tracing::warn;
+
+/// Requests with a bigger body are rejected
+const MAX_BODY_SIZE: usize = 64 * 1024;
+/// Requests with a bigger request line and headers are rejected
+const MAX_HEADER_SIZE: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait after a failed accept, it usually fail because there are too many open files
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    /// Path without the query string
+    pub path: String,
+    /// (name, value) with the spaces around the value removed
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    /// Value of the first header with this name, names are case insensitive
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(header, _)| header.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: String,
+}
+
+impl Response {
+    pub fn new(status: u16, content_type: &'static str, body: String) -> Self {
+        Self {
+            status,
+            content_type,
+            body,
+        }
+    }
+
+    pub fn not_found() -> Self {
+        Self::new(404, "text/plain", "Not Found\n".to_string())
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            408 => "Request Timeout",
+            413 => "Payload Too Large",
+            431 => "Request Header Fields Too Large",
+            503 => "Service Unavailable",
+            _ => "",
+        }
+    }
+}
+
+/// Listen on `address` and answer every request with `handler`
+pub async fn serve<F>(address: SocketAddr, handler: F) -> std::io::Result<()>
+where
+    F: Fn(Request) -> Response + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(address).await?;
+    let handler = Arc::new(handler);
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                warn!(%address, "can not accept a connection: {}", e);
+                time::sleep(ACCEPT_ERROR_DELAY).await;
+                continue;
+            }
+        };
+        let handler = handler.clone();
+        task::spawn(async move {
+            // The client is gone, nothing to answer
+            let _ = handle_connection(stream, handler.as_ref()).await;
+        });
+    }
+}
+
+async fn handle_connection<F>(mut stream: TcpStream, handler: &F) -> std::io::Result<()>
+where
+    F: Fn(Request) -> Response,
+{
+    let response = match time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await {
+        Ok(request) => match request? {
+            Ok(request) => handler(request),
+            Err(response) => response,
+        },
+        Err(_) => Response::new(408, "text/plain", "Request Timeout\n".to_string()),
+    };
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.reason(),
+        response.content_type,
+        response.body.len()
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Return the response to send back when the request is not valid
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<Result<Request, Response>> {
+    let bad_request = || Response::new(400, "text/plain", "Bad Request\n".to_string());
+    let too_large = || {
+        Response::new(
+            431,
+            "text/plain",
+            "Request Header Fields Too Large\n".to_string(),
+        )
+    };
+    let mut reader = BufReader::new(stream);
+    // Bytes of the request line and headers that can still be read
+    let mut header_size_left = MAX_HEADER_SIZE;
+    let mut request_line = String::new();
+    match read_line(&mut reader, &mut request_line, &mut header_size_left).await? {
+        Some(_) => (),
+        None => return Ok(Err(too_large())),
+    }
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = match (parts.next(), parts.next()) {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Ok(Err(bad_request())),
+    };
+    let path = target.split('?').next().unwrap_or("").to_string();
+    let mut headers = Vec::new();
+    let mut content_length = 0;
+    loop {
+        let mut header = String::new();
+        match read_line(&mut reader, &mut header, &mut header_size_left).await? {
+            Some(0) => return Ok(Err(bad_request())),
+            Some(_) => (),
+            None => return Ok(Err(too_large())),
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            let (name, value) = (name.trim(), value.trim());
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = match value.parse() {
+                    Ok(length) => length,
+                    Err(_) => return Ok(Err(bad_request())),
+                };
+            }
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    if content_length > MAX_BODY_SIZE {
+        return Ok(Err(Response::new(
+            413,
+            "text/plain",
+            "Payload Too Large\n".to_string(),
+        )));
+    }
+    let mut body = vec![0; content_length];
+    reader.read_exact(&mut body).await?;
+    Ok(Ok(Request {
+        method,
+        path,
+        headers,
+        body,
+    }))
+}
+
+/// Read a line of at most `size_left` bytes and subtract its length. Return None if the line is
+/// longer, otherwise the bytes read, 0 when the connection is closed.
+async fn read_line(
+    reader: &mut BufReader<&mut TcpStream>,
+    line: &mut String,
+    size_left: &mut usize,
+) -> std::io::Result<Option<usize>> {
+    if *size_left == 0 {
+        return Ok(None);
+    }
+    let read = (&mut *reader)
+        .take(*size_left as u64)
+        .read_line(line)
+        .await?;
+    *size_left -= read;
+    match *size_left == 0 && !line.ends_with('\n') {
+        true => Ok(None),
+        false => Ok(Some(read)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Send `request` to a connection handled by `handle_connection` and return the response
+    async fn exchange(request: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let handler = |request: Request| {
+                let body = format!(
+                    "{} {} {} {}",
+                    request.method,
+                    request.path,
+                    request.header("x-token").unwrap_or(""),
+                    request.body.len()
+                );
+                Response::new(200, "text/plain", body)
+            };
+            handle_connection(stream, &handler).await.unwrap();
+        });
+        let mut client = TcpStream::connect(address).await.unwrap();
+        client.write_all(&request).await.unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).await.unwrap();
+        server.await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn request_is_passed_to_the_handler() {
+        let response = exchange(
+            b"POST /admin?x=1 HTTP/1.1\r\nX-Token:  abc \r\nContent-Length: 3\r\n\r\nabc".to_vec(),
+        )
+        .await;
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\nPOST /admin abc 3"));
+    }
+
+    #[tokio::test]
+    async fn oversized_headers_are_rejected() {
+        // Every byte is read by the server, a close with unread bytes would reset the connection
+        let mut request = b"GET /metrics HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.resize(MAX_HEADER_SIZE, b'a');
+        let response = exchange(request).await;
+        assert!(response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn malformed_request_is_rejected() {
+        let response = exchange(b"GET\r\n\r\n".to_vec()).await;
+        assert!(response.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+    }
+}
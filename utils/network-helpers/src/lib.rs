@@ -7,6 +7,10 @@ pub use noise_connection_async_std::{connect, listen, Connection};
 #[cfg(feature = "async_std")]
 pub use plain_connection_async_std::{plain_connect, plain_listen, PlainConnection};
 
+#[cfg(feature = "tokio")]
+pub mod http_tokio;
+#[cfg(feature = "with_logging")]
+pub mod logging;
 #[cfg(feature = "tokio")]
 pub mod noise_connection_tokio;
 #[cfg(feature = "tokio")]
@@ -0,0 +1,97 @@
+//! Subscriber of the `tracing` events emitted by the roles and by the connections of this crate.
+//! Events are written on stdout as text or as one JSON object per line, both carry the fields of
+//! the spans in which they are emitted (connection, remote address, channel, message type).
+//!
+//! The filter is a level (`info`) or a list of directives as `info,pool::lib::mining_pool=debug`,
+//! when the `RUST_LOG` environment variable is set it replace the configured filter.
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+use tracing_subscriber::EnvFilter;
+
+/// Filter used by the roles that are not configured
+pub const DEFAULT_FILTER: &str = "info";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(Error::InvalidFormat(s.to_string())),
+        }
+    }
+}
+
+// `#[default]` on enum variants is not available with the toolchain of the repo
+#[allow(clippy::derivable_impls)]
+impl Default for LogFormat {
+    fn default() -> Self {
+        LogFormat::Text
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidFilter(String),
+    InvalidFormat(String),
+    AlreadyInitialized,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::InvalidFilter(filter) => write!(
+                f,
+                "Invalid log filter `{}`, expected a level (error, warn, info, debug, trace) or \
+                directives as `info,pool=debug`",
+                filter
+            ),
+            Error::InvalidFormat(format) => {
+                write!(f, "Invalid log format `{}`, expected text or json", format)
+            }
+            Error::AlreadyInitialized => write!(f, "Logging already initialized"),
+        }
+    }
+}
+
+fn parse_filter(filter: &str) -> Result<EnvFilter, Error> {
+    EnvFilter::try_new(filter).map_err(|_| Error::InvalidFilter(filter.to_string()))
+}
+
+/// Used to validate the filter when a config is loaded
+pub fn validate_filter(filter: &str) -> Result<(), Error> {
+    parse_filter(filter).map(|_| ())
+}
+
+/// Install the global subscriber, it can be done only once
+pub fn init(filter: &str, format: LogFormat) -> Result<(), Error> {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(from_env) => parse_filter(&from_env)?,
+        Err(_) => parse_filter(filter)?,
+    };
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let installed = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().try_init(),
+    };
+    installed.map_err(|_| Error::AlreadyInitialized)
+}
+
+/// For the roles that do not have a config file: the filter is `RUST_LOG` or `DEFAULT_FILTER` and
+/// the format is `SV2_LOG_FORMAT` (text or json) or text
+pub fn init_from_env() -> Result<(), Error> {
+    let format = match std::env::var("SV2_LOG_FORMAT") {
+        Ok(format) => format.parse()?,
+        Err(_) => LogFormat::default(),
+    };
+    init(DEFAULT_FILTER, format)
+}
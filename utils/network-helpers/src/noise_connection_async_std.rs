@@ -8,6 +8,7 @@ use async_std::{
 use binary_sv2::{Deserialize, Serialize};
 use core::convert::TryInto;
 use std::time::Duration;
+use tracing::{debug, info_span, warn, Instrument};
 
 use binary_sv2::GetSize;
 use codec_sv2::{
@@ -30,6 +31,10 @@ impl Connection {
         Receiver<StandardEitherFrame<Message>>,
         Sender<StandardEitherFrame<Message>>,
     ) {
+        let span = match stream.peer_addr() {
+            Ok(remote) => info_span!("connection", %remote),
+            Err(_) => info_span!("connection"),
+        };
         let (mut reader, writer) = (stream.clone(), stream.clone());
 
         let (sender_incoming, receiver_incoming): (
@@ -49,55 +54,63 @@ impl Connection {
         let cloned2 = connection.clone();
 
         // RECEIVE AND PARSE INCOMING MESSAGES FROM TCP STREAM
-        task::spawn(async move {
-            let mut decoder = StandardNoiseDecoder::<Message>::new();
-
-            loop {
-                let writable = decoder.writable();
-                match reader.read_exact(writable).await {
-                    Ok(_) => {
-                        let mut connection = cloned1.lock().await;
-
-                        if let Ok(x) = decoder.next_frame(&mut connection.state) {
-                            sender_incoming.send(x).await.unwrap();
+        task::spawn(
+            async move {
+                let mut decoder = StandardNoiseDecoder::<Message>::new();
+
+                loop {
+                    let writable = decoder.writable();
+                    match reader.read_exact(writable).await {
+                        Ok(_) => {
+                            let mut connection = cloned1.lock().await;
+
+                            if let Ok(x) = decoder.next_frame(&mut connection.state) {
+                                sender_incoming.send(x).await.unwrap();
+                            }
+                        }
+                        Err(e) => {
+                            debug!(error = %e, "connection closed");
+                            let _ = reader.shutdown(async_std::net::Shutdown::Both);
+                            break;
                         }
-                    }
-                    Err(_) => {
-                        let _ = reader.shutdown(async_std::net::Shutdown::Both);
-                        break;
                     }
                 }
             }
-        });
+            .instrument(span.clone()),
+        );
 
         let receiver_outgoing_cloned = receiver_outgoing.clone();
 
         // ENCODE AND SEND INCOMING MESSAGES TO TCP STREAM
-        task::spawn(async move {
-            let mut encoder = codec_sv2::NoiseEncoder::<Message>::new();
-
-            loop {
-                let received = receiver_outgoing.recv().await;
-                match received {
-                    Ok(frame) => {
-                        let mut connection = cloned2.lock().await;
-                        let b = encoder.encode(frame, &mut connection.state).unwrap();
-                        let b = b.as_ref();
-
-                        match (&writer).write_all(b).await {
-                            Ok(_) => (),
-                            Err(_) => {
-                                let _ = writer.shutdown(async_std::net::Shutdown::Both);
+        task::spawn(
+            async move {
+                let mut encoder = codec_sv2::NoiseEncoder::<Message>::new();
+
+                loop {
+                    let received = receiver_outgoing.recv().await;
+                    match received {
+                        Ok(frame) => {
+                            let mut connection = cloned2.lock().await;
+                            let b = encoder.encode(frame, &mut connection.state).unwrap();
+                            let b = b.as_ref();
+
+                            match (&writer).write_all(b).await {
+                                Ok(_) => (),
+                                Err(e) => {
+                                    warn!(error = %e, "write failed");
+                                    let _ = writer.shutdown(async_std::net::Shutdown::Both);
+                                }
                             }
                         }
-                    }
-                    Err(_) => {
-                        let _ = writer.shutdown(async_std::net::Shutdown::Both);
-                        break;
-                    }
-                };
+                        Err(_) => {
+                            let _ = writer.shutdown(async_std::net::Shutdown::Both);
+                            break;
+                        }
+                    };
+                }
             }
-        });
+            .instrument(span),
+        );
 
         // DO THE NOISE HANDSHAKE
         let transport_mode = match role {
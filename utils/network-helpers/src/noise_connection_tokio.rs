@@ -1,13 +1,14 @@
 use async_channel::{bounded, Receiver, Sender};
 use binary_sv2::{Deserialize, Serialize};
 use core::convert::TryInto;
-use std::{sync::Arc, time::Duration};
+use std::{io::ErrorKind, sync::Arc, time::Duration};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     sync::Mutex,
     task,
 };
+use tracing::{debug, info_span, warn, Instrument};
 
 use binary_sv2::GetSize;
 use codec_sv2::{
@@ -29,6 +30,10 @@ impl Connection {
         Receiver<StandardEitherFrame<Message>>,
         Sender<StandardEitherFrame<Message>>,
     ) {
+        let span = match stream.peer_addr() {
+            Ok(remote) => info_span!("connection", %remote),
+            Err(_) => info_span!("connection"),
+        };
         let (mut reader, mut writer) = stream.into_split();
 
         let (sender_incoming, receiver_incoming): (
@@ -48,58 +53,71 @@ impl Connection {
         let cloned2 = connection.clone();
 
         // RECEIVE AND PARSE INCOMING MESSAGES FROM TCP STREAM
-        task::spawn(async move {
-            let mut decoder = StandardNoiseDecoder::<Message>::new();
-
-            loop {
-                let writable = decoder.writable();
-                match reader.read_exact(writable).await {
-                    Ok(_) => {
-                        let mut connection = cloned1.lock().await;
-
-                        if let Ok(x) = decoder.next_frame(&mut connection.state) {
-                            sender_incoming.send(x).await.unwrap();
+        // When the connection is closed the incoming channel is closed too, so that the role
+        // reinitialize everything
+        task::spawn(
+            async move {
+                let mut decoder = StandardNoiseDecoder::<Message>::new();
+
+                loop {
+                    let writable = decoder.writable();
+                    match reader.read_exact(writable).await {
+                        Ok(_) => {
+                            let mut connection = cloned1.lock().await;
+
+                            if let Ok(x) = decoder.next_frame(&mut connection.state) {
+                                if sender_incoming.send(x).await.is_err() {
+                                    debug!("incoming messages no more received");
+                                    break;
+                                }
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                            debug!("connection closed by the remote");
+                            break;
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "read failed, connection closed");
+                            break;
                         }
-                    }
-                    Err(_) => {
-                        // Just fail and force to reinitialize everything
-                        panic!()
                     }
                 }
             }
-        });
+            .instrument(span.clone()),
+        );
 
         let receiver_outgoing_cloned = receiver_outgoing.clone();
 
         // ENCODE AND SEND INCOMING MESSAGES TO TCP STREAM
-        task::spawn(async move {
-            let mut encoder = codec_sv2::NoiseEncoder::<Message>::new();
-
-            loop {
-                let received = receiver_outgoing.recv().await;
-                match received {
-                    Ok(frame) => {
-                        let mut connection = cloned2.lock().await;
-                        let b = encoder.encode(frame, &mut connection.state).unwrap();
-                        let b = b.as_ref();
-
-                        match (&mut writer).write_all(b).await {
-                            Ok(_) => (),
-                            Err(_) => {
+        // When the connection is closed the outgoing channel is closed too
+        task::spawn(
+            async move {
+                let mut encoder = codec_sv2::NoiseEncoder::<Message>::new();
+
+                loop {
+                    let received = receiver_outgoing.recv().await;
+                    match received {
+                        Ok(frame) => {
+                            let mut connection = cloned2.lock().await;
+                            let b = encoder.encode(frame, &mut connection.state).unwrap();
+                            let b = b.as_ref();
+
+                            if let Err(e) = (&mut writer).write_all(b).await {
+                                warn!(error = %e, "write failed, connection closed");
                                 let _ = writer.shutdown().await;
-                                // Just fail and force to reinitialize everything
-                                panic!()
+                                break;
                             }
                         }
-                    }
-                    Err(_) => {
-                        // Just fail and force to reinitilize everything
-                        let _ = writer.shutdown().await;
-                        panic!()
-                    }
-                };
+                        Err(_) => {
+                            debug!("outgoing messages no more sent, connection closed");
+                            let _ = writer.shutdown().await;
+                            break;
+                        }
+                    };
+                }
             }
-        });
+            .instrument(span),
+        );
 
         // DO THE NOISE HANDSHAKE
         let transport_mode = match role {
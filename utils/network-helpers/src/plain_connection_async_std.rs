@@ -6,6 +6,7 @@ use async_std::{
 };
 use binary_sv2::{Deserialize, Serialize};
 use core::convert::TryInto;
+use tracing::{debug, info_span, warn, Instrument};
 
 use binary_sv2::GetSize;
 use codec_sv2::{StandardDecoder, StandardEitherFrame};
@@ -22,6 +23,10 @@ impl PlainConnection {
         Receiver<StandardEitherFrame<Message>>,
         Sender<StandardEitherFrame<Message>>,
     ) {
+        let span = match stream.peer_addr() {
+            Ok(remote) => info_span!("connection", %remote),
+            Err(_) => info_span!("connection"),
+        };
         let (mut reader, writer) = (stream.clone(), stream);
 
         let (sender_incoming, receiver_incoming): (
@@ -34,49 +39,57 @@ impl PlainConnection {
         ) = bounded(capacity);
 
         // RECEIVE AND PARSE INCOMING MESSAGES FROM TCP STREAM
-        task::spawn(async move {
-            let mut decoder = StandardDecoder::<Message>::new();
+        task::spawn(
+            async move {
+                let mut decoder = StandardDecoder::<Message>::new();
 
-            loop {
-                let writable = decoder.writable();
-                match reader.read_exact(writable).await {
-                    Ok(_) => {
-                        if let Ok(x) = decoder.next_frame() {
-                            sender_incoming.send(x.into()).await.unwrap();
+                loop {
+                    let writable = decoder.writable();
+                    match reader.read_exact(writable).await {
+                        Ok(_) => {
+                            if let Ok(x) = decoder.next_frame() {
+                                sender_incoming.send(x.into()).await.unwrap();
+                            }
+                        }
+                        Err(e) => {
+                            debug!(error = %e, "connection closed");
+                            let _ = reader.shutdown(async_std::net::Shutdown::Both);
+                            break;
                         }
-                    }
-                    Err(_) => {
-                        let _ = reader.shutdown(async_std::net::Shutdown::Both);
-                        break;
                     }
                 }
             }
-        });
+            .instrument(span.clone()),
+        );
 
         // ENCODE AND SEND INCOMING MESSAGES TO TCP STREAM
-        task::spawn(async move {
-            let mut encoder = codec_sv2::Encoder::<Message>::new();
+        task::spawn(
+            async move {
+                let mut encoder = codec_sv2::Encoder::<Message>::new();
 
-            loop {
-                let received = receiver_outgoing.recv().await;
-                match received {
-                    Ok(frame) => {
-                        let b = encoder.encode(frame.try_into().unwrap()).unwrap();
+                loop {
+                    let received = receiver_outgoing.recv().await;
+                    match received {
+                        Ok(frame) => {
+                            let b = encoder.encode(frame.try_into().unwrap()).unwrap();
 
-                        match (&writer).write_all(b).await {
-                            Ok(_) => (),
-                            Err(_) => {
-                                let _ = writer.shutdown(async_std::net::Shutdown::Both);
+                            match (&writer).write_all(b).await {
+                                Ok(_) => (),
+                                Err(e) => {
+                                    warn!(error = %e, "write failed");
+                                    let _ = writer.shutdown(async_std::net::Shutdown::Both);
+                                }
                             }
                         }
-                    }
-                    Err(_) => {
-                        let _ = writer.shutdown(async_std::net::Shutdown::Both);
-                        break;
-                    }
-                };
+                        Err(_) => {
+                            let _ = writer.shutdown(async_std::net::Shutdown::Both);
+                            break;
+                        }
+                    };
+                }
             }
-        });
+            .instrument(span),
+        );
 
         (receiver_incoming, sender_outgoing)
     }
@@ -1,11 +1,13 @@
 use async_channel::{bounded, Receiver, Sender};
 use binary_sv2::{Deserialize, Serialize};
 use core::convert::TryInto;
+use std::io::ErrorKind;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
     task,
 };
+use tracing::{debug, info_span, warn, Instrument};
 
 use binary_sv2::GetSize;
 use codec_sv2::{StandardDecoder, StandardEitherFrame};
@@ -21,6 +23,10 @@ impl PlainConnection {
         Receiver<StandardEitherFrame<Message>>,
         Sender<StandardEitherFrame<Message>>,
     ) {
+        let span = match stream.peer_addr() {
+            Ok(remote) => info_span!("connection", %remote),
+            Err(_) => info_span!("connection"),
+        };
         let (mut reader, mut writer) = stream.into_split();
 
         let (sender_incoming, receiver_incoming): (
@@ -33,50 +39,65 @@ impl PlainConnection {
         ) = bounded(10); // TODO caller should provide this param
 
         // RECEIVE AND PARSE INCOMING MESSAGES FROM TCP STREAM
-        task::spawn(async move {
-            let mut decoder = StandardDecoder::<Message>::new();
+        // When the connection is closed the incoming channel is closed too, so that the role
+        // reinitialize everything
+        task::spawn(
+            async move {
+                let mut decoder = StandardDecoder::<Message>::new();
 
-            loop {
-                let writable = decoder.writable();
-                match reader.read_exact(writable).await {
-                    Ok(_) => {
-                        if let Ok(x) = decoder.next_frame() {
-                            sender_incoming.send(x.into()).await.unwrap();
+                loop {
+                    let writable = decoder.writable();
+                    match reader.read_exact(writable).await {
+                        Ok(_) => {
+                            if let Ok(x) = decoder.next_frame() {
+                                if sender_incoming.send(x.into()).await.is_err() {
+                                    debug!("incoming messages no more received");
+                                    break;
+                                }
+                            }
+                        }
+                        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+                            debug!("connection closed by the remote");
+                            break;
+                        }
+                        Err(e) => {
+                            warn!(error = %e, "read failed, connection closed");
+                            break;
                         }
-                    }
-                    Err(_) => {
-                        // Just fail and force to reinitialize everything
-                        panic!()
                     }
                 }
             }
-        });
+            .instrument(span.clone()),
+        );
 
         // ENCODE AND SEND INCOMING MESSAGES TO TCP STREAM
-        task::spawn(async move {
-            let mut encoder = codec_sv2::Encoder::<Message>::new();
+        // When the connection is closed the outgoing channel is closed too
+        task::spawn(
+            async move {
+                let mut encoder = codec_sv2::Encoder::<Message>::new();
 
-            loop {
-                let received = receiver_outgoing.recv().await;
-                match received {
-                    Ok(frame) => {
-                        let b = encoder.encode(frame.try_into().unwrap()).unwrap();
+                loop {
+                    let received = receiver_outgoing.recv().await;
+                    match received {
+                        Ok(frame) => {
+                            let b = encoder.encode(frame.try_into().unwrap()).unwrap();
 
-                        match (&mut writer).write_all(b).await {
-                            Ok(_) => (),
-                            Err(_) => {
+                            if let Err(e) = (&mut writer).write_all(b).await {
+                                warn!(error = %e, "write failed, connection closed");
                                 let _ = writer.shutdown().await;
+                                break;
                             }
                         }
-                    }
-                    Err(_) => {
-                        // Just fail and force to reinitilize everything
-                        let _ = writer.shutdown().await;
-                        panic!()
-                    }
-                };
+                        Err(_) => {
+                            debug!("outgoing messages no more sent, connection closed");
+                            let _ = writer.shutdown().await;
+                            break;
+                        }
+                    };
+                }
             }
-        });
+            .instrument(span),
+        );
 
         (receiver_incoming, sender_outgoing)
     }